@@ -2,11 +2,8 @@ use std::time::Duration;
 
 use rustyline::DefaultEditor;
 use vex_v5_serial::{
-    connection::{
-        bluetooth::{self, BluetoothError},
-        Connection,
-    },
-    packets::dash::{DashScreen, SelectDashPacket, SelectDashPayload},
+    packets::dash::{SelectDashPacket, SelectDashPayload},
+    prelude::*,
 };
 
 #[tokio::main]
@@ -23,7 +20,7 @@ async fn main() -> Result<(), BluetoothError> {
     let devices = bluetooth::find_devices(Duration::from_secs(10), Some(1)).await?;
 
     // Open a connection to the device
-    let mut connection = devices[0].connect().await?;
+    let mut connection = devices[0].connect(Duration::from_secs(30)).await?;
 
     if !connection.is_paired().await? {
         connection.request_pairing().await?;
@@ -2,10 +2,8 @@ use std::time::Duration;
 
 use log::info;
 use vex_v5_serial::{
-    connection::{
-        serial::{self, SerialError},
-        Connection,
-    }, packets::device::{GetDeviceStatusPacket, GetDeviceStatusReplyPacket}
+    packets::device::{GetDeviceStatusPacket, GetDeviceStatusReplyPacket},
+    prelude::*,
 };
 
 #[tokio::main]
@@ -20,6 +18,20 @@ async fn main() -> Result<(), SerialError> {
 
     let devices = serial::find_devices()?;
 
+    // Show what's plugged in before picking a device
+    for device in &devices {
+        match serial::probe(device).await {
+            Ok(info) => info!(
+                "{}: {:?} running VEXos {:?}, serial number {:08X?}",
+                device.system_port(),
+                info.product_type,
+                info.version,
+                info.ssn
+            ),
+            Err(err) => info!("{}: couldn't probe: {}", device.system_port(), err),
+        }
+    }
+
     // Open a connection to the device
     let mut connection = devices[0].connect(Duration::from_secs(30))?;
 
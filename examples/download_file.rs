@@ -2,19 +2,11 @@ use std::{str::FromStr, time::Duration};
 
 use tokio::{fs::File, io::AsyncWriteExt};
 use vex_v5_serial::{
-    commands::file::DownloadFile,
-    connection::{
-        serial::{self, SerialError},
-        Connection,
+    packets::radio::{
+        RadioChannel, SelectRadioChannelPacket, SelectRadioChannelPayload,
+        SelectRadioChannelReplyPacket,
     },
-    packets::{
-        file::{FileTransferTarget, FileVendor},
-        radio::{
-            RadioChannel, SelectRadioChannelPacket, SelectRadioChannelPayload,
-            SelectRadioChannelReplyPacket,
-        },
-    },
-    string::FixedString,
+    prelude::*,
 };
 
 #[tokio::main]
@@ -56,14 +48,17 @@ async fn main() -> Result<(), SerialError> {
             vendor: FileVendor::User,
             target: Some(FileTransferTarget::Qspi),
             load_addr: 0x03800000,
+            resume_from: 0,
             progress_callback: Some(Box::new(move |progress| {
                 log::info!("{}: {:.2}%", file, progress);
             }) as Box<dyn FnMut(f32) + Send>),
         })
         .await?;
 
+    println!("{}", download.summary);
+
     let mut file = File::create_new(file).await?;
-    file.write_all(&download).await?;
+    file.write_all(&download.data).await?;
 
     Ok(())
 }
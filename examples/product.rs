@@ -2,11 +2,8 @@ use std::time::Duration;
 
 use log::info;
 use vex_v5_serial::{
-    connection::{
-        serial::{self, SerialError},
-        Connection,
-    },
     packets::system::{GetSystemVersionPacket, GetSystemVersionReplyPacket},
+    prelude::*,
 };
 
 #[tokio::main]
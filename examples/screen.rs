@@ -1,14 +1,7 @@
 use std::time::Duration;
 
 use tokio::time::sleep;
-use vex_v5_serial::{
-    commands::screen::{MockTap, OpenDashScreen, ScreenCapture},
-    connection::{
-        serial::{self, SerialError},
-        Connection,
-    },
-    packets::dash::DashScreen,
-};
+use vex_v5_serial::prelude::*;
 
 #[tokio::main]
 async fn main() -> Result<(), SerialError> {
@@ -0,0 +1,64 @@
+use std::{io::Write, str::FromStr, time::Duration};
+
+use flate2::write::GzDecoder;
+use vex_v5_serial::prelude::*;
+
+#[tokio::main]
+async fn main() -> Result<(), SerialError> {
+    // Initialize the logger
+    simplelog::TermLogger::init(
+        log::LevelFilter::Debug,
+        simplelog::Config::default(),
+        simplelog::TerminalMode::Mixed,
+        simplelog::ColorChoice::Always,
+    )
+    .unwrap();
+
+    // Find all vex devices on the serial ports
+    let devices = serial::find_devices()?;
+
+    // Open a connection to the device
+    let mut connection = devices[0].connect(Duration::from_secs(30))?;
+
+    // Programs uploaded with compression are stored gzipped
+    let file = "slot_1.bin";
+    let file_name = FixedString::from_str(file).unwrap();
+    let metadata = BrainFs::new(&mut connection)
+        .metadata(FileVendor::User, &file_name)
+        .await?
+        .expect("The file should exist");
+
+    let mut reader = BrainFileReader::open(
+        &mut connection,
+        DownloadFile {
+            file_name,
+            size: metadata.size,
+            vendor: FileVendor::User,
+            target: Some(FileTransferTarget::Qspi),
+            load_addr: metadata.load_address,
+            resume_from: 0,
+            progress_callback: None,
+        },
+    )
+    .await?;
+
+    // Decompress the file as it's read instead of downloading all of it first
+    let mut decoder = GzDecoder::new(Vec::new());
+    let mut buf = [0; 1024];
+    loop {
+        let len = reader.read(&mut buf).await?;
+        if len == 0 {
+            break;
+        }
+        decoder.write_all(&buf[..len])?;
+    }
+    let program = decoder.finish()?;
+
+    println!(
+        "{file}: {} bytes on the brain, {} bytes decompressed",
+        reader.file_size(),
+        program.len()
+    );
+
+    Ok(())
+}
@@ -3,14 +3,11 @@ use std::time::Duration;
 use log::{error, info};
 use tokio::time::sleep;
 use vex_v5_serial::{
-    connection::{
-        serial::{self, SerialError},
-        Connection,
-    },
     packets::{
         match_mode::{MatchMode, SetMatchModePacket, SetMatchModePayload, SetMatchModeReplyPacket},
         system::{GetSystemVersionPacket, GetSystemVersionReplyPacket},
     },
+    prelude::*,
 };
 
 #[tokio::main]
@@ -37,12 +34,9 @@ async fn main() -> Result<(), SerialError> {
         )
         .await?;
 
-    match response.payload.product_type {
-        vex_v5_serial::packets::system::ProductType::Brain => {
-            error!("You must be connected to the Brain over controller to use field control");
-            return Ok(());
-        }
-        vex_v5_serial::packets::system::ProductType::Controller => {}
+    if response.payload.product_type != vex_v5_serial::packets::system::ProductType::Controller {
+        error!("You must be connected to the Brain over controller to use field control");
+        return Ok(());
     }
 
     info!("Setting match mode to auto");
@@ -1,19 +1,6 @@
 use std::time::Duration;
 
-use vex_v5_serial::{
-    commands::file::{ProgramData, UploadProgram},
-    connection::{
-        serial::{self, SerialError},
-        Connection,
-    },
-    packets::{
-        file::FileExitAction,
-        radio::{
-            RadioChannel, SelectRadioChannelPacket, SelectRadioChannelPayload,
-            SelectRadioChannelReplyPacket,
-        },
-    },
-};
+use vex_v5_serial::prelude::*;
 
 #[tokio::main]
 async fn main() -> Result<(), SerialError> {
@@ -39,20 +26,8 @@ async fn main() -> Result<(), SerialError> {
         })
     };
 
-    connection
-        .packet_handshake::<SelectRadioChannelReplyPacket>(
-            Duration::from_millis(500),
-            10,
-            SelectRadioChannelPacket::new(SelectRadioChannelPayload {
-                channel: RadioChannel::Pit,
-            }),
-        )
-        .await?
-        .try_into_inner()
-        .unwrap();
-
     // Upload program file
-    connection
+    let report = connection
         .execute_command(UploadProgram {
             name: "quick".to_string(),
             description: "A basic vexide program".to_string(),
@@ -62,11 +37,28 @@ async fn main() -> Result<(), SerialError> {
             data: ProgramData::Monolith(program_data),
             compress_program: true,
             after_upload: FileExitAction::RunProgram,
+            storage_capacity: None,
+            wireless_pacing: None,
+            download_channel: true,
+            long_text: LongTextPolicy::Reject,
+            low_battery: LowBatteryPolicy::Refuse {
+                threshold: DEFAULT_MIN_BATTERY_PERCENT,
+            },
             ini_callback: Some(callback_generator("INI")),
             lib_callback: Some(callback_generator("Lib")),
             bin_callback: Some(callback_generator("Bin")),
         })
         .await?;
 
+    for file in &report.files {
+        match file.summary() {
+            Some(summary) => println!("{summary}"),
+            None => println!("{}: {:?}", file.file_name, file.outcome),
+        }
+        if let Some(latency) = file.stats.open_latency {
+            println!("  transfer opened in {latency:?}");
+        }
+    }
+
     Ok(())
 }
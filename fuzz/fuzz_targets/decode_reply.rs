@@ -0,0 +1,77 @@
+//! Decodes arbitrary bytes as every reply packet type.
+//!
+//! The first byte of the input picks the reply type from [`REPLIES`], and the rest is decoded
+//! as that type. Decoding may fail, but it must never panic.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use vex_v5_serial::{
+    decode::Decode,
+    packets::{
+        capture::*, controller::*, dash::*, device::*, factory::*, file::*, kv::*, log::*,
+        match_mode::*, radio::*, system::*,
+    },
+};
+
+macro_rules! replies {
+    ($($packet:ty,)*) => {
+        &[$(|data| {
+            _ = <$packet>::decode(data.iter().copied());
+        },)*]
+    };
+}
+
+/// Every reply type that can be decoded.
+///
+/// Only ever append to this list. The selector bytes in the checked-in corpus refer to
+/// positions in it.
+///
+/// The program and file cleanup/format replies are missing because they don't implement
+/// [`Decode`] yet.
+const REPLIES: &[fn(&[u8])] = replies! {
+    GetSystemVersionReplyPacket,
+    GetSystemStatusReplyPacket,
+    GetSystemFlagsReplyPacket,
+    Query1ReplyPacket,
+    GetDeviceStatusReplyPacket,
+    InitFileTransferReplyPacket,
+    ExitFileTransferReplyPacket,
+    WriteFileReplyPacket,
+    ReadFileReplyPacket,
+    LinkFileReplyPacket,
+    GetDirectoryFileCountReplyPacket,
+    GetDirectoryEntryReplyPacket,
+    LoadFileActionReplyPacket,
+    GetFileMetadataReplyPacket,
+    SetFileMetadataReplyPacket,
+    EraseFileReplyPacket,
+    ReadKeyValueReplyPacket,
+    WriteKeyValueReplyPacket,
+    ScreenCaptureReplyPacket,
+    SendDashTouchReplyPacket,
+    SelectDashReplyPacket,
+    GetRadioStatusReplyPacket,
+    SelectRadioChannelReplyPacket,
+    UserFifoReplyPacket,
+    ForceControllerRadioReplyPacket,
+    ControllerRadioModeReplyPacket,
+    ControllerVersionExpectReplyPacket,
+    GetLogCountReplyPacket,
+    ReadLogPageReplyPacket,
+    SetMatchModeReplyPacket,
+    GetFdtStatusReplyPacket,
+    GetFactoryStatusReplyPacket,
+    FactoryEnableReplyPacket,
+    FactoryChallengeReplyPacket,
+    FactoryResponseReplyPacket,
+};
+
+fuzz_target!(|data: &[u8]| {
+    let Some((&selector, data)) = data.split_first() else {
+        return;
+    };
+    if let Some(decode) = REPLIES.get(usize::from(selector)) {
+        decode(data);
+    }
+});
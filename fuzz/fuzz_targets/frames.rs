@@ -0,0 +1,24 @@
+//! Feeds arbitrary bytes to the frame-level parsers: CRC verification and the user port
+//! demuxer.
+//!
+//! The demuxer is fed in chunks whose sizes come from the input, so that frames split across
+//! reads are covered too.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use vex_v5_serial::{crc::verify_cdc2_frame, user_channel::UserChannelDemuxer};
+
+fuzz_target!(|data: &[u8]| {
+    _ = verify_cdc2_frame(data);
+
+    let Some((&chunk_size, stream)) = data.split_first() else {
+        return;
+    };
+    let mut demuxer = UserChannelDemuxer::new();
+    for chunk in stream.chunks(usize::from(chunk_size).max(1)) {
+        demuxer.push(chunk);
+        while demuxer.next_event().is_some() {}
+    }
+    demuxer.flush();
+});
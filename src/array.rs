@@ -0,0 +1,178 @@
+use std::{marker::PhantomData, ops::Deref};
+
+use crate::{
+    decode::{Decode, DecodeError},
+    encode::{Encode, EncodeError},
+    varint::VarU16,
+};
+
+/// An integer type used to prefix a [`CountedVec`] with its length.
+pub trait Count {
+    /// Decodes a length prefix.
+    fn decode_count(data: impl IntoIterator<Item = u8>) -> Result<usize, DecodeError>;
+
+    /// Encodes a length prefix, failing if `count` doesn't fit in this type.
+    fn encode_count(count: usize) -> Result<Vec<u8>, EncodeError>;
+}
+
+macro_rules! impl_count {
+    ($($ty:ty),*) => {
+        $(
+            impl Count for $ty {
+                fn decode_count(data: impl IntoIterator<Item = u8>) -> Result<usize, DecodeError> {
+                    Ok(<$ty>::decode(data)? as usize)
+                }
+
+                fn encode_count(count: usize) -> Result<Vec<u8>, EncodeError> {
+                    <$ty>::try_from(count)
+                        .map(|count| count.to_le_bytes().to_vec())
+                        .map_err(|_| EncodeError::CountTooLarge)
+                }
+            }
+        )*
+    };
+}
+impl_count!(u8, u16, u32);
+
+impl Count for VarU16 {
+    fn decode_count(data: impl IntoIterator<Item = u8>) -> Result<usize, DecodeError> {
+        Ok(VarU16::decode(data)?.into_inner() as usize)
+    }
+
+    fn encode_count(count: usize) -> Result<Vec<u8>, EncodeError> {
+        VarU16::try_from_len(count)
+            .map_err(|_| EncodeError::CountTooLarge)?
+            .encode()
+    }
+}
+
+/// A list of items prefixed by the number of items in it.
+///
+/// `C` is the type of the count (such as `u8` or [`VarU16`]) and `T` is the type of each item.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CountedVec<C, T> {
+    items: Vec<T>,
+    _count: PhantomData<C>,
+}
+
+impl<C, T> CountedVec<C, T> {
+    pub fn new(items: Vec<T>) -> Self {
+        Self {
+            items,
+            _count: PhantomData,
+        }
+    }
+
+    pub fn into_inner(self) -> Vec<T> {
+        self.items
+    }
+}
+
+impl<C, T> Default for CountedVec<C, T> {
+    fn default() -> Self {
+        Self::new(Vec::new())
+    }
+}
+
+impl<C, T> From<Vec<T>> for CountedVec<C, T> {
+    fn from(items: Vec<T>) -> Self {
+        Self::new(items)
+    }
+}
+
+impl<C, T> Deref for CountedVec<C, T> {
+    type Target = [T];
+
+    fn deref(&self) -> &Self::Target {
+        &self.items
+    }
+}
+
+impl<C, T> IntoIterator for CountedVec<C, T> {
+    type Item = T;
+    type IntoIter = std::vec::IntoIter<T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.items.into_iter()
+    }
+}
+
+impl<'a, C, T> IntoIterator for &'a CountedVec<C, T> {
+    type Item = &'a T;
+    type IntoIter = std::slice::Iter<'a, T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.items.iter()
+    }
+}
+
+impl<C: Count, T: Decode> Decode for CountedVec<C, T> {
+    fn decode(data: impl IntoIterator<Item = u8>) -> Result<Self, DecodeError> {
+        let mut data = data.into_iter();
+        let count = C::decode_count(&mut data)?;
+
+        let mut items = Vec::with_capacity(count);
+        for _ in 0..count {
+            items.push(T::decode(&mut data)?);
+        }
+
+        Ok(Self::new(items))
+    }
+}
+
+impl<C: Count, T: Encode> Encode for CountedVec<C, T> {
+    fn encode(&self) -> Result<Vec<u8>, EncodeError> {
+        let mut encoded = C::encode_count(self.items.len())?;
+        for item in &self.items {
+            encoded.extend(item.encode()?);
+        }
+        Ok(encoded)
+    }
+}
+
+/// A blob of raw bytes prefixed by its length.
+pub type LengthPrefixedBytes<C> = CountedVec<C, u8>;
+
+#[cfg(test)]
+mod tests {
+    use super::{CountedVec, LengthPrefixedBytes};
+    use crate::{
+        decode::{Decode, DecodeError},
+        encode::{Encode, EncodeError},
+        varint::VarU16,
+    };
+
+    #[test]
+    fn empty() {
+        let empty = LengthPrefixedBytes::<u16>::default();
+        assert_eq!(empty.encode().unwrap(), [0, 0]);
+        assert_eq!(LengthPrefixedBytes::<u16>::decode([0, 0]).unwrap(), empty);
+    }
+
+    #[test]
+    fn round_trip() {
+        let bytes = LengthPrefixedBytes::<VarU16>::new(vec![0xAB; 0x80]);
+        let encoded = bytes.encode().unwrap();
+        assert_eq!(encoded[..2], [0x80, 0x80]);
+        assert_eq!(LengthPrefixedBytes::<VarU16>::decode(encoded).unwrap(), bytes);
+    }
+
+    #[test]
+    fn max_count() {
+        let full = LengthPrefixedBytes::<u8>::new(vec![1; u8::MAX as usize]);
+        let encoded = full.encode().unwrap();
+        assert_eq!(encoded[0], 0xFF);
+        assert_eq!(LengthPrefixedBytes::<u8>::decode(encoded).unwrap(), full);
+
+        let overfull = LengthPrefixedBytes::<u8>::new(vec![1; u8::MAX as usize + 1]);
+        assert_eq!(overfull.encode(), Err(EncodeError::CountTooLarge));
+    }
+
+    #[test]
+    fn too_short() {
+        assert_eq!(
+            CountedVec::<u8, u16>::decode([2, 0x01, 0x00, 0x02]),
+            Err(DecodeError::PacketTooShort)
+        );
+    }
+}
@@ -0,0 +1,551 @@
+use std::{
+    ops::{Deref, DerefMut},
+    time::Duration,
+};
+
+use log::{debug, warn};
+
+use crate::{
+    connection::Connection,
+    packets::{
+        controller::{
+            ControllerRadioMode, ControllerRadioModePacket, ForceControllerRadioPacket,
+            ForceControllerRadioPayload, UserFifoPacket, UserFifoPayload, USER_FIFO_MAX_WRITE,
+            USER_FIFO_WRITE_OVERHEAD,
+        },
+        radio::{
+            GetRadioStatusPacket, RadioChannel, RadioStatus, SelectRadioChannelPacket,
+            SelectRadioChannelPayload,
+        },
+    },
+};
+
+use super::{poll_until, Command, CommandError};
+
+/// How often the radio status is polled after changing the controller's radio.
+const RADIO_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// How long the controller's link to the brain is given to come back after changing its radio
+/// before failing with [`CommandError::RadioLinkLost`].
+pub const RADIO_LINK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Polls the brain's radio status every 250 ms until a controller is reported as connected,
+/// for up to [`RADIO_LINK_TIMEOUT`].
+async fn wait_for_radio_link<C: Connection + ?Sized>(
+    connection: &mut C,
+) -> Result<RadioStatus, C::Error> {
+    let status = poll_until(
+        connection,
+        RADIO_POLL_INTERVAL,
+        RADIO_LINK_TIMEOUT,
+        async |connection| {
+            // Failures are expected here while the radio is reconnecting.
+            match connection
+                .request(Duration::from_millis(500), 1, GetRadioStatusPacket::new(()))
+                .await
+            {
+                Ok(reply) => match reply.try_into_inner() {
+                    Ok(status) if status.device != 0 => return Ok(Some(status)),
+                    Ok(_) => debug!("Radio link has not come back yet"),
+                    Err(nack) => debug!("Radio status query was NACKed: {nack}"),
+                },
+                Err(e) => debug!("Radio status query failed: {e}"),
+            }
+            Ok(None)
+        },
+    )
+    .await?;
+
+    status.ok_or_else(|| {
+        warn!("Controller radio link did not come back");
+        CommandError::RadioLinkLost.into()
+    })
+}
+
+/// Forces the radio of a tethered controller into a specific mode and channel.
+///
+/// After the radio has been forced, the radio status is polled until the controller's
+/// link to the brain comes back. If it doesn't, [`CommandError::RadioLinkLost`] is returned.
+#[derive(Debug, Clone, Copy)]
+pub struct ForceRadio {
+    pub mode: ControllerRadioMode,
+    pub channel: u8,
+}
+impl Command for ForceRadio {
+    type Output = RadioStatus;
+
+    async fn execute<C: Connection + ?Sized>(
+        self,
+        connection: &mut C,
+    ) -> Result<Self::Output, C::Error> {
+        let connection_type = connection.connection_type();
+        if !connection_type.is_controller() {
+            return Err(CommandError::UnsupportedConnectionType(connection_type).into());
+        }
+
+        connection
+            .request(
+                Duration::from_millis(500),
+                5,
+                ForceControllerRadioPacket::new(ForceControllerRadioPayload {
+                    mode: self.mode,
+                    channel: self.channel,
+                }),
+            )
+            .await?
+            .try_into_inner()?;
+
+        wait_for_radio_link(connection).await
+    }
+}
+
+/// Switches the radio of a tethered controller into a different mode.
+///
+/// The controller's screen changes to show the new mode. Modes other than the one used to
+/// drive a robot, such as Bluetooth pairing, drop the controller's link to the brain, so the
+/// brain's radio status can only be used to check that the link came back when switching to a
+/// mode that keeps it.
+#[derive(Debug, Clone, Copy)]
+pub struct SetControllerRadioMode {
+    pub mode: ControllerRadioMode,
+    /// Whether to poll the radio status until the controller's link to the brain comes back,
+    /// returning [`CommandError::RadioLinkLost`] if it doesn't.
+    pub wait_for_link: bool,
+}
+impl Command for SetControllerRadioMode {
+    type Output = Option<RadioStatus>;
+
+    async fn execute<C: Connection + ?Sized>(
+        self,
+        connection: &mut C,
+    ) -> Result<Self::Output, C::Error> {
+        let connection_type = connection.connection_type();
+        if !connection_type.is_controller() {
+            return Err(CommandError::UnsupportedConnectionType(connection_type).into());
+        }
+
+        debug!("Switching the controller radio to mode {:?}", self.mode);
+        connection
+            .request(
+                Duration::from_millis(500),
+                5,
+                ControllerRadioModePacket::new(self.mode),
+            )
+            .await?
+            .try_into_inner()?;
+
+        if !self.wait_for_link {
+            return Ok(None);
+        }
+        wait_for_radio_link(connection).await.map(Some)
+    }
+}
+
+/// Switches the radio channel, waiting for a controller's link to come back afterwards.
+async fn select_radio_channel<C: Connection + ?Sized>(
+    connection: &mut C,
+    channel: RadioChannel,
+) -> Result<(), C::Error> {
+    debug!("Switching to the {:?} radio channel", channel);
+    connection
+        .request(
+            Duration::from_millis(500),
+            10,
+            SelectRadioChannelPacket::new(SelectRadioChannelPayload { channel }),
+        )
+        .await?
+        .try_into_inner()?;
+
+    if connection.connection_type().is_controller() {
+        wait_for_radio_link(connection).await?;
+    }
+    Ok(())
+}
+
+/// Keeps the radio on [`RadioChannel::Download`] until released, then switches back to
+/// [`RadioChannel::Pit`].
+///
+/// The guard dereferences to the connection, so several commands can be run in one download
+/// channel session before calling [`release`](Self::release).
+///
+/// If the guard is dropped without being released, for example because a command failed and
+/// `?` returned early, the pit channel is restored from [`Drop`] as a best effort. This
+/// blocks the current thread and only works inside a multi-threaded tokio runtime, so
+/// [`release`](Self::release) should be called whenever possible. Nothing can be done if the
+/// process exits without dropping the guard, such as when it's killed by Ctrl-C without a
+/// signal handler.
+pub struct DownloadChannelGuard<'a, C: Connection + ?Sized> {
+    connection: &'a mut C,
+    released: bool,
+}
+impl<'a, C: Connection + ?Sized> DownloadChannelGuard<'a, C> {
+    /// Switches the radio to the download channel.
+    pub async fn acquire(connection: &'a mut C) -> Result<Self, C::Error> {
+        select_radio_channel(connection, RadioChannel::Download).await?;
+        Ok(Self {
+            connection,
+            released: false,
+        })
+    }
+
+    /// Switches the radio back to the pit channel.
+    pub async fn release(mut self) -> Result<(), C::Error> {
+        self.released = true;
+        select_radio_channel(self.connection, RadioChannel::Pit).await
+    }
+}
+impl<C: Connection + ?Sized> Deref for DownloadChannelGuard<'_, C> {
+    type Target = C;
+
+    fn deref(&self) -> &C {
+        self.connection
+    }
+}
+impl<C: Connection + ?Sized> DerefMut for DownloadChannelGuard<'_, C> {
+    fn deref_mut(&mut self) -> &mut C {
+        self.connection
+    }
+}
+impl<C: Connection + ?Sized> Drop for DownloadChannelGuard<'_, C> {
+    fn drop(&mut self) {
+        if self.released {
+            return;
+        }
+        warn!("Download channel guard dropped without being released");
+
+        #[cfg(any(feature = "serial", feature = "bluetooth"))]
+        if let Ok(handle) = tokio::runtime::Handle::try_current() {
+            if handle.runtime_flavor() == tokio::runtime::RuntimeFlavor::MultiThread {
+                let result = tokio::task::block_in_place(|| {
+                    handle.block_on(select_radio_channel(self.connection, RadioChannel::Pit))
+                });
+                if let Err(err) = result {
+                    warn!("Failed to restore the pit channel: {}", err);
+                }
+                return;
+            }
+        }
+
+        warn!("Could not restore the pit channel without a multi-threaded tokio runtime");
+    }
+}
+
+/// How a [`WriteUserFifo`] splits up and retries a write.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UserFifoSettings {
+    /// The largest packet to send, including its header and CRC.
+    ///
+    /// Writes are split into chunks that fit, up to [`USER_FIFO_MAX_WRITE`] bytes each.
+    pub max_packet_size: usize,
+    /// How long to wait for each chunk to be acknowledged.
+    pub timeout: Duration,
+    /// How many times a chunk is retried while the FIFO is full.
+    pub retries: usize,
+    /// The gap left before a retry, multiplied by the number of attempts so far.
+    ///
+    /// This is applied through [`Connection::set_send_pacing`], so connections that don't
+    /// support pacing retry straight away.
+    pub backoff: Duration,
+}
+impl UserFifoSettings {
+    /// Returns settings that suit the radio channel the controller is on.
+    ///
+    /// [`RadioChannel::Pit`] keeps to the 224 byte writes that have always worked while
+    /// driving, while [`RadioChannel::Download`] fills each packet.
+    pub fn for_channel(channel: RadioChannel) -> Self {
+        let max_write = match channel {
+            RadioChannel::Pit => 224,
+            RadioChannel::Download => USER_FIFO_MAX_WRITE,
+        };
+        Self {
+            max_packet_size: max_write + USER_FIFO_WRITE_OVERHEAD,
+            timeout: Duration::from_millis(100),
+            retries: 5,
+            backoff: Duration::from_millis(10),
+        }
+    }
+
+    /// Returns the number of bytes written by each packet.
+    pub fn chunk_size(&self) -> usize {
+        self.max_packet_size
+            .saturating_sub(USER_FIFO_WRITE_OVERHEAD)
+            .clamp(1, USER_FIFO_MAX_WRITE)
+    }
+}
+impl Default for UserFifoSettings {
+    fn default() -> Self {
+        Self::for_channel(RadioChannel::Pit)
+    }
+}
+
+/// Writes to a user program's stdin through the user FIFO packet.
+///
+/// This is how [`Connection::write_user`] writes over a controller, which has no user port.
+/// A chunk that's NACKed or goes unanswered is taken to mean the brain's FIFO is full, and is
+/// retried after backing off. If a chunk still isn't accepted, the number of bytes written
+/// before it is returned, or the error if nothing was written at all.
+#[derive(Debug, Clone, Copy)]
+pub struct WriteUserFifo<'a> {
+    pub data: &'a [u8],
+    pub settings: UserFifoSettings,
+}
+impl Command for WriteUserFifo<'_> {
+    type Output = usize;
+
+    async fn execute<C: Connection + ?Sized>(
+        self,
+        connection: &mut C,
+    ) -> Result<Self::Output, C::Error> {
+        let previous_pacing = connection.send_pacing();
+        let result = write_user_fifo(connection, self.data, self.settings).await;
+        connection.set_send_pacing(previous_pacing);
+        result
+    }
+}
+
+async fn write_user_fifo<C: Connection + ?Sized>(
+    connection: &mut C,
+    data: &[u8],
+    settings: UserFifoSettings,
+) -> Result<usize, C::Error> {
+    let previous_pacing = connection.send_pacing();
+    let mut written = 0;
+
+    for chunk in data.chunks(settings.chunk_size()) {
+        let mut attempt = 0;
+        loop {
+            let result = connection
+                .request(
+                    settings.timeout,
+                    0,
+                    UserFifoPacket::new(UserFifoPayload {
+                        channel: 2, // stdio channel
+                        write: Some(chunk.to_vec()),
+                    }),
+                )
+                .await
+                .and_then(|reply| Ok(reply.try_into_inner()?));
+
+            match result {
+                Ok(_) => break,
+                Err(err) if attempt < settings.retries => {
+                    attempt += 1;
+                    debug!("User FIFO write was not accepted, backing off: {err}");
+                    let backoff = settings.backoff * attempt as u32;
+                    let pacing = previous_pacing.map_or(backoff, |pacing| pacing.max(backoff));
+                    connection.set_send_pacing(Some(pacing));
+                }
+                Err(err) if written > 0 => {
+                    warn!("User FIFO is still full after {written} bytes: {err}");
+                    return Ok(written);
+                }
+                Err(err) => return Err(err),
+            }
+        }
+
+        connection.set_send_pacing(previous_pacing);
+        written += chunk.len();
+    }
+
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        DownloadChannelGuard, ForceRadio, SetControllerRadioMode, UserFifoSettings, WriteUserFifo,
+        RADIO_LINK_TIMEOUT, RADIO_POLL_INTERVAL,
+    };
+    use crate::{
+        commands::{Command, CommandError},
+        connection::{
+            mock::{block_on, cdc2_reply, fifo_reply, MockConnection, MockError, Responder},
+            ConnectionType,
+        },
+        packets::{cdc2::Cdc2Ack, controller::ControllerRadioMode, radio::RadioChannel},
+    };
+
+    /// Returns the bytes written by a sent user FIFO packet.
+    fn fifo_write(packet: &[u8]) -> &[u8] {
+        // Skip the header, command IDs, and the one or two byte payload size
+        let payload_start = if packet[6] & 0x80 != 0 { 8 } else { 7 };
+        // Skip the channel to reach the write length
+        let len = packet[payload_start + 1] as usize;
+        &packet[payload_start + 2..payload_start + 2 + len]
+    }
+
+    #[test]
+    fn set_controller_radio_mode() {
+        let command = SetControllerRadioMode {
+            mode: ControllerRadioMode(0x02),
+            wait_for_link: true,
+        };
+
+        let mut wired = MockConnection::default();
+        assert!(matches!(
+            block_on(command.execute(&mut wired)),
+            Err(MockError::Command(CommandError::UnsupportedConnectionType(
+                ConnectionType::Wired
+            )))
+        ));
+        assert!(wired.sent.is_empty());
+
+        // Controller commands reply with command ID 0x58 rather than 0x56
+        let mut mode_reply = cdc2_reply(0x41, &[]);
+        mode_reply[2] = 0x58;
+        // A connected controller reports a non-zero device in its radio status
+        let mut controller = MockConnection {
+            connection_type: Some(ConnectionType::Controller),
+            replies: [mode_reply, cdc2_reply(0x26, &[4, 100, 0, 0xC0, 0xFF, 1, 0])].into(),
+            ..Default::default()
+        };
+        let status = block_on(command.execute(&mut controller)).unwrap().unwrap();
+        assert_eq!(status.device, 4);
+        // The mode is the last byte before the CRC
+        let sent = &controller.sent[0];
+        assert_eq!(sent[5], 0x41);
+        assert_eq!(sent[sent.len() - 3], 0x02);
+    }
+
+    #[test]
+    fn force_radio_link() {
+        let command = ForceRadio {
+            mode: ControllerRadioMode(0x02),
+            channel: 7,
+        };
+        let mut force_reply = cdc2_reply(0x3F, &[]);
+        force_reply[2] = 0x58;
+        let mut controller = MockConnection {
+            connection_type: Some(ConnectionType::Controller),
+            replies: [
+                force_reply.clone(),
+                cdc2_reply(0x26, &[0, 0, 0, 0, 0, 0, 0]),
+                cdc2_reply(0x26, &[4, 100, 0, 0xC0, 0xFF, 1, 0]),
+            ]
+            .into(),
+            ..Default::default()
+        };
+        let status = block_on(command.execute(&mut controller)).unwrap();
+        assert_eq!(status.device, 4);
+        assert_eq!(controller.sleeps, [RADIO_POLL_INTERVAL]);
+        let sent = &controller.sent[0];
+        assert_eq!(sent[sent.len() - 4..sent.len() - 2], [0x02, 7]);
+
+        // A link that stays down is reported once the time budget runs out
+        controller.sleeps.clear();
+        controller.respond = Some(Responder(Box::new(move |sent| match sent[5] {
+            0x3F => force_reply.clone(),
+            _ => cdc2_reply(0x26, &[0, 0, 0, 0, 0, 0, 0]),
+        })));
+        assert!(matches!(
+            block_on(command.execute(&mut controller)),
+            Err(MockError::Command(CommandError::RadioLinkLost))
+        ));
+        assert_eq!(
+            controller.sleeps.len() as u32,
+            RADIO_LINK_TIMEOUT.as_millis() as u32 / RADIO_POLL_INTERVAL.as_millis() as u32
+        );
+    }
+
+    #[test]
+    fn download_channel_guard() {
+        let mut connection = MockConnection {
+            replies: [cdc2_reply(0x10, &[]), cdc2_reply(0x10, &[])].into(),
+            ..Default::default()
+        };
+
+        block_on(async {
+            let guard = DownloadChannelGuard::acquire(&mut connection).await.unwrap();
+            guard.release().await.unwrap();
+        });
+
+        // The channel is the last byte before the CRC
+        let channels: Vec<u8> = connection
+            .sent
+            .iter()
+            .map(|packet| packet[packet.len() - 3])
+            .collect();
+        assert_eq!(channels, [0x01, 0x00]);
+    }
+
+    #[test]
+    fn user_fifo_full_retries() {
+        let data: Vec<u8> = (0..64 * 1024).map(|i| (i % 251) as u8).collect();
+        let settings = UserFifoSettings::for_channel(RadioChannel::Download);
+        let chunks = data.len().div_ceil(settings.chunk_size());
+
+        // Every fifth chunk is NACKed twice before the FIFO has room for it
+        let mut replies = Vec::new();
+        for chunk in 0..chunks {
+            if chunk % 5 == 0 {
+                replies.extend([fifo_reply(Cdc2Ack::Nack), fifo_reply(Cdc2Ack::Nack)]);
+            }
+            replies.push(fifo_reply(Cdc2Ack::Ack));
+        }
+        let mut connection = MockConnection {
+            connection_type: Some(ConnectionType::Controller),
+            replies: replies.into(),
+            ..Default::default()
+        };
+
+        let written = block_on(
+            WriteUserFifo {
+                data: &data,
+                settings,
+            }
+            .execute(&mut connection),
+        )
+        .unwrap();
+        assert_eq!(written, data.len());
+
+        // Retried chunks are sent again in full, so only the accepted writes are kept
+        let mut received: Vec<u8> = Vec::new();
+        let mut sent = connection.sent.iter();
+        for chunk in 0..chunks {
+            if chunk % 5 == 0 {
+                sent.nth(1);
+            }
+            received.extend(fifo_write(sent.next().unwrap()));
+        }
+        assert_eq!(received, data);
+        for packet in &connection.sent {
+            assert!(packet.len() <= settings.max_packet_size);
+        }
+    }
+
+    #[test]
+    fn user_fifo_partial_write() {
+        let data = vec![b'x'; 1000];
+        let settings = UserFifoSettings {
+            retries: 2,
+            ..Default::default()
+        };
+        assert_eq!(settings.chunk_size(), 224);
+
+        // The second chunk never fits
+        let mut connection = MockConnection {
+            connection_type: Some(ConnectionType::Controller),
+            replies: [
+                fifo_reply(Cdc2Ack::Ack),
+                fifo_reply(Cdc2Ack::Nack),
+                fifo_reply(Cdc2Ack::Nack),
+                fifo_reply(Cdc2Ack::Nack),
+            ]
+            .into(),
+            ..Default::default()
+        };
+        let command = WriteUserFifo {
+            data: &data,
+            settings,
+        };
+        assert_eq!(block_on(command.execute(&mut connection)).unwrap(), 224);
+        assert_eq!(connection.sent.len(), 4);
+
+        // Nothing written at all is an error
+        let mut connection = MockConnection::default();
+        assert!(matches!(
+            block_on(command.execute(&mut connection)),
+            Err(MockError::Handshake(_))
+        ));
+    }
+}
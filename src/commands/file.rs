@@ -1,31 +1,61 @@
-use std::{io::Write, str::FromStr, time::Duration};
+use std::{
+    collections::BTreeMap,
+    fmt,
+    str::{FromStr, Utf8Error},
+    time::Duration,
+};
 
-use flate2::{Compression, GzBuilder};
-use log::{debug, trace};
+use log::{debug, error, info, trace, warn};
+#[cfg(feature = "serde")]
 use serde::{Deserialize, Serialize};
+use thiserror::Error;
 
-#[cfg(feature = "bluetooth")]
-use crate::connection::bluetooth::BluetoothConnection;
 use crate::{
-    connection::{Connection, ConnectionType},
+    connection::{clock, Connection, ConnectionType, TransferState, BLUETOOTH_MAX_PACKET_SIZE},
     crc::VEX_CRC32,
-    packets::file::{
-        ExitFileTransferPacket, ExitFileTransferReplyPacket, ExtensionType, FileExitAction,
-        FileInitAction, FileInitOption, FileMetadata, FileTransferTarget, FileVendor,
-        InitFileTransferPacket, InitFileTransferPayload, InitFileTransferReplyPacket,
-        LinkFilePacket, LinkFilePayload, LinkFileReplyPacket, ReadFilePacket, ReadFilePayload,
-        ReadFileReplyPacket, WriteFilePacket, WriteFilePayload, WriteFileReplyPacket,
+    decode::DecodeError,
+    encode::Encode,
+    packets::{
+        cdc2::{Cdc2Ack, Cdc2ReplyMeta},
+        file::{
+            EraseFilePacket, EraseFilePayload, ExitFileTransferPacket, ExtensionType,
+            FileEraseOption, FileExitAction, FileInitAction, FileInitOption, FileMetadata,
+            FileTransferTarget, FileVendor, GetDirectoryEntryReplyPayload,
+            GetDirectoryFileCountPacket, GetDirectoryFileCountPayload, GetFileMetadataPacket,
+            GetFileMetadataPayload, InitFileTransferPacket, InitFileTransferPayload,
+            InitFileTransferReplyPacket, FileLoadAction, LinkFilePacket, LinkFilePayload,
+            LinkFileReplyPacket, LoadFileActionPacket,
+            LoadFileActionPayload, ReadFilePacket, ReadFilePayload, WriteFilePacket,
+            WriteFilePayload,
+        },
+        system::GetSystemFlagsPacket,
     },
     string::FixedString,
-    timestamp::j2000_timestamp,
     version::Version,
 };
+#[cfg(feature = "ini")]
+use crate::timestamp::j2000_timestamp;
 
-use super::Command;
+#[cfg(feature = "ini")]
+use super::controller::DownloadChannelGuard;
+use super::{
+    fs::BrainFs,
+    poll_until,
+    progress::{progress_channel, ProgressStream},
+    Command, CommandError, SharedError,
+};
 
 pub const PROS_HOT_BIN_LOAD_ADDR: u32 = 0x7800000;
 pub const USER_PROGRAM_LOAD_ADDR: u32 = 0x3800000;
 const USER_PROGRAM_CHUNK_SIZE: u16 = 4096;
+/// The smallest chunk size tried when a brain rejects file reads without reporting a window size.
+const MIN_READ_CHUNK_SIZE: u16 = 256;
+
+/// A gap between packets for [`UploadProgram::wireless_pacing`].
+///
+/// This value hasn't been measured against a controller, so nothing paces uploads with it
+/// unless asked to.
+pub const DEFAULT_WIRELESS_PACING: Duration = Duration::from_millis(5);
 
 pub struct DownloadFile {
     pub file_name: FixedString<23>,
@@ -33,114 +63,570 @@ pub struct DownloadFile {
     pub vendor: FileVendor,
     pub target: Option<FileTransferTarget>,
     pub load_addr: u32,
+    /// Offset into the file to start downloading from.
+    ///
+    /// Set this to the `next_offset` of a [`CommandError::DownloadInterrupted`] to download
+    /// only the rest of the file. The returned data then starts at this offset.
+    pub resume_from: u32,
 
     pub progress_callback: Option<Box<dyn FnMut(f32) + Send>>,
 }
+impl DownloadFile {
+    /// Returns a stream of the download's progress, alongside `progress_callback`.
+    ///
+    /// See the [`progress`](super::progress) module for how the stream behaves.
+    pub fn progress_stream(&mut self) -> ProgressStream {
+        let (sender, stream) = progress_channel();
+        self.progress_callback = Some(sender.attach(self.progress_callback.take()));
+        stream
+    }
+}
 impl Command for DownloadFile {
-    type Output = Vec<u8>;
+    type Output = DownloadedFile;
 
     async fn execute<C: Connection + ?Sized>(
-        mut self,
+        self,
         connection: &mut C,
     ) -> Result<Self::Output, C::Error> {
-        let target = self.target.unwrap_or(FileTransferTarget::Qspi);
+        download_file(connection, self).await
+    }
+}
 
-        let transfer_response = connection
-            .packet_handshake::<InitFileTransferReplyPacket>(
-                Duration::from_millis(500),
-                5,
-                InitFileTransferPacket::new(InitFileTransferPayload {
-                    operation: FileInitAction::Read,
-                    target,
-                    vendor: self.vendor,
-                    options: FileInitOption::None,
-                    file_size: self.size,
-                    write_file_crc: 0,
-                    load_address: self.load_addr,
-                    metadata: FileMetadata {
-                        extension: FixedString::from_str("ini").unwrap(),
-                        extension_type: ExtensionType::EncryptedBinary,
-                        timestamp: 0,
-                        version: Version {
-                            major: 1,
-                            minor: 0,
-                            build: 0,
-                            beta: 0,
-                        },
+/// A file downloaded by a [`DownloadFile`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DownloadedFile {
+    pub data: Vec<u8>,
+    pub summary: TransferSummary,
+}
+
+/// Downloads a file from the brain.
+///
+/// This is the implementation of [`DownloadFile`], for use inside other commands.
+pub async fn download_file<C: Connection + ?Sized>(
+    connection: &mut C,
+    file: DownloadFile,
+) -> Result<DownloadedFile, C::Error> {
+    let file_name = file.file_name.to_string();
+    begin_transfer(
+        connection,
+        TransferState {
+            operation: FileInitAction::Read,
+            vendor: file.vendor,
+            file_name: file_name.clone(),
+            size: file.size,
+        },
+    )?;
+    let start = clock::now();
+    let phase_start = connection.stats();
+    let mut peak_throughput = None;
+    let result = read_file_transfer(connection, file, &mut peak_throughput).await;
+    connection.set_active_transfer(None);
+
+    let data = result?;
+    Ok(DownloadedFile {
+        summary: TransferSummary {
+            file_name,
+            size: data.len(),
+            duration: clock::since(start),
+            peak_throughput,
+            retries: connection.stats().since(&phase_start).failed_attempts,
+            uncompressed_size: gzip_size(&data),
+            warning: None,
+        },
+        data,
+    })
+}
+
+/// Marks a transfer as open on the connection, failing if another one already is.
+fn begin_transfer<C: Connection + ?Sized>(
+    connection: &mut C,
+    transfer: TransferState,
+) -> Result<(), C::Error> {
+    if let Some(active) = connection.active_transfer() {
+        return Err(CommandError::TransferAlreadyInProgress(active.clone()).into());
+    }
+    connection.set_active_transfer(Some(transfer));
+    Ok(())
+}
+
+async fn read_file_transfer<C: Connection + ?Sized>(
+    connection: &mut C,
+    mut file: DownloadFile,
+    peak_throughput: &mut Option<u64>,
+) -> Result<Vec<u8>, C::Error> {
+    let mut transfer = open_read_transfer(connection, &file).await?;
+
+    let mut data = Vec::with_capacity(transfer.file_size.saturating_sub(transfer.offset) as usize);
+    while !transfer.is_done() {
+        let address = transfer.next_address()?;
+        match transfer.read_chunk(connection, address).await {
+            Ok(chunk_data) => data.extend(chunk_data),
+            // Hand back what was downloaded so far so that the caller can resume from here
+            Err(err) => {
+                return Err(CommandError::DownloadInterrupted {
+                    partial: data,
+                    next_offset: transfer.offset,
+                    source: SharedError::new(err),
+                }
+                .into())
+            }
+        }
+
+        if let Some(callback) = &mut file.progress_callback {
+            callback(transfer.progress());
+        }
+    }
+    *peak_throughput = transfer.peak_throughput;
+    Ok(data)
+}
+
+/// A read transfer opened with [`open_read_transfer`], read a chunk at a time.
+struct ReadTransfer {
+    load_addr: u32,
+    file_size: u32,
+    /// The offset of the next chunk to read.
+    offset: u32,
+    max_chunk_size: u16,
+    /// Whether the chunk size is still being found by trial.
+    negotiate: bool,
+    peak_throughput: Option<u64>,
+}
+
+/// Opens `file` for reading, starting at its `resume_from` offset.
+async fn open_read_transfer<C: Connection + ?Sized>(
+    connection: &mut C,
+    file: &DownloadFile,
+) -> Result<ReadTransfer, C::Error> {
+    let target = file.target.unwrap_or(FileTransferTarget::Qspi);
+
+    // A stale init reply would be accepted with the wrong window size
+    connection.flush_incoming().await?;
+
+    let transfer_response = connection
+        .request(
+            Duration::from_millis(500),
+            5,
+            InitFileTransferPacket::new(InitFileTransferPayload {
+                operation: FileInitAction::Read,
+                target,
+                vendor: file.vendor,
+                options: FileInitOption::None,
+                file_size: file.size,
+                write_file_crc: 0,
+                load_address: file.load_addr,
+                metadata: FileMetadata {
+                    extension: FixedString::from_str("ini").unwrap(),
+                    extension_type: ExtensionType::EncryptedBinary,
+                    timestamp: 0,
+                    version: Version {
+                        major: 1,
+                        minor: 0,
+                        build: 0,
+                        beta: 0,
                     },
-                    file_name: self.file_name,
-                }),
-            )
-            .await?;
-        let transfer_response = transfer_response.try_into_inner()?;
+                },
+                file_name: file.file_name.clone(),
+            }),
+        )
+        .await?;
+    let transfer_response = transfer_response.try_into_inner()?;
+    if file.resume_from > transfer_response.file_size {
+        return Err(CommandError::ResumePastEnd {
+            file_name: file.file_name.to_string(),
+            resume_from: file.resume_from,
+            file_size: transfer_response.file_size,
+        }
+        .into());
+    }
 
-        let max_chunk_size = if transfer_response.window_size > 0
-            && transfer_response.window_size <= USER_PROGRAM_CHUNK_SIZE
-        {
-            transfer_response.window_size
-        } else {
-            USER_PROGRAM_CHUNK_SIZE
-        };
+    // Some VEXos builds don't report a window size for reads, so the chunk size has to be
+    // found by trial unless an earlier read on this connection already found it
+    let negotiate = transfer_response.window_size == 0;
+    let max_chunk_size = if negotiate {
+        connection
+            .read_chunk_size()
+            .unwrap_or(USER_PROGRAM_CHUNK_SIZE)
+    } else {
+        transfer_response.window_size.min(USER_PROGRAM_CHUNK_SIZE)
+    };
+
+    Ok(ReadTransfer {
+        load_addr: file.load_addr,
+        file_size: transfer_response.file_size,
+        offset: file.resume_from,
+        max_chunk_size,
+        negotiate,
+        peak_throughput: None,
+    })
+}
+
+impl ReadTransfer {
+    fn is_done(&self) -> bool {
+        self.offset >= self.file_size
+    }
+
+    /// Returns how much of the file has been read, in percent.
+    fn progress(&self) -> f32 {
+        self.offset as f32 / self.file_size as f32 * 100.0
+    }
+
+    /// Returns the address of the next chunk, failing if it's past the end of the address
+    /// space.
+    fn next_address(&self) -> Result<u32, CommandError> {
+        self.load_addr
+            .checked_add(self.offset)
+            .ok_or(CommandError::ReadAddressOverflow {
+                load_addr: self.load_addr,
+                offset: self.offset,
+            })
+    }
 
-        let mut data = Vec::with_capacity(transfer_response.file_size as usize);
-        let mut offset = 0;
+    /// Reads the chunk at `address`, the address of the chunk at [`ReadTransfer::offset`].
+    ///
+    /// If the read fails, the offset isn't moved, so the same chunk can be read again.
+    async fn read_chunk<C: Connection + ?Sized>(
+        &mut self,
+        connection: &mut C,
+        address: u32,
+    ) -> Result<Vec<u8>, C::Error> {
         loop {
+            let sent = clock::now();
             let read = connection
-                .packet_handshake::<ReadFileReplyPacket>(
+                .request(
                     Duration::from_millis(500),
                     5,
                     ReadFilePacket::new(ReadFilePayload {
-                        address: self.load_addr + offset,
-                        size: max_chunk_size,
+                        address,
+                        size: self.max_chunk_size,
                     }),
                 )
-                .await?;
+                .await
+                .map(|read| {
+                    trace!(
+                        "File read reply: {:?}, {} bytes, CRC {:#06x}",
+                        read.ack(),
+                        read.declared_size(),
+                        read.crc()
+                    );
+                    read.payload.unwrap()
+                });
+
+            // Only the first chunk is used to find the chunk size
+            if self.negotiate {
+                match read {
+                    Ok(Err(Cdc2Ack::NackTransferSize | Cdc2Ack::NackPacketLength))
+                        if self.max_chunk_size / 2 >= MIN_READ_CHUNK_SIZE =>
+                    {
+                        self.max_chunk_size /= 2;
+                        debug!(
+                            "Read chunk size rejected, retrying with {} bytes",
+                            self.max_chunk_size
+                        );
+                        continue;
+                    }
+                    Ok(Ok(_)) if connection.read_chunk_size() != Some(self.max_chunk_size) => {
+                        info!(
+                            "Negotiated a read chunk size of {} bytes",
+                            self.max_chunk_size
+                        );
+                        connection.set_read_chunk_size(self.max_chunk_size);
+                    }
+                    _ => {}
+                }
+            }
+            let (_, mut chunk_data) = read??;
+            self.negotiate = false;
+            let elapsed = clock::since(sent);
+            raise_peak(&mut self.peak_throughput, chunk_data.len(), elapsed);
+
+            // Since data is returned in fixed-size chunks read from flash, VEXos will sometimes
+            // read past the end of the file in the last chunk, returning whatever garbled
+            // nonsense happens to be stored next in QSPI. This is a feature™️, and something we
+            // need to handle ourselves.
+            chunk_data.truncate((self.file_size - self.offset) as usize);
+            self.offset += chunk_data.len() as u32;
+            return Ok(chunk_data);
+        }
+    }
+}
+
+/// Reads a file from the brain a chunk at a time, for streaming a file into something like a
+/// decoder or hasher without holding all of it in memory.
+///
+/// This reads the same way as [`download_file`], but only requests the next chunk once the
+/// last one has been read. Reads have no closing packet, so the transfer is closed when the
+/// reader is dropped, whether or not the file was read to the end.
+///
+/// A read that fails can be tried again, which asks for the same chunk. If the brain has
+/// given up on the transfer, the rest of the file can be read with a new reader whose
+/// `resume_from` is [`BrainFileReader::position`].
+pub struct BrainFileReader<'a, C: Connection + ?Sized> {
+    connection: &'a mut C,
+    transfer: ReadTransfer,
+    progress_callback: Option<Box<dyn FnMut(f32) + Send>>,
+    /// The last chunk read from the brain.
+    chunk: Vec<u8>,
+    /// How much of `chunk` has been read.
+    chunk_position: usize,
+}
+impl<'a, C: Connection + ?Sized> BrainFileReader<'a, C> {
+    /// Opens `file` for reading, starting at its `resume_from` offset.
+    ///
+    /// `file.progress_callback` is called after each chunk is read from the brain.
+    pub async fn open(connection: &'a mut C, mut file: DownloadFile) -> Result<Self, C::Error> {
+        begin_transfer(
+            connection,
+            TransferState {
+                operation: FileInitAction::Read,
+                vendor: file.vendor,
+                file_name: file.file_name.to_string(),
+                size: file.size,
+            },
+        )?;
+        let transfer = match open_read_transfer(connection, &file).await {
+            Ok(transfer) => transfer,
+            Err(err) => {
+                connection.set_active_transfer(None);
+                return Err(err);
+            }
+        };
+        Ok(Self {
+            connection,
+            transfer,
+            progress_callback: file.progress_callback.take(),
+            chunk: Vec::new(),
+            chunk_position: 0,
+        })
+    }
 
-            let (_, chunk_data) = read.payload.unwrap()?;
-            offset += chunk_data.len() as u32;
-            let progress = (offset as f32 / transfer_response.file_size as f32) * 100.0;
+    /// Returns the size of the file, as reported by the brain.
+    pub fn file_size(&self) -> u32 {
+        self.transfer.file_size
+    }
+
+    /// Returns the offset in the file of the next byte to be read.
+    pub fn position(&self) -> u32 {
+        self.transfer.offset - (self.chunk.len() - self.chunk_position) as u32
+    }
 
+    /// Reads some of the file into `buf`, returning how many bytes were read.
+    ///
+    /// A chunk is only requested from the brain once the last one has been read. Returns 0 at
+    /// the end of the file, or if `buf` is empty.
+    pub async fn read(&mut self, buf: &mut [u8]) -> Result<usize, C::Error> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        while self.chunk_position == self.chunk.len() {
+            if self.transfer.is_done() {
+                return Ok(0);
+            }
+            let address = self.transfer.next_address()?;
+            self.chunk = self.transfer.read_chunk(self.connection, address).await?;
+            self.chunk_position = 0;
             if let Some(callback) = &mut self.progress_callback {
-                callback(progress);
+                callback(self.transfer.progress());
             }
+        }
 
-            if transfer_response.file_size <= offset {
-                // Since data is returned in fixed-size chunks read from flash, VEXos will sometimes read
-                // past the end of the file in the last chunk, returning whatever garbled nonsense happens
-                // to be stored next in QSPI. This is a feature™️, and something we need to handle ourselves.
-                let eof = chunk_data.len() - (offset - transfer_response.file_size) as usize;
-                data.extend(&chunk_data[0..eof]);
-                break; // we're done here
-            } else {
-                data.extend(chunk_data);
+        let chunk = &self.chunk[self.chunk_position..];
+        let len = buf.len().min(chunk.len());
+        buf[..len].copy_from_slice(&chunk[..len]);
+        self.chunk_position += len;
+        Ok(len)
+    }
+}
+impl<C: Connection + ?Sized> Drop for BrainFileReader<'_, C> {
+    fn drop(&mut self) {
+        self.connection.set_active_transfer(None);
+    }
+}
+
+/// The largest number of bytes that can be read with a single [`ReadMemory`] command.
+pub const MAX_MEMORY_READ_SIZE: u32 = 0x100000;
+
+/// Reads a region of memory from the brain using the file transfer machinery.
+///
+/// `address` and `len` must both be 4-byte aligned, and `len` may be at most
+/// [`MAX_MEMORY_READ_SIZE`] bytes.
+///
+/// # Targets
+///
+/// [`FileTransferTarget::Ddr`] is main memory and is safe to read while a program is
+/// running, although the values may change during the read. [`FileTransferTarget::Cbuf`]
+/// and [`FileTransferTarget::Vbuf`] are the screen buffers and are also safe to read.
+/// Reading from other targets while a program is running is untested and may stall
+/// the brain until the read finishes.
+pub struct ReadMemory {
+    pub target: FileTransferTarget,
+    pub address: u32,
+    pub len: u32,
+}
+impl Command for ReadMemory {
+    type Output = Vec<u8>;
+
+    async fn execute<C: Connection + ?Sized>(
+        self,
+        connection: &mut C,
+    ) -> Result<Self::Output, C::Error> {
+        if !self.address.is_multiple_of(4)
+            || !self.len.is_multiple_of(4)
+            || self.len > MAX_MEMORY_READ_SIZE
+        {
+            return Err(CommandError::InvalidMemoryRead {
+                address: self.address,
+                len: self.len,
             }
+            .into());
+        }
+
+        begin_transfer(
+            connection,
+            TransferState {
+                operation: FileInitAction::Read,
+                vendor: FileVendor::Sys,
+                file_name: String::new(),
+                size: self.len,
+            },
+        )?;
+        let result = read_memory(connection, &self).await;
+        connection.set_active_transfer(None);
+        result
+    }
+}
+
+async fn read_memory<C: Connection + ?Sized>(
+    connection: &mut C,
+    read: &ReadMemory,
+) -> Result<Vec<u8>, C::Error> {
+    connection.flush_incoming().await?;
+
+    // Some firmware versions will only read memory after a transfer has been initialized.
+    let transfer_response = connection
+        .request(
+            Duration::from_millis(500),
+            5,
+            InitFileTransferPacket::new(InitFileTransferPayload {
+                operation: FileInitAction::Read,
+                target: read.target,
+                vendor: FileVendor::Sys,
+                options: FileInitOption::None,
+                file_size: read.len,
+                write_file_crc: 0,
+                load_address: read.address,
+                metadata: FileMetadata {
+                    extension: FixedString::default(),
+                    extension_type: ExtensionType::default(),
+                    timestamp: 0,
+                    version: Version {
+                        major: 1,
+                        minor: 0,
+                        build: 0,
+                        beta: 0,
+                    },
+                },
+                file_name: FixedString::default(),
+            }),
+        )
+        .await?
+        .try_into_inner()?;
+
+    let max_chunk_size =
+        max_chunk_size(connection.connection_type(), transfer_response.window_size) as u32;
+
+    let mut data = Vec::with_capacity(read.len as usize);
+    while (data.len() as u32) < read.len {
+        let size = max_chunk_size.min(read.len - data.len() as u32);
+        let offset = data.len() as u32;
+        let address =
+            read.address
+                .checked_add(offset)
+                .ok_or(CommandError::ReadAddressOverflow {
+                    load_addr: read.address,
+                    offset,
+                })?;
+        let (_, chunk_data) = connection
+            .request(
+                Duration::from_millis(500),
+                5,
+                ReadFilePacket::new(ReadFilePayload {
+                    address,
+                    size: size as u16,
+                }),
+            )
+            .await?
+            .payload
+            .unwrap()?;
+        trace!("read {} bytes of memory", chunk_data.len());
+        if chunk_data.is_empty() {
+            return Err(DecodeError::PacketTooShort.into());
         }
 
-        Ok(data)
+        // Never return more than was asked for, even if the brain sends extra.
+        let size = chunk_data.len().min((read.len as usize) - data.len());
+        data.extend(&chunk_data[..size]);
     }
+
+    Ok(data)
 }
 
-#[cfg(feature = "bluetooth")]
 fn max_chunk_size(con_type: ConnectionType, window_size: u16) -> u16 {
     if con_type.is_bluetooth() {
-        let max_chunk_size =
-            (BluetoothConnection::MAX_PACKET_SIZE as u16).min(window_size / 2) - 14;
-        max_chunk_size - (max_chunk_size % 4)
+        // Read replies don't always report a window size, so only a reported one lowers the
+        // limit. Chunks are word-aligned, so they're never made smaller than a word.
+        let limit = match window_size {
+            0 => BLUETOOTH_MAX_PACKET_SIZE as u16,
+            window_size => (BLUETOOTH_MAX_PACKET_SIZE as u16).min(window_size / 2),
+        };
+        let max_chunk_size = limit.saturating_sub(14);
+        (max_chunk_size - (max_chunk_size % 4)).max(4)
     } else if window_size > 0 && window_size <= USER_PROGRAM_CHUNK_SIZE {
         window_size
     } else {
         USER_PROGRAM_CHUNK_SIZE
     }
 }
-#[cfg(not(feature = "bluetooth"))]
-fn max_chunk_size(_con_type: ConnectionType, window_size: u16) -> u16 {
-    if window_size > 0 && window_size <= USER_PROGRAM_CHUNK_SIZE {
-        window_size
-    } else {
-        USER_PROGRAM_CHUNK_SIZE
+
+/// How the bytes of an upload were compressed before being sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompressionApplied {
+    /// The bytes are sent as they are.
+    None,
+    /// The bytes are gzip-compressed, either by [`UploadProgram`] or by the caller.
+    Gzip,
+}
+impl CompressionApplied {
+    /// Returns [`CompressionApplied::Gzip`] if `data` starts with the gzip magic bytes.
+    pub fn detect(data: &[u8]) -> Self {
+        if data.starts_with(&GZIP_MAGIC) {
+            Self::Gzip
+        } else {
+            Self::None
+        }
     }
 }
 
+/// The first two bytes of every gzip stream.
+const GZIP_MAGIC: [u8; 2] = [0x1F, 0x8B];
+
+/// Returns the CRC32 to declare for an upload of `data` when opening the transfer.
+///
+/// VEXos checks the declared CRC against the bytes it stores, which are the bytes that were
+/// sent. Compressed uploads are stored compressed and only decompressed when the program
+/// runs, so their CRC is always of the compressed bytes and never of the program inside.
+/// A mismatch is reported when the transfer is closed, as [`CommandError::UploadCrcMismatch`].
+///
+/// The CRC is [`VEX_CRC32`] in both cases. `compression` documents which bytes are expected.
+/// Declaring [`CompressionApplied::Gzip`] for data that isn't gzip-compressed panics in
+/// debug builds, since it usually means the CRC was meant for the other bytes.
+pub fn upload_crc(data: &[u8], compression: CompressionApplied) -> u32 {
+    debug_assert!(
+        compression == CompressionApplied::None || data.starts_with(&GZIP_MAGIC),
+        "Upload declared as gzip-compressed doesn't start with the gzip magic bytes"
+    );
+    VEX_CRC32.checksum(data)
+}
+
 pub struct LinkedFile {
     pub filename: FixedString<23>,
     pub vendor: Option<FileVendor>,
@@ -155,158 +641,1324 @@ pub struct UploadFile<'a> {
     pub load_addr: u32,
     pub linked_file: Option<LinkedFile>,
     pub after_upload: FileExitAction,
+    /// Whether to make sure that a program uploaded wirelessly with
+    /// [`FileExitAction::RunProgram`] actually starts.
+    ///
+    /// Over a controller or Bluetooth, the brain has been reported to occasionally start the
+    /// slot's previous binary or nothing at all, as described on [`FileExitAction::RunProgram`].
+    /// With this set, the running slot is polled every 250 ms after the upload, and if the
+    /// file's slot (from a `slot_N.bin` name) isn't running within
+    /// [`RUN_CONFIRM_GRACE_PERIOD`], it's started explicitly with a [`LoadFileActionPacket`].
+    /// Wired uploads and other file names are unaffected.
+    pub confirm_run: bool,
+    /// Whether to make sure that the vendor's directory exists before starting the transfer.
+    ///
+    /// Without this, writing to a vendor without a directory, such as [`FileVendor::Dev1`] on
+    /// most brains, fails with [`CommandError::NoDirectory`] only once the transfer has been
+    /// opened. With it, the directory's file count is queried first, which costs one request.
+    pub check_directory: bool,
+    /// What to do if the brain's battery is low before the upload starts.
+    pub low_battery: LowBatteryPolicy<'a>,
 
     pub progress_callback: Option<Box<dyn FnMut(f32) + Send + 'a>>,
 }
+impl UploadFile<'_> {
+    /// Returns a stream of the upload's progress, alongside `progress_callback`.
+    ///
+    /// See the [`progress`](super::progress) module for how the stream behaves.
+    pub fn progress_stream(&mut self) -> ProgressStream {
+        let (sender, stream) = progress_channel();
+        self.progress_callback = Some(sender.attach(self.progress_callback.take()));
+        stream
+    }
+}
 impl Command for UploadFile<'_> {
-    type Output = ();
+    type Output = TransferSummary;
     async fn execute<C: Connection + ?Sized>(
-        mut self,
+        self,
         connection: &mut C,
     ) -> Result<Self::Output, C::Error> {
-        debug!("Uploading file: {}", self.filename);
-        let vendor = self.vendor.unwrap_or(FileVendor::User);
-        let target = self.target.unwrap_or(FileTransferTarget::Qspi);
+        upload_file(connection, self).await
+    }
+}
 
-        let crc = VEX_CRC32.checksum(&self.data);
+/// The battery percentage below which uploads are likely to fail partway through writing to
+/// flash.
+pub const DEFAULT_MIN_BATTERY_PERCENT: u8 = 20;
 
-        let transfer_response = connection
-            .packet_handshake::<InitFileTransferReplyPacket>(
-                Duration::from_millis(500),
-                5,
-                InitFileTransferPacket::new(InitFileTransferPayload {
-                    operation: FileInitAction::Write,
-                    target,
-                    vendor,
-                    options: FileInitOption::Overwrite,
-                    file_size: self.data.len() as u32,
-                    load_address: self.load_addr,
-                    write_file_crc: crc,
-                    metadata: self.metadata,
-                    file_name: self.filename.clone(),
-                }),
-            )
-            .await?;
-        debug!("transfer init responded");
-        let transfer_response = transfer_response.try_into_inner()?;
+/// What an upload does when the brain's battery is below a threshold, such as
+/// [`DEFAULT_MIN_BATTERY_PERCENT`].
+///
+/// The battery is read with a single system flags query before anything is sent. If the query
+/// fails, the upload goes ahead.
+pub enum LowBatteryPolicy<'a> {
+    /// Upload without checking the battery.
+    Skip,
+    /// Logs a warning and calls `callback` with the battery percentage, then uploads anyway.
+    Warn {
+        threshold: u8,
+        callback: Box<dyn FnMut(u8) + Send + 'a>,
+    },
+    /// Fails with [`CommandError::BatteryTooLow`].
+    Refuse { threshold: u8 },
+}
 
-        if let Some(linked_file) = self.linked_file {
-            connection
-                .packet_handshake::<LinkFileReplyPacket>(
-                    Duration::from_millis(500),
-                    5,
-                    LinkFilePacket::new(LinkFilePayload {
-                        vendor: linked_file.vendor.unwrap_or(FileVendor::User),
-                        option: 0,
-                        required_file: linked_file.filename,
-                    }),
-                )
-                .await?
-                .try_into_inner()?;
+/// Fails with [`CommandError::NoDirectory`] if the brain NACKs a file count query for
+/// `vendor`'s directory with [`Cdc2Ack::NackNoDirectory`].
+///
+/// Any other reply, including a count of zero, is taken to mean that the directory exists.
+async fn check_directory<C: Connection + ?Sized>(
+    connection: &mut C,
+    vendor: FileVendor,
+) -> Result<(), C::Error> {
+    let reply = connection
+        .request(
+            Duration::from_millis(500),
+            5,
+            GetDirectoryFileCountPacket::new(GetDirectoryFileCountPayload { vendor, option: 0 }),
+        )
+        .await?;
+    match reply.try_into_inner() {
+        Err(Cdc2Ack::NackNoDirectory) => Err(CommandError::NoDirectory(vendor).into()),
+        reply => {
+            reply?;
+            Ok(())
+        }
+    }
+}
+
+/// Reads the battery level and applies a [`LowBatteryPolicy`] to it.
+async fn check_battery<C: Connection + ?Sized>(
+    connection: &mut C,
+    policy: &mut LowBatteryPolicy<'_>,
+) -> Result<(), C::Error> {
+    let threshold = match policy {
+        LowBatteryPolicy::Skip => return Ok(()),
+        LowBatteryPolicy::Warn { threshold, .. } | LowBatteryPolicy::Refuse { threshold } => {
+            *threshold
         }
+    };
 
-        let window_size = transfer_response.window_size;
+    let percent = match connection
+        .request(Duration::from_millis(500), 1, GetSystemFlagsPacket::new(()))
+        .await
+    {
+        Ok(reply) => match reply.try_into_inner() {
+            Ok(flags) => flags.battery_percent(),
+            Err(nack) => {
+                debug!("Battery check skipped, system flags query was NACKed: {nack}");
+                return Ok(());
+            }
+        },
+        Err(e) => {
+            debug!("Battery check skipped, system flags query failed: {e}");
+            return Ok(());
+        }
+    };
+    if percent >= threshold {
+        return Ok(());
+    }
 
-        // The maximum packet size is 244 bytes for bluetooth
-        let max_chunk_size = max_chunk_size(connection.connection_type(), window_size);
+    match policy {
+        LowBatteryPolicy::Warn { callback, .. } => {
+            warn!("Brain battery is at {percent}%, uploads may fail below {threshold}%");
+            callback(percent);
+            Ok(())
+        }
+        _ => Err(CommandError::BatteryTooLow { percent, threshold }.into()),
+    }
+}
 
-        debug!("max_chunk_size: {}", max_chunk_size);
+/// Uploads a file to the brain.
+///
+/// If the file was written but closing the transfer or starting the program couldn't be
+/// confirmed, this still succeeds, with [`TransferSummary::warning`] saying what went wrong.
+///
+/// This is the implementation of [`UploadFile`], for use inside other commands.
+pub async fn upload_file<C: Connection + ?Sized>(
+    connection: &mut C,
+    file: UploadFile<'_>,
+) -> Result<TransferSummary, C::Error> {
+    let file_name = file.filename.to_string();
+    let size = file.data.len();
+    let uncompressed_size = gzip_size(&file.data);
+    let start = clock::now();
+    let mut stats = TransferStats::default();
+    let warning = upload_file_with_stats(connection, file, &mut stats).await?;
 
-        let mut offset = 0;
-        for chunk in self.data.chunks(max_chunk_size as _) {
-            let chunk = if chunk.len() < max_chunk_size as _ && chunk.len() % 4 != 0 {
-                let mut new_chunk = Vec::new();
-                new_chunk.extend_from_slice(chunk);
-                new_chunk.resize(chunk.len() + (4 - chunk.len() % 4), 0);
-                new_chunk
-            } else {
-                chunk.to_vec()
-            };
-            trace!("sending chunk of size: {}", chunk.len());
-            let progress = (offset as f32 / self.data.len() as f32) * 100.0;
-            if let Some(callback) = &mut self.progress_callback {
-                callback(progress);
-            }
+    Ok(TransferSummary {
+        file_name,
+        size,
+        duration: clock::since(start),
+        peak_throughput: stats.peak_throughput,
+        retries: stats.retries(),
+        uncompressed_size,
+        warning,
+    })
+}
 
-            let packet = WriteFilePacket::new(WriteFilePayload {
-                address: (self.load_addr + offset) as _,
-                chunk_data: chunk.clone(),
-            });
+/// Uploads a file to the brain, recording how the link behaved in `stats`.
+///
+/// An error means the file isn't on the brain. A warning means it was written and verified,
+/// but closing the transfer or confirming that the program started failed.
+async fn upload_file_with_stats<C: Connection + ?Sized>(
+    connection: &mut C,
+    mut file: UploadFile<'_>,
+    stats: &mut TransferStats,
+) -> Result<Option<UploadWarning>, C::Error> {
+    debug!("Uploading file: {}", file.filename);
+    check_battery(connection, &mut file.low_battery).await?;
+    if file.check_directory {
+        check_directory(connection, file.vendor.unwrap_or(FileVendor::User)).await?;
+    }
 
-            // On bluetooth, we dont wait for the reply
-            if connection.connection_type() == ConnectionType::Bluetooth {
-                connection.send_packet(packet).await?;
-            } else {
-                connection
-                    .packet_handshake::<WriteFileReplyPacket>(Duration::from_millis(500), 5, packet)
-                    .await?
-                    .try_into_inner()?;
+    begin_transfer(
+        connection,
+        TransferState {
+            operation: FileInitAction::Write,
+            vendor: file.vendor.unwrap_or(FileVendor::User),
+            file_name: file.filename.to_string(),
+            size: file.data.len() as u32,
+        },
+    )?;
+    let result = write_file_transfer(connection, file, stats).await;
+    connection.set_active_transfer(None);
+    result
+}
+
+async fn write_file_transfer<C: Connection + ?Sized>(
+    connection: &mut C,
+    mut file: UploadFile<'_>,
+    stats: &mut TransferStats,
+) -> Result<Option<UploadWarning>, C::Error> {
+    let vendor = file.vendor.unwrap_or(FileVendor::User);
+    let target = file.target.unwrap_or(FileTransferTarget::Qspi);
+
+    let crc = upload_crc(&file.data, CompressionApplied::detect(&file.data));
+
+    // A stale init reply would be accepted with the wrong window size
+    connection.flush_incoming().await?;
+    let phase_start = connection.stats();
+    let opened = clock::now();
+
+    let init = InitFileTransferPacket::new(InitFileTransferPayload {
+        operation: FileInitAction::Write,
+        target,
+        vendor,
+        options: FileInitOption::Overwrite,
+        file_size: file.data.len() as u32,
+        load_address: file.load_addr,
+        write_file_crc: crc,
+        metadata: file.metadata,
+        file_name: file.filename.clone(),
+    });
+    let link = file.linked_file.map(|linked_file| {
+        LinkFilePacket::new(LinkFilePayload {
+            vendor: linked_file.vendor.unwrap_or(FileVendor::User),
+            option: 0,
+            required_file: linked_file.filename,
+        })
+    });
+
+    let batched = match &link {
+        Some(link) => open_linked_transfer(connection, &init, link).await?,
+        None => None,
+    };
+    // The batch isn't a handshake, so a failed one isn't in the connection's stats
+    let batch_failed = link.is_some() && batched.is_none();
+    let (transfer_response, linked) = match batched {
+        Some((transfer_response, linked)) => (transfer_response, Some(linked)),
+        None => (
+            connection
+                .request(Duration::from_millis(500), 5, init)
+                .await?,
+            None,
+        ),
+    };
+    debug!("transfer init responded");
+    let transfer_response = match stats.check(transfer_response.try_into_inner()) {
+        Err(Cdc2Ack::NackNoDirectory) => return Err(CommandError::NoDirectory(vendor).into()),
+        transfer_response => transfer_response?,
+    };
+
+    // Without batching, the link is only sent once the transfer is known to be open
+    let linked = match (linked, link) {
+        (Some(linked), _) => Some(linked),
+        (None, Some(link)) => Some(
+            connection
+                .request(Duration::from_millis(500), 5, link)
+                .await?,
+        ),
+        (None, None) => None,
+    };
+    if let Some(linked) = linked {
+        stats.check(linked.try_into_inner())?;
+    }
+    stats.init_retries =
+        connection.stats().since(&phase_start).failed_attempts + u64::from(batch_failed);
+    let open_latency = clock::since(opened);
+    debug!("Transfer opened in {open_latency:?}");
+    stats.open_latency = Some(open_latency);
+
+    let window_size = transfer_response.window_size;
+
+    // The maximum packet size is 244 bytes for bluetooth
+    let max_chunk_size = max_chunk_size(connection.connection_type(), window_size);
+
+    debug!("max_chunk_size: {}", max_chunk_size);
+    stats.chunk_size = max_chunk_size;
+
+    let phase_start = connection.stats();
+    let mut offset = 0;
+    for chunk in file.data.chunks(max_chunk_size as _) {
+        let payload =
+            WriteFilePayload::new_aligned((file.load_addr + offset) as _, chunk.to_vec());
+        let chunk_len = payload.chunk_data.len();
+        trace!("sending chunk of size: {}", chunk_len);
+        let progress = (offset as f32 / file.data.len() as f32) * 100.0;
+        if let Some(callback) = &mut file.progress_callback {
+            callback(progress);
+        }
+
+        let packet = WriteFilePacket::new(payload);
+
+        // On bluetooth, we dont wait for the reply
+        if connection.connection_type() == ConnectionType::Bluetooth {
+            connection.send_packet(packet).await?;
+        } else {
+            let sent = clock::now();
+            let reply = connection
+                .request(Duration::from_millis(500), 5, packet)
+                .await?;
+            if let Err(nack) = stats.check(reply.try_into_inner()) {
+                if nack == Cdc2Ack::NackPacketCrc {
+                    stats.crc_nacks += 1;
+                }
+                stats.write_retries = connection.stats().since(&phase_start).failed_attempts;
+                return Err(nack.into());
             }
+            raise_peak(&mut stats.peak_throughput, chunk_len, clock::since(sent));
+        }
+
+        stats.chunks += 1;
+        offset += chunk_len as u32;
+    }
+    stats.write_retries = connection.stats().since(&phase_start).failed_attempts;
+    if let Some(callback) = &mut file.progress_callback {
+        callback(100.0);
+    }
 
-            offset += chunk.len() as u32;
+    // Every chunk has been written, so from here on only a CRC NACK means the file has to be
+    // uploaded again
+    let phase_start = connection.stats();
+    let reply = connection
+        .request(
+            Duration::from_millis(1000),
+            5,
+            ExitFileTransferPacket::new(file.after_upload),
+        )
+        .await;
+    stats.exit_retries = connection.stats().since(&phase_start).failed_attempts;
+    let reply = match reply {
+        Ok(reply) => reply,
+        Err(e) => return Ok(Some(UploadWarning::ExitTimedOut(SharedError::new(e)))),
+    };
+    match stats.check(reply.try_into_inner()) {
+        Ok(()) => {}
+        Err(Cdc2Ack::NackProgramCrc) => {
+            return Err(CommandError::UploadCrcMismatch {
+                file_name: file.filename.to_string(),
+                declared: crc,
+                size: file.data.len(),
+            }
+            .into());
         }
-        if let Some(callback) = &mut self.progress_callback {
-            callback(100.0);
+        Err(nack) => return Ok(Some(UploadWarning::ExitNacked(nack))),
+    }
+
+    if file.confirm_run
+        && file.after_upload == FileExitAction::RunProgram
+        && !connection.connection_type().is_wired()
+    {
+        if let Some(slot) = program_slot(file.filename.as_ref()) {
+            match confirm_program_running(connection, slot, vendor, file.filename.clone()).await {
+                Ok(Ok(())) => {}
+                Ok(Err(nack)) => return Ok(Some(UploadWarning::RunNacked(nack))),
+                Err(e) => return Ok(Some(UploadWarning::RunTimedOut(SharedError::new(e)))),
+            }
         }
+    }
 
-        connection
-            .packet_handshake::<ExitFileTransferReplyPacket>(
-                Duration::from_millis(1000),
-                5,
-                ExitFileTransferPacket::new(self.after_upload),
-            )
-            .await?
-            .try_into_inner()?;
+    debug!("Successfully uploaded file: {}", file.filename.into_inner());
+    Ok(None)
+}
 
-        debug!("Successfully uploaded file: {}", self.filename.into_inner());
-        Ok(())
+/// Opens a transfer and links it to another file, sending both packets in a single write.
+///
+/// Sending them separately costs a USB frame per packet, which adds up over the files of a
+/// program. Returns `None` if either reply doesn't arrive, in which case the packets should
+/// be sent again one at a time.
+async fn open_linked_transfer<C: Connection + ?Sized>(
+    connection: &mut C,
+    init: &InitFileTransferPacket,
+    link: &LinkFilePacket,
+) -> Result<Option<(InitFileTransferReplyPacket, LinkFileReplyPacket)>, C::Error> {
+    let sent = connection
+        .send_batch(&[init.encode()?, link.encode()?])
+        .await;
+    let replies = match sent {
+        Ok(()) => match connection
+            .receive_packet::<InitFileTransferReplyPacket>(Duration::from_millis(500))
+            .await
+        {
+            Ok(init_reply) => connection
+                .receive_packet::<LinkFileReplyPacket>(Duration::from_millis(500))
+                .await
+                .map(|link_reply| (init_reply, link_reply)),
+            Err(e) => Err(e),
+        },
+        Err(e) => Err(e),
+    };
+
+    match replies {
+        Ok(replies) => Ok(Some(replies)),
+        Err(e) => {
+            warn!("Opening and linking the transfer together failed, retrying separately: {e}");
+            // A late reply would be mistaken for one to the separate packets
+            connection.flush_incoming().await?;
+            Ok(None)
+        }
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub enum ProgramData {
-    #[cfg_attr(feature = "serde_bytes", serde(with = "serde_bytes"))]
-    Monolith(Vec<u8>),
-    HotCold {
-        #[cfg_attr(feature = "serde_bytes", serde(with = "serde_bytes"))]
-        hot: Option<Vec<u8>>,
+/// How often the running program is polled while waiting for it to start.
+const RUN_CONFIRM_POLL_INTERVAL: Duration = Duration::from_millis(250);
 
-        #[cfg_attr(feature = "serde_bytes", serde(with = "serde_bytes"))]
-        cold: Option<Vec<u8>>,
+/// How long a program is given to start after an upload before it's started explicitly.
+pub const RUN_CONFIRM_GRACE_PERIOD: Duration = Duration::from_secs(2);
+
+/// Waits up to [`RUN_CONFIRM_GRACE_PERIOD`] for the program in `slot` to start, starting it
+/// with a [`LoadFileActionPacket`] if it doesn't.
+async fn confirm_program_running<C: Connection + ?Sized>(
+    connection: &mut C,
+    slot: u8,
+    vendor: FileVendor,
+    file_name: FixedString<23>,
+) -> Result<Result<(), Cdc2Ack>, C::Error> {
+    let running = poll_until(
+        connection,
+        RUN_CONFIRM_POLL_INTERVAL,
+        RUN_CONFIRM_GRACE_PERIOD,
+        async |connection| {
+            // Failures are expected here while the brain is still loading the program.
+            match connection
+                .request(Duration::from_millis(500), 1, GetSystemFlagsPacket::new(()))
+                .await
+            {
+                Ok(reply) => match reply.try_into_inner() {
+                    Ok(flags) if flags.current_program == slot => return Ok(Some(())),
+                    Ok(flags) => debug!(
+                        "Slot {} is running, waiting for {slot}",
+                        flags.current_program
+                    ),
+                    Err(nack) => debug!("System flags query was NACKed: {nack}"),
+                },
+                Err(e) => debug!("System flags query failed: {e}"),
+            }
+            Ok(None)
+        },
+    )
+    .await?;
+    if running.is_some() {
+        return Ok(Ok(()));
+    }
+
+    warn!("Slot {slot} didn't start after the upload, starting it explicitly");
+    let reply = connection
+        .request(
+            Duration::from_millis(500),
+            5,
+            LoadFileActionPacket::new(LoadFileActionPayload {
+                vendor,
+                action: FileLoadAction::Run,
+                file_name,
+            }),
+        )
+        .await?;
+    Ok(reply.try_into_inner())
+}
+
+/// The cold library that a [`HotColdUpload`] links its hot binary to.
+pub enum ColdLibrary<'a> {
+    /// Uploads the library, unless the brain already has an identical copy.
+    Upload(UploadFile<'a>),
+    /// Links to a library that must already be on the brain.
+    Existing {
+        file_name: FixedString<23>,
+        vendor: FileVendor,
     },
 }
+impl ColdLibrary<'_> {
+    fn file_name(&self) -> &FixedString<23> {
+        match self {
+            Self::Upload(file) => &file.filename,
+            Self::Existing { file_name, .. } => file_name,
+        }
+    }
+
+    fn vendor(&self) -> FileVendor {
+        match self {
+            Self::Upload(file) => file.vendor.unwrap_or(FileVendor::User),
+            Self::Existing { vendor, .. } => *vendor,
+        }
+    }
+}
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct Program {
-    pub name: String,
-    pub slot: u8,
-    pub icon: String,
-    pub iconalt: String,
-    pub description: String,
+/// Uploads a PROS-style hot binary along with the cold library it links to.
+///
+/// The brain only checks that a linked library exists when the program is run, so uploading
+/// the hot binary without its library fails with [`Cdc2Ack::NackProgramFile`] at program
+/// start rather than during the upload. This command makes sure the library is on the brain
+/// first, skipping its upload if a copy with the same size and CRC is already there, then
+/// uploads the hot binary linked to it. The hot binary's `linked_file` is overwritten.
+pub struct HotColdUpload<'a> {
+    pub hot: UploadFile<'a>,
+    pub cold: ColdLibrary<'a>,
 }
-#[derive(Serialize, Deserialize, Debug)]
-pub struct Project {
-    // version: String,
-    pub ide: String,
-    // file: String,
+impl HotColdUpload<'_> {
+    /// Returns the files that may be uploaded.
+    #[cfg(feature = "ini")]
+    fn files(&self) -> impl Iterator<Item = &UploadFile<'_>> {
+        let cold = match &self.cold {
+            ColdLibrary::Upload(file) => Some(file),
+            ColdLibrary::Existing { .. } => None,
+        };
+        std::iter::once(&self.hot).chain(cold)
+    }
 }
+impl Command for HotColdUpload<'_> {
+    type Output = UploadReport;
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct ProgramIniConfig {
-    pub project: Project,
-    pub program: Program,
+    async fn execute<C: Connection + ?Sized>(
+        self,
+        connection: &mut C,
+    ) -> Result<Self::Output, C::Error> {
+        hot_cold_upload(connection, self).await
+    }
 }
 
-pub struct UploadProgram<'a> {
-    pub name: String,
-    pub description: String,
-    pub icon: String,
-    pub program_type: String,
-    /// 0-indexed slot
-    pub slot: u8,
-    pub compress_program: bool,
+/// Uploads a hot binary along with the cold library it links to.
+///
+/// This is the implementation of [`HotColdUpload`], for use inside other commands.
+pub async fn hot_cold_upload<C: Connection + ?Sized>(
+    connection: &mut C,
+    upload: HotColdUpload<'_>,
+) -> Result<UploadReport, C::Error> {
+    let mut report = UploadReport::default();
+    upload_hot_cold_and_report(connection, upload, &mut report).await?;
+
+    if report.failed_file().is_some() {
+        return Err(CommandError::UploadFailed(Box::new(report)).into());
+    }
+    Ok(report)
+}
+
+/// Runs a [`HotColdUpload`], adding its files to `report`.
+async fn upload_hot_cold_and_report<C: Connection + ?Sized>(
+    connection: &mut C,
+    upload: HotColdUpload<'_>,
+    report: &mut UploadReport,
+) -> Result<(), C::Error> {
+    let file_name = upload.cold.file_name().clone();
+    let vendor = upload.cold.vendor();
+
+    // Nothing more will be uploaded after a failure, so there's no point checking the library
+    let metadata = if report.failed_file().is_none() {
+        connection
+            .request(
+                Duration::from_millis(500),
+                5,
+                GetFileMetadataPacket::new(GetFileMetadataPayload {
+                    vendor,
+                    option: 0,
+                    file_name: file_name.clone(),
+                }),
+            )
+            .await?
+            .try_into_inner()?
+    } else {
+        None
+    };
+
+    match upload.cold {
+        ColdLibrary::Upload(cold) => {
+            let crc = upload_crc(&cold.data, CompressionApplied::detect(&cold.data));
+            let size = cold.data.len();
+            if metadata.is_some_and(|m| m.crc32 == crc && m.size as usize == size) {
+                debug!("Cold library {} is already up to date", file_name);
+                report.files.push(FileUploadResult {
+                    file_name: file_name.to_string(),
+                    size,
+                    uncompressed_size: gzip_size(&cold.data),
+                    duration: Duration::ZERO,
+                    outcome: FileUploadOutcome::UpToDate,
+                    stats: TransferStats::default(),
+                });
+            } else {
+                upload_and_report(connection, cold, report).await;
+            }
+        }
+        ColdLibrary::Existing { .. } => {
+            if metadata.is_none() && report.failed_file().is_none() {
+                return Err(CommandError::MissingColdLibrary(file_name.to_string()).into());
+            }
+        }
+    }
+
+    debug!("Hot binary will be linked to cold library: {}", file_name);
+    let mut hot = upload.hot;
+    hot.linked_file = Some(LinkedFile {
+        filename: file_name,
+        vendor: Some(vendor),
+    });
+    upload_and_report(connection, hot, report).await;
+
+    Ok(())
+}
+
+/// Uploads one of several files, adding the result to `report`.
+///
+/// Once a file in the report has failed, the file is skipped instead, since it may depend on
+/// the file that failed.
+async fn upload_and_report<C: Connection + ?Sized>(
+    connection: &mut C,
+    upload: UploadFile<'_>,
+    report: &mut UploadReport,
+) {
+    let file_name = upload.filename.to_string();
+    let size = upload.data.len();
+    let uncompressed_size = gzip_size(&upload.data);
+
+    if report.failed_file().is_some() {
+        report.files.push(FileUploadResult {
+            file_name,
+            size,
+            uncompressed_size,
+            duration: Duration::ZERO,
+            outcome: FileUploadOutcome::Skipped,
+            stats: TransferStats::default(),
+        });
+        return;
+    }
+
+    debug!("Uploading {}", file_name);
+    let start = clock::now();
+    let mut stats = TransferStats::default();
+    let outcome = match upload_file_with_stats(connection, upload, &mut stats).await {
+        Ok(None) => FileUploadOutcome::Uploaded,
+        Ok(Some(warning)) => {
+            warn!("Uploaded {}, but {}", file_name, warning);
+            FileUploadOutcome::CompletedWithWarnings(warning)
+        }
+        Err(err) => {
+            error!("Failed to upload {}: {}", file_name, err);
+            FileUploadOutcome::Failed(SharedError::new(err))
+        }
+    };
+    report.files.push(FileUploadResult {
+        file_name,
+        size,
+        uncompressed_size,
+        duration: clock::since(start),
+        outcome,
+        stats,
+    });
+}
+
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum ProgramData {
+    #[cfg_attr(all(feature = "serde", feature = "serde_bytes"), serde(with = "serde_bytes"))]
+    Monolith(Vec<u8>),
+    HotCold {
+        #[cfg_attr(all(feature = "serde", feature = "serde_bytes"), serde(with = "serde_bytes"))]
+        hot: Option<Vec<u8>>,
+
+        #[cfg_attr(all(feature = "serde", feature = "serde_bytes"), serde(with = "serde_bytes"))]
+        cold: Option<Vec<u8>>,
+    },
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Program {
+    pub name: String,
+    pub slot: u8,
+    pub icon: String,
+    pub iconalt: String,
+    pub description: String,
+}
+/// The longest program name, in bytes, that VEXos shows in the slot UI.
+pub const MAX_PROGRAM_NAME_LEN: usize = 32;
+/// The longest program description, in bytes, that VEXos accepts.
+pub const MAX_PROGRAM_DESCRIPTION_LEN: usize = 128;
+
+/// What [`UploadProgram`] does with a name or description that's too long for VEXos.
+///
+/// An overlong name uploads without any error from the brain, but shows up blank in the slot UI.
+#[cfg(feature = "ini")]
+pub enum LongTextPolicy<'a> {
+    /// Fail with [`CommandError::ProgramTextTooLong`] before uploading anything.
+    Reject,
+    /// Cut the text down to the limit without splitting a character.
+    ///
+    /// The callback is given a warning describing each truncation, so that it can be reported.
+    Truncate(Box<dyn FnMut(&str) + Send + 'a>),
+}
+
+/// Checks a program's name or description against its limit, truncating it if the policy allows.
+#[cfg(feature = "ini")]
+fn fit_program_text(
+    field: &'static str,
+    mut text: String,
+    max: usize,
+    policy: &mut LongTextPolicy<'_>,
+) -> Result<String, CommandError> {
+    if text.len() <= max {
+        return Ok(text);
+    }
+
+    match policy {
+        LongTextPolicy::Reject => Err(CommandError::ProgramTextTooLong {
+            field,
+            len: text.len(),
+            max,
+        }),
+        LongTextPolicy::Truncate(callback) => {
+            let end = (0..=max)
+                .rev()
+                .find(|&index| text.is_char_boundary(index))
+                .unwrap_or(0);
+            let warning = format!(
+                "Program {field} truncated from {} to {end} bytes: {:?}",
+                text.len(),
+                &text[..end]
+            );
+            warn!("{warning}");
+            callback(&warning);
+            text.truncate(end);
+            Ok(text)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Project {
+    // version: String,
+    pub ide: String,
+    // file: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ProgramIniConfig {
+    pub project: Project,
+    pub program: Program,
+}
+impl ProgramIniConfig {
+    /// Parses a program's `slot_N.ini` file.
+    ///
+    /// Parsing is lenient so that the ini files written by VEXcode, PROS, and vexide can all be
+    /// read. Section names and keys are case-insensitive, values may be wrapped in double quotes,
+    /// lines starting with `;` or `#` are comments, and missing sections or keys are left at
+    /// their defaults. Keys that aren't part of [`ProgramIniConfig`] are returned separately,
+    /// named `section.key`.
+    pub fn from_ini_bytes(bytes: &[u8]) -> Result<(Self, BTreeMap<String, String>), IniParseError> {
+        let contents = std::str::from_utf8(bytes)?;
+        let contents = contents.strip_prefix('\u{feff}').unwrap_or(contents);
+
+        let mut config = Self::default();
+        let mut unknown = BTreeMap::new();
+        let mut section = String::new();
+
+        for line in contents.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with([';', '#']) {
+                continue;
+            }
+
+            if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                section = name.trim().to_ascii_lowercase();
+                continue;
+            }
+
+            let Some((key, value)) = line.split_once('=') else {
+                warn!("Skipping malformed ini line: {:?}", line);
+                continue;
+            };
+            let key = key.trim().to_ascii_lowercase();
+            let value = value.trim();
+            let value = value
+                .strip_prefix('"')
+                .and_then(|v| v.strip_suffix('"'))
+                .unwrap_or(value)
+                .to_string();
+
+            match (section.as_str(), key.as_str()) {
+                ("project", "ide") => config.project.ide = value,
+                ("program", "name") => config.program.name = value,
+                ("program", "slot") => {
+                    config.program.slot = value
+                        .parse()
+                        .map_err(|_| IniParseError::InvalidSlot(value))?
+                }
+                ("program", "icon") => config.program.icon = value,
+                ("program", "iconalt") => config.program.iconalt = value,
+                ("program", "description") => config.program.description = value,
+                _ => {
+                    unknown.insert(format!("{section}.{key}"), value);
+                }
+            }
+        }
+
+        Ok((config, unknown))
+    }
+}
+
+/// An error that occurred while parsing a program's ini file.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum IniParseError {
+    #[error("Program ini is not valid UTF-8: {0}")]
+    InvalidUtf8(#[from] Utf8Error),
+    #[error("Invalid program slot: {0:?}")]
+    InvalidSlot(String),
+}
+
+/// The result of uploading a single file as part of an [`UploadProgram`] or [`HotColdUpload`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FileUploadOutcome {
+    /// The file was uploaded successfully.
+    Uploaded,
+    /// The file was not uploaded because the brain already has an identical copy.
+    UpToDate,
+    /// The file was not uploaded because an earlier file failed.
+    Skipped,
+    /// The file failed to upload with the given error from the connection.
+    Failed(SharedError),
+    /// The file is on the brain, but the transfer couldn't be closed or the program couldn't be
+    /// confirmed to have started.
+    ///
+    /// The file doesn't need uploading again. If it was meant to run, it can be started with
+    /// [`LoadFileActionPacket`].
+    CompletedWithWarnings(UploadWarning),
+}
+impl FileUploadOutcome {
+    /// Returns `true` if the file was written to the brain, even if what should have happened
+    /// after it couldn't be confirmed.
+    pub fn is_uploaded(&self) -> bool {
+        matches!(self, Self::Uploaded | Self::CompletedWithWarnings(_))
+    }
+}
+
+/// Why what should have happened after a file was written couldn't be confirmed.
+///
+/// The file itself was written and its CRC was accepted, so it doesn't need uploading again.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum UploadWarning {
+    /// The packet closing the transfer got no reply, even after retrying.
+    #[error("closing the transfer got no reply: {0}")]
+    ExitTimedOut(SharedError),
+    /// The brain NACKed the packet closing the transfer.
+    #[error("closing the transfer was NACKed: {0}")]
+    ExitNacked(Cdc2Ack),
+    /// The program didn't start by itself, and the packet starting it got no reply.
+    #[error("the program didn't start and starting it got no reply: {0}")]
+    RunTimedOut(SharedError),
+    /// The program didn't start by itself, and the brain NACKed the packet starting it.
+    #[error("the program didn't start and starting it was NACKed: {0}")]
+    RunNacked(Cdc2Ack),
+}
+
+/// A file that an [`UploadProgram`] or [`HotColdUpload`] attempted to upload.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileUploadResult {
+    pub file_name: String,
+    /// Size of the uploaded data in bytes, after compression.
+    pub size: usize,
+    /// Size of the data before compression, if it was gzip-compressed.
+    pub uncompressed_size: Option<usize>,
+    pub duration: Duration,
+    pub outcome: FileUploadOutcome,
+    /// How the link behaved while the file was uploaded.
+    pub stats: TransferStats,
+}
+impl FileUploadResult {
+    /// Returns the average upload speed in bytes per second, including the time spent opening
+    /// and closing the transfer.
+    ///
+    /// Returns `None` if the file wasn't uploaded.
+    pub fn throughput(&self) -> Option<f64> {
+        if !self.outcome.is_uploaded() || self.duration.is_zero() {
+            return None;
+        }
+        Some(self.size as f64 / self.duration.as_secs_f64())
+    }
+
+    /// Returns a [`TransferSummary`] of the upload, or `None` if the file wasn't uploaded.
+    pub fn summary(&self) -> Option<TransferSummary> {
+        self.outcome.is_uploaded().then(|| TransferSummary {
+            file_name: self.file_name.clone(),
+            size: self.size,
+            duration: self.duration,
+            peak_throughput: self.stats.peak_throughput,
+            retries: self.stats.retries(),
+            uncompressed_size: self.uncompressed_size,
+            warning: match &self.outcome {
+                FileUploadOutcome::CompletedWithWarnings(warning) => Some(warning.clone()),
+                _ => None,
+            },
+        })
+    }
+}
+
+/// How the link behaved while a file was uploaded, for diagnosing slow or failed uploads.
+///
+/// Retries are failed handshake attempts, as counted by [`Connection::stats`]. They're
+/// always zero on connections that don't keep statistics.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TransferStats {
+    /// The size of the chunks that the file was written in, or 0 if writing never started.
+    pub chunk_size: u16,
+    /// The number of chunks that were written.
+    pub chunks: usize,
+    /// Failed attempts while opening the transfer, including linking it to another file.
+    ///
+    /// A failed attempt to open and link the transfer in one write counts as one retry.
+    pub init_retries: u64,
+    /// Failed attempts while writing chunks.
+    pub write_retries: u64,
+    /// Failed attempts while closing the transfer.
+    pub exit_retries: u64,
+    /// How long opening the transfer took, from sending the first init packet to receiving
+    /// the last reply, including the link reply of a linked file.
+    ///
+    /// This is `None` if the transfer couldn't be opened.
+    pub open_latency: Option<Duration>,
+    /// Chunks that the brain NACKed with [`Cdc2Ack::NackPacketCrc`], which fails the upload.
+    pub crc_nacks: usize,
+    /// Every NACK received during the transfer, with how many times it was received.
+    pub nacks: Vec<(Cdc2Ack, usize)>,
+    /// The throughput of the fastest chunk in bytes per second, from sending it to its reply.
+    ///
+    /// This is `None` if no chunk waited for a reply, as on Bluetooth.
+    pub peak_throughput: Option<u64>,
+}
+impl TransferStats {
+    /// Returns the total number of failed attempts.
+    pub fn retries(&self) -> u64 {
+        self.init_retries + self.write_retries + self.exit_retries
+    }
+
+    /// Counts the NACK in a reply, if there is one.
+    fn check<T>(&mut self, reply: Result<T, Cdc2Ack>) -> Result<T, Cdc2Ack> {
+        if let Err(nack) = reply {
+            match self.nacks.iter_mut().find(|(code, _)| *code == nack) {
+                Some((_, count)) => *count += 1,
+                None => self.nacks.push((nack, 1)),
+            }
+        }
+        reply
+    }
+}
+
+/// Raises `peak` to the throughput of a chunk of `bytes` that took `elapsed` to be answered.
+fn raise_peak(peak: &mut Option<u64>, bytes: usize, elapsed: Duration) {
+    if elapsed.is_zero() {
+        return;
+    }
+    let throughput = (bytes as f64 / elapsed.as_secs_f64()) as u64;
+    *peak = Some(peak.map_or(throughput, |peak| peak.max(throughput)));
+}
+
+/// Returns the uncompressed size stored at the end of gzip-compressed `data`.
+///
+/// Gzip stores the size modulo 2^32, which is exact for any file that fits on a brain.
+fn gzip_size(data: &[u8]) -> Option<usize> {
+    if CompressionApplied::detect(data) != CompressionApplied::Gzip {
+        return None;
+    }
+    Some(u32::from_le_bytes(*data.last_chunk()?) as usize)
+}
+
+/// How a finished upload or download went, with a [`Display`](fmt::Display) impl that
+/// summarizes it on one line.
+///
+/// Durations are measured with the [`clock`] module.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransferSummary {
+    pub file_name: String,
+    /// Bytes transferred, which for compressed files is the compressed size.
+    pub size: usize,
+    /// How long the transfer took, from opening it to closing it.
+    pub duration: Duration,
+    /// The throughput of the fastest chunk in bytes per second, if any chunk was timed.
+    pub peak_throughput: Option<u64>,
+    /// Failed handshake attempts.
+    pub retries: u64,
+    /// Size of the data before compression, if it was gzip-compressed.
+    pub uncompressed_size: Option<usize>,
+    /// Set if an upload wrote the file, but what should have happened afterwards couldn't be
+    /// confirmed.
+    pub warning: Option<UploadWarning>,
+}
+impl TransferSummary {
+    /// Returns the average throughput in bytes per second, or `None` if no time was measured.
+    pub fn throughput(&self) -> Option<f64> {
+        (!self.duration.is_zero()).then(|| self.size as f64 / self.duration.as_secs_f64())
+    }
+
+    /// Returns the compressed size as a fraction of the uncompressed size.
+    pub fn compression_ratio(&self) -> Option<f64> {
+        self.uncompressed_size
+            .filter(|&size| size != 0)
+            .map(|size| self.size as f64 / size as f64)
+    }
+}
+
+/// Formats the summary like `slot_1.bin: 1.9 MiB in 3.4 s (573 KiB/s), gzip 41%, 0 retries`.
+impl fmt::Display for TransferSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} in {:.1} s",
+            self.file_name,
+            binary_size(self.size as f64, 1),
+            self.duration.as_secs_f64()
+        )?;
+        if let Some(throughput) = self.throughput() {
+            write!(f, " ({}/s)", binary_size(throughput, 0))?;
+        }
+        if let Some(ratio) = self.compression_ratio() {
+            write!(f, ", gzip {:.0}%", ratio * 100.0)?;
+        }
+        match self.retries {
+            1 => f.write_str(", 1 retry")?,
+            retries => write!(f, ", {retries} retries")?,
+        }
+        if let Some(warning) = &self.warning {
+            write!(f, "; {warning}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Formats a number of bytes in B, KiB, or MiB, with `precision` decimal places for the
+/// larger units.
+fn binary_size(bytes: f64, precision: usize) -> String {
+    const KIB: f64 = 1024.0;
+    if bytes < KIB {
+        format!("{bytes:.0} B")
+    } else if bytes < KIB * KIB {
+        format!("{:.precision$} KiB", bytes / KIB)
+    } else {
+        format!("{:.precision$} MiB", bytes / KIB / KIB)
+    }
+}
+
+/// A report of every file uploaded by an [`UploadProgram`] or [`HotColdUpload`], in upload order.
+///
+/// If any file fails to upload, the remaining files are skipped and the report is
+/// returned in [`CommandError::UploadFailed`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UploadReport {
+    pub files: Vec<FileUploadResult>,
+}
+impl UploadReport {
+    /// Returns a likely cause of a slow or failed upload, judged from the files' [`TransferStats`].
+    ///
+    /// The rules are simple thresholds on the share of chunks sent that failed:
+    ///
+    /// - A CRC NACK means a chunk reached the brain corrupted. If at least
+    ///   [`DIAGNOSIS_THRESHOLD_PERCENT`] of chunks were NACKed this way, the radio link is being
+    ///   interfered with. The NACKed chunk isn't resent, so this only happens to a failed upload.
+    /// - A write that gets no reply was lost entirely. If at least
+    ///   [`DIAGNOSIS_THRESHOLD_PERCENT`] of chunks needed another attempt, the signal is weak,
+    ///   usually because the controller is too far from the brain.
+    ///
+    /// CRC NACKs are checked first, since interference also loses packets.
+    pub fn diagnosis(&self) -> Option<String> {
+        // A NACKed chunk was sent, but isn't counted as written
+        let (chunks, crc_nacks, write_retries) =
+            self.files
+                .iter()
+                .fold((0, 0, 0), |(chunks, crc, write), file| {
+                    (
+                        chunks + (file.stats.chunks + file.stats.crc_nacks) as u64,
+                        crc + file.stats.crc_nacks as u64,
+                        write + file.stats.write_retries,
+                    )
+                });
+        if chunks == 0 {
+            return None;
+        }
+        let percent = |retries: u64| (retries * 100).div_ceil(chunks);
+
+        if percent(crc_nacks) >= DIAGNOSIS_THRESHOLD_PERCENT {
+            Some(format!(
+                "{}% of chunks failed with a CRC NACK, likely radio interference",
+                percent(crc_nacks)
+            ))
+        } else if percent(write_retries) >= DIAGNOSIS_THRESHOLD_PERCENT {
+            Some(format!(
+                "{}% of chunks retried after getting no reply, likely a weak radio signal",
+                percent(write_retries)
+            ))
+        } else {
+            None
+        }
+    }
+
+    /// Returns the file that failed to upload, if any.
+    pub fn failed_file(&self) -> Option<&FileUploadResult> {
+        self.files
+            .iter()
+            .find(|file| matches!(file.outcome, FileUploadOutcome::Failed(_)))
+    }
+
+    /// Returns the files that were uploaded with [`FileUploadOutcome::CompletedWithWarnings`].
+    pub fn unconfirmed_files(&self) -> impl Iterator<Item = &FileUploadResult> {
+        self.files
+            .iter()
+            .filter(|file| matches!(file.outcome, FileUploadOutcome::CompletedWithWarnings(_)))
+    }
+
+    /// Returns the program slots with files that were uploaded.
+    pub fn slots_changed(&self) -> SlotsChanged {
+        SlotsChanged::from_file_names(
+            self.files
+                .iter()
+                .filter(|file| file.outcome.is_uploaded())
+                .map(|file| file.file_name.as_str()),
+        )
+    }
+}
+
+/// Summarizes the upload on one line, ending with its [`diagnosis`](UploadReport::diagnosis)
+/// if there is one.
+impl fmt::Display for UploadReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let uploaded: Vec<_> = self
+            .files
+            .iter()
+            .filter(|file| file.outcome.is_uploaded())
+            .collect();
+        write!(
+            f,
+            "Uploaded {} of {} files",
+            uploaded.len(),
+            self.files.len()
+        )?;
+
+        let size: usize = uploaded.iter().map(|file| file.size).sum();
+        let duration: Duration = uploaded.iter().map(|file| file.duration).sum();
+        if !duration.is_zero() {
+            write!(
+                f,
+                " ({:.1} KiB at {:.1} KiB/s",
+                size as f64 / 1024.0,
+                size as f64 / 1024.0 / duration.as_secs_f64()
+            )?;
+            if let Some(chunk_size) = uploaded.iter().map(|file| file.stats.chunk_size).max() {
+                write!(f, " in {chunk_size} byte chunks")?;
+            }
+            f.write_str(")")?;
+        }
+        if let Some(failed) = self.failed_file() {
+            write!(f, ", failed at {}", failed.file_name)?;
+        }
+        for file in self.unconfirmed_files() {
+            write!(f, ", couldn't confirm {} afterwards", file.file_name)?;
+        }
+        if let Some(diagnosis) = self.diagnosis() {
+            write!(f, "; {diagnosis}")?;
+        }
+        Ok(())
+    }
+}
+
+/// The share of chunks, in percent, that have to fail for [`UploadReport::diagnosis`]
+/// to blame the link.
+pub const DIAGNOSIS_THRESHOLD_PERCENT: u64 = 5;
+
+/// Returns the 1-indexed program slot that a `slot_N.ini`, `slot_N.bin`, or `slot_N_lib.bin`
+/// file belongs to.
+fn program_slot(file_name: &str) -> Option<u8> {
+    let rest = file_name.strip_prefix("slot_")?;
+    let (slot, suffix) = rest.split_at(rest.find(|c: char| !c.is_ascii_digit())?);
+    if !matches!(suffix, ".ini" | ".bin" | "_lib.bin") {
+        return None;
+    }
+    slot.parse().ok()
+}
+
+/// The program slots whose files were changed by a command.
+///
+/// Tools that cache slot information can use this to refresh only the affected slots.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SlotsChanged {
+    /// 1-indexed slots in ascending order.
+    pub slots: Vec<u8>,
+}
+impl SlotsChanged {
+    fn from_file_names<'a>(file_names: impl IntoIterator<Item = &'a str>) -> Self {
+        let mut slots: Vec<u8> = file_names.into_iter().filter_map(program_slot).collect();
+        slots.sort_unstable();
+        slots.dedup();
+        Self { slots }
+    }
+}
+
+/// The files stored on the brain under a single vendor.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StorageUsage {
+    pub files: Vec<GetDirectoryEntryReplyPayload>,
+}
+impl StorageUsage {
+    pub fn file_count(&self) -> usize {
+        self.files.len()
+    }
+
+    /// Returns the combined size of every file in bytes.
+    pub fn used(&self) -> u64 {
+        self.files.iter().map(|file| file.size as u64).sum()
+    }
+
+    /// Estimates the free space left in bytes, given the total user storage capacity.
+    ///
+    /// This is only an approximation. VEXos has no free space query, doesn't report the
+    /// overhead of its filesystem, and the capacity differs between products, so it must
+    /// be supplied by the caller.
+    pub fn estimated_free(&self, capacity: u64) -> u64 {
+        capacity.saturating_sub(self.used())
+    }
+}
+
+/// Lists the files stored under a vendor to find how much storage they use.
+pub struct GetStorageUsage {
+    pub vendor: FileVendor,
+}
+impl Command for GetStorageUsage {
+    type Output = StorageUsage;
+
+    async fn execute<C: Connection + ?Sized>(
+        self,
+        connection: &mut C,
+    ) -> Result<Self::Output, C::Error> {
+        let usage = StorageUsage {
+            files: BrainFs::new(connection).list(self.vendor).await?.to_vec(),
+        };
+
+        debug!("{} files using {} bytes", usage.file_count(), usage.used());
+        Ok(usage)
+    }
+}
+
+/// The size and CRC32 of a file in a program slot.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SlotFileDigest {
+    pub file_name: String,
+    pub size: u32,
+    pub crc: u32,
+}
+
+/// The files stored in a program slot.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SlotDigest {
+    /// 1-indexed slot
+    pub slot: u8,
+    pub files: Vec<SlotFileDigest>,
+}
+
+/// Summarizes the files of every program slot.
+///
+/// The digest comes from a single listing of the user directory rather than a metadata query
+/// for each slot's files, so it can be fetched often and compared against a previous digest to
+/// find which slots changed. Slots without any files are left out.
+pub struct GetSlotDigest;
+impl Command for GetSlotDigest {
+    type Output = Vec<SlotDigest>;
+
+    async fn execute<C: Connection + ?Sized>(
+        self,
+        connection: &mut C,
+    ) -> Result<Self::Output, C::Error> {
+        let usage = connection
+            .execute_command(GetStorageUsage {
+                vendor: FileVendor::User,
+            })
+            .await?;
+
+        let mut slots = BTreeMap::<u8, Vec<SlotFileDigest>>::new();
+        for file in usage.files {
+            if let Some(slot) = program_slot(&file.file_name) {
+                slots.entry(slot).or_default().push(SlotFileDigest {
+                    file_name: file.file_name,
+                    size: file.size,
+                    crc: file.crc,
+                });
+            }
+        }
+
+        Ok(slots
+            .into_iter()
+            .map(|(slot, files)| SlotDigest { slot, files })
+            .collect())
+    }
+}
+
+/// Uploads a program and its ini config to a slot on the brain.
+///
+/// The slots changed by the upload can be found with [`UploadReport::slots_changed`].
+///
+/// Requires the `ini` feature. Compressing the program requires the `compression` feature.
+#[cfg(feature = "ini")]
+pub struct UploadProgram<'a> {
+    pub name: String,
+    pub description: String,
+    pub icon: String,
+    pub program_type: String,
+    /// 0-indexed slot
+    pub slot: u8,
+    /// Whether to gzip the program's binaries before uploading them.
+    ///
+    /// Without the `compression` feature, setting this fails the upload with
+    /// [`CommandError::CompressionUnavailable`].
+    pub compress_program: bool,
     pub data: ProgramData,
     pub after_upload: FileExitAction,
+    /// The brain's total user storage in bytes.
+    ///
+    /// If set, [`GetStorageUsage`] is used to check that the program will fit before
+    /// anything is uploaded, failing with [`CommandError::InsufficientStorage`] if it won't.
+    pub storage_capacity: Option<u64>,
+    /// Minimum time between packets when uploading through a controller's radio link.
+    ///
+    /// `None` sends packets as fast as possible. [`DEFAULT_WIRELESS_PACING`] is meant to keep
+    /// the controller responsive during the upload, but it hasn't been measured yet. Pacing
+    /// already set on the connection takes precedence.
+    pub wireless_pacing: Option<Duration>,
+    /// Whether to switch a controller's radio to the download channel for the upload.
+    ///
+    /// The pit channel is restored afterwards, even if the upload fails. Leave this off if
+    /// the channel is already managed with a [`DownloadChannelGuard`].
+    pub download_channel: bool,
+    /// What to do if the name is longer than [`MAX_PROGRAM_NAME_LEN`] or the description is
+    /// longer than [`MAX_PROGRAM_DESCRIPTION_LEN`].
+    pub long_text: LongTextPolicy<'a>,
+    /// What to do if the brain's battery is low.
+    ///
+    /// The battery is checked once, before any of the program's files are uploaded.
+    pub low_battery: LowBatteryPolicy<'a>,
 
     /// Called when progress has been made on the ini file.
     ///
@@ -321,8 +1973,34 @@ pub struct UploadProgram<'a> {
     /// 100.0 should be considered a finished upload.
     pub lib_callback: Option<Box<dyn FnMut(f32) + Send + 'a>>,
 }
+#[cfg(feature = "ini")]
+impl UploadProgram<'_> {
+    /// Returns a stream of the progress of each file in the program, alongside the callbacks.
+    ///
+    /// Progress starts again from 0 for the ini file, the cold library, and the binary, in the
+    /// order that they're uploaded. Only the files in `data` are waited for, so set `data`
+    /// before calling this.
+    ///
+    /// See the [`progress`](super::progress) module for how the stream behaves.
+    pub fn progress_stream(&mut self) -> ProgressStream {
+        let (sender, stream) = progress_channel();
+        let (has_lib, has_bin) = match &self.data {
+            ProgramData::Monolith(_) => (false, true),
+            ProgramData::HotCold { hot, cold } => (cold.is_some(), hot.is_some()),
+        };
+        if has_lib {
+            self.lib_callback = Some(sender.clone().attach(self.lib_callback.take()));
+        }
+        if has_bin {
+            self.bin_callback = Some(sender.clone().attach(self.bin_callback.take()));
+        }
+        self.ini_callback = Some(sender.attach(self.ini_callback.take()));
+        stream
+    }
+}
+#[cfg(feature = "ini")]
 impl Command for UploadProgram<'_> {
-    type Output = ();
+    type Output = UploadReport;
 
     async fn execute<C: Connection + ?Sized>(
         mut self,
@@ -330,44 +2008,54 @@ impl Command for UploadProgram<'_> {
     ) -> Result<Self::Output, C::Error> {
         let base_file_name = format!("slot_{}", self.slot);
 
-        debug!("Uploading program ini file");
+        let mut uploads = Vec::new();
+
+        let name = fit_program_text("name", self.name, MAX_PROGRAM_NAME_LEN, &mut self.long_text)?;
+        let description = fit_program_text(
+            "description",
+            self.description,
+            MAX_PROGRAM_DESCRIPTION_LEN,
+            &mut self.long_text,
+        )?;
 
         let ini = ProgramIniConfig {
             program: Program {
-                description: self.description,
+                description,
                 icon: self.icon,
                 iconalt: String::new(),
                 slot: self.slot - 1,
-                name: self.name,
+                name,
             },
             project: Project {
                 ide: self.program_type,
             },
         };
 
-        connection
-            .execute_command(UploadFile {
-                filename: FixedString::new(format!("{}.ini", base_file_name))?,
-                metadata: FileMetadata {
-                    extension: FixedString::new("ini".to_string())?,
-                    extension_type: ExtensionType::default(),
-                    timestamp: j2000_timestamp(),
-                    version: Version {
-                        major: 1,
-                        minor: 0,
-                        build: 0,
-                        beta: 0,
-                    },
+        uploads.push(UploadFile {
+            filename: FixedString::new(format!("{}.ini", base_file_name))?,
+            metadata: FileMetadata {
+                extension: FixedString::new("ini".to_string())?,
+                extension_type: ExtensionType::default(),
+                timestamp: j2000_timestamp(),
+                version: Version {
+                    major: 1,
+                    minor: 0,
+                    build: 0,
+                    beta: 0,
                 },
-                vendor: None,
-                data: serde_ini::to_vec(&ini).unwrap(),
-                target: None,
-                load_addr: USER_PROGRAM_LOAD_ADDR,
-                linked_file: None,
-                after_upload: FileExitAction::DoNothing,
-                progress_callback: self.ini_callback.take(),
-            })
-            .await?;
+            },
+            vendor: None,
+            data: serde_ini::to_vec(&ini).unwrap(),
+            target: None,
+            load_addr: USER_PROGRAM_LOAD_ADDR,
+            linked_file: None,
+            after_upload: FileExitAction::DoNothing,
+            confirm_run: false,
+            check_directory: false,
+            // The battery is checked once for the whole program
+            low_battery: LowBatteryPolicy::Skip,
+            progress_callback: self.ini_callback.take(),
+        });
 
         let program_bin_name = format!("{base_file_name}.bin");
         let program_lib_name = format!("{base_file_name}_lib.bin");
@@ -378,100 +2066,1448 @@ impl Command for UploadProgram<'_> {
             ProgramData::Monolith(data) => (Some(data), None),
         };
 
-        if let Some(mut library_data) = library_data {
-            debug!("Uploading cold library binary");
-
+        let library = if let Some(mut library_data) = library_data {
             // Compress the file to improve upload times
             // We don't need to change any other flags, the brain is smart enough to decompress it
             if self.compress_program {
                 debug!("Compressing cold library binary");
-                compress(&mut library_data);
+                compress(&mut library_data)?;
                 debug!("Compression complete");
             }
 
-            connection
-                .execute_command(UploadFile {
-                    filename: FixedString::new(program_lib_name.clone())?,
-                    metadata: FileMetadata {
-                        extension: FixedString::new("bin".to_string())?,
-                        extension_type: ExtensionType::default(),
-                        timestamp: j2000_timestamp(),
-                        version: Version {
-                            major: 1,
-                            minor: 0,
-                            build: 0,
-                            beta: 0,
-                        },
-                    },
-                    vendor: None,
-                    data: library_data,
-                    target: None,
-                    load_addr: PROS_HOT_BIN_LOAD_ADDR,
-                    linked_file: None,
-                    after_upload: if is_monolith {
-                        self.after_upload
-                    } else {
-                        // we are still uploading, so the post-upload action should not yet be performed
-                        FileExitAction::DoNothing
-                    },
-                    progress_callback: self.lib_callback.take(),
-                })
-                .await?;
-        }
-
-        if let Some(mut program_data) = program_data {
-            debug!("Uploading program binary");
+            Some(UploadFile {
+                filename: FixedString::new(program_lib_name.clone())?,
+                metadata: FileMetadata {
+                    extension: FixedString::new("bin".to_string())?,
+                    extension_type: ExtensionType::default(),
+                    timestamp: j2000_timestamp(),
+                    version: Version {
+                        major: 1,
+                        minor: 0,
+                        build: 0,
+                        beta: 0,
+                    },
+                },
+                vendor: None,
+                data: library_data,
+                target: None,
+                load_addr: PROS_HOT_BIN_LOAD_ADDR,
+                linked_file: None,
+                after_upload: if is_monolith {
+                    self.after_upload
+                } else {
+                    // we are still uploading, so the post-upload action should not yet be performed
+                    FileExitAction::DoNothing
+                },
+                confirm_run: false,
+                check_directory: false,
+                low_battery: LowBatteryPolicy::Skip,
+                progress_callback: self.lib_callback.take(),
+            })
+        } else {
+            None
+        };
 
+        let program = if let Some(mut program_data) = program_data {
             if self.compress_program {
                 debug!("Compressing program binary");
-                compress(&mut program_data);
+                compress(&mut program_data)?;
                 debug!("Compression complete");
             }
 
-            // Only ask the brain to link to a library if the program expects it.
-            // Monolith programs don't have libraries.
-            let linked_file = if is_monolith {
+            Some(UploadFile {
+                filename: FixedString::new(program_bin_name)?,
+                metadata: FileMetadata {
+                    extension: FixedString::new("bin".to_string())?,
+                    extension_type: ExtensionType::default(),
+                    timestamp: j2000_timestamp(),
+                    version: Version {
+                        major: 1,
+                        minor: 0,
+                        build: 0,
+                        beta: 0,
+                    },
+                },
+                vendor: None,
+                data: program_data,
+                target: None,
+                load_addr: USER_PROGRAM_LOAD_ADDR,
+                linked_file: None,
+                after_upload: self.after_upload,
+                confirm_run: true,
+                check_directory: false,
+                low_battery: LowBatteryPolicy::Skip,
+                progress_callback: self.bin_callback.take(),
+            })
+        } else {
+            None
+        };
+
+        // Hot binaries are linked to their library, which has to be on the brain first.
+        // Monolith programs don't have libraries.
+        let hot_cold = match (library, program) {
+            (Some(library), Some(program)) => Some(HotColdUpload {
+                hot: program,
+                cold: ColdLibrary::Upload(library),
+            }),
+            (None, Some(program)) if !is_monolith => Some(HotColdUpload {
+                hot: program,
+                cold: ColdLibrary::Existing {
+                    file_name: FixedString::new(program_lib_name)?,
+                    vendor: FileVendor::User,
+                },
+            }),
+            (library, program) => {
+                uploads.extend(library);
+                uploads.extend(program);
                 None
-            } else {
-                debug!("Program will be linked to cold library: {program_lib_name:?}");
-                Some(LinkedFile {
-                    filename: FixedString::new(program_lib_name)?,
-                    vendor: None,
-                })
-            };
+            }
+        };
 
-            connection
-                .execute_command(UploadFile {
-                    filename: FixedString::new(program_bin_name)?,
-                    metadata: FileMetadata {
-                        extension: FixedString::new("bin".to_string())?,
-                        extension_type: ExtensionType::default(),
-                        timestamp: j2000_timestamp(),
-                        version: Version {
-                            major: 1,
-                            minor: 0,
-                            build: 0,
-                            beta: 0,
-                        },
-                    },
-                    vendor: None,
-                    data: program_data,
-                    target: None,
-                    load_addr: USER_PROGRAM_LOAD_ADDR,
-                    linked_file,
-                    after_upload: self.after_upload,
-                    progress_callback: self.bin_callback.take(),
+        check_battery(connection, &mut self.low_battery).await?;
+
+        if let Some(capacity) = self.storage_capacity {
+            let usage = connection
+                .execute_command(GetStorageUsage {
+                    vendor: FileVendor::User,
                 })
                 .await?;
+
+            // Files that are about to be overwritten don't count against the free space
+            let replaced: u64 = usage
+                .files
+                .iter()
+                .filter(|file| {
+                    uploads
+                        .iter()
+                        .chain(hot_cold.iter().flat_map(HotColdUpload::files))
+                        .any(|upload| upload.filename.as_ref() == file.file_name)
+                })
+                .map(|file| file.size as u64)
+                .sum();
+
+            let needed = uploads
+                .iter()
+                .chain(hot_cold.iter().flat_map(HotColdUpload::files))
+                .map(|upload| upload.data.len() as u64)
+                .sum();
+            let available = usage.estimated_free(capacity) + replaced;
+            if needed > available {
+                return Err(CommandError::InsufficientStorage { needed, available }.into());
+            }
         }
 
-        Ok(())
+        let report = if self.download_channel && connection.connection_type().is_controller() {
+            let mut guard = DownloadChannelGuard::acquire(connection).await?;
+            let result =
+                upload_program_files(&mut *guard, uploads, hot_cold, self.wireless_pacing).await;
+            // The upload's error is more useful than a failure to switch back
+            let released = guard.release().await;
+            let report = result?;
+            released?;
+            report
+        } else {
+            upload_program_files(connection, uploads, hot_cold, self.wireless_pacing).await?
+        };
+
+        if report.failed_file().is_some() {
+            return Err(CommandError::UploadFailed(Box::new(report)).into());
+        }
+        Ok(report)
+    }
+}
+
+/// Uploads the files of an [`UploadProgram`] in order.
+#[cfg(feature = "ini")]
+async fn upload_program_files<C: Connection + ?Sized>(
+    connection: &mut C,
+    uploads: Vec<UploadFile<'_>>,
+    hot_cold: Option<HotColdUpload<'_>>,
+    wireless_pacing: Option<Duration>,
+) -> Result<UploadReport, C::Error> {
+    let previous_pacing = connection.send_pacing();
+    if connection.connection_type().is_controller() && previous_pacing.is_none() {
+        connection.set_send_pacing(wireless_pacing);
+    }
+
+    let mut report = UploadReport::default();
+    for upload in uploads {
+        upload_and_report(connection, upload, &mut report).await;
+    }
+    let result = match hot_cold {
+        Some(hot_cold) => upload_hot_cold_and_report(connection, hot_cold, &mut report).await,
+        None => Ok(()),
+    };
+
+    connection.set_send_pacing(previous_pacing);
+    result.map(|()| report)
+}
+
+/// How often the file's metadata is read while waiting for a background erase to finish.
+const ERASE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long [`EraseFile`] waits for a background erase to finish before failing with
+/// [`CommandError::EraseTimedOut`].
+///
+/// This is a generous allowance rather than a measured worst case.
+pub const ERASE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Erases a single file from the brain.
+///
+/// The erase is requested with [`FileEraseOption::Background`] so that erasing a large file
+/// doesn't hold up the handshake, then the file's metadata is polled every 100 ms until the
+/// brain reports that the file no longer exists, for up to [`ERASE_TIMEOUT`].
+pub struct EraseFile {
+    pub file_name: FixedString<23>,
+    pub vendor: FileVendor,
+}
+impl Command for EraseFile {
+    type Output = ();
+
+    async fn execute<C: Connection + ?Sized>(
+        self,
+        connection: &mut C,
+    ) -> Result<Self::Output, C::Error> {
+        erase_file(connection, self).await
+    }
+}
+
+/// Erases a single file from the brain.
+///
+/// This is the implementation of [`EraseFile`], for use inside other commands.
+pub async fn erase_file<C: Connection + ?Sized>(
+    connection: &mut C,
+    file: EraseFile,
+) -> Result<(), C::Error> {
+    debug!("Erasing file: {}", file.file_name);
+
+    connection
+        .request(
+            Duration::from_millis(500),
+            5,
+            EraseFilePacket::new(EraseFilePayload {
+                vendor: file.vendor,
+                option: FileEraseOption::Background,
+                file_name: file.file_name.clone(),
+            }),
+        )
+        .await?
+        .try_into_inner()?;
+
+    let erased = poll_until(
+        connection,
+        ERASE_POLL_INTERVAL,
+        ERASE_TIMEOUT,
+        async |connection| {
+            let metadata = connection
+                .request(
+                    Duration::from_millis(500),
+                    5,
+                    GetFileMetadataPacket::new(GetFileMetadataPayload {
+                        vendor: file.vendor,
+                        option: 0,
+                        file_name: file.file_name.clone(),
+                    }),
+                )
+                .await?
+                .try_into_inner()?;
+            Ok(metadata.is_none().then_some(()))
+        },
+    )
+    .await?;
+
+    match erased {
+        Some(()) => {
+            debug!("Successfully erased file: {}", file.file_name);
+            Ok(())
+        }
+        None => Err(CommandError::EraseTimedOut {
+            file_name: file.file_name.to_string(),
+            waited: ERASE_TIMEOUT,
+        }
+        .into()),
+    }
+}
+
+/// Erases the ini, binary, and (if present) cold library files of a program slot.
+///
+/// The slot is only reported as changed if it had any files to erase.
+pub struct EraseProgram {
+    /// 1-indexed slot
+    pub slot: u8,
+}
+impl Command for EraseProgram {
+    type Output = SlotsChanged;
+
+    async fn execute<C: Connection + ?Sized>(
+        self,
+        connection: &mut C,
+    ) -> Result<Self::Output, C::Error> {
+        let base_file_name = format!("slot_{}", self.slot);
+        let mut changed = SlotsChanged::default();
+        let mut fs = BrainFs::new(connection);
+
+        for file_name in [
+            format!("{base_file_name}.ini"),
+            format!("{base_file_name}.bin"),
+            format!("{base_file_name}_lib.bin"),
+        ] {
+            let file_name = FixedString::new(file_name)?;
+
+            // Nothing to erase
+            if fs.metadata(FileVendor::User, &file_name).await?.is_none() {
+                continue;
+            }
+
+            fs.remove(FileVendor::User, file_name).await?;
+            changed.slots = vec![self.slot];
+        }
+
+        Ok(changed)
     }
 }
 
 /// Apply gzip compression to the given data
-fn compress(data: &mut Vec<u8>) {
+#[cfg(all(feature = "ini", feature = "compression"))]
+fn compress(data: &mut Vec<u8>) -> Result<(), CommandError> {
+    use flate2::{Compression, GzBuilder};
+    use std::io::Write;
+
     let mut encoder = GzBuilder::new().write(Vec::new(), Compression::default());
     encoder.write_all(data).unwrap();
     *data = encoder.finish().unwrap();
+    Ok(())
+}
+
+/// Fails, since compressing needs the `compression` feature.
+#[cfg(all(feature = "ini", not(feature = "compression")))]
+fn compress(_data: &mut Vec<u8>) -> Result<(), CommandError> {
+    Err(CommandError::CompressionUnavailable)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{
+        download_file, erase_file, gzip_size, hot_cold_upload, max_chunk_size, program_slot,
+        upload_and_report, upload_crc, upload_file, BrainFileReader, ColdLibrary,
+        CompressionApplied, DownloadFile, EraseFile, FileExitAction, FileTransferTarget,
+        FileUploadOutcome, FileUploadResult, FileVendor, GetSlotDigest, HotColdUpload,
+        IniParseError, LinkedFile, LowBatteryPolicy, Program, ProgramIniConfig, Project,
+        ReadMemory, SlotDigest, SlotFileDigest, TransferStats, TransferSummary, UploadFile,
+        UploadReport, UploadWarning, DEFAULT_MIN_BATTERY_PERCENT, ERASE_TIMEOUT,
+        RUN_CONFIRM_GRACE_PERIOD,
+    };
+    use crate::{
+        commands::{
+            progress::{tests::ready_items, TransferProgress},
+            Command, CommandError, SharedError,
+        },
+        connection::{
+            mock::{
+                battery_reply, block_on, cdc2_reply, cdc2_reply_with, flags_reply,
+                init_transfer_reply, metadata_reply, read_nack, read_reply, MockConnection,
+                MockError, Responder,
+            },
+            Connection, ConnectionType, TransferState,
+        },
+        crc::VEX_CRC32,
+        packets::{
+            cdc2::Cdc2Ack,
+            file::{ExtensionType, FileInitAction, FileMetadata},
+        },
+        string::FixedString,
+        version::Version,
+    };
+
+    fn download(resume_from: u32) -> DownloadFile {
+        DownloadFile {
+            file_name: FixedString::new("log.txt".to_string()).unwrap(),
+            size: 12,
+            vendor: FileVendor::User,
+            target: Some(FileTransferTarget::Qspi),
+            load_addr: 0x1000,
+            resume_from,
+            progress_callback: None,
+        }
+    }
+
+    #[test]
+    fn download_interrupted() {
+        // The reply to the last chunk never arrives
+        let mut connection = MockConnection {
+            replies: [
+                init_transfer_reply(4, 12),
+                read_reply(0x1000, &[1, 2, 3, 4]),
+                read_reply(0x1004, &[5, 6, 7, 8]),
+            ]
+            .into(),
+            ..Default::default()
+        };
+        let Err(MockError::Command(CommandError::DownloadInterrupted {
+            partial,
+            next_offset,
+            ..
+        })) = block_on(download_file(&mut connection, download(0)))
+        else {
+            panic!("Download should have been interrupted");
+        };
+        assert_eq!(partial, [1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(next_offset, 8);
+
+        let mut connection = MockConnection {
+            replies: [
+                init_transfer_reply(4, 12),
+                read_reply(0x1008, &[9, 10, 11, 12]),
+            ]
+            .into(),
+            ..Default::default()
+        };
+        let rest = block_on(download_file(&mut connection, download(next_offset))).unwrap();
+        assert_eq!(rest.data, [9, 10, 11, 12]);
+        // The resumed download asks for the rest of the file, not the start
+        assert_eq!(connection.sent[1][7..11], 0x1008u32.to_le_bytes());
+    }
+
+    #[test]
+    fn brain_file_reader() {
+        let mut connection = MockConnection {
+            replies: [
+                init_transfer_reply(4, 10),
+                read_reply(0x1000, &[1, 2, 3, 4]),
+                read_reply(0x1004, &[5, 6, 7, 8]),
+                // The last chunk runs past the end of the file
+                read_reply(0x1008, &[9, 10, 0xFF, 0xFF]),
+            ]
+            .into(),
+            ..Default::default()
+        };
+
+        block_on(async {
+            let mut reader = BrainFileReader::open(&mut connection, download(0))
+                .await
+                .unwrap();
+            assert_eq!(reader.file_size(), 10);
+            let mut buf = [0; 3];
+            assert_eq!(reader.read(&mut buf).await.unwrap(), 3);
+            assert_eq!(buf, [1, 2, 3]);
+            assert_eq!(reader.position(), 3);
+        });
+        // Only the chunk that was needed was read, and dropping the reader closed the transfer
+        assert_eq!(connection.sent.len(), 2);
+        assert!(connection.active_transfer().is_none());
+
+        let mut connection = MockConnection {
+            replies: [
+                init_transfer_reply(4, 10),
+                read_reply(0x1004, &[5, 6, 7, 8]),
+                read_reply(0x1008, &[9, 10, 0xFF, 0xFF]),
+            ]
+            .into(),
+            ..Default::default()
+        };
+        let data = block_on(async {
+            let mut reader = BrainFileReader::open(&mut connection, download(4))
+                .await
+                .unwrap();
+            let mut data = Vec::new();
+            let mut buf = [0; 3];
+            loop {
+                let len = reader.read(&mut buf).await.unwrap();
+                if len == 0 {
+                    break;
+                }
+                data.extend_from_slice(&buf[..len]);
+            }
+            assert_eq!(reader.position(), 10);
+            data
+        });
+        assert_eq!(data, [5, 6, 7, 8, 9, 10]);
+    }
+
+    #[test]
+    fn download_bad_offsets() {
+        // The file shrank since the interrupted download
+        let mut connection = MockConnection {
+            replies: [init_transfer_reply(4, 8)].into(),
+            ..Default::default()
+        };
+        let Err(MockError::Command(CommandError::ResumePastEnd { file_size, .. })) =
+            block_on(download_file(&mut connection, download(12)))
+        else {
+            panic!("Resuming past the end of the file should fail");
+        };
+        assert_eq!(file_size, 8);
+
+        let mut connection = MockConnection {
+            replies: [init_transfer_reply(4, 12)].into(),
+            ..Default::default()
+        };
+        let mut file = download(8);
+        file.load_addr = u32::MAX - 4;
+        let Err(MockError::Command(CommandError::ReadAddressOverflow { offset, .. })) =
+            block_on(download_file(&mut connection, file))
+        else {
+            panic!("Reading past the end of the address space should fail");
+        };
+        assert_eq!(offset, 8);
+        // Nothing is read from the wrapped address
+        assert_eq!(connection.sent.len(), 1);
+    }
+
+    #[test]
+    fn bluetooth_memory_read() {
+        // Read replies don't always report a window size
+        let mut connection = MockConnection {
+            replies: [
+                init_transfer_reply(0, 8),
+                read_reply(0x1000, &[1, 2, 3, 4, 5, 6, 7, 8]),
+            ]
+            .into(),
+            connection_type: Some(ConnectionType::Bluetooth),
+            ..Default::default()
+        };
+        let read = ReadMemory {
+            target: FileTransferTarget::Ddr,
+            address: 0x1000,
+            len: 8,
+        };
+        assert_eq!(
+            block_on(read.execute(&mut connection)).unwrap(),
+            [1, 2, 3, 4, 5, 6, 7, 8]
+        );
+
+        // Windows too small to fit the packet overhead still leave room for a word
+        assert_eq!(max_chunk_size(ConnectionType::Bluetooth, 20), 4);
+        assert_eq!(max_chunk_size(ConnectionType::Bluetooth, 0), 228);
+    }
+
+    #[test]
+    fn memory_read_address_overflow() {
+        // The brain returns less than asked for, leaving the next chunk past the address space
+        let mut connection = MockConnection {
+            replies: [
+                init_transfer_reply(0, 8),
+                read_reply(u32::MAX - 3, &[1, 2, 3, 4]),
+            ]
+            .into(),
+            ..Default::default()
+        };
+        let read = ReadMemory {
+            target: FileTransferTarget::Ddr,
+            address: u32::MAX - 3,
+            len: 8,
+        };
+        let Err(MockError::Command(CommandError::ReadAddressOverflow { offset, .. })) =
+            block_on(read.execute(&mut connection))
+        else {
+            panic!("Reading past the end of the address space should fail");
+        };
+        assert_eq!(offset, 4);
+        assert_eq!(connection.sent.len(), 2);
+    }
+
+    #[test]
+    fn read_chunk_size_negotiation() {
+        // A brain that doesn't report a window size and only accepts reads of up to 1024 bytes
+        let mut connection = MockConnection {
+            replies: [
+                init_transfer_reply(0, 12),
+                read_nack(Cdc2Ack::NackTransferSize),
+                read_nack(Cdc2Ack::NackPacketLength),
+                read_reply(0x1000, &[1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12]),
+                init_transfer_reply(0, 12),
+                read_reply(0x1000, &[1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12]),
+            ]
+            .into(),
+            ..Default::default()
+        };
+        let read_size = |packet: &Vec<u8>| u16::from_le_bytes([packet[11], packet[12]]);
+
+        let file = block_on(download_file(&mut connection, download(0))).unwrap();
+        assert_eq!(file.data, [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12]);
+        assert_eq!(file.summary.size, 12);
+        assert_eq!(file.summary.retries, 0);
+        let sizes: Vec<_> = connection.sent[1..].iter().map(read_size).collect();
+        assert_eq!(sizes, [4096, 2048, 1024]);
+        assert_eq!(connection.read_chunk_size, Some(1024));
+
+        // The next read on the connection starts with the size that worked
+        connection.sent.clear();
+        block_on(download_file(&mut connection, download(0))).unwrap();
+        assert_eq!(read_size(&connection.sent[1]), 1024);
+    }
+
+    #[test]
+    fn read_chunk_size_floor() {
+        let mut connection = MockConnection {
+            replies: [init_transfer_reply(0, 12)].into(),
+            ..Default::default()
+        };
+        connection
+            .replies
+            .extend((0..5).map(|_| read_nack(Cdc2Ack::NackTransferSize)));
+        let Err(MockError::Command(CommandError::DownloadInterrupted { source, .. })) =
+            block_on(download_file(&mut connection, download(0)))
+        else {
+            panic!("The download should give up at the smallest chunk size");
+        };
+        assert!(matches!(
+            source.downcast_ref(),
+            Some(MockError::Nack(Cdc2Ack::NackTransferSize))
+        ));
+        // 4096, 2048, 1024, 512, then 256 bytes
+        assert_eq!(connection.sent.len(), 6);
+        assert_eq!(connection.read_chunk_size, None);
+    }
+
+    #[test]
+    fn download_progress_stream() {
+        let (sent, received) = std::sync::mpsc::channel();
+        let mut file = download(0);
+        file.progress_callback = Some(Box::new(move |percent| sent.send(percent).unwrap()));
+        let mut stream = file.progress_stream();
+        let mut connection = MockConnection {
+            replies: [
+                init_transfer_reply(8, 12),
+                read_reply(0x1000, &[1, 2, 3, 4, 5, 6, 7, 8]),
+                read_reply(0x1008, &[9, 10, 11, 12, 0, 0, 0, 0]),
+            ]
+            .into(),
+            ..Default::default()
+        };
+        block_on(download_file(&mut connection, file)).unwrap();
+
+        let (items, ended) = ready_items(&mut stream);
+        assert!(ended);
+        assert_eq!(items.last(), Some(&TransferProgress::Finished));
+        // The callback still sees every event
+        assert_eq!(received.try_iter().last(), Some(100.0));
+
+        // An interrupted download stops the stream without finishing it
+        let mut file = download(0);
+        let mut stream = file.progress_stream();
+        let mut connection = MockConnection {
+            replies: [init_transfer_reply(4, 12)].into(),
+            ..Default::default()
+        };
+        assert!(block_on(download_file(&mut connection, file)).is_err());
+        assert_eq!(
+            ready_items(&mut stream),
+            (vec![TransferProgress::Stopped], true)
+        );
+    }
+
+    const COLD: &[u8] = &[1, 2, 3, 4, 5, 6, 7, 8];
+
+    fn upload(file_name: &str, data: &[u8]) -> UploadFile<'static> {
+        UploadFile {
+            filename: FixedString::new(file_name.to_string()).unwrap(),
+            metadata: FileMetadata {
+                extension: FixedString::new("bin".to_string()).unwrap(),
+                extension_type: ExtensionType::default(),
+                timestamp: 0,
+                version: Version {
+                    major: 1,
+                    minor: 0,
+                    build: 0,
+                    beta: 0,
+                },
+            },
+            vendor: None,
+            data: data.to_vec(),
+            target: None,
+            load_addr: 0x0780_0000,
+            linked_file: None,
+            after_upload: FileExitAction::DoNothing,
+            confirm_run: false,
+            check_directory: false,
+            low_battery: LowBatteryPolicy::Skip,
+            progress_callback: None,
+        }
+    }
+
+    fn hot_cold() -> HotColdUpload<'static> {
+        HotColdUpload {
+            hot: upload("slot_1.bin", &[9, 10, 11, 12]),
+            cold: ColdLibrary::Upload(upload("slot_1_lib.bin", COLD)),
+        }
+    }
+
+    /// Replies to uploading a file, optionally linked to another.
+    fn upload_replies(size: u32, linked: bool) -> Vec<Vec<u8>> {
+        let mut replies = vec![init_transfer_reply(4096, size)];
+        if linked {
+            replies.push(cdc2_reply(0x15, &[]));
+        }
+        replies.extend([cdc2_reply(0x13, &[]), cdc2_reply(0x12, &[])]);
+        replies
+    }
+
+    fn outcomes(report: &UploadReport) -> Vec<FileUploadOutcome> {
+        report
+            .files
+            .iter()
+            .map(|file| file.outcome.clone())
+            .collect()
+    }
+
+    fn run_upload(
+        connection_type: ConnectionType,
+        flags: impl IntoIterator<Item = Vec<u8>>,
+    ) -> MockConnection {
+        let mut replies = upload_replies(4, false);
+        replies.extend(flags);
+        replies.push(cdc2_reply(0x18, &[]));
+        let mut connection = MockConnection {
+            replies: replies.into(),
+            connection_type: Some(connection_type),
+            ..Default::default()
+        };
+
+        let mut file = upload("slot_1.bin", &[9, 10, 11, 12]);
+        file.after_upload = FileExitAction::RunProgram;
+        file.confirm_run = true;
+        let summary = block_on(upload_file(&mut connection, file)).unwrap();
+        assert_eq!(summary.warning, None);
+        connection
+    }
+
+    /// Checks the CRC declared when opening an upload against the bytes written, as the brain
+    /// does when the transfer is closed.
+    fn brain_crc_check(sent: &[Vec<u8>]) -> bool {
+        let init = &sent[0];
+        let file_size = u32::from_le_bytes(init[11..15].try_into().unwrap()) as usize;
+        let declared = u32::from_le_bytes(init[19..23].try_into().unwrap());
+        let mut stored: Vec<u8> = sent[1..sent.len() - 1]
+            .iter()
+            .flat_map(|write| write[11..write.len() - 2].to_vec())
+            .collect();
+        stored.truncate(file_size);
+        VEX_CRC32.checksum(&stored) == declared
+    }
+
+    #[cfg(all(feature = "ini", not(feature = "compression")))]
+    #[test]
+    fn compression_unavailable() {
+        let mut data = vec![1, 2, 3];
+        assert_eq!(
+            super::compress(&mut data),
+            Err(CommandError::CompressionUnavailable)
+        );
+    }
+
+    #[test]
+    fn upload_crc_paths() {
+        let plain = b"plain program bytes!".to_vec();
+        #[allow(unused_mut)]
+        let mut compressed = plain.clone();
+        #[cfg(all(feature = "ini", feature = "compression"))]
+        {
+            super::compress(&mut compressed).unwrap();
+            assert_eq!(CompressionApplied::detect(&compressed), CompressionApplied::Gzip);
+        }
+
+        for data in [plain, compressed] {
+            let mut connection = MockConnection {
+                replies: upload_replies(data.len() as u32, false).into(),
+                ..Default::default()
+            };
+            block_on(upload_file(&mut connection, upload("slot_1.bin", &data))).unwrap();
+            assert!(brain_crc_check(&connection.sent));
+            let declared = upload_crc(&data, CompressionApplied::detect(&data));
+            assert_eq!(connection.sent[0][19..23], declared.to_le_bytes());
+        }
+    }
+
+    #[test]
+    fn upload_crc_mismatch() {
+        let mut replies = upload_replies(8, false);
+        *replies.last_mut().unwrap() = cdc2_reply_with(0x12, Cdc2Ack::NackProgramCrc, &[]);
+        let mut connection = MockConnection {
+            replies: replies.into(),
+            ..Default::default()
+        };
+        let data = [1, 2, 3, 4, 5, 6, 7, 8];
+        let Err(MockError::Command(CommandError::UploadCrcMismatch {
+            file_name,
+            declared,
+            size,
+        })) = block_on(upload_file(&mut connection, upload("slot_1.bin", &data)))
+        else {
+            panic!("A CRC NACK at exit should be reported as a mismatch");
+        };
+        assert_eq!(file_name, "slot_1.bin");
+        assert_eq!(declared, VEX_CRC32.checksum(&data));
+        assert_eq!(size, 8);
+    }
+
+    #[test]
+    fn overlapping_transfer() {
+        let active = TransferState {
+            operation: FileInitAction::Read,
+            vendor: FileVendor::User,
+            file_name: "slot_1.bin".to_string(),
+            size: 4,
+        };
+        let mut connection = MockConnection {
+            active_transfer: Some(active.clone()),
+            ..Default::default()
+        };
+
+        let file = upload("notes.txt", &[1, 2, 3, 4]);
+        let Err(MockError::Command(CommandError::TransferAlreadyInProgress(transfer))) =
+            block_on(upload_file(&mut connection, file))
+        else {
+            panic!("A second transfer should be refused");
+        };
+        assert_eq!(transfer, active);
+        assert!(connection.sent.is_empty());
+
+        // Finished and failed transfers both clear the active transfer
+        connection.active_transfer = None;
+        connection.replies = upload_replies(4, false).into();
+        let file = upload("notes.txt", &[1, 2, 3, 4]);
+        block_on(upload_file(&mut connection, file)).unwrap();
+        assert_eq!(connection.active_transfer(), None);
+        assert!(block_on(upload_file(&mut connection, upload("notes.txt", &[1]))).is_err());
+        assert_eq!(connection.active_transfer(), None);
+    }
+
+    #[test]
+    fn low_battery_warning() {
+        let mut replies = vec![battery_reply(16)];
+        replies.extend(upload_replies(4, false));
+        let mut connection = MockConnection {
+            replies: replies.into(),
+            ..Default::default()
+        };
+
+        let mut warned = None;
+        let mut file = upload("notes.txt", &[1, 2, 3, 4]);
+        file.low_battery = LowBatteryPolicy::Warn {
+            threshold: DEFAULT_MIN_BATTERY_PERCENT,
+            callback: Box::new(|percent| warned = Some(percent)),
+        };
+        block_on(upload_file(&mut connection, file)).unwrap();
+        assert_eq!(warned, Some(16));
+        // The flags query, then init, one chunk, and exit
+        assert_eq!(connection.sent.len(), 4);
+    }
+
+    #[test]
+    fn low_battery_refusal() {
+        let mut connection = MockConnection {
+            replies: [battery_reply(8), battery_reply(40)].into(),
+            ..Default::default()
+        };
+        let refuse = || LowBatteryPolicy::Refuse {
+            threshold: DEFAULT_MIN_BATTERY_PERCENT,
+        };
+
+        let mut file = upload("notes.txt", &[1, 2, 3, 4]);
+        file.low_battery = refuse();
+        let Err(MockError::Command(CommandError::BatteryTooLow { percent, threshold })) =
+            block_on(upload_file(&mut connection, file))
+        else {
+            panic!("The upload should be refused");
+        };
+        assert_eq!((percent, threshold), (8, DEFAULT_MIN_BATTERY_PERCENT));
+        assert_eq!(connection.sent.len(), 1);
+
+        // A healthy battery goes on to start the transfer
+        let mut file = upload("notes.txt", &[1, 2, 3, 4]);
+        file.low_battery = refuse();
+        assert!(block_on(upload_file(&mut connection, file)).is_err());
+        assert_eq!(connection.sent[2][4..6], [0x56, 0x11]);
+    }
+
+    #[test]
+    fn confirm_run_started() {
+        let connection = run_upload(ConnectionType::Controller, [flags_reply(0), flags_reply(1)]);
+        // Init, one chunk, exit, and two polls
+        assert_eq!(connection.sent.len(), 5);
+        assert_eq!(connection.sent[4][4..6], [0x56, 0x20]);
+    }
+
+    #[test]
+    fn confirm_run_retry() {
+        // One poll up front, then one after each interval of the grace period
+        let polls = (RUN_CONFIRM_GRACE_PERIOD.as_millis() / 250) as usize + 1;
+        let connection = run_upload(ConnectionType::Controller, vec![flags_reply(0); polls]);
+        assert_eq!(connection.sent.len(), 3 + polls + 1);
+        assert_eq!(
+            connection.sleeps,
+            vec![Duration::from_millis(250); polls - 1]
+        );
+        // The program is started explicitly after the last poll
+        let load = connection.sent.last().unwrap();
+        assert_eq!(load[4..6], [0x56, 0x18]);
+        assert!(load.windows(10).any(|w| w == b"slot_1.bin"));
+    }
+
+    #[test]
+    fn confirm_run_nacked() {
+        let polls = (RUN_CONFIRM_GRACE_PERIOD.as_millis() / 250) as usize + 1;
+        let mut replies = upload_replies(4, false);
+        replies.extend(vec![flags_reply(0); polls]);
+        replies.push(cdc2_reply_with(0x18, Cdc2Ack::NackProgramFile, &[]));
+        let mut connection = MockConnection {
+            replies: replies.into(),
+            connection_type: Some(ConnectionType::Controller),
+            ..Default::default()
+        };
+
+        let mut file = upload("slot_1.bin", &[9, 10, 11, 12]);
+        file.after_upload = FileExitAction::RunProgram;
+        file.confirm_run = true;
+        let summary = block_on(upload_file(&mut connection, file)).unwrap();
+        assert_eq!(
+            summary.warning,
+            Some(UploadWarning::RunNacked(Cdc2Ack::NackProgramFile))
+        );
+    }
+
+    #[test]
+    fn confirm_run_wired() {
+        let connection = run_upload(ConnectionType::Wired, []);
+        assert_eq!(connection.sent.len(), 3);
+    }
+
+    #[test]
+    fn hot_cold_upload_cold_missing() {
+        let mut replies = vec![cdc2_reply(0x19, &[0xFF])];
+        replies.extend(upload_replies(8, false));
+        replies.extend(upload_replies(4, true));
+        let mut connection = MockConnection {
+            replies: replies.into(),
+            ..Default::default()
+        };
+
+        let report = block_on(hot_cold_upload(&mut connection, hot_cold())).unwrap();
+        assert_eq!(
+            outcomes(&report),
+            [FileUploadOutcome::Uploaded, FileUploadOutcome::Uploaded]
+        );
+        // The link is sent right after the hot binary's transfer is started
+        assert_eq!(connection.sent.len(), 8);
+        assert_eq!(connection.sent[4][4..6], [0x56, 0x11]);
+        assert_eq!(connection.sent[5][4..6], [0x56, 0x15]);
+        assert!(connection.sent[5]
+            .windows(14)
+            .any(|w| w == b"slot_1_lib.bin"));
+    }
+
+    #[test]
+    fn hot_cold_upload_cold_stale() {
+        let mut replies = vec![metadata_reply(&[0; 8])];
+        replies.extend(upload_replies(8, false));
+        replies.extend(upload_replies(4, true));
+        let mut connection = MockConnection {
+            replies: replies.into(),
+            ..Default::default()
+        };
+
+        let report = block_on(hot_cold_upload(&mut connection, hot_cold())).unwrap();
+        assert_eq!(
+            outcomes(&report),
+            [FileUploadOutcome::Uploaded, FileUploadOutcome::Uploaded]
+        );
+        assert_eq!(connection.sent.len(), 8);
+    }
+
+    #[test]
+    fn hot_cold_upload_both_fresh() {
+        let mut replies = vec![metadata_reply(COLD)];
+        replies.extend(upload_replies(4, true));
+        let mut connection = MockConnection {
+            replies: replies.into(),
+            ..Default::default()
+        };
+
+        let report = block_on(hot_cold_upload(&mut connection, hot_cold())).unwrap();
+        assert_eq!(
+            outcomes(&report),
+            [FileUploadOutcome::UpToDate, FileUploadOutcome::Uploaded]
+        );
+        assert_eq!(connection.sent.len(), 5);
+        // The hot binary is opened and linked in one write
+        assert_eq!(connection.batches, [2]);
+        assert_eq!(connection.sent[1][4..6], [0x56, 0x11]);
+        assert_eq!(connection.sent[2][4..6], [0x56, 0x15]);
+    }
+
+    #[test]
+    fn background_erase() {
+        let erase = || EraseFile {
+            file_name: FixedString::new("slot_1.bin".to_string()).unwrap(),
+            vendor: FileVendor::User,
+        };
+        let mut connection = MockConnection {
+            replies: [
+                cdc2_reply(0x1B, &[]),
+                metadata_reply(&[1, 2, 3, 4]),
+                cdc2_reply(0x19, &[0xFF]),
+            ]
+            .into(),
+            ..Default::default()
+        };
+        block_on(erase_file(&mut connection, erase())).unwrap();
+        assert_eq!(connection.sleeps, [Duration::from_millis(100)]);
+
+        // A file that never goes away is given up on once the budget is used up
+        connection.sleeps.clear();
+        connection.respond = Some(Responder(Box::new(|sent| match sent[5] {
+            0x1B => cdc2_reply(0x1B, &[]),
+            _ => metadata_reply(&[1, 2, 3, 4]),
+        })));
+        let Err(MockError::Command(CommandError::EraseTimedOut { file_name, waited })) =
+            block_on(erase_file(&mut connection, erase()))
+        else {
+            panic!("An erase that never finishes should time out");
+        };
+        assert_eq!((file_name.as_str(), waited), ("slot_1.bin", ERASE_TIMEOUT));
+        assert_eq!(connection.sleeps.len(), 300);
+    }
+
+    #[test]
+    fn missing_directory() {
+        let count_nack = cdc2_reply_with(0x16, Cdc2Ack::NackNoDirectory, &[0, 0]);
+        let mut connection = MockConnection {
+            replies: [count_nack].into(),
+            ..Default::default()
+        };
+        let mut file = upload("data.bin", &[1, 2, 3, 4]);
+        file.vendor = Some(FileVendor::Dev1);
+        file.check_directory = true;
+        let Err(MockError::Command(CommandError::NoDirectory(vendor))) =
+            block_on(upload_file(&mut connection, file))
+        else {
+            panic!("A missing directory should fail before the transfer is opened");
+        };
+        assert_eq!(vendor, FileVendor::Dev1);
+        assert_eq!(connection.sent.len(), 1);
+
+        // Without the check, the init NACK is reported the same way
+        let mut replies = upload_replies(4, false);
+        replies[0] = cdc2_reply_with(0x11, Cdc2Ack::NackNoDirectory, &[0; 10]);
+        connection.replies = replies.into();
+        let mut file = upload("data.bin", &[1, 2, 3, 4]);
+        file.vendor = Some(FileVendor::Dev1);
+        assert!(matches!(
+            block_on(upload_file(&mut connection, file)),
+            Err(MockError::Command(CommandError::NoDirectory(_)))
+        ));
+    }
+
+    #[test]
+    fn exit_timeout() {
+        let mut replies = upload_replies(4, false);
+        replies.pop();
+        replies.extend(vec![Vec::new(); 6]);
+        let mut connection = MockConnection {
+            replies: replies.into(),
+            ..Default::default()
+        };
+
+        let mut file = upload("slot_1.bin", &[9, 10, 11, 12]);
+        file.after_upload = FileExitAction::RunProgram;
+        let mut report = UploadReport::default();
+        block_on(upload_and_report(&mut connection, file, &mut report));
+        let file = &report.files[0];
+        let FileUploadOutcome::CompletedWithWarnings(UploadWarning::ExitTimedOut(error)) =
+            &file.outcome
+        else {
+            panic!("Expected an exit timeout, got {:?}", file.outcome);
+        };
+        assert!(matches!(
+            error.downcast_ref(),
+            Some(MockError::Handshake(_))
+        ));
+        assert_eq!(file.stats.exit_retries, 6);
+        assert!(report.failed_file().is_none());
+        assert_eq!(report.unconfirmed_files().count(), 1);
+        assert!(report.to_string().starts_with("Uploaded 1 of 1 files"));
+
+        // A single upload succeeds with the same warning
+        let mut replies = upload_replies(4, false);
+        replies.pop();
+        replies.extend(vec![Vec::new(); 6]);
+        connection.replies = replies.into();
+        let file = upload("slot_1.bin", &[9, 10, 11, 12]);
+        let summary = block_on(upload_file(&mut connection, file)).unwrap();
+        assert!(matches!(
+            summary.warning,
+            Some(UploadWarning::ExitTimedOut(_))
+        ));
+
+        let mut replies = upload_replies(4, false);
+        *replies.last_mut().unwrap() = cdc2_reply_with(0x12, Cdc2Ack::Nack, &[]);
+        connection.replies = replies.into();
+        let file = upload("slot_1.bin", &[9, 10, 11, 12]);
+        let summary = block_on(upload_file(&mut connection, file)).unwrap();
+        assert_eq!(
+            summary.warning,
+            Some(UploadWarning::ExitNacked(Cdc2Ack::Nack))
+        );
+        assert!(summary
+            .to_string()
+            .contains(", 0 retries; closing the transfer was NACKed: "));
+
+        // A CRC NACK at exit is still a failure
+        let mut replies = upload_replies(4, false);
+        *replies.last_mut().unwrap() = cdc2_reply_with(0x12, Cdc2Ack::NackProgramCrc, &[]);
+        connection.replies = replies.into();
+        let file = upload("slot_1.bin", &[9, 10, 11, 12]);
+        block_on(upload_and_report(&mut connection, file, &mut report));
+        assert!(matches!(
+            report.files[1].outcome,
+            FileUploadOutcome::Failed(_)
+        ));
+    }
+
+    #[test]
+    fn linked_upload_unbatched() {
+        let mut replies = vec![init_transfer_reply(4096, 4), Vec::new()];
+        replies.extend(upload_replies(4, true));
+        let mut connection = MockConnection {
+            replies: replies.into(),
+            ..Default::default()
+        };
+
+        let mut file = upload("slot_1.bin", &[9, 10, 11, 12]);
+        file.linked_file = Some(LinkedFile {
+            filename: FixedString::new("slot_1_lib.bin".to_string()).unwrap(),
+            vendor: None,
+        });
+        let mut report = UploadReport::default();
+        block_on(upload_and_report(&mut connection, file, &mut report));
+        assert_eq!(report.files[0].outcome, FileUploadOutcome::Uploaded);
+        assert_eq!(report.files[0].stats.init_retries, 1);
+        assert!(report.files[0].stats.open_latency.is_some());
+        // The failed batch isn't a handshake
+        assert_eq!(connection.stats.failed_attempts, 0);
+        assert_eq!(connection.stats.handshakes, 4);
+        // The lost link reply makes the packets go again one at a time
+        let commands: Vec<_> = connection.sent.iter().map(|packet| packet[5]).collect();
+        assert_eq!(commands, [0x11, 0x15, 0x11, 0x15, 0x13, 0x12]);
+    }
+
+    #[test]
+    fn hot_cold_upload_existing_missing() {
+        let mut connection = MockConnection {
+            replies: [cdc2_reply(0x19, &[0xFF])].into(),
+            ..Default::default()
+        };
+        let upload = HotColdUpload {
+            hot: upload("slot_1.bin", &[9, 10, 11, 12]),
+            cold: ColdLibrary::Existing {
+                file_name: FixedString::new("slot_1_lib.bin".to_string()).unwrap(),
+                vendor: FileVendor::User,
+            },
+        };
+
+        let Err(MockError::Command(CommandError::MissingColdLibrary(file_name))) =
+            block_on(hot_cold_upload(&mut connection, upload))
+        else {
+            panic!("Upload should have failed on the missing library");
+        };
+        assert_eq!(file_name, "slot_1_lib.bin");
+        // The hot binary is never uploaded
+        assert_eq!(connection.sent.len(), 1);
+    }
+
+    // The samples below are synthesized from the layouts each tool is known to write, not
+    // captured from real brains.
+
+    #[test]
+    fn program_slots() {
+        assert_eq!(program_slot("slot_1.ini"), Some(1));
+        assert_eq!(program_slot("slot_8.bin"), Some(8));
+        assert_eq!(program_slot("slot_3_lib.bin"), Some(3));
+        assert_eq!(program_slot("slot_.bin"), None);
+        assert_eq!(program_slot("slot_1.txt"), None);
+        assert_eq!(program_slot("libpros.a"), None);
+    }
+
+    #[test]
+    fn report_slots_changed() {
+        let result = |file_name: &str, outcome| FileUploadResult {
+            file_name: file_name.to_string(),
+            size: 0,
+            uncompressed_size: None,
+            duration: Duration::ZERO,
+            outcome,
+            stats: TransferStats::default(),
+        };
+        let report = UploadReport {
+            files: vec![
+                result("slot_2.ini", FileUploadOutcome::Uploaded),
+                result("slot_2_lib.bin", FileUploadOutcome::UpToDate),
+                result("slot_2.bin", FileUploadOutcome::Uploaded),
+                result("slot_5.bin", FileUploadOutcome::Skipped),
+            ],
+        };
+        assert_eq!(report.slots_changed().slots, [2]);
+    }
+
+    #[test]
+    fn upload_stats() {
+        let crc_nack = cdc2_reply_with(0x13, Cdc2Ack::NackPacketCrc, &[]);
+        let mut connection = MockConnection {
+            replies: [
+                init_transfer_reply(4, 8),
+                // The first chunk's reply is lost
+                Vec::new(),
+                cdc2_reply(0x13, &[]),
+                // The second chunk is corrupted, which fails the upload
+                crc_nack,
+            ]
+            .into(),
+            ..Default::default()
+        };
+
+        let mut report = UploadReport::default();
+        block_on(upload_and_report(
+            &mut connection,
+            upload("notes.txt", &[1, 2, 3, 4, 5, 6, 7, 8]),
+            &mut report,
+        ));
+        let file = &report.files[0];
+        assert!(matches!(file.outcome, FileUploadOutcome::Failed(_)));
+        assert_eq!(
+            file.stats,
+            TransferStats {
+                chunk_size: 4,
+                chunks: 1,
+                init_retries: 0,
+                write_retries: 1,
+                exit_retries: 0,
+                crc_nacks: 1,
+                nacks: vec![(Cdc2Ack::NackPacketCrc, 1)],
+                open_latency: file.stats.open_latency,
+                peak_throughput: file.stats.peak_throughput,
+            }
+        );
+        // The chunk wasn't resent
+        assert_eq!(connection.sent.len(), 4);
+        assert_eq!(connection.stats.failed_attempts, 1);
+        assert_eq!(
+            report.diagnosis().as_deref(),
+            Some("50% of chunks failed with a CRC NACK, likely radio interference")
+        );
+    }
+
+    #[test]
+    fn transfer_summary() {
+        let mut summary = TransferSummary {
+            file_name: "slot_1.bin".to_string(),
+            size: 1_994_752,
+            duration: Duration::from_millis(3400),
+            peak_throughput: None,
+            retries: 0,
+            uncompressed_size: Some(4_865_000),
+            warning: None,
+        };
+        assert_eq!(
+            summary.to_string(),
+            "slot_1.bin: 1.9 MiB in 3.4 s (573 KiB/s), gzip 41%, 0 retries"
+        );
+
+        summary.size = 100;
+        summary.duration = Duration::ZERO;
+        summary.retries = 1;
+        summary.uncompressed_size = None;
+        assert_eq!(summary.to_string(), "slot_1.bin: 100 B in 0.0 s, 1 retry");
+
+        // Gzip streams end with the size of the data they contain
+        let mut gzip = vec![0x1F, 0x8B, 0x08, 0, 0, 0, 0, 0, 0, 0xFF, 0x03, 0x00];
+        gzip.extend([0; 4]);
+        gzip.extend(300u32.to_le_bytes());
+        assert_eq!(gzip_size(&gzip), Some(300));
+        assert_eq!(gzip_size(&[1, 2, 3, 4, 5]), None);
+    }
+
+    #[test]
+    fn report_display() {
+        let result = |file_name: &str, outcome, chunks, write_retries| FileUploadResult {
+            file_name: file_name.to_string(),
+            size: 2048,
+            uncompressed_size: None,
+            duration: Duration::from_secs(1),
+            outcome,
+            stats: TransferStats {
+                chunk_size: 244,
+                chunks,
+                write_retries,
+                ..Default::default()
+            },
+        };
+        let mut report = UploadReport {
+            files: vec![
+                result("slot_1.ini", FileUploadOutcome::Uploaded, 1, 0),
+                result("slot_1.bin", FileUploadOutcome::Uploaded, 9, 0),
+            ],
+        };
+        assert_eq!(
+            report.to_string(),
+            "Uploaded 2 of 2 files (4.0 KiB at 2.0 KiB/s in 244 byte chunks)"
+        );
+
+        report.files[1] = result(
+            "slot_1.bin",
+            FileUploadOutcome::Failed(SharedError::new(MockError::Timeout)),
+            9,
+            2,
+        );
+        assert_eq!(
+            report.to_string(),
+            "Uploaded 1 of 2 files (2.0 KiB at 2.0 KiB/s in 244 byte chunks), failed at slot_1.bin; \
+             20% of chunks retried after getting no reply, likely a weak radio signal"
+        );
+    }
+
+    #[test]
+    fn slot_digest() {
+        let entry = |file_index: u8, name: &str, size: u32, crc: u32| {
+            let mut payload = vec![file_index];
+            payload.extend(size.to_le_bytes());
+            payload.extend(0x0380_0000u32.to_le_bytes());
+            payload.extend(crc.to_le_bytes());
+            // No metadata
+            payload.extend([0xFF; 12]);
+            let mut name = name.as_bytes().to_vec();
+            name.resize(24, 0);
+            payload.extend(name);
+            cdc2_reply(0x17, &payload)
+        };
+        let mut connection = MockConnection {
+            replies: [
+                cdc2_reply(0x16, &3u16.to_le_bytes()),
+                entry(0, "slot_2.bin", 100, 0x1111),
+                entry(1, "notes.txt", 5, 0x2222),
+                entry(2, "slot_1.ini", 30, 0x3333),
+            ]
+            .into(),
+            ..Default::default()
+        };
+
+        let digest = block_on(GetSlotDigest.execute(&mut connection)).unwrap();
+        let file = |file_name: &str, size, crc| SlotFileDigest {
+            file_name: file_name.to_string(),
+            size,
+            crc,
+        };
+        assert_eq!(
+            digest,
+            [
+                SlotDigest {
+                    slot: 1,
+                    files: vec![file("slot_1.ini", 30, 0x3333)],
+                },
+                SlotDigest {
+                    slot: 2,
+                    files: vec![file("slot_2.bin", 100, 0x1111)],
+                },
+            ]
+        );
+        // One directory listing, with no per-slot metadata queries
+        assert_eq!(connection.sent.len(), 4);
+    }
+
+    #[cfg(feature = "ini")]
+    #[test]
+    fn program_text_limits() {
+        use super::{fit_program_text, LongTextPolicy, MAX_PROGRAM_NAME_LEN};
+
+        let name = "a".repeat(MAX_PROGRAM_NAME_LEN);
+        assert_eq!(
+            fit_program_text("name", name.clone(), 32, &mut LongTextPolicy::Reject),
+            Ok(name.clone())
+        );
+        assert_eq!(
+            fit_program_text("name", format!("{name}b"), 32, &mut LongTextPolicy::Reject),
+            Err(CommandError::ProgramTextTooLong {
+                field: "name",
+                len: 33,
+                max: 32,
+            })
+        );
+
+        // The two-byte 'é' would be split at the limit, so it's dropped entirely
+        let mut warnings = Vec::new();
+        let mut policy =
+            LongTextPolicy::Truncate(Box::new(|warning: &str| warnings.push(warning.to_string())));
+        assert_eq!(
+            fit_program_text("name", format!("{}é", &name[1..]), 32, &mut policy),
+            Ok(name[1..].to_string())
+        );
+        drop(policy);
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].starts_with("Program name truncated from 33 to 31 bytes"));
+    }
+
+    #[test]
+    fn parse_quoted_ini() {
+        let ini =
+            "[project]\r\nversion = \"1.0\"\r\nide = \"VEXcode\"\r\nfile = \"drive.v5blocks\"\r\n\
+                   [program]\r\nversion = \"1.0.0.0\"\r\nname = \"Drive\"\r\nslot = \"2\"\r\n\
+                   icon = \"VEXcodeBlocks.bmp\"\r\ndate = \"2024-01-01T00:00:00.000Z\"\r\n";
+        let (config, unknown) = ProgramIniConfig::from_ini_bytes(ini.as_bytes()).unwrap();
+
+        assert_eq!(config.project.ide, "VEXcode");
+        assert_eq!(config.program.name, "Drive");
+        assert_eq!(config.program.slot, 2);
+        assert_eq!(config.program.icon, "VEXcodeBlocks.bmp");
+        assert_eq!(unknown["project.version"], "1.0");
+        assert_eq!(unknown["project.file"], "drive.v5blocks");
+        assert_eq!(unknown["program.version"], "1.0.0.0");
+        assert_eq!(unknown["program.date"], "2024-01-01T00:00:00.000Z");
+    }
+
+    #[test]
+    fn parse_unquoted_ini() {
+        let ini = "; generated by a build tool\n[Project]\nIDE = PROS\n\n[Program]\n\
+                   Name = My Robot\nSlot = 0\nIcon = USER902x.bmp\niconalt =\n\
+                   description = Drives around; very fast\n";
+        let (config, unknown) = ProgramIniConfig::from_ini_bytes(ini.as_bytes()).unwrap();
+
+        assert_eq!(
+            config,
+            ProgramIniConfig {
+                project: Project {
+                    ide: "PROS".to_string(),
+                },
+                program: Program {
+                    name: "My Robot".to_string(),
+                    slot: 0,
+                    icon: "USER902x.bmp".to_string(),
+                    iconalt: String::new(),
+                    description: "Drives around; very fast".to_string(),
+                },
+            }
+        );
+        assert!(unknown.is_empty());
+    }
+
+    #[test]
+    fn parse_compact_ini() {
+        let ini =
+            "\u{feff}[program]\r\nname=quick\r\nslot=3\r\nicon=USER029x.bmp\r\nbogus line\r\n";
+        let (config, _) = ProgramIniConfig::from_ini_bytes(ini.as_bytes()).unwrap();
+
+        assert_eq!(config.project, Project::default());
+        assert_eq!(config.program.name, "quick");
+        assert_eq!(config.program.slot, 3);
+        assert_eq!(config.program.icon, "USER029x.bmp");
+    }
+
+    #[test]
+    fn parse_invalid_slot() {
+        assert_eq!(
+            ProgramIniConfig::from_ini_bytes(b"[program]\nslot = nine\n"),
+            Err(IniParseError::InvalidSlot("nine".to_string()))
+        );
+    }
 }
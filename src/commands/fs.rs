@@ -0,0 +1,296 @@
+//! A filesystem-shaped view of the files stored on a brain.
+//!
+//! [`BrainFs`] wraps a connection and maps each filesystem operation onto the file transfer
+//! packets and commands, so that tools can list, read, write, and remove files without dealing
+//! with the packet exchanges behind them:
+//!
+//! ```no_run
+//! # async fn example<C: vex_v5_serial::connection::Connection>(connection: &mut C) -> Result<(), C::Error> {
+//! use vex_v5_serial::{commands::fs::BrainFs, packets::file::FileVendor};
+//!
+//! let mut fs = BrainFs::new(connection);
+//! for entry in fs.list(FileVendor::User).await? {
+//!     println!("{} ({} bytes)", entry.file_name, entry.size);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::time::Duration;
+
+use log::debug;
+
+use super::{
+    file::{
+        download_file, erase_file, upload_file, DownloadFile, EraseFile, LowBatteryPolicy,
+        UploadFile,
+    },
+    CommandError,
+};
+use crate::{
+    connection::Connection,
+    packets::file::{
+        FileExitAction, FileMetadata, FileTransferTarget, FileVendor, GetDirectoryEntryPacket,
+        GetDirectoryEntryPayload, GetDirectoryEntryReplyPayload, GetDirectoryFileCountPacket,
+        GetDirectoryFileCountPayload, GetFileMetadataPacket, GetFileMetadataPayload,
+        GetFileMetadataReplyPayload,
+    },
+    string::FixedString,
+};
+
+/// How a file is stored by [`BrainFs::write`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WriteOptions {
+    pub metadata: FileMetadata,
+    /// Defaults to [`FileTransferTarget::Qspi`].
+    pub target: Option<FileTransferTarget>,
+    pub load_addr: u32,
+    pub after_upload: FileExitAction,
+}
+
+/// Filesystem operations on the files stored on a brain.
+///
+/// The last directory listing is cached, so calling [`BrainFs::list`] again for the same vendor
+/// doesn't list the directory again. Writing or removing a file through the facade clears the
+/// cache, but changes made any other way (including by other commands on the same connection)
+/// aren't noticed until [`BrainFs::invalidate`] is called.
+pub struct BrainFs<'c, C: Connection + ?Sized> {
+    connection: &'c mut C,
+    listing: Option<(FileVendor, Vec<GetDirectoryEntryReplyPayload>)>,
+}
+
+impl<'c, C: Connection + ?Sized> BrainFs<'c, C> {
+    pub fn new(connection: &'c mut C) -> Self {
+        Self {
+            connection,
+            listing: None,
+        }
+    }
+
+    /// Returns the wrapped connection, for operations the facade doesn't cover.
+    pub fn connection(&mut self) -> &mut C {
+        self.connection
+    }
+
+    /// Clears the cached directory listing.
+    pub fn invalidate(&mut self) {
+        self.listing = None;
+    }
+
+    /// Lists the files stored under a vendor.
+    pub async fn list(
+        &mut self,
+        vendor: FileVendor,
+    ) -> Result<&[GetDirectoryEntryReplyPayload], C::Error> {
+        if !matches!(&self.listing, Some((cached, _)) if *cached == vendor) {
+            let entries = list_directory(self.connection, vendor).await?;
+            self.listing = Some((vendor, entries));
+        }
+
+        Ok(self
+            .listing
+            .as_ref()
+            .map_or(&[], |(_, entries)| entries.as_slice()))
+    }
+
+    /// Fetches a file's metadata, or `None` if it doesn't exist.
+    pub async fn metadata(
+        &mut self,
+        vendor: FileVendor,
+        file_name: &FixedString<23>,
+    ) -> Result<Option<GetFileMetadataReplyPayload>, C::Error> {
+        Ok(self
+            .connection
+            .request(
+                Duration::from_millis(500),
+                5,
+                GetFileMetadataPacket::new(GetFileMetadataPayload {
+                    vendor,
+                    option: 0,
+                    file_name: file_name.clone(),
+                }),
+            )
+            .await?
+            .try_into_inner()?)
+    }
+
+    /// Reads the whole contents of a file.
+    ///
+    /// Fails with [`CommandError::FileNotFound`] if the file doesn't exist.
+    pub async fn read(
+        &mut self,
+        vendor: FileVendor,
+        file_name: FixedString<23>,
+    ) -> Result<Vec<u8>, C::Error> {
+        let Some(metadata) = self.metadata(vendor, &file_name).await? else {
+            return Err(CommandError::FileNotFound(file_name.into_inner()).into());
+        };
+
+        download_file(
+            self.connection,
+            DownloadFile {
+                file_name,
+                size: metadata.size,
+                vendor,
+                target: None,
+                load_addr: metadata.load_address,
+                resume_from: 0,
+                progress_callback: None,
+            },
+        )
+        .await
+        .map(|file| file.data)
+    }
+
+    /// Writes a file, replacing it if it already exists.
+    pub async fn write(
+        &mut self,
+        vendor: FileVendor,
+        file_name: FixedString<23>,
+        data: Vec<u8>,
+        options: WriteOptions,
+    ) -> Result<(), C::Error> {
+        self.invalidate();
+        upload_file(
+            self.connection,
+            UploadFile {
+                filename: file_name,
+                metadata: options.metadata,
+                vendor: Some(vendor),
+                data,
+                target: options.target,
+                load_addr: options.load_addr,
+                linked_file: None,
+                after_upload: options.after_upload,
+                confirm_run: false,
+                check_directory: false,
+                low_battery: LowBatteryPolicy::Skip,
+                progress_callback: None,
+            },
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Removes a file.
+    pub async fn remove(
+        &mut self,
+        vendor: FileVendor,
+        file_name: FixedString<23>,
+    ) -> Result<(), C::Error> {
+        self.invalidate();
+        erase_file(self.connection, EraseFile { file_name, vendor }).await
+    }
+}
+
+/// Fetches every entry of a vendor's directory.
+async fn list_directory<C: Connection + ?Sized>(
+    connection: &mut C,
+    vendor: FileVendor,
+) -> Result<Vec<GetDirectoryEntryReplyPayload>, C::Error> {
+    let file_count = connection
+        .request(
+            Duration::from_millis(500),
+            5,
+            GetDirectoryFileCountPacket::new(GetDirectoryFileCountPayload { vendor, option: 0 }),
+        )
+        .await?
+        .try_into_inner()?;
+
+    let mut entries = Vec::new();
+    for file_index in 0..u8::try_from(file_count).unwrap_or(u8::MAX) {
+        let entry = connection
+            .request(
+                Duration::from_millis(500),
+                5,
+                GetDirectoryEntryPacket::new(GetDirectoryEntryPayload {
+                    file_index,
+                    unknown: 0,
+                }),
+            )
+            .await?
+            .try_into_inner()?;
+
+        entries.extend(entry);
+    }
+
+    debug!("Listed {} files under {:?}", entries.len(), vendor);
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BrainFs;
+    use crate::{
+        commands::CommandError,
+        connection::mock::{block_on, cdc2_reply, entry_reply, MockConnection, MockError},
+        packets::file::FileVendor,
+        string::FixedString,
+    };
+
+    fn file_name(name: &str) -> FixedString<23> {
+        FixedString::new(name.to_string()).unwrap()
+    }
+
+    #[test]
+    fn cached_listing() {
+        let mut connection = MockConnection {
+            replies: [
+                cdc2_reply(0x16, &1u16.to_le_bytes()),
+                entry_reply(0, "slot_1.bin"),
+                cdc2_reply(0x16, &0u16.to_le_bytes()),
+            ]
+            .into(),
+            ..Default::default()
+        };
+        let mut fs = BrainFs::new(&mut connection);
+
+        for _ in 0..2 {
+            let entries = block_on(fs.list(FileVendor::User)).unwrap();
+            assert_eq!(entries.len(), 1);
+            assert_eq!(entries[0].file_name, "slot_1.bin");
+        }
+
+        fs.invalidate();
+        assert!(block_on(fs.list(FileVendor::User)).unwrap().is_empty());
+        assert_eq!(connection.sent.len(), 3);
+    }
+
+    #[test]
+    fn read_missing_file() {
+        let mut connection = MockConnection {
+            replies: [cdc2_reply(0x19, &[0xFF])].into(),
+            ..Default::default()
+        };
+        let mut fs = BrainFs::new(&mut connection);
+
+        let Err(MockError::Command(CommandError::FileNotFound(name))) =
+            block_on(fs.read(FileVendor::User, file_name("missing.txt")))
+        else {
+            panic!("Reading a missing file should fail");
+        };
+        assert_eq!(name, "missing.txt");
+        // Only the metadata is queried
+        assert_eq!(connection.sent.len(), 1);
+    }
+
+    #[test]
+    fn remove_invalidates_listing() {
+        let mut connection = MockConnection {
+            replies: [
+                cdc2_reply(0x16, &1u16.to_le_bytes()),
+                entry_reply(0, "notes.txt"),
+                cdc2_reply(0x1B, &[]),
+                cdc2_reply(0x19, &[0xFF]),
+                cdc2_reply(0x16, &0u16.to_le_bytes()),
+            ]
+            .into(),
+            ..Default::default()
+        };
+        let mut fs = BrainFs::new(&mut connection);
+
+        assert_eq!(block_on(fs.list(FileVendor::User)).unwrap().len(), 1);
+        block_on(fs.remove(FileVendor::User, file_name("notes.txt"))).unwrap();
+        assert!(block_on(fs.list(FileVendor::User)).unwrap().is_empty());
+    }
+}
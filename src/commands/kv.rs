@@ -0,0 +1,192 @@
+//! Reading and writing the brain's key-value store.
+//!
+//! [`ReadKv`] and [`WriteKv`] work with any [`KvKey`]. Keys listed in
+//! [`keys`](crate::packets::kv::keys) have their own helpers that check values before sending
+//! them, such as [`set_team_number`].
+
+use std::time::Duration;
+
+use super::{Command, CommandError};
+use crate::{
+    connection::Connection,
+    packets::kv::{
+        keys::{self, KnownKey},
+        KvKey, ReadKeyValuePacket, WriteKeyValuePacket, WriteKeyValuePayload, MAX_VALUE_LEN,
+    },
+    string::FixedString,
+};
+
+/// Reads the value stored under a key.
+///
+/// Keys that have never been written read as an empty string.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReadKv {
+    pub key: KvKey,
+}
+impl Command for ReadKv {
+    type Output = String;
+
+    async fn execute<C: Connection + ?Sized>(
+        self,
+        connection: &mut C,
+    ) -> Result<Self::Output, C::Error> {
+        read_kv(connection, &self.key).await
+    }
+}
+
+/// Writes a value under a key.
+///
+/// Fails with [`CommandError::KvValueTooLong`] if the value is longer than [`MAX_VALUE_LEN`],
+/// or than the limit of a known key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WriteKv {
+    pub key: KvKey,
+    pub value: String,
+}
+impl Command for WriteKv {
+    type Output = ();
+
+    async fn execute<C: Connection + ?Sized>(
+        self,
+        connection: &mut C,
+    ) -> Result<Self::Output, C::Error> {
+        write_kv(connection, &self.key, &self.value).await
+    }
+}
+
+pub async fn read_kv<C: Connection + ?Sized>(
+    connection: &mut C,
+    key: &KvKey,
+) -> Result<String, C::Error> {
+    Ok(connection
+        .request(
+            Duration::from_millis(500),
+            5,
+            ReadKeyValuePacket::new(key.clone().into_inner()),
+        )
+        .await?
+        .try_into_inner()?
+        .value)
+}
+
+pub async fn write_kv<C: Connection + ?Sized>(
+    connection: &mut C,
+    key: &KvKey,
+    value: &str,
+) -> Result<(), C::Error> {
+    let max = keys::find(key.as_str()).map_or(MAX_VALUE_LEN, |known| known.max_len);
+    if value.len() > max {
+        return Err(CommandError::KvValueTooLong {
+            key: key.to_string(),
+            len: value.len(),
+            max,
+        }
+        .into());
+    }
+
+    connection
+        .request(
+            Duration::from_millis(500),
+            5,
+            WriteKeyValuePacket::new(WriteKeyValuePayload {
+                key: key.clone().into_inner(),
+                // Checked against `MAX_VALUE_LEN` above
+                value: FixedString::new(value.to_string()).unwrap(),
+            }),
+        )
+        .await?
+        .try_into_inner()?;
+    Ok(())
+}
+
+async fn read_known<C: Connection + ?Sized>(
+    connection: &mut C,
+    key: KnownKey,
+) -> Result<String, C::Error> {
+    read_kv(connection, &key.into()).await
+}
+
+/// Reads the brain's team number.
+pub async fn team_number<C: Connection + ?Sized>(connection: &mut C) -> Result<String, C::Error> {
+    read_known(connection, keys::TEAM_NUMBER).await
+}
+
+/// Sets the brain's team number.
+///
+/// Fails with [`CommandError::InvalidTeamNumber`] without sending anything if `team_number`
+/// doesn't pass [`keys::is_valid_team_number`].
+pub async fn set_team_number<C: Connection + ?Sized>(
+    connection: &mut C,
+    team_number: &str,
+) -> Result<(), C::Error> {
+    if !keys::is_valid_team_number(team_number) {
+        return Err(CommandError::InvalidTeamNumber(team_number.to_string()).into());
+    }
+    write_kv(connection, &keys::TEAM_NUMBER.into(), team_number).await
+}
+
+/// Reads the brain's robot name.
+pub async fn robot_name<C: Connection + ?Sized>(connection: &mut C) -> Result<String, C::Error> {
+    read_known(connection, keys::ROBOT_NAME).await
+}
+
+/// Sets the brain's robot name.
+pub async fn set_robot_name<C: Connection + ?Sized>(
+    connection: &mut C,
+    robot_name: &str,
+) -> Result<(), C::Error> {
+    write_kv(connection, &keys::ROBOT_NAME.into(), robot_name).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_kv, set_team_number, team_number, WriteKv};
+    use crate::{
+        commands::{Command, CommandError},
+        connection::mock::{block_on, cdc2_reply, kv_reply, MockConnection, MockError},
+        packets::kv::{KvKey, MAX_VALUE_LEN},
+    };
+
+    #[test]
+    fn team_number_round_trip() {
+        let mut connection = MockConnection {
+            replies: [cdc2_reply(0x2F, &[]), kv_reply("1234A")].into(),
+            ..Default::default()
+        };
+
+        block_on(set_team_number(&mut connection, "1234A")).unwrap();
+        // The key and value are both sent nul-terminated without padding, before the CRC
+        let sent = &connection.sent[0];
+        assert!(sent[..sent.len() - 2].ends_with(b"teamnumber\x001234A\0"));
+        assert_eq!(block_on(team_number(&mut connection)).unwrap(), "1234A");
+    }
+
+    #[test]
+    fn rejected_values() {
+        let mut connection = MockConnection::default();
+
+        let Err(MockError::Command(CommandError::InvalidTeamNumber(team))) =
+            block_on(set_team_number(&mut connection, "12345AB"))
+        else {
+            panic!("An invalid team number should be rejected");
+        };
+        assert_eq!(team, "12345AB");
+
+        let Err(MockError::Command(CommandError::KvValueTooLong { len, max, .. })) = block_on(
+            WriteKv {
+                key: KvKey::new("custom").unwrap(),
+                value: "x".repeat(MAX_VALUE_LEN + 1),
+            }
+            .execute(&mut connection),
+        ) else {
+            panic!("An overlong value should be rejected");
+        };
+        assert_eq!((len, max), (MAX_VALUE_LEN + 1, MAX_VALUE_LEN));
+        assert!(connection.sent.is_empty());
+
+        // Unknown keys can still be read
+        connection.replies.push_back(kv_reply(""));
+        let key = KvKey::new("custom").unwrap();
+        assert_eq!(block_on(read_kv(&mut connection, &key)).unwrap(), "");
+    }
+}
@@ -0,0 +1,435 @@
+//! An advisory lock that keeps tools from talking to the same brain at once.
+//!
+//! Two tools sending packets to one brain at the same time get each other's replies, which
+//! shows up as failed handshakes and confusing decode errors. Serial ports can be opened
+//! exclusively, but Bluetooth connections have no such guard. [`acquire_lock`] writes the name
+//! of the tool using the brain and when its lease runs out to a key in the brain's key-value
+//! store, and checks that key before doing so.
+//!
+//! Nothing takes the lock unless asked to. Call [`acquire_lock`] after connecting, and
+//! [`BrainLock::refresh_if_due`] between commands to keep it.
+//!
+//! # Limitations
+//!
+//! - The lock is only advisory. Tools that don't check it aren't kept out by it.
+//! - Leases are timed with the host's wall clock, so tools on computers whose clocks disagree
+//!   see a lease end early or late by the difference.
+//! - Checking the key and writing it are separate requests, so two tools taking the lock at the
+//!   same moment can both get it. The key is read back after writing to make this less likely,
+//!   not impossible.
+//! - VEXos may ignore writes to keys it doesn't know, such as [`DEFAULT_LOCK_KEY`], and which
+//!   versions keep them hasn't been checked. Reading the key back catches this, and
+//!   [`acquire_lock`] fails with [`CommandError::LockNotStored`] instead of handing out a lock
+//!   that nothing else can see.
+//! - The lock is only refreshed when asked to. A command that takes longer than the lease, such
+//!   as a slow upload over Bluetooth, lets it lapse partway through.
+//! - A lock left behind by a tool that crashed keeps other tools out until its lease ends.
+//! - VEXos may keep the key-value store in flash, so short leases refreshed often are best
+//!   avoided.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::warn;
+
+use super::{
+    kv::{read_kv, write_kv},
+    Command, CommandError,
+};
+use crate::{connection::Connection, packets::kv::KvKey};
+
+/// The key that the lock is stored under unless another is chosen.
+pub const DEFAULT_LOCK_KEY: &str = "hostlock";
+
+/// How long a lock lasts without being refreshed unless another lease is chosen.
+pub const DEFAULT_LEASE: Duration = Duration::from_secs(30);
+
+/// What [`acquire_lock`] does when another tool holds the lock.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LockPolicy {
+    /// Logs a warning and takes the lock anyway.
+    Warn,
+    /// Fails with [`CommandError::BrainLocked`].
+    #[default]
+    Refuse,
+    /// Takes the lock without checking who holds it.
+    Force,
+}
+
+/// A tool holding the lock, as stored on the brain.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LockHolder {
+    pub holder: String,
+    /// When the lease runs out, to the second.
+    pub expires: SystemTime,
+}
+impl LockHolder {
+    /// Parses a value written by [`LockHolder::encode`], formatted as `holder@expiry` with the
+    /// expiry in seconds since the Unix epoch.
+    ///
+    /// Any tool can write to the key, so a value that doesn't parse, including an expiry too far
+    /// away to represent, isn't a lock.
+    fn parse(value: &str) -> Option<Self> {
+        let (holder, expires) = value.rsplit_once('@')?;
+        Some(Self {
+            holder: holder.to_string(),
+            expires: UNIX_EPOCH.checked_add(Duration::from_secs(expires.parse().ok()?))?,
+        })
+    }
+
+    fn encode(&self) -> String {
+        let expires = self.expires.duration_since(UNIX_EPOCH).unwrap_or_default();
+        format!("{}@{}", self.holder, expires.as_secs())
+    }
+
+    /// Returns how long is left of the lease, which is zero once it has run out.
+    pub fn remaining(&self) -> Duration {
+        self.expires
+            .duration_since(SystemTime::now())
+            .unwrap_or_default()
+    }
+}
+
+/// Reads who holds the lock stored under `key`.
+///
+/// Returns `None` if nobody does, including when the last holder's lease has run out or the
+/// key holds something that isn't a lock.
+pub async fn lock_holder<C: Connection + ?Sized>(
+    connection: &mut C,
+    key: &KvKey,
+) -> Result<Option<LockHolder>, C::Error> {
+    let value = read_kv(connection, key).await?;
+    Ok(LockHolder::parse(&value).filter(|holder| !holder.remaining().is_zero()))
+}
+
+/// Returns a holder name made up of the host's name, if the environment has it, and this
+/// process's ID.
+pub fn default_holder() -> String {
+    let host = std::env::var("HOSTNAME")
+        .or_else(|_| std::env::var("COMPUTERNAME"))
+        .unwrap_or_else(|_| "unknown host".to_string());
+    format!("{host} pid {}", std::process::id())
+}
+
+/// Takes the lock on a brain, as described in the [module docs](self).
+///
+/// `holder` is shown to other tools that find the brain locked, so tools should set it to
+/// something a user would recognize, such as their own name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AcquireLock {
+    pub key: KvKey,
+    pub holder: String,
+    pub lease: Duration,
+    pub policy: LockPolicy,
+}
+impl Default for AcquireLock {
+    fn default() -> Self {
+        Self {
+            key: KvKey::new(DEFAULT_LOCK_KEY).unwrap(),
+            holder: default_holder(),
+            lease: DEFAULT_LEASE,
+            policy: LockPolicy::default(),
+        }
+    }
+}
+impl Command for AcquireLock {
+    type Output = BrainLock;
+
+    async fn execute<C: Connection + ?Sized>(
+        self,
+        connection: &mut C,
+    ) -> Result<Self::Output, C::Error> {
+        acquire_lock(connection, self).await
+    }
+}
+
+/// Applies a [`LockPolicy`] to another tool found holding the lock.
+fn contend(policy: LockPolicy, other: LockHolder) -> Result<(), CommandError> {
+    match policy {
+        LockPolicy::Refuse => Err(CommandError::BrainLocked {
+            remaining: other.remaining(),
+            holder: other.holder,
+        }),
+        LockPolicy::Warn | LockPolicy::Force => {
+            warn!(
+                "Taking the brain's lock from {}, which held it for another {} s",
+                other.holder,
+                other.remaining().as_secs()
+            );
+            Ok(())
+        }
+    }
+}
+
+/// Takes the lock on a brain, applying `options.policy` if another tool holds it.
+///
+/// After writing the lock, the key is read back. This fails with
+/// [`CommandError::LockNotStored`] if the brain didn't keep the value, and with
+/// [`CommandError::BrainLocked`] if another tool's lock replaced it, whatever the policy.
+///
+/// This is the implementation of [`AcquireLock`], for use inside other commands.
+pub async fn acquire_lock<C: Connection + ?Sized>(
+    connection: &mut C,
+    options: AcquireLock,
+) -> Result<BrainLock, C::Error> {
+    let AcquireLock {
+        key,
+        holder,
+        lease,
+        policy,
+    } = options;
+
+    if policy != LockPolicy::Force {
+        if let Some(other) = lock_holder(connection, &key).await? {
+            if other.holder != holder {
+                contend(policy, other)?;
+            }
+        }
+    }
+
+    let mut lock = BrainLock {
+        key,
+        holder,
+        lease,
+        expires: UNIX_EPOCH,
+    };
+    lock.write(connection).await?;
+
+    // The brain may not have kept the value, or another tool may have taken the lock between
+    // the read and the write
+    match lock_holder(connection, &lock.key).await? {
+        Some(other) if other.holder != lock.holder => {
+            contend(LockPolicy::Refuse, other)?;
+        }
+        Some(_) => {}
+        None => return Err(CommandError::LockNotStored(lock.key.to_string()).into()),
+    }
+    Ok(lock)
+}
+
+/// A lock on a brain taken with [`acquire_lock`].
+///
+/// The lock isn't released when this is dropped, since that would need the connection. Call
+/// [`BrainLock::release`] when done, or let the lease run out.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BrainLock {
+    key: KvKey,
+    holder: String,
+    lease: Duration,
+    expires: SystemTime,
+}
+impl BrainLock {
+    pub fn holder(&self) -> &str {
+        &self.holder
+    }
+
+    /// Returns when the lease runs out unless the lock is refreshed.
+    pub fn expires(&self) -> SystemTime {
+        self.expires
+    }
+
+    /// Returns whether less than half of the lease is left.
+    pub fn is_refresh_due(&self) -> bool {
+        let remaining = self
+            .expires
+            .duration_since(SystemTime::now())
+            .unwrap_or_default();
+        remaining < self.lease / 2
+    }
+
+    async fn write<C: Connection + ?Sized>(&mut self, connection: &mut C) -> Result<(), C::Error> {
+        let expires = SystemTime::now() + self.lease;
+        let holder = LockHolder {
+            holder: self.holder.clone(),
+            // Stored to the second, so round down to match what's read back
+            expires: UNIX_EPOCH
+                + Duration::from_secs(
+                    expires
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_secs(),
+                ),
+        };
+        write_kv(connection, &self.key, &holder.encode()).await?;
+        self.expires = holder.expires;
+        Ok(())
+    }
+
+    /// Starts a new lease.
+    ///
+    /// Fails with [`CommandError::BrainLocked`] if another tool has taken the lock since it was
+    /// last refreshed.
+    pub async fn refresh<C: Connection + ?Sized>(
+        &mut self,
+        connection: &mut C,
+    ) -> Result<(), C::Error> {
+        if let Some(other) = lock_holder(connection, &self.key).await? {
+            if other.holder != self.holder {
+                return contend(LockPolicy::Refuse, other).map_err(Into::into);
+            }
+        }
+        self.write(connection).await
+    }
+
+    /// Calls [`BrainLock::refresh`] if [`BrainLock::is_refresh_due`], and does nothing
+    /// otherwise.
+    pub async fn refresh_if_due<C: Connection + ?Sized>(
+        &mut self,
+        connection: &mut C,
+    ) -> Result<(), C::Error> {
+        if self.is_refresh_due() {
+            self.refresh(connection).await?;
+        }
+        Ok(())
+    }
+
+    /// Clears the lock, unless another tool has taken it since it was last refreshed.
+    pub async fn release<C: Connection + ?Sized>(self, connection: &mut C) -> Result<(), C::Error> {
+        if let Some(other) = lock_holder(connection, &self.key).await? {
+            if other.holder != self.holder {
+                return Ok(());
+            }
+        }
+        write_kv(connection, &self.key, "").await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use super::{acquire_lock, AcquireLock, LockHolder, LockPolicy};
+    use crate::{
+        commands::CommandError,
+        connection::mock::{block_on, cdc2_reply, kv_reply, MockConnection, MockError},
+    };
+
+    fn held_by(holder: &str) -> Vec<u8> {
+        let expires = SystemTime::now() + Duration::from_secs(100);
+        let expires = expires.duration_since(UNIX_EPOCH).unwrap().as_secs();
+        kv_reply(&format!("{holder}@{expires}"))
+    }
+
+    fn options(policy: LockPolicy) -> AcquireLock {
+        AcquireLock {
+            holder: "cargo-v5".to_string(),
+            policy,
+            ..Default::default()
+        }
+    }
+
+    /// Returns the value written by the `index`th packet sent.
+    fn written(connection: &MockConnection, index: usize) -> String {
+        let sent = &connection.sent[index];
+        let payload = &sent[..sent.len() - 2];
+        let start = payload.windows(9).position(|w| w == b"hostlock\0").unwrap() + 9;
+        String::from_utf8(payload[start..payload.len() - 1].to_vec()).unwrap()
+    }
+
+    #[test]
+    fn acquire_and_release() {
+        let mut connection = MockConnection {
+            replies: [
+                kv_reply(""),
+                cdc2_reply(0x2F, &[]),
+                held_by("cargo-v5"),
+                held_by("cargo-v5"),
+                cdc2_reply(0x2F, &[]),
+            ]
+            .into(),
+            ..Default::default()
+        };
+
+        let lock = block_on(acquire_lock(&mut connection, options(LockPolicy::Refuse))).unwrap();
+        assert!(written(&connection, 1).starts_with("cargo-v5@"));
+        assert!(!lock.is_refresh_due());
+
+        block_on(lock.release(&mut connection)).unwrap();
+        assert_eq!(written(&connection, 4), "");
+    }
+
+    #[test]
+    fn held_elsewhere() {
+        let mut connection = MockConnection {
+            replies: [held_by("dashboard")].into(),
+            ..Default::default()
+        };
+        let Err(MockError::Command(CommandError::BrainLocked { holder, remaining })) =
+            block_on(acquire_lock(&mut connection, options(LockPolicy::Refuse)))
+        else {
+            panic!("A held lock should be refused");
+        };
+        assert_eq!(holder, "dashboard");
+        assert!(remaining > Duration::from_secs(90));
+        assert_eq!(connection.sent.len(), 1);
+
+        // An expired lease is taken without complaint
+        connection.replies = [
+            kv_reply("dashboard@1"),
+            cdc2_reply(0x2F, &[]),
+            held_by("cargo-v5"),
+        ]
+        .into();
+        block_on(acquire_lock(&mut connection, options(LockPolicy::Refuse))).unwrap();
+
+        // Forcing skips the check
+        connection.sent.clear();
+        connection.replies = [cdc2_reply(0x2F, &[]), held_by("cargo-v5")].into();
+        block_on(acquire_lock(&mut connection, options(LockPolicy::Force))).unwrap();
+        assert!(written(&connection, 0).starts_with("cargo-v5@"));
+    }
+
+    #[test]
+    fn overflowing_expiry() {
+        assert!(LockHolder::parse("x@18446744073709551615").is_none());
+
+        // The value is ignored, so the lock is taken
+        let mut connection = MockConnection {
+            replies: [
+                kv_reply("x@18446744073709551615"),
+                cdc2_reply(0x2F, &[]),
+                held_by("cargo-v5"),
+            ]
+            .into(),
+            ..Default::default()
+        };
+        block_on(acquire_lock(&mut connection, options(LockPolicy::Refuse))).unwrap();
+        assert!(written(&connection, 1).starts_with("cargo-v5@"));
+    }
+
+    #[test]
+    fn lock_not_stored() {
+        // The brain acknowledges the write, but doesn't keep the unknown key
+        let mut connection = MockConnection {
+            replies: [kv_reply(""), cdc2_reply(0x2F, &[]), kv_reply("")].into(),
+            ..Default::default()
+        };
+        let Err(MockError::Command(CommandError::LockNotStored(key))) =
+            block_on(acquire_lock(&mut connection, options(LockPolicy::Warn)))
+        else {
+            panic!("A lock that wasn't kept should fail");
+        };
+        assert_eq!(key, "hostlock");
+    }
+
+    #[test]
+    fn refresh_after_takeover() {
+        let mut connection = MockConnection {
+            replies: [
+                kv_reply(""),
+                cdc2_reply(0x2F, &[]),
+                held_by("cargo-v5"),
+                held_by("dashboard"),
+            ]
+            .into(),
+            ..Default::default()
+        };
+        let mut lock = block_on(acquire_lock(&mut connection, options(LockPolicy::Warn))).unwrap();
+
+        let Err(MockError::Command(CommandError::BrainLocked { holder, .. })) =
+            block_on(lock.refresh(&mut connection))
+        else {
+            panic!("Refreshing a lock taken by another tool should fail");
+        };
+        assert_eq!(holder, "dashboard");
+    }
+}
@@ -0,0 +1,420 @@
+//! Reading the brain's event log a page at a time.
+//!
+//! [`ReadLogPagePacket`] picks entries by how far back from the newest entry a page starts, so
+//! every offset shifts whenever the brain logs something new, and entries that were already
+//! read shift again once the log is full and its oldest entries are dropped. [`ReadLog`] hides
+//! this behind a [`LogCursor`], which finds its place again by looking for the entries that it
+//! has already read:
+//!
+//! ```no_run
+//! # async fn example<C: vex_v5_serial::connection::Connection>(connection: &mut C) -> Result<(), C::Error> {
+//! use vex_v5_serial::commands::log::ReadLog;
+//!
+//! // The 8 newest entries
+//! let newest = connection.execute_command(ReadLog::newest(8)).await?;
+//! // The 8 entries before those
+//! let older = connection.execute_command(newest.cursor.next_page()).await?;
+//! // Everything logged since the first read
+//! let newer = connection.execute_command(ReadLog::newer_than(&older.cursor)).await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Entries are recognized by value, so two entries with the same code, type, description and
+//! timestamp could be mistaken for each other. Timestamps are in milliseconds, which makes this
+//! unlikely.
+
+use std::time::Duration;
+
+use super::{Command, CommandError};
+use crate::{
+    connection::Connection,
+    packets::log::{GetLogCountPacket, Log, ReadLogPagePacket, ReadLogPagePayload},
+};
+
+/// A position in the brain's event log, returned with each [`LogPage`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LogCursor {
+    page_size: u32,
+    /// The oldest entry read, along with its index counting up from the oldest entry in the
+    /// log when it was read.
+    oldest: Option<(u32, Log)>,
+    /// The newest entry read.
+    newest: Option<Log>,
+}
+impl LogCursor {
+    /// Returns a command reading the page of entries just older than the ones read so far.
+    ///
+    /// The page is empty once the start of the log has been reached.
+    pub fn next_page(&self) -> ReadLog {
+        ReadLog {
+            direction: Direction::Older,
+            cursor: *self,
+        }
+    }
+
+    /// Returns the number of entries read per page.
+    pub fn page_size(&self) -> u32 {
+        self.page_size
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    Newest,
+    Older,
+    Newer,
+}
+
+/// Reads entries from the brain's event log.
+///
+/// See the [module documentation](self) for how to page through the log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReadLog {
+    direction: Direction,
+    cursor: LogCursor,
+}
+impl ReadLog {
+    /// Reads the newest `page_size` entries.
+    pub fn newest(page_size: u32) -> Self {
+        Self {
+            direction: Direction::Newest,
+            cursor: LogCursor {
+                page_size: page_size.max(1),
+                oldest: None,
+                newest: None,
+            },
+        }
+    }
+
+    /// Reads every entry logged since the newest entry that `cursor` has read.
+    ///
+    /// If the brain has dropped that entry from the log since, every entry still in the log
+    /// is read and [`LogPage::missed`] is set.
+    pub fn newer_than(cursor: &LogCursor) -> Self {
+        Self {
+            direction: Direction::Newer,
+            cursor: *cursor,
+        }
+    }
+}
+impl Command for ReadLog {
+    type Output = LogPage;
+
+    async fn execute<C: Connection + ?Sized>(
+        self,
+        connection: &mut C,
+    ) -> Result<Self::Output, C::Error> {
+        read_log(connection, self).await
+    }
+}
+
+/// Entries read by a [`ReadLog`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogPage {
+    /// The entries, oldest first.
+    pub entries: Vec<Log>,
+    /// Where to carry on reading from.
+    pub cursor: LogCursor,
+    /// Whether entries may have been dropped from the log before they could be read.
+    pub missed: bool,
+}
+
+/// Returns the number of entries in the brain's event log.
+pub async fn log_count<C: Connection + ?Sized>(connection: &mut C) -> Result<u32, C::Error> {
+    Ok(connection
+        .request(Duration::from_millis(500), 5, GetLogCountPacket::new(()))
+        .await?
+        .try_into_inner()?
+        .count)
+}
+
+pub async fn read_log<C: Connection + ?Sized>(
+    connection: &mut C,
+    read: ReadLog,
+) -> Result<LogPage, C::Error> {
+    let mut cursor = read.cursor;
+    let page_size = cursor.page_size;
+    let count = log_count(connection).await?;
+
+    match read.direction {
+        Direction::Newest => {
+            let start = count.saturating_sub(page_size);
+            let entries = read_range(connection, count, start, count, page_size).await?;
+            cursor.oldest = entries.first().map(|entry| (start, *entry));
+            cursor.newest = entries.last().copied();
+            Ok(LogPage {
+                entries,
+                cursor,
+                missed: false,
+            })
+        }
+        Direction::Newer => {
+            let (start, mut entries, found) = match &cursor.newest {
+                Some(newest) => find(connection, count, count, newest, page_size).await?,
+                None => (
+                    0,
+                    read_range(connection, count, 0, count, page_size).await?,
+                    None,
+                ),
+            };
+            let missed = cursor.newest.is_some() && found.is_none();
+            if let Some(position) = found {
+                entries.drain(..=position);
+            } else {
+                // Everything older than the newest entry read is gone too
+                cursor.oldest = entries.first().map(|entry| (start, *entry));
+            }
+            if let Some(newest) = entries.last() {
+                cursor.newest = Some(*newest);
+            }
+            Ok(LogPage {
+                entries,
+                cursor,
+                missed,
+            })
+        }
+        Direction::Older => {
+            let Some((index, oldest)) = cursor.oldest else {
+                return Ok(LogPage {
+                    entries: Vec::new(),
+                    cursor,
+                    missed: false,
+                });
+            };
+
+            // Dropping old entries only ever moves the rest down, so the oldest entry read is
+            // at or below its old index. Read it along with the page before it.
+            let end = (index + 1).min(count);
+            let (start, mut entries, found) =
+                find(connection, count, end, &oldest, page_size.saturating_add(1)).await?;
+            let Some(position) = found else {
+                cursor.oldest = None;
+                return Ok(LogPage {
+                    entries: Vec::new(),
+                    cursor,
+                    missed: true,
+                });
+            };
+
+            let anchor = start + position as u32;
+            let page_start = anchor.saturating_sub(page_size);
+            entries.truncate(position);
+            if page_start < start {
+                let mut page = read_range(connection, count, page_start, start, page_size).await?;
+                page.extend(entries);
+                entries = page;
+            } else {
+                entries.drain(..(page_start - start) as usize);
+            }
+
+            cursor.oldest = Some(match entries.first() {
+                Some(entry) => (page_start, *entry),
+                None => (anchor, oldest),
+            });
+            Ok(LogPage {
+                entries,
+                cursor,
+                missed: false,
+            })
+        }
+    }
+}
+
+/// Reads the entries from index `start` up to `end` of a log holding `count` entries, oldest
+/// first, in pages of up to `page_size` entries.
+async fn read_range<C: Connection + ?Sized>(
+    connection: &mut C,
+    count: u32,
+    start: u32,
+    end: u32,
+    page_size: u32,
+) -> Result<Vec<Log>, C::Error> {
+    // The range comes from the count the brain reported, so it only grows as pages arrive
+    let mut entries = Vec::new();
+    let mut index = start;
+    while index < end {
+        let len = (end - index).min(page_size);
+        let page = connection
+            .request(
+                Duration::from_millis(500),
+                5,
+                // The offset counts back from the end of the log
+                ReadLogPagePacket::new(ReadLogPagePayload {
+                    offset: count - index,
+                    count: len,
+                }),
+            )
+            .await?
+            .try_into_inner()?;
+
+        if page.entries.len() != len as usize {
+            return Err(CommandError::ShortLogPage {
+                requested: len,
+                received: page.entries.len(),
+            }
+            .into());
+        }
+        entries.extend(page.entries);
+        index += len;
+    }
+    Ok(entries)
+}
+
+/// Searches back through the log from index `end` for `anchor`, a page at a time.
+///
+/// Returns the entries read, oldest first, along with the index of the first of them and the
+/// position of `anchor` among them if it was found.
+async fn find<C: Connection + ?Sized>(
+    connection: &mut C,
+    count: u32,
+    end: u32,
+    anchor: &Log,
+    page_size: u32,
+) -> Result<(u32, Vec<Log>, Option<usize>), C::Error> {
+    let mut start = end;
+    let mut entries = Vec::new();
+    while start > 0 {
+        let page_start = start.saturating_sub(page_size);
+        let mut page = read_range(connection, count, page_start, start, page_size).await?;
+        let found = page.iter().rposition(|entry| entry == anchor);
+        page.extend(entries);
+        entries = page;
+        start = page_start;
+
+        if found.is_some() {
+            return Ok((start, entries, found));
+        }
+    }
+    Ok((0, entries, None))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LogPage, ReadLog};
+    use crate::{
+        connection::{
+            mock::{block_on, cdc2_reply, MockConnection, Responder},
+            Connection,
+        },
+        packets::log::Log,
+    };
+
+    fn entry(name: char) -> Log {
+        Log {
+            code: name as u8,
+            log_type: 0,
+            description: 0,
+            spare: 0,
+            time: name as u32 * 10,
+        }
+    }
+
+    fn entries(names: &str) -> Vec<Log> {
+        names.chars().map(entry).collect()
+    }
+
+    /// Answers log requests from `log`, the way the brain indexes it.
+    fn log_brain(log: Vec<Log>) -> Responder {
+        Responder(Box::new(move |sent| {
+            let count = log.len() as u32;
+            match sent[5] {
+                36 => {
+                    let mut payload = vec![0];
+                    payload.extend(count.to_le_bytes());
+                    cdc2_reply(36, &payload)
+                }
+                37 => {
+                    let offset = u32::from_le_bytes(sent[7..11].try_into().unwrap());
+                    let len = u32::from_le_bytes(sent[11..15].try_into().unwrap());
+                    let start = (count - offset) as usize;
+                    let page = &log[start..(start + len as usize).min(log.len())];
+
+                    let mut payload = vec![8];
+                    payload.extend(offset.to_le_bytes());
+                    payload.extend((page.len() as u16).to_le_bytes());
+                    for entry in page {
+                        payload.extend([entry.code, entry.log_type, entry.description, 0]);
+                        payload.extend(entry.time.to_le_bytes());
+                    }
+                    cdc2_reply(37, &payload)
+                }
+                ext => panic!("Unexpected command {ext}"),
+            }
+        }))
+    }
+
+    fn read(connection: &mut MockConnection, read: ReadLog) -> LogPage {
+        block_on(connection.execute_command(read)).unwrap()
+    }
+
+    #[test]
+    fn pages_newest_first() {
+        let alphabet = ('A'..='Z').collect::<String>();
+        let mut connection = MockConnection {
+            respond: Some(log_brain(entries(&alphabet))),
+            ..Default::default()
+        };
+
+        let mut page = read(&mut connection, ReadLog::newest(5));
+        let mut pages = vec![page.entries.clone()];
+        while !page.entries.is_empty() {
+            page = read(&mut connection, page.cursor.next_page());
+            assert!(!page.missed);
+            pages.push(page.entries.clone());
+        }
+        assert_eq!(
+            pages,
+            [
+                entries("VWXYZ"),
+                entries("QRSTU"),
+                entries("LMNOP"),
+                entries("GHIJK"),
+                entries("BCDEF"),
+                entries("A"),
+                Vec::new(),
+            ]
+        );
+
+        // Nothing has been logged since
+        let newer = read(&mut connection, ReadLog::newer_than(&page.cursor));
+        assert_eq!(newer.entries, []);
+        assert!(!newer.missed);
+    }
+
+    #[test]
+    fn log_grows_and_wraps() {
+        let mut connection = MockConnection {
+            respond: Some(log_brain(entries("ABCDEFGHIJ"))),
+            ..Default::default()
+        };
+        let first = read(&mut connection, ReadLog::newest(3));
+        assert_eq!(first.entries, entries("HIJ"));
+
+        // Two entries are logged, and the log is full so the two oldest are dropped
+        connection.respond = Some(log_brain(entries("CDEFGHIJKL")));
+        let newer = read(&mut connection, ReadLog::newer_than(&first.cursor));
+        assert_eq!(newer.entries, entries("KL"));
+        assert!(!newer.missed);
+
+        // Older pages carry on from where the first read left off
+        let older = read(&mut connection, newer.cursor.next_page());
+        assert_eq!(older.entries, entries("EFG"));
+        let older = read(&mut connection, older.cursor.next_page());
+        assert_eq!(older.entries, entries("CD"));
+
+        // So many entries are logged that the newest one read has been dropped
+        connection.respond = Some(log_brain(entries("MNOPQRSTUV")));
+        let newer = read(&mut connection, ReadLog::newer_than(&newer.cursor));
+        assert_eq!(newer.entries, entries("MNOPQRSTUV"));
+        assert!(newer.missed);
+        let newer = read(&mut connection, ReadLog::newer_than(&newer.cursor));
+        assert_eq!(newer.entries, []);
+        assert!(!newer.missed);
+
+        // The entries before the first page are gone as well
+        let older = read(&mut connection, first.cursor.next_page());
+        assert_eq!(older.entries, []);
+        assert!(older.missed);
+    }
+}
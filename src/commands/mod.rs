@@ -1,8 +1,34 @@
-use std::future::Future;
+//! Higher level operations built out of packet exchanges.
+//!
+//! Each operation is a type implementing [`Command`], run with
+//! [`Connection::execute_command`]. Commands that other commands build on, such as file
+//! transfers, are also exposed as free async functions taking the connection and the command's
+//! parameters (for example [`file::upload_file`] and [`file::download_file`]), with the
+//! [`Command`] impl being a thin wrapper around them.
+//!
+//! Third-party commands should follow the same pattern: write the logic as an
+//! `async fn(connection: &mut C, ...)` generic over `C: Connection + ?Sized`, and call other
+//! commands' functions directly. Since the connection is just passed along as a reborrowed
+//! `&mut C`, helpers can be awaited in loops without fighting the borrow checker.
 
-use crate::connection::Connection;
+use std::{error::Error as StdError, fmt, future::Future, sync::Arc, time::Duration};
 
+use thiserror::Error;
+
+use crate::{
+    connection::{clock, Connection, ConnectionType, TransferState},
+    packets::file::FileVendor,
+};
+
+use self::file::{UploadReport, MAX_MEMORY_READ_SIZE};
+
+pub mod controller;
 pub mod file;
+pub mod fs;
+pub mod kv;
+pub mod lock;
+pub mod log;
+pub mod progress;
 #[cfg(feature = "screen-command")]
 pub mod screen;
 
@@ -14,3 +40,142 @@ pub trait Command {
         connection: &mut C,
     ) -> impl Future<Output = Result<Self::Output, C::Error>>;
 }
+
+/// Errors produced by [`Command`]s that aren't caused by a packet exchange itself.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum CommandError {
+    #[error("This command is not supported over a {0:?} connection")]
+    UnsupportedConnectionType(ConnectionType),
+    #[error("The controller radio link did not come back after being changed")]
+    RadioLinkLost,
+    #[error(
+        "Program upload failed at {}",
+        .0.failed_file().map_or("an unknown file", |file| file.file_name.as_str())
+    )]
+    UploadFailed(Box<UploadReport>),
+    #[error("Compressing a program requires this crate's `compression` feature")]
+    CompressionUnavailable,
+    #[error(
+        "Invalid memory read of {len} bytes at {address:#x}. Reads must be 4-byte aligned and at most {MAX_MEMORY_READ_SIZE} bytes"
+    )]
+    InvalidMemoryRead { address: u32, len: u32 },
+    #[error("Not enough storage on the brain: {needed} bytes needed, about {available} bytes available")]
+    InsufficientStorage { needed: u64, available: u64 },
+    #[error("Download interrupted at offset {next_offset}: {source}")]
+    DownloadInterrupted {
+        /// The data downloaded before the failure.
+        partial: Vec<u8>,
+        /// The offset to pass as `resume_from` to download the rest of the file.
+        next_offset: u32,
+        /// The connection's error for the read that failed.
+        source: SharedError,
+    },
+    #[error(
+        "Can't resume {file_name} from offset {resume_from}, past the end of its {file_size} bytes"
+    )]
+    ResumePastEnd {
+        file_name: String,
+        resume_from: u32,
+        file_size: u32,
+    },
+    #[error(
+        "Offset {offset} from load address {load_addr:#x} is past the end of the address space"
+    )]
+    ReadAddressOverflow { load_addr: u32, offset: u32 },
+    #[error("The brain's battery is at {percent}%, below the {threshold}% needed to upload")]
+    BatteryTooLow { percent: u8, threshold: u8 },
+    #[error("A file transfer of {} is already in progress", .0.file_name)]
+    TransferAlreadyInProgress(TransferState),
+    #[error(
+        "The brain rejected {file_name}: its {size} bytes don't match the declared CRC32 {declared:#010x} (NACK 0xD2, the brain doesn't report the CRC it computed)"
+    )]
+    UploadCrcMismatch {
+        file_name: String,
+        /// The CRC32 that was declared, from [`upload_crc`](file::upload_crc).
+        declared: u32,
+        size: usize,
+    },
+    #[error("Cold library {0} is not on the brain")]
+    MissingColdLibrary(String),
+    #[error("File {0} is not on the brain")]
+    FileNotFound(String),
+    #[error("The value for key {key} is {len} bytes long, but at most {max} are allowed")]
+    KvValueTooLong { key: String, len: usize, max: usize },
+    #[error("{0:?} is not a valid team number")]
+    InvalidTeamNumber(String),
+    #[error("Asked the brain for {requested} log entries, but it sent {received}")]
+    ShortLogPage { requested: u32, received: usize },
+    #[error("Screen capture was {received} bytes, but the screen takes {expected}")]
+    ShortScreenCapture { expected: usize, received: usize },
+    #[error("Program {field} is {len} bytes long, but VEXos only allows {max}")]
+    ProgramTextTooLong {
+        field: &'static str,
+        len: usize,
+        max: usize,
+    },
+    #[error("The brain is locked by {holder} for another {} s", .remaining.as_secs())]
+    BrainLocked { holder: String, remaining: Duration },
+    #[error("The brain didn't keep the lock written to the {0:?} key")]
+    LockNotStored(String),
+    #[error("The brain has no {0:?} directory to write to. FileVendor::User is always available")]
+    NoDirectory(FileVendor),
+    #[error("{file_name} was still on the brain {} s after it was erased", .waited.as_secs())]
+    EraseTimedOut { file_name: String, waited: Duration },
+}
+
+/// A connection's error, kept as the source of a [`CommandError`].
+///
+/// Clones share the same error, and two of these are only equal if they share it.
+#[derive(Debug, Clone)]
+pub struct SharedError(Arc<dyn StdError + Send + Sync>);
+impl SharedError {
+    pub fn new(error: impl StdError + Send + Sync + 'static) -> Self {
+        Self(Arc::new(error))
+    }
+
+    /// Returns the error if it's a `T`.
+    pub fn downcast_ref<T: StdError + 'static>(&self) -> Option<&T> {
+        self.0.downcast_ref()
+    }
+}
+impl fmt::Display for SharedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+impl StdError for SharedError {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        self.0.source()
+    }
+}
+impl PartialEq for SharedError {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+impl Eq for SharedError {}
+
+/// Calls `poll` every `interval` until it returns `Some`, or returns `None` once `budget` has
+/// passed.
+///
+/// Time spent in [`Connection::sleep`] counts towards the budget even if the connection
+/// returns straight away, so `poll` is called at most `budget / interval + 1` times.
+pub(crate) async fn poll_until<C: Connection + ?Sized, T>(
+    connection: &mut C,
+    interval: Duration,
+    budget: Duration,
+    mut poll: impl AsyncFnMut(&mut C) -> Result<Option<T>, C::Error>,
+) -> Result<Option<T>, C::Error> {
+    let start = clock::now();
+    let mut slept = Duration::ZERO;
+    loop {
+        if let Some(done) = poll(connection).await? {
+            return Ok(Some(done));
+        }
+        if clock::since(start).max(slept) >= budget {
+            return Ok(None);
+        }
+        connection.sleep(interval).await;
+        slept += interval;
+    }
+}
@@ -0,0 +1,231 @@
+//! Transfer progress as a [`Stream`], for async UIs.
+//!
+//! Commands that transfer files report progress through boxed callbacks, which have to be
+//! `Send` and can't await anything. Calling `progress_stream` on one of those commands (such
+//! as [`DownloadFile::progress_stream`]) returns a [`ProgressStream`] instead, which can be
+//! polled from a UI task while the command runs elsewhere:
+//!
+//! ```no_run
+//! # async fn example<C: vex_v5_serial::connection::Connection>(connection: &mut C, mut download: vex_v5_serial::commands::file::DownloadFile) -> Result<(), C::Error> {
+//! use vex_v5_serial::commands::progress::TransferProgress;
+//!
+//! let progress = download.progress_stream();
+//! // Hand `progress` to a UI task, which polls it with e.g. `StreamExt::next`
+//! # drop(progress);
+//! let data = connection.execute_command(download).await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Events are buffered in a bounded queue of [`PROGRESS_STREAM_CAPACITY`] items. If the
+//! consumer falls behind, the oldest events are dropped rather than making the command wait.
+//! Callbacks set on the command before the stream was created keep being called.
+//!
+//! # Cancellation
+//!
+//! The stream always ends with a single [`TransferProgress::Finished`] or
+//! [`TransferProgress::Stopped`] item, which is never dropped. It's sent once the command is
+//! done with its files, which includes the command's future being dropped partway through a
+//! transfer, or the command being dropped without running at all. Either way, the stream ends
+//! after that item.
+//!
+//! [`DownloadFile::progress_stream`]: super::file::DownloadFile::progress_stream
+
+use std::{
+    collections::VecDeque,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Waker},
+};
+
+use futures_core::Stream;
+
+/// The number of progress events a [`ProgressStream`] holds before dropping the oldest.
+pub const PROGRESS_STREAM_CAPACITY: usize = 32;
+
+/// An item of a [`ProgressStream`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TransferProgress {
+    /// The percentage of the current file sent or received, from 0 to 100.
+    ///
+    /// Commands that transfer several files start again from 0 for each one.
+    Progress(f32),
+    /// Every file reached 100%. This is always the last item.
+    ///
+    /// The command can still fail after its data has been transferred, so check its result too.
+    Finished,
+    /// The command failed or was cancelled before every file reached 100%. This is always the
+    /// last item.
+    Stopped,
+}
+
+struct Shared {
+    events: VecDeque<f32>,
+    /// Senders that haven't been dropped yet.
+    senders: usize,
+    /// Senders that haven't reported 100% yet.
+    unfinished: usize,
+    ended: bool,
+    waker: Option<Waker>,
+}
+impl Shared {
+    fn wake(&mut self) {
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// Creates a stream along with the sender feeding it.
+///
+/// The sender can be cloned once for each file that the command will transfer. The stream
+/// finishes when every clone has reported 100% and been dropped.
+pub(crate) fn progress_channel() -> (ProgressSender, ProgressStream) {
+    let shared = Arc::new(Mutex::new(Shared {
+        events: VecDeque::with_capacity(PROGRESS_STREAM_CAPACITY),
+        senders: 1,
+        unfinished: 1,
+        ended: false,
+        waker: None,
+    }));
+    (
+        ProgressSender {
+            shared: shared.clone(),
+            finished: false,
+        },
+        ProgressStream { shared },
+    )
+}
+
+/// The command side of a [`ProgressStream`], for one file.
+pub(crate) struct ProgressSender {
+    shared: Arc<Mutex<Shared>>,
+    finished: bool,
+}
+impl ProgressSender {
+    fn send(&mut self, percent: f32) {
+        let mut shared = self.shared.lock().unwrap();
+        if shared.events.len() == PROGRESS_STREAM_CAPACITY {
+            shared.events.pop_front();
+        }
+        shared.events.push_back(percent);
+        if percent >= 100.0 && !self.finished {
+            self.finished = true;
+            shared.unfinished -= 1;
+        }
+        shared.wake();
+    }
+
+    /// Returns a progress callback that sends to the stream after calling `callback`.
+    pub(crate) fn attach<'a>(
+        mut self,
+        mut callback: Option<Box<dyn FnMut(f32) + Send + 'a>>,
+    ) -> Box<dyn FnMut(f32) + Send + 'a> {
+        Box::new(move |percent| {
+            if let Some(callback) = &mut callback {
+                callback(percent);
+            }
+            self.send(percent);
+        })
+    }
+}
+impl Clone for ProgressSender {
+    fn clone(&self) -> Self {
+        let mut shared = self.shared.lock().unwrap();
+        shared.senders += 1;
+        shared.unfinished += 1;
+        Self {
+            shared: self.shared.clone(),
+            finished: false,
+        }
+    }
+}
+impl Drop for ProgressSender {
+    fn drop(&mut self) {
+        let mut shared = self.shared.lock().unwrap();
+        shared.senders -= 1;
+        if shared.senders == 0 {
+            shared.wake();
+        }
+    }
+}
+
+/// A stream of [`TransferProgress`] events from a command.
+///
+/// See the [module documentation](self) for how events are buffered and how the stream ends.
+pub struct ProgressStream {
+    shared: Arc<Mutex<Shared>>,
+}
+impl Stream for ProgressStream {
+    type Item = TransferProgress;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut shared = self.shared.lock().unwrap();
+        if let Some(percent) = shared.events.pop_front() {
+            return Poll::Ready(Some(TransferProgress::Progress(percent)));
+        }
+        if shared.senders > 0 {
+            shared.waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        if shared.ended {
+            return Poll::Ready(None);
+        }
+
+        shared.ended = true;
+        Poll::Ready(Some(if shared.unfinished == 0 {
+            TransferProgress::Finished
+        } else {
+            TransferProgress::Stopped
+        }))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{
+        pin::Pin,
+        task::{Context, Poll, Waker},
+    };
+
+    use futures_core::Stream;
+
+    use super::{progress_channel, ProgressStream, TransferProgress, PROGRESS_STREAM_CAPACITY};
+
+    /// Polls every item that's ready, and whether the stream has ended.
+    pub(crate) fn ready_items(stream: &mut ProgressStream) -> (Vec<TransferProgress>, bool) {
+        let mut cx = Context::from_waker(Waker::noop());
+        let mut items = Vec::new();
+        loop {
+            match Pin::new(&mut *stream).poll_next(&mut cx) {
+                Poll::Ready(Some(item)) => items.push(item),
+                Poll::Ready(None) => return (items, true),
+                Poll::Pending => return (items, false),
+            }
+        }
+    }
+
+    #[test]
+    fn lagging_consumer() {
+        let (sender, mut stream) = progress_channel();
+        let second = sender.clone();
+        let mut callback = sender.attach(None);
+        for percent in 0..=100 {
+            callback(percent as f32);
+        }
+        drop(callback);
+
+        // Only the newest events are kept
+        let (items, ended) = ready_items(&mut stream);
+        assert_eq!(items.len(), PROGRESS_STREAM_CAPACITY);
+        assert_eq!(items.last(), Some(&TransferProgress::Progress(100.0)));
+        assert!(!ended);
+
+        // The second file was dropped without making any progress
+        drop(second);
+        assert_eq!(
+            ready_items(&mut stream),
+            (vec![TransferProgress::Stopped], true)
+        );
+    }
+}
@@ -5,17 +5,24 @@ use log::info;
 use crate::{
     connection::Connection,
     packets::{
-        capture::{ScreenCapturePacket, ScreenCaptureReplyPacket},
+        capture::ScreenCapturePacket,
         dash::{
-            DashScreen, SelectDashPacket, SelectDashPayload, SelectDashReplyPacket,
-            SendDashTouchPacket, SendDashTouchPayload, SendDashTouchReplyPacket,
+            DashScreen, SelectDashPacket, SelectDashPayload, SendDashTouchPacket,
+            SendDashTouchPayload,
         },
         file::{FileTransferTarget, FileVendor},
+        system::GetSystemFlagsPacket,
     },
     string::FixedString,
 };
 
-use super::{file::DownloadFile, Command};
+use super::{
+    file::{download_file, DownloadFile},
+    Command, CommandError,
+};
+
+/// The size of a screen capture, in 4 byte pixels of a 512 pixel wide framebuffer.
+const SCREEN_CAPTURE_SIZE: usize = 512 * 272 * 4;
 
 #[derive(Debug, Clone, Copy)]
 pub struct ScreenCapture;
@@ -28,7 +35,7 @@ impl Command for ScreenCapture {
     ) -> Result<Self::Output, C::Error> {
         // Tell the brain we want to take a screenshot
         connection
-            .packet_handshake::<ScreenCaptureReplyPacket>(
+            .request(
                 Duration::from_millis(100),
                 5,
                 ScreenCapturePacket::new(()),
@@ -36,21 +43,24 @@ impl Command for ScreenCapture {
             .await?;
 
         // Grab the image data
-        let cap = connection
-            .execute_command(DownloadFile {
+        let cap = download_file(
+            connection,
+            DownloadFile {
                 file_name: FixedString::new("screen".to_string()).unwrap(),
                 vendor: FileVendor::Sys,
                 target: Some(FileTransferTarget::Cbuf),
                 load_addr: 0,
-                size: 512 * 272 * 4,
+                resume_from: 0,
+                size: SCREEN_CAPTURE_SIZE as u32,
                 progress_callback: Some(Box::new(|progress| {
                     info!("Downloading screen: {:.2}%", progress)
                 })),
-            })
-            .await
-            .unwrap();
+            },
+        )
+        .await?;
 
         let colors = cap
+            .data
             .chunks(4)
             .filter_map(|p| {
                 if p.len() == 4 {
@@ -64,7 +74,13 @@ impl Command for ScreenCapture {
             .flatten()
             .collect::<Vec<_>>();
 
-        let image = image::RgbImage::from_vec(512, 272, colors).unwrap();
+        let Some(image) = image::RgbImage::from_vec(512, 272, colors) else {
+            return Err(CommandError::ShortScreenCapture {
+                expected: SCREEN_CAPTURE_SIZE,
+                received: cap.data.len(),
+            }
+            .into());
+        };
         Ok(image::GenericImageView::view(&image, 0, 0, 480, 272).to_image())
     }
 }
@@ -83,7 +99,7 @@ impl Command for MockTouch {
         connection: &mut C,
     ) -> Result<Self::Output, C::Error> {
         connection
-            .packet_handshake::<SendDashTouchReplyPacket>(
+            .request(
                 Duration::from_millis(100),
                 5,
                 SendDashTouchPacket::new(SendDashTouchPayload {
@@ -139,7 +155,7 @@ impl Command for OpenDashScreen {
         connection: &mut C,
     ) -> Result<Self::Output, C::Error> {
         connection
-            .packet_handshake::<SelectDashReplyPacket>(
+            .request(
                 Duration::from_millis(100),
                 5,
                 SelectDashPacket::new(SelectDashPayload {
@@ -152,3 +168,75 @@ impl Command for OpenDashScreen {
         Ok(())
     }
 }
+
+/// Reads which dashboard screen the brain is showing.
+///
+/// This is `None` if the brain is showing a page that has no [`DashScreen`] variant. The page's
+/// index can still be read with [`SystemFlags::page_index`].
+///
+/// [`SystemFlags::page_index`]: crate::packets::system::SystemFlags::page_index
+#[derive(Debug, Clone, Copy)]
+pub struct GetCurrentDashScreen;
+impl Command for GetCurrentDashScreen {
+    type Output = Option<DashScreen>;
+    async fn execute<C: Connection + ?Sized>(
+        self,
+        connection: &mut C,
+    ) -> Result<Self::Output, C::Error> {
+        let flags = connection
+            .request(Duration::from_millis(500), 5, GetSystemFlagsPacket::new(()))
+            .await?
+            .try_into_inner()?;
+
+        Ok(flags.dash_screen())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{GetCurrentDashScreen, ScreenCapture, SCREEN_CAPTURE_SIZE};
+    use crate::{
+        commands::CommandError,
+        connection::{
+            mock::{
+                block_on, cdc2_reply, init_transfer_reply, read_reply, MockConnection, MockError,
+            },
+            Connection,
+        },
+        packets::dash::DashScreen,
+    };
+
+    #[test]
+    fn short_screen_capture() {
+        let mut connection = MockConnection {
+            replies: [
+                cdc2_reply(0x28, &[]),
+                init_transfer_reply(4096, 8),
+                read_reply(0, &[0; 8]),
+            ]
+            .into(),
+            ..Default::default()
+        };
+
+        let Err(MockError::Command(CommandError::ShortScreenCapture { expected, received })) =
+            block_on(connection.execute_command(ScreenCapture))
+        else {
+            panic!("A short capture should be an error");
+        };
+        assert_eq!((expected, received), (SCREEN_CAPTURE_SIZE, 8));
+    }
+
+    #[test]
+    fn current_dash_screen() {
+        let flags_reply = |page: u8| cdc2_reply(0x20, &[0, 0, 0, page, 0, 0, 0]);
+        let mut connection = MockConnection {
+            replies: [flags_reply(16), flags_reply(44)].into(),
+            ..Default::default()
+        };
+
+        let screen = block_on(connection.execute_command(GetCurrentDashScreen)).unwrap();
+        assert_eq!(screen, Some(DashScreen::Devices));
+        let screen = block_on(connection.execute_command(GetCurrentDashScreen)).unwrap();
+        assert_eq!(screen, None);
+    }
+}
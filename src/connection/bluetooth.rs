@@ -1,22 +1,28 @@
-use std::time::{Duration, Instant};
+use std::{collections::VecDeque, future::Future, pin::Pin, time::Duration};
 
 use btleplug::api::{
-    Central, CentralEvent, Characteristic, Manager as _, Peripheral as _, ScanFilter, WriteType,
+    Central, CentralEvent, Characteristic, Manager as _, Peripheral as _, ScanFilter,
+    ValueNotification, WriteType,
 };
 use btleplug::platform::{Manager, Peripheral};
-use log::{debug, error, trace, warn};
+use log::{debug, trace, warn};
 use thiserror::Error;
 use tokio::select;
-use tokio::time::sleep;
-use tokio_stream::StreamExt;
+use tokio::time::{sleep, timeout};
+use tokio_stream::{Stream, StreamExt};
 use uuid::Uuid;
 
-use crate::connection::trim_packets;
+use crate::commands::CommandError;
 use crate::decode::{Decode, DecodeError};
 use crate::encode::{Encode, EncodeError};
+use crate::hex::HexPreview;
 use crate::packets::cdc2::Cdc2Ack;
 
-use super::{CheckHeader, Connection, ConnectionType, RawPacket};
+use super::{
+    clock::{self, Instant},
+    handshake_matched, receive_matched, CheckHeader, Connection, ConnectionStats, ConnectionType,
+    HandshakeError, MatchReceive, Matcher, PacketRouter, TransferState,
+};
 
 /// The BLE GATT Service that V5 Brains provide
 pub const V5_SERVICE: Uuid = Uuid::from_u128(0x08590f7e_db05_467e_8757_72f6faeb13d5);
@@ -34,12 +40,43 @@ pub const CHARACTERISTIC_PAIRING: Uuid = Uuid::from_u128(0x08590f7e_db05_467e_87
 
 pub const UNPAIRED_MAGIC: u32 = 0xdeadface;
 
+/// How long notifications must stop for before [`BluetoothConnection::flush_incoming`] stops
+/// draining them.
+const FLUSH_TIMEOUT: Duration = Duration::from_millis(20);
+
 #[derive(Debug, Clone)]
 pub struct BluetoothDevice(pub Peripheral);
 
 impl BluetoothDevice {
-    pub async fn connect(&self) -> Result<BluetoothConnection, BluetoothError> {
-        BluetoothConnection::open(self.clone()).await
+    /// Opens a connection to the device, giving each step of opening it `timeout` to finish.
+    ///
+    /// See [`BluetoothConnection::open_with_timeout`].
+    pub async fn connect(&self, timeout: Duration) -> Result<BluetoothConnection, BluetoothError> {
+        BluetoothConnection::open_with_timeout(self.clone(), timeout).await
+    }
+}
+
+/// A step of opening a [`BluetoothConnection`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpenPhase {
+    /// Connecting to the peripheral.
+    Connect,
+    /// Discovering the peripheral's GATT services.
+    DiscoverServices,
+    /// Subscribing to the system and user characteristics.
+    Subscribe,
+}
+
+/// Runs one step of opening a connection, failing with [`BluetoothError::OpenTimeout`] if it
+/// doesn't finish within `duration`.
+async fn open_phase<T>(
+    phase: OpenPhase,
+    duration: Duration,
+    future: impl Future<Output = Result<T, btleplug::Error>>,
+) -> Result<T, BluetoothError> {
+    match timeout(duration, future).await {
+        Ok(result) => Ok(result?),
+        Err(_) => Err(BluetoothError::OpenTimeout(phase)),
     }
 }
 
@@ -68,7 +105,7 @@ pub async fn find_devices(
     let mut devices = Vec::<BluetoothDevice>::new();
 
     // Scan for peripherals using the V5 service UUID.
-    let scan_start_time = Instant::now();
+    let scan_start_time = clock::now();
     adapter
         .start_scan(ScanFilter {
             services: vec![V5_SERVICE],
@@ -102,7 +139,7 @@ pub async fn find_devices(
         }
 
         // Also break if we've exceeded the provided scan time.
-        if scan_start_time.elapsed() > scan_time {
+        if clock::since(scan_start_time) > scan_time {
             break;
         }
     }
@@ -110,12 +147,65 @@ pub async fn find_devices(
     debug!(
         "Found {} devices in {:?}",
         devices.len(),
-        scan_start_time.elapsed()
+        clock::since(scan_start_time)
     );
 
     Ok(devices)
 }
 
+/// Waits for the next notification from the brain and routes it by characteristic.
+///
+/// System port notifications are whole packets and are added to `packets`, while user port
+/// notifications are appended to `user`. Notifications from any other characteristic are
+/// ignored.
+async fn receive_notification<S: Stream<Item = ValueNotification> + Unpin>(
+    notifications: &mut S,
+    packets: &mut PacketRouter,
+    user: &mut VecDeque<u8>,
+) -> Result<(), BluetoothError> {
+    let notification = notifications
+        .next()
+        .await
+        .ok_or(BluetoothError::NoResponse)?;
+
+    match notification.uuid {
+        CHARACTERISTIC_SYSTEM_TX => {
+            debug!("Received packet: {:x?}", HexPreview(&notification.value));
+            packets.push(notification.value);
+        }
+        CHARACTERISTIC_USER_TX => {
+            trace!("Received user data: {:x?}", HexPreview(&notification.value));
+            user.extend(notification.value);
+        }
+        uuid => warn!("Ignoring notification from characteristic {}", uuid),
+    }
+
+    Ok(())
+}
+
+/// Receives notifications until none arrive for [`FLUSH_TIMEOUT`], then discards every packet
+/// in `packets`.
+///
+/// User port data is kept in `user`, since it isn't a reply to anything.
+async fn drain_notifications<S: Stream<Item = ValueNotification> + Unpin>(
+    notifications: &mut S,
+    packets: &mut PacketRouter,
+    user: &mut VecDeque<u8>,
+) -> Result<(), BluetoothError> {
+    while let Ok(result) = timeout(
+        FLUSH_TIMEOUT,
+        receive_notification(notifications, packets, user),
+    )
+    .await
+    {
+        result?;
+    }
+
+    debug!("Flushing {} incoming packets", packets.len());
+    packets.clear();
+    Ok(())
+}
+
 pub struct BluetoothConnection {
     pub peripheral: Peripheral,
     pub system_tx: Characteristic,
@@ -124,22 +214,72 @@ pub struct BluetoothConnection {
     pub user_rx: Characteristic,
     pub pairing: Characteristic,
 
-    incoming_packets: Vec<RawPacket>,
+    /// Notifications from both TX characteristics, for the lifetime of the connection.
+    notifications: Pin<Box<dyn Stream<Item = ValueNotification> + Send>>,
+    incoming_packets: PacketRouter,
+    /// User port bytes that haven't been read yet.
+    user_buffer: VecDeque<u8>,
+    active_transfer: Option<TransferState>,
+    read_chunk_size: Option<u16>,
+    timeout_scale: f64,
+    stats: ConnectionStats,
 }
 
 impl BluetoothConnection {
-    pub const MAX_PACKET_SIZE: usize = 244;
+    pub const MAX_PACKET_SIZE: usize = super::BLUETOOTH_MAX_PACKET_SIZE;
 
+    /// Opens a connection to a device without any deadline.
+    ///
+    /// Some adapters never finish connecting to a device that has gone out of range, so
+    /// [`open_with_timeout`](Self::open_with_timeout) should usually be preferred.
     pub async fn open(device: BluetoothDevice) -> Result<Self, BluetoothError> {
+        Self::open_with_timeout(device, Duration::MAX).await
+    }
+
+    /// Opens a connection to a device, giving each step of opening it `timeout` to finish.
+    ///
+    /// If a step takes longer, [`BluetoothError::OpenTimeout`] reports which one. Finding the
+    /// characteristics is done on the services already discovered, so it can't time out.
+    /// On any failure, the peripheral is disconnected so that a half-open connection doesn't
+    /// block the next attempt.
+    pub async fn open_with_timeout(
+        device: BluetoothDevice,
+        timeout: Duration,
+    ) -> Result<Self, BluetoothError> {
         let peripheral = device.0;
 
-        if !peripheral.is_connected().await? {
-            peripheral.connect().await?;
+        match Self::open_peripheral(peripheral.clone(), timeout).await {
+            Ok(connection) => Ok(connection),
+            Err(err) => {
+                warn!("Failed to open connection: {}", err);
+                if let Err(disconnect_err) =
+                    open_phase(OpenPhase::Connect, timeout, peripheral.disconnect()).await
+                {
+                    warn!("Failed to disconnect peripheral: {}", disconnect_err);
+                }
+                Err(err)
+            }
+        }
+    }
+
+    async fn open_peripheral(
+        peripheral: Peripheral,
+        timeout: Duration,
+    ) -> Result<Self, BluetoothError> {
+        let is_connected =
+            open_phase(OpenPhase::Connect, timeout, peripheral.is_connected()).await?;
+        if !is_connected {
+            open_phase(OpenPhase::Connect, timeout, peripheral.connect()).await?;
         } else {
             warn!("Peripheral already connected?");
         }
 
-        peripheral.discover_services().await?;
+        open_phase(
+            OpenPhase::DiscoverServices,
+            timeout,
+            peripheral.discover_services(),
+        )
+        .await?;
 
         let mut system_tx: Option<Characteristic> = None;
         let mut system_rx: Option<Characteristic> = None;
@@ -168,24 +308,40 @@ impl BluetoothConnection {
             }
         }
 
-        let connection = Self {
+        let system_tx = system_tx.ok_or(BluetoothError::MissingCharacteristic)?;
+        let user_tx = user_tx.ok_or(BluetoothError::MissingCharacteristic)?;
+
+        open_phase(
+            OpenPhase::Subscribe,
+            timeout,
+            peripheral.subscribe(&system_tx),
+        )
+        .await?;
+        open_phase(
+            OpenPhase::Subscribe,
+            timeout,
+            peripheral.subscribe(&user_tx),
+        )
+        .await?;
+        let notifications =
+            open_phase(OpenPhase::Subscribe, timeout, peripheral.notifications()).await?;
+
+        Ok(Self {
             peripheral,
-            system_tx: system_tx.ok_or(BluetoothError::MissingCharacteristic)?,
+            system_tx,
             system_rx: system_rx.ok_or(BluetoothError::MissingCharacteristic)?,
-            user_tx: user_tx.ok_or(BluetoothError::MissingCharacteristic)?,
+            user_tx,
             user_rx: user_rx.ok_or(BluetoothError::MissingCharacteristic)?,
             pairing: pairing.ok_or(BluetoothError::MissingCharacteristic)?,
 
-            incoming_packets: Vec::new(),
-        };
-
-        connection
-            .peripheral
-            .subscribe(&connection.system_tx)
-            .await?;
-        connection.peripheral.subscribe(&connection.user_tx).await?;
-
-        Ok(connection)
+            notifications,
+            incoming_packets: PacketRouter::new(),
+            user_buffer: VecDeque::new(),
+            active_transfer: None,
+            read_chunk_size: None,
+            timeout_scale: 1.0,
+            stats: ConnectionStats::default(),
+        })
     }
 
     pub async fn is_paired(&self) -> Result<bool, BluetoothError> {
@@ -220,25 +376,35 @@ impl BluetoothConnection {
         Ok(())
     }
 
-    async fn receive_one_packet(&mut self) -> Result<(), BluetoothError> {
-        //TODO: get notifications and store it rather than creating it every time this method is called
-        let mut notifs = self.peripheral.notifications().await?;
-
-        loop {
-            let Some(notification) = notifs.next().await else {
-                return Err(BluetoothError::NoResponse);
-            };
+    /// Receives a single notification, adding it to the incoming packets or the user buffer.
+    async fn receive_one_notification(&mut self) -> Result<(), BluetoothError> {
+        receive_notification(
+            &mut self.notifications,
+            &mut self.incoming_packets,
+            &mut self.user_buffer,
+        )
+        .await
+    }
+}
 
-            if notification.uuid == CHARACTERISTIC_SYSTEM_TX {
-                let data = notification.value;
-                debug!("Received packet: {:x?}", data);
-                let packet = RawPacket::new(data);
-                self.incoming_packets.push(packet);
-                break;
-            }
+impl MatchReceive for BluetoothConnection {
+    async fn receive_matching(
+        &mut self,
+        timeout: Duration,
+        matcher: &mut Matcher<'_>,
+    ) -> Result<Instant, BluetoothError> {
+        // Return an error if the right packet is not received within the timeout
+        select! {
+            result = async {
+                loop {
+                    if let Some(claimed) = self.incoming_packets.claim_matching(matcher) {
+                        return claimed.map_err(BluetoothError::DecodeError);
+                    }
+                    self.receive_one_notification().await?;
+                }
+            } => result,
+            _ = sleep(timeout) => Err(BluetoothError::Timeout)
         }
-
-        Ok(())
     }
 }
 
@@ -249,6 +415,46 @@ impl Connection for BluetoothConnection {
         ConnectionType::Bluetooth
     }
 
+    fn set_active_transfer(&mut self, transfer: Option<TransferState>) {
+        self.active_transfer = transfer;
+    }
+
+    fn active_transfer(&self) -> Option<&TransferState> {
+        self.active_transfer.as_ref()
+    }
+
+    fn set_read_chunk_size(&mut self, size: u16) {
+        self.read_chunk_size = Some(size);
+    }
+
+    fn read_chunk_size(&self) -> Option<u16> {
+        self.read_chunk_size
+    }
+
+    fn set_timeout_scale(&mut self, scale: f64) {
+        self.timeout_scale = scale;
+    }
+
+    fn timeout_scale(&self) -> f64 {
+        self.timeout_scale
+    }
+
+    fn record_handshake(&mut self, failed_attempts: usize, succeeded: bool) {
+        self.stats.record_handshake(failed_attempts, succeeded);
+    }
+
+    fn record_round_trip(&mut self, round_trip: Duration) {
+        self.stats.record_round_trip(round_trip);
+    }
+
+    fn stats(&self) -> ConnectionStats {
+        self.stats
+    }
+
+    async fn sleep(&mut self, duration: Duration) {
+        sleep(duration).await;
+    }
+
     async fn send_packet(&mut self, packet: impl Encode) -> Result<(), BluetoothError> {
         if !self.is_paired().await? {
             return Err(BluetoothError::PairingRequired);
@@ -257,7 +463,7 @@ impl Connection for BluetoothConnection {
         // Encode the packet
         let encoded = packet.encode()?;
 
-        trace!("Sending packet: {:x?}", encoded);
+        trace!("Sending packet: {:x?}", HexPreview(&encoded));
 
         // Write the packet to the system rx characteristic.
         self.peripheral
@@ -268,35 +474,46 @@ impl Connection for BluetoothConnection {
     }
 
     async fn receive_packet<P: Decode + CheckHeader>(&mut self, timeout: Duration) -> Result<P, BluetoothError> {
-        // Return an error if the right packet is not received within the timeout
-        select! {
-            result = async {
-                loop {
-                    for packet in self.incoming_packets.iter_mut() {
-                        if packet.check_header::<P>() {
-                            match packet.decode_and_use::<P>() {
-                                Ok(decoded) => {
-                                    trim_packets(&mut self.incoming_packets);
-                                    return Ok(decoded);
-                                }
-                                Err(e) => {
-                                    error!("Failed to decode packet with valid header: {}", e);
-                                    packet.used = true;
-                                    return Err(BluetoothError::DecodeError(e));
-                                }
-                            }
-                        }
-                    }
-                    trim_packets(&mut self.incoming_packets);
-                    self.receive_one_packet().await?;
-                }
-            } => result,
-            _ = sleep(timeout) => Err(BluetoothError::Timeout)
-        }
+        receive_matched(self, timeout)
+            .await
+            .map(|(packet, _)| packet)
     }
 
-    async fn read_user(&mut self, _buf: &mut [u8]) -> Result<usize, BluetoothError> {
-        todo!();
+    fn receive_packet_timed<P: Decode + CheckHeader>(
+        &mut self,
+        timeout: Duration,
+    ) -> impl Future<Output = Result<(P, Instant), BluetoothError>> {
+        receive_matched(self, timeout)
+    }
+
+    fn packet_handshake<D: Decode + CheckHeader>(
+        &mut self,
+        timeout: Duration,
+        retries: usize,
+        packet: impl Encode + Clone,
+    ) -> impl Future<Output = Result<D, BluetoothError>> {
+        handshake_matched(self, timeout, retries, packet)
+    }
+
+    async fn flush_incoming(&mut self) -> Result<(), BluetoothError> {
+        drain_notifications(
+            &mut self.notifications,
+            &mut self.incoming_packets,
+            &mut self.user_buffer,
+        )
+        .await
+    }
+
+    async fn read_user(&mut self, buf: &mut [u8]) -> Result<usize, BluetoothError> {
+        while self.user_buffer.is_empty() {
+            self.receive_one_notification().await?;
+        }
+
+        let len = buf.len().min(self.user_buffer.len());
+        for (byte, buffered) in buf.iter_mut().zip(self.user_buffer.drain(..len)) {
+            *byte = buffered;
+        }
+        Ok(len)
     }
 
     async fn write_user(&mut self, _buf: &[u8]) -> Result<usize, BluetoothError> {
@@ -316,6 +533,10 @@ pub enum BluetoothError {
     Timeout,
     #[error("NACK received: {0:?}")]
     Nack(#[from] Cdc2Ack),
+    #[error("Command error: {0}")]
+    CommandError(#[from] CommandError),
+    #[error("{0}")]
+    HandshakeError(#[from] HandshakeError),
     #[error("Bluetooth Error")]
     Btleplug(#[from] btleplug::Error),
     #[error("No response received over bluetooth")]
@@ -324,8 +545,100 @@ pub enum BluetoothError {
     NoBluetoothAdapter,
     #[error("Expected a Bluetooth characteristic that didn't exist")]
     MissingCharacteristic,
+    #[error("Timed out opening the connection during {0:?}")]
+    OpenTimeout(OpenPhase),
     #[error("Authentication PIN code was incorrect")]
     IncorrectPin,
     #[error("Pairing is required")]
     PairingRequired,
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use btleplug::api::ValueNotification;
+    use tokio_stream::StreamExt;
+    use uuid::Uuid;
+
+    use super::{
+        drain_notifications, receive_notification, BluetoothError, PacketRouter,
+        CHARACTERISTIC_SYSTEM_TX, CHARACTERISTIC_USER_TX,
+    };
+    use crate::packets::cdc::CdcReplyPacket;
+
+    fn notification(uuid: Uuid, value: &[u8]) -> ValueNotification {
+        ValueNotification {
+            uuid,
+            value: value.to_vec(),
+        }
+    }
+
+    #[test]
+    fn interleaved_notifications() {
+        let mut notifications = tokio_stream::iter([
+            notification(CHARACTERISTIC_USER_TX, b"hello "),
+            notification(CHARACTERISTIC_SYSTEM_TX, &[0xAA, 0x55, 0x21, 0x01, 0x07]),
+            notification(CHARACTERISTIC_USER_TX, b"world"),
+            notification(CHARACTERISTIC_SYSTEM_TX, &[0xAA, 0x55, 0x22, 0x01, 0x08]),
+        ]);
+        let mut packets = PacketRouter::new();
+        let mut user = VecDeque::new();
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            for _ in 0..4 {
+                receive_notification(&mut notifications, &mut packets, &mut user)
+                    .await
+                    .unwrap();
+            }
+            assert!(matches!(
+                receive_notification(&mut notifications, &mut packets, &mut user).await,
+                Err(BluetoothError::NoResponse)
+            ));
+        });
+
+        assert_eq!(user, b"hello world");
+        let (first, _) = packets
+            .claim::<CdcReplyPacket<0x21, u8>>()
+            .unwrap()
+            .unwrap();
+        let (second, _) = packets
+            .claim::<CdcReplyPacket<0x22, u8>>()
+            .unwrap()
+            .unwrap();
+        assert_eq!((first.payload, second.payload), (7, 8));
+        assert_eq!(packets.len(), 0);
+    }
+
+    #[test]
+    fn flush_keeps_user_data() {
+        // The stream stays open, like a connected peripheral that has gone quiet
+        let mut notifications = tokio_stream::iter([
+            notification(CHARACTERISTIC_SYSTEM_TX, &[0xAA, 0x55, 0x21, 0x01, 0x07]),
+            notification(CHARACTERISTIC_USER_TX, b"hello"),
+            notification(CHARACTERISTIC_SYSTEM_TX, &[0xAA, 0x55, 0x22, 0x01, 0x08]),
+        ])
+        .chain(tokio_stream::pending());
+        let mut packets = PacketRouter::new();
+        packets.push(vec![0xAA, 0x55, 0x23, 0x01, 0x09]);
+        let mut user = VecDeque::new();
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap();
+        runtime
+            .block_on(drain_notifications(
+                &mut notifications,
+                &mut packets,
+                &mut user,
+            ))
+            .unwrap();
+
+        assert_eq!(packets.len(), 0);
+        assert_eq!(user, b"hello");
+    }
+}
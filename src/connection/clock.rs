@@ -0,0 +1,68 @@
+//! The monotonic clock used to timestamp packets as they are received and to time transfers.
+//!
+//! Every timestamp and duration measured by this crate comes from [`now`]. By default the clock
+//! reads [`std::time::Instant`], which isn't available on every platform (such as
+//! `wasm32-unknown-unknown`). Those platforms supply their own clock with [`set_source`] before
+//! opening a connection.
+
+use std::{
+    ops::Add,
+    sync::OnceLock,
+    time::{self, Duration},
+};
+
+/// A function returning the monotonic time that has passed since an arbitrary, fixed point.
+///
+/// The time must never go backwards, and every call must measure from the same point.
+pub type ClockSource = fn() -> Duration;
+
+static SOURCE: OnceLock<ClockSource> = OnceLock::new();
+
+/// A point in time read from the crate's clock with [`now`].
+///
+/// Instants only mean something relative to each other, and only within the process that
+/// read them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(Duration);
+impl Instant {
+    /// Returns the time from `earlier` to this instant, or zero if `earlier` is later.
+    pub fn saturating_duration_since(&self, earlier: Instant) -> Duration {
+        self.0.saturating_sub(earlier.0)
+    }
+
+    /// Returns the time from `earlier` to this instant, or `None` if `earlier` is later.
+    pub fn checked_duration_since(&self, earlier: Instant) -> Option<Duration> {
+        self.0.checked_sub(earlier.0)
+    }
+}
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        Instant(self.0 + duration)
+    }
+}
+
+/// Reads [`std::time::Instant`], measuring from the first time it's called.
+fn std_source() -> Duration {
+    static START: OnceLock<time::Instant> = OnceLock::new();
+    START.get_or_init(time::Instant::now).elapsed()
+}
+
+/// Replaces the clock that [`now`] reads.
+///
+/// This can only be done once, before the clock is first read. Otherwise the clock is left as
+/// it is and `source` is returned as the error.
+pub fn set_source(source: ClockSource) -> Result<(), ClockSource> {
+    SOURCE.set(source)
+}
+
+/// Returns the current time.
+pub fn now() -> Instant {
+    Instant(SOURCE.get_or_init(|| std_source)())
+}
+
+/// Returns the time that has passed since `earlier`, according to [`now`].
+pub fn since(earlier: Instant) -> Duration {
+    now().saturating_duration_since(earlier)
+}
@@ -1,19 +1,35 @@
 use crate::{
-    connection::{bluetooth, serial, Connection, ConnectionType},
+    commands::CommandError,
+    connection::{bluetooth, clock::Instant, serial, Connection, ConnectionType},
     decode::{Decode, DecodeError},
     encode::{Encode, EncodeError},
     packets::cdc2::Cdc2Ack,
 };
 use futures::{try_join, TryFutureExt};
-use std::time::Duration;
+use std::{future::Future, time::Duration};
 use thiserror::Error;
 
-use super::{bluetooth::BluetoothError, serial::SerialError, CheckHeader};
+use super::{
+    bluetooth::BluetoothError, handshake_matched, receive_matched, serial::SerialError,
+    CheckHeader, ConnectionStats, HandshakeError, MatchReceive, Matcher, TransferState,
+};
 
 pub enum GenericConnection {
     Bluetooth(bluetooth::BluetoothConnection),
     Serial(serial::SerialConnection),
 }
+impl MatchReceive for GenericConnection {
+    async fn receive_matching(
+        &mut self,
+        timeout: Duration,
+        matcher: &mut Matcher<'_>,
+    ) -> Result<Instant, GenericError> {
+        Ok(match self {
+            GenericConnection::Bluetooth(c) => c.receive_matching(timeout, matcher).await?,
+            GenericConnection::Serial(s) => s.receive_matching(timeout, matcher).await?,
+        })
+    }
+}
 impl Connection for GenericConnection {
     type Error = GenericError;
 
@@ -32,14 +48,129 @@ impl Connection for GenericConnection {
         Ok(())
     }
 
+    async fn send_batch<P: Encode>(&mut self, packets: &[P]) -> Result<(), GenericError> {
+        match self {
+            GenericConnection::Bluetooth(c) => c.send_batch(packets).await?,
+            GenericConnection::Serial(s) => s.send_batch(packets).await?,
+        };
+        Ok(())
+    }
+
+    fn set_send_pacing(&mut self, pacing: Option<Duration>) {
+        match self {
+            GenericConnection::Bluetooth(c) => c.set_send_pacing(pacing),
+            GenericConnection::Serial(s) => s.set_send_pacing(pacing),
+        }
+    }
+
+    fn send_pacing(&self) -> Option<Duration> {
+        match self {
+            GenericConnection::Bluetooth(c) => c.send_pacing(),
+            GenericConnection::Serial(s) => s.send_pacing(),
+        }
+    }
+
+    async fn sleep(&mut self, duration: Duration) {
+        match self {
+            GenericConnection::Bluetooth(c) => c.sleep(duration).await,
+            GenericConnection::Serial(s) => s.sleep(duration).await,
+        }
+    }
+
+    fn set_active_transfer(&mut self, transfer: Option<TransferState>) {
+        match self {
+            GenericConnection::Bluetooth(c) => c.set_active_transfer(transfer),
+            GenericConnection::Serial(s) => s.set_active_transfer(transfer),
+        }
+    }
+
+    fn active_transfer(&self) -> Option<&TransferState> {
+        match self {
+            GenericConnection::Bluetooth(c) => c.active_transfer(),
+            GenericConnection::Serial(s) => s.active_transfer(),
+        }
+    }
+
+    fn set_read_chunk_size(&mut self, size: u16) {
+        match self {
+            GenericConnection::Bluetooth(c) => c.set_read_chunk_size(size),
+            GenericConnection::Serial(s) => s.set_read_chunk_size(size),
+        }
+    }
+
+    fn read_chunk_size(&self) -> Option<u16> {
+        match self {
+            GenericConnection::Bluetooth(c) => c.read_chunk_size(),
+            GenericConnection::Serial(s) => s.read_chunk_size(),
+        }
+    }
+
+    fn set_timeout_scale(&mut self, scale: f64) {
+        match self {
+            GenericConnection::Bluetooth(c) => c.set_timeout_scale(scale),
+            GenericConnection::Serial(s) => s.set_timeout_scale(scale),
+        }
+    }
+
+    fn timeout_scale(&self) -> f64 {
+        match self {
+            GenericConnection::Bluetooth(c) => c.timeout_scale(),
+            GenericConnection::Serial(s) => s.timeout_scale(),
+        }
+    }
+
+    fn record_handshake(&mut self, failed_attempts: usize, succeeded: bool) {
+        match self {
+            GenericConnection::Bluetooth(c) => c.record_handshake(failed_attempts, succeeded),
+            GenericConnection::Serial(s) => s.record_handshake(failed_attempts, succeeded),
+        }
+    }
+
+    fn record_round_trip(&mut self, round_trip: Duration) {
+        match self {
+            GenericConnection::Bluetooth(c) => c.record_round_trip(round_trip),
+            GenericConnection::Serial(s) => s.record_round_trip(round_trip),
+        }
+    }
+
+    fn stats(&self) -> ConnectionStats {
+        match self {
+            GenericConnection::Bluetooth(c) => c.stats(),
+            GenericConnection::Serial(s) => s.stats(),
+        }
+    }
+
     async fn receive_packet<P: Decode + CheckHeader>(
         &mut self,
         timeout: std::time::Duration,
     ) -> Result<P, GenericError> {
-        Ok(match self {
-            GenericConnection::Bluetooth(c) => c.receive_packet(timeout).await?,
-            GenericConnection::Serial(s) => s.receive_packet(timeout).await?,
-        })
+        receive_matched(self, timeout)
+            .await
+            .map(|(packet, _)| packet)
+    }
+
+    fn receive_packet_timed<P: Decode + CheckHeader>(
+        &mut self,
+        timeout: Duration,
+    ) -> impl Future<Output = Result<(P, Instant), GenericError>> {
+        receive_matched(self, timeout)
+    }
+
+    fn packet_handshake<D: Decode + CheckHeader>(
+        &mut self,
+        timeout: Duration,
+        retries: usize,
+        packet: impl Encode + Clone,
+    ) -> impl Future<Output = Result<D, GenericError>> {
+        handshake_matched(self, timeout, retries, packet)
+    }
+
+    async fn flush_incoming(&mut self) -> Result<(), GenericError> {
+        match self {
+            GenericConnection::Bluetooth(c) => c.flush_incoming().await?,
+            GenericConnection::Serial(s) => s.flush_incoming().await?,
+        };
+        Ok(())
     }
 
     async fn read_user(&mut self, buf: &mut [u8]) -> Result<usize, GenericError> {
@@ -119,7 +250,7 @@ pub enum GenericDevice {
 impl GenericDevice {
     pub async fn connect(&self, timeout: Duration) -> Result<GenericConnection, GenericError> {
         match self.clone() {
-            GenericDevice::Bluetooth(d) => Ok(GenericConnection::Bluetooth(d.connect().await?)),
+            GenericDevice::Bluetooth(d) => Ok(GenericConnection::Bluetooth(d.connect(timeout).await?)),
             GenericDevice::Serial(d) => Ok(GenericConnection::Serial(d.connect(timeout)?)),
         }
     }
@@ -140,7 +271,7 @@ pub async fn find_devices() -> Result<Vec<GenericDevice>, GenericError> {
         bluetooth_devices().map_err(GenericError::BluetoothError),
         serial_devices().map_err(GenericError::SerialError),
     }
-    .map(|(bluetooth, serial)| bluetooth.into_iter().chain(serial.into_iter()).collect())?;
+    .map(|(bluetooth, serial)| bluetooth.into_iter().chain(serial).collect())?;
     Ok(res)
 }
 
@@ -169,6 +300,10 @@ pub enum GenericError {
     DecodeError(#[from] DecodeError),
     #[error("NACK received: {0:?}")]
     Nack(#[from] Cdc2Ack),
+    #[error("Command error: {0}")]
+    CommandError(#[from] CommandError),
+    #[error("{0}")]
+    HandshakeError(#[from] HandshakeError),
     #[error("Pairing is not supported over any connection other than Bluetooth")]
     PairingNotSupported,
 }
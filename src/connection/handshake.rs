@@ -0,0 +1,61 @@
+//! The retry loop shared by every implementation of [`Connection::packet_handshake`].
+
+use log::{error, warn};
+
+use super::{
+    clock::{self, Instant},
+    Connection, HandshakeError, HandshakeFailure, HandshakeStage, HANDSHAKE_FLUSH_THRESHOLD,
+};
+
+/// Sends `encoded` until `receive` succeeds, as described on [`Connection::packet_handshake`].
+///
+/// `receive` returns the reply along with the time that it arrived, which is used to record the
+/// round trip. `reply` names the expected reply in logs and in the returned [`HandshakeError`].
+pub(crate) async fn handshake<C: Connection + ?Sized, T>(
+    connection: &mut C,
+    retries: usize,
+    encoded: &[u8],
+    reply: &'static str,
+    mut receive: impl AsyncFnMut(&mut C) -> Result<(T, Instant), C::Error>,
+) -> Result<T, C::Error> {
+    let mut failures = Vec::new();
+
+    for attempt in 0..=retries {
+        if attempt >= HANDSHAKE_FLUSH_THRESHOLD {
+            connection.flush_incoming().await?;
+        }
+
+        let sent = clock::now();
+        let (stage, error) = match connection.send_packet(encoded.to_vec()).await {
+            Ok(()) => match receive(connection).await {
+                Ok((received, arrived)) => {
+                    connection.record_handshake(attempt, true);
+                    // A reply buffered before the packet was sent isn't a round trip
+                    if let Some(round_trip) = arrived.checked_duration_since(sent) {
+                        connection.record_round_trip(round_trip);
+                    }
+                    return Ok(received);
+                }
+                Err(e) => (HandshakeStage::Receive, e),
+            },
+            Err(e) => (HandshakeStage::Send, e),
+        };
+        warn!(
+            "Handshake attempt {} for {} failed to {}: {:?}",
+            attempt + 1,
+            reply,
+            stage,
+            error
+        );
+        failures.push(HandshakeFailure {
+            attempt,
+            stage,
+            message: error.to_string(),
+        });
+    }
+
+    let error = HandshakeError { reply, failures };
+    connection.record_handshake(error.failures.len(), false);
+    error!("{error}");
+    Err(error.into())
+}
@@ -0,0 +1,108 @@
+//! Receiving replies without compiling the receive path once per reply type.
+//!
+//! Commands are generic over both the connection and the replies they wait for, so a receive
+//! loop written in terms of the reply type is compiled again for every reply on every
+//! connection. Connections implementing [`MatchReceive`] instead wait for a packet picked out
+//! by a [`Matcher`], which leaves only the small [`receive_matched`] and [`handshake_matched`]
+//! wrappers to be compiled per reply type.
+
+use std::{any::type_name, time::Duration};
+
+use super::{clock::Instant, handshake::handshake, CheckHeader, Connection};
+use crate::{
+    decode::{Decode, DecodeError},
+    encode::Encode,
+};
+
+/// The result of decoding a packet that a [`Matcher`] accepted.
+pub(crate) type DecodeOutcome = Result<(), DecodeError>;
+
+/// Picks out the packet that a receive is waiting for.
+///
+/// Returns `None` for packets meant for another receive. Otherwise the packet is decoded and
+/// kept by the matcher, and the outcome of decoding it is returned.
+pub(crate) type Matcher<'a> = dyn FnMut(&[u8]) -> Option<DecodeOutcome> + 'a;
+
+/// Returns a matcher that accepts packets with `P`'s header and decodes them into `slot`.
+pub(crate) fn decode_into<P: Decode + CheckHeader>(
+    slot: &mut Option<P>,
+) -> impl FnMut(&[u8]) -> Option<DecodeOutcome> + '_ {
+    move |bytes| {
+        if !P::has_valid_header(bytes.iter().copied()) {
+            return None;
+        }
+        Some(P::decode(bytes.iter().copied()).map(|packet| *slot = Some(packet)))
+    }
+}
+
+/// A connection that can wait for a packet picked out by a [`Matcher`].
+pub(crate) trait MatchReceive: Connection {
+    /// Receives the oldest packet that `matcher` accepts, returning the time that it arrived.
+    ///
+    /// A packet that the matcher accepts but fails to decode is still used up, and the decode
+    /// error is returned.
+    async fn receive_matching(
+        &mut self,
+        timeout: Duration,
+        matcher: &mut Matcher<'_>,
+    ) -> Result<Instant, Self::Error>;
+}
+
+/// Multiplies `timeout` by the connection's [`Connection::timeout_scale`].
+fn scaled_timeout<C: Connection + ?Sized>(connection: &C, timeout: Duration) -> Duration {
+    match connection.timeout_scale() {
+        1.0 => timeout,
+        scale => timeout.mul_f64(scale),
+    }
+}
+
+/// Implements [`Connection::receive_packet_timed`] with [`MatchReceive::receive_matching`].
+pub(crate) async fn receive_matched<C: MatchReceive + ?Sized, P: Decode + CheckHeader>(
+    connection: &mut C,
+    timeout: Duration,
+) -> Result<(P, Instant), C::Error> {
+    let timeout = scaled_timeout(connection, timeout);
+    let mut packet = None;
+    let received = connection
+        .receive_matching(timeout, &mut decode_into(&mut packet))
+        .await?;
+    Ok((packet.expect("Matched packets should be decoded"), received))
+}
+
+/// Implements [`Connection::packet_handshake`] with [`MatchReceive::receive_matching`].
+pub(crate) async fn handshake_matched<C: MatchReceive + ?Sized, D: Decode + CheckHeader>(
+    connection: &mut C,
+    timeout: Duration,
+    retries: usize,
+    packet: impl Encode,
+) -> Result<D, C::Error> {
+    let encoded = packet.encode()?;
+    let timeout = scaled_timeout(connection, timeout);
+    let mut reply = None;
+    handshake_matching(
+        connection,
+        timeout,
+        retries,
+        &encoded,
+        type_name::<D>(),
+        &mut decode_into(&mut reply),
+    )
+    .await?;
+    Ok(reply.expect("Matched packets should be decoded"))
+}
+
+/// The part of [`handshake_matched`] that doesn't depend on the reply type.
+async fn handshake_matching<C: MatchReceive + ?Sized>(
+    connection: &mut C,
+    timeout: Duration,
+    retries: usize,
+    encoded: &[u8],
+    reply: &'static str,
+    matcher: &mut Matcher<'_>,
+) -> Result<(), C::Error> {
+    handshake(connection, retries, encoded, reply, async |connection| {
+        Ok(((), connection.receive_matching(timeout, matcher).await?))
+    })
+    .await?;
+    Ok(())
+}
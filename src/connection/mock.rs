@@ -0,0 +1,316 @@
+//! A scripted [`Connection`] for testing commands without a brain attached.
+
+use std::{
+    collections::VecDeque,
+    fmt,
+    future::Future,
+    pin::pin,
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+
+use thiserror::Error;
+
+use super::{
+    clock::{self, Instant},
+    handshake_matched, receive_matched, CheckHeader, Connection, ConnectionStats, ConnectionType,
+    HandshakeError, MatchReceive, Matcher, TransferState,
+};
+use crate::{
+    commands::CommandError,
+    crc::{VEX_CRC16, VEX_CRC32},
+    decode::{Decode, DecodeError},
+    encode::{Encode, EncodeError},
+    packets::{cdc2::Cdc2Ack, HOST_BOUND_HEADER},
+    varint::VarU16,
+};
+
+#[derive(Error, Debug)]
+pub(crate) enum MockError {
+    #[error(transparent)]
+    Encode(#[from] EncodeError),
+    #[error(transparent)]
+    Decode(#[from] DecodeError),
+    #[error(transparent)]
+    Nack(#[from] Cdc2Ack),
+    #[error(transparent)]
+    Command(#[from] CommandError),
+    #[error(transparent)]
+    Handshake(#[from] HandshakeError),
+    #[error("Write failed")]
+    Send,
+    #[error("Timed out")]
+    Timeout,
+}
+
+/// A connection that answers each sent packet with the next frame in `replies`.
+///
+/// Once `replies` runs out, sent packets go unanswered and receives time out.
+/// The first `send_failures` sends fail without sending anything.
+#[derive(Debug, Default)]
+pub(crate) struct MockConnection {
+    /// Frames that have been received but not claimed yet.
+    pub incoming: Vec<Vec<u8>>,
+    /// Frames to reply with, in order.
+    pub replies: VecDeque<Vec<u8>>,
+    /// Every packet that has been sent.
+    pub sent: Vec<Vec<u8>>,
+    /// The number of packets in each [`Connection::send_batch`] call.
+    pub batches: Vec<usize>,
+    /// The reported connection type, or [`ConnectionType::Wired`] if unset.
+    pub connection_type: Option<ConnectionType>,
+    /// The number of upcoming sends that fail with [`MockError::Send`].
+    pub send_failures: usize,
+    pub active_transfer: Option<TransferState>,
+    pub read_chunk_size: Option<u16>,
+    /// The timeout scale, or 1 if unset.
+    pub timeout_scale: Option<f64>,
+    /// The timeout of every receive, after scaling.
+    pub timeouts: Vec<Duration>,
+    /// Every call to [`Connection::sleep`], which returns straight away.
+    pub sleeps: Vec<Duration>,
+    pub stats: ConnectionStats,
+    /// Computes the reply to each sent packet, in place of `replies`.
+    pub respond: Option<Responder>,
+}
+
+/// A function from a sent packet to the frame that the device replies with.
+pub(crate) struct Responder(pub Box<Respond>);
+type Respond = dyn FnMut(&[u8]) -> Vec<u8>;
+impl fmt::Debug for Responder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Responder")
+    }
+}
+
+impl MatchReceive for MockConnection {
+    async fn receive_matching(
+        &mut self,
+        timeout: Duration,
+        matcher: &mut Matcher<'_>,
+    ) -> Result<Instant, MockError> {
+        self.timeouts.push(timeout);
+        let (index, outcome) = self
+            .incoming
+            .iter()
+            .enumerate()
+            .find_map(|(index, frame)| Some((index, matcher(frame)?)))
+            .ok_or(MockError::Timeout)?;
+        self.incoming.remove(index);
+        outcome?;
+        Ok(clock::now())
+    }
+}
+
+impl Connection for MockConnection {
+    type Error = MockError;
+
+    fn connection_type(&self) -> ConnectionType {
+        self.connection_type.unwrap_or(ConnectionType::Wired)
+    }
+
+    fn set_active_transfer(&mut self, transfer: Option<TransferState>) {
+        self.active_transfer = transfer;
+    }
+
+    fn active_transfer(&self) -> Option<&TransferState> {
+        self.active_transfer.as_ref()
+    }
+
+    fn set_read_chunk_size(&mut self, size: u16) {
+        self.read_chunk_size = Some(size);
+    }
+
+    fn read_chunk_size(&self) -> Option<u16> {
+        self.read_chunk_size
+    }
+
+    fn set_timeout_scale(&mut self, scale: f64) {
+        self.timeout_scale = Some(scale);
+    }
+
+    fn timeout_scale(&self) -> f64 {
+        self.timeout_scale.unwrap_or(1.0)
+    }
+
+    fn record_handshake(&mut self, failed_attempts: usize, succeeded: bool) {
+        self.stats.record_handshake(failed_attempts, succeeded);
+    }
+
+    fn record_round_trip(&mut self, round_trip: Duration) {
+        self.stats.record_round_trip(round_trip);
+    }
+
+    fn stats(&self) -> ConnectionStats {
+        self.stats
+    }
+
+    async fn sleep(&mut self, duration: Duration) {
+        self.sleeps.push(duration);
+    }
+
+    async fn send_packet(&mut self, packet: impl Encode) -> Result<(), MockError> {
+        if self.send_failures > 0 {
+            self.send_failures -= 1;
+            return Err(MockError::Send);
+        }
+        let encoded = packet.encode()?;
+        match &mut self.respond {
+            Some(Responder(respond)) => self.incoming.push(respond(&encoded)),
+            None => self.incoming.extend(self.replies.pop_front()),
+        }
+        self.sent.push(encoded);
+        Ok(())
+    }
+
+    async fn send_batch<P: Encode>(&mut self, packets: &[P]) -> Result<(), MockError> {
+        self.batches.push(packets.len());
+        for packet in packets {
+            self.send_packet(packet.encode()?).await?;
+        }
+        Ok(())
+    }
+
+    async fn receive_packet<P: Decode + CheckHeader>(
+        &mut self,
+        timeout: Duration,
+    ) -> Result<P, MockError> {
+        receive_matched(self, timeout)
+            .await
+            .map(|(packet, _)| packet)
+    }
+
+    fn packet_handshake<D: Decode + CheckHeader>(
+        &mut self,
+        timeout: Duration,
+        retries: usize,
+        packet: impl Encode + Clone,
+    ) -> impl Future<Output = Result<D, MockError>> {
+        handshake_matched(self, timeout, retries, packet)
+    }
+
+    async fn flush_incoming(&mut self) -> Result<(), MockError> {
+        self.incoming.clear();
+        Ok(())
+    }
+
+    async fn read_user(&mut self, _buf: &mut [u8]) -> Result<usize, MockError> {
+        Ok(0)
+    }
+
+    async fn write_user(&mut self, buf: &[u8]) -> Result<usize, MockError> {
+        Ok(buf.len())
+    }
+}
+
+/// Polls a future that never waits to completion.
+pub(crate) fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    let mut cx = Context::from_waker(Waker::noop());
+    match future.as_mut().poll(&mut cx) {
+        Poll::Ready(output) => output,
+        Poll::Pending => panic!("Mock connection futures should never wait"),
+    }
+}
+
+/// Builds a CDC2 reply frame for the extended command `ext_id` whose payload is `body`.
+fn cdc2_frame(ext_id: u8, body: &[u8]) -> Vec<u8> {
+    let mut frame = HOST_BOUND_HEADER.to_vec();
+    frame.push(0x56);
+    // The size counts the extended command ID and the CRC
+    let size = VarU16::try_from_len(body.len() + 3).expect("Mock reply is too long");
+    frame.extend(size.encode().unwrap());
+    frame.push(ext_id);
+    frame.extend(body);
+    frame.extend(VEX_CRC16.checksum(&frame).to_be_bytes());
+    frame
+}
+
+/// Builds a CDC2 reply frame for the extended command `ext_id`, answered with `ack`.
+///
+/// Every other reply built here goes through this, and each one ends with a valid CRC16.
+pub(crate) fn cdc2_reply_with(ext_id: u8, ack: Cdc2Ack, payload: &[u8]) -> Vec<u8> {
+    let mut body = vec![ack.value()];
+    body.extend(payload);
+    cdc2_frame(ext_id, &body)
+}
+
+/// Builds an acknowledged CDC2 reply frame for the extended command `ext_id`.
+pub(crate) fn cdc2_reply(ext_id: u8, payload: &[u8]) -> Vec<u8> {
+    cdc2_reply_with(ext_id, Cdc2Ack::Ack, payload)
+}
+
+/// Builds an `InitFileTransferReplyPacket` frame.
+pub(crate) fn init_transfer_reply(window_size: u16, file_size: u32) -> Vec<u8> {
+    let mut payload = window_size.to_le_bytes().to_vec();
+    payload.extend(file_size.to_le_bytes());
+    payload.extend([0x12, 0x34, 0x56, 0x78]);
+    cdc2_reply(0x11, &payload)
+}
+
+/// Builds a `ReadFileReplyPacket` frame returning `data` read from `address`.
+///
+/// Successful reads start with the address rather than an ack.
+pub(crate) fn read_reply(address: u32, data: &[u8]) -> Vec<u8> {
+    let mut body = address.to_le_bytes().to_vec();
+    body.extend(data);
+    cdc2_frame(0x14, &body)
+}
+
+/// Builds a `ReadFileReplyPacket` frame rejecting a read with `nack`.
+pub(crate) fn read_nack(nack: Cdc2Ack) -> Vec<u8> {
+    cdc2_reply_with(0x14, nack, &[0xFF; 3])
+}
+
+/// Builds a `GetFileMetadataReplyPacket` frame for a user program file holding `data`.
+pub(crate) fn metadata_reply(data: &[u8]) -> Vec<u8> {
+    let mut payload = vec![0x00];
+    payload.extend((data.len() as u32).to_le_bytes());
+    payload.extend(0x0780_0000u32.to_le_bytes());
+    payload.extend(VEX_CRC32.checksum(data).to_le_bytes());
+    payload.extend(b"bin\0");
+    payload.extend([0; 8]);
+    cdc2_reply(0x19, &payload)
+}
+
+/// Builds a `GetDirectoryEntryReplyPacket` frame for a 4 byte file without metadata.
+pub(crate) fn entry_reply(file_index: u8, name: &str) -> Vec<u8> {
+    let mut payload = vec![file_index];
+    payload.extend(4u32.to_le_bytes());
+    payload.extend(0x0380_0000u32.to_le_bytes());
+    payload.extend(0u32.to_le_bytes());
+    // No metadata
+    payload.extend([0xFF; 12]);
+    let mut name = name.as_bytes().to_vec();
+    name.resize(24, 0);
+    payload.extend(name);
+    cdc2_reply(0x17, &payload)
+}
+
+/// Builds a `GetSystemFlagsReplyPacket` frame reporting `slot` as running.
+pub(crate) fn flags_reply(slot: u8) -> Vec<u8> {
+    cdc2_reply(0x20, &[0, 0, 0, 0, 0, 0, slot])
+}
+
+/// Builds a `GetSystemFlagsReplyPacket` frame reporting the battery at `percent`.
+pub(crate) fn battery_reply(percent: u8) -> Vec<u8> {
+    cdc2_reply(0x20, &[0, 0, 0, 0, (percent / 8) << 4, 0, 0])
+}
+
+/// Builds a `ReadKeyValueReplyPacket` frame holding `value`.
+pub(crate) fn kv_reply(value: &str) -> Vec<u8> {
+    cdc2_reply(0x2E, format!("{value}\0").as_bytes())
+}
+
+/// Builds a `UserFifoReplyPacket` frame answered with `ack`.
+pub(crate) fn fifo_reply(ack: Cdc2Ack) -> Vec<u8> {
+    cdc2_reply_with(0x27, ack, &[2])
+}
+
+/// Builds a `GetSystemVersionReplyPacket` frame for VEXos 1.1.4 on `product`.
+#[cfg(feature = "serial")]
+pub(crate) fn version_reply(product: u8) -> Vec<u8> {
+    vec![
+        0xAA, 0x55, 0xA4, 0x07, 0x01, 0x01, 0x04, 0x00, 0x00, product, 0x00,
+    ]
+}
@@ -1,75 +1,232 @@
 //! Implements functions and structures for interacting with vex devices.
 
-use std::{future::Future, time::Instant};
+use std::future::Future;
 
-use log::{error, trace, warn};
-use std::time::Duration;
+use std::{
+    fmt,
+    ops::{Deref, DerefMut},
+    time::Duration,
+};
+use thiserror::Error;
 
 use crate::{
-    commands::Command,
+    commands::{Command, CommandError},
+    connection::clock::Instant,
     decode::{Decode, DecodeError},
     encode::{Encode, EncodeError},
-    packets::cdc2::Cdc2Ack,
+    packets::{
+        cdc2::Cdc2Ack,
+        file::{FileInitAction, FileVendor},
+        CdcCommand,
+    },
 };
 
 #[cfg(feature = "bluetooth")]
 pub mod bluetooth;
-#[cfg(all(feature = "serial", feature = "bluetooth"))]
+pub mod clock;
+#[cfg(test)]
+pub(crate) mod mock;
+#[cfg(all(any(feature = "serial", feature = "smol-serial"), feature = "bluetooth"))]
 pub mod generic;
-#[cfg(feature = "serial")]
+mod handshake;
+#[cfg(any(test, feature = "serial", feature = "smol-serial", feature = "bluetooth"))]
+mod matching;
+#[cfg(any(feature = "serial", feature = "smol-serial", feature = "bluetooth"))]
+mod router;
+#[cfg(any(feature = "serial", feature = "smol-serial"))]
+mod runtime;
+#[cfg(any(feature = "serial", feature = "smol-serial"))]
 pub mod serial;
 
+#[cfg(any(test, feature = "serial", feature = "smol-serial", feature = "bluetooth"))]
+pub(crate) use matching::{handshake_matched, receive_matched, MatchReceive, Matcher};
+#[cfg(any(feature = "serial", feature = "smol-serial", feature = "bluetooth"))]
+pub(crate) use router::PacketRouter;
+
+/// The largest packet that can be sent over a Bluetooth connection.
+pub(crate) const BLUETOOTH_MAX_PACKET_SIZE: usize = 244;
+
+/// A file transfer that's open on the brain.
+///
+/// VEXos only supports one open transfer at a time, so transfer commands record theirs with
+/// [`Connection::set_active_transfer`] and refuse to start while another is open.
+///
+/// The guard only works on connections that implement [`Connection::set_active_transfer`] and
+/// [`Connection::active_transfer`]. With their default implementations, nothing is recorded
+/// and overlapping transfers aren't caught.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransferState {
+    pub operation: FileInitAction,
+    pub vendor: FileVendor,
+    /// The file being transferred, or an empty string for a memory read.
+    pub file_name: String,
+    pub size: u32,
+}
+
 pub trait CheckHeader {
     fn has_valid_header(data: impl IntoIterator<Item = u8>) -> bool;
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
-pub(crate) struct RawPacket {
-    bytes: Vec<u8>,
-    used: bool,
-    timestamp: Instant,
-}
-impl RawPacket {
-    pub fn new(bytes: Vec<u8>) -> Self {
-        Self {
-            bytes,
-            used: false,
-            timestamp: Instant::now(),
+/// The number of failed attempts after which [`Connection::packet_handshake`] starts
+/// flushing incoming packets before each retry.
+pub const HANDSHAKE_FLUSH_THRESHOLD: usize = 2;
+
+/// The step of a handshake attempt that failed.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum HandshakeStage {
+    /// The packet couldn't be sent.
+    Send,
+    /// The packet was sent, but no valid reply was received.
+    Receive,
+}
+impl fmt::Display for HandshakeStage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Send => "send",
+            Self::Receive => "receive",
+        })
+    }
+}
+
+/// A failed attempt in a [`HandshakeError`].
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct HandshakeFailure {
+    /// The zero-based index of the attempt.
+    pub attempt: usize,
+    pub stage: HandshakeStage,
+    /// The connection's error, formatted as text.
+    pub message: String,
+}
+
+/// Counts of how [`Connection::packet_handshake`] requests have gone on a connection.
+///
+/// An attempt fails when its packet can't be sent or no valid reply arrives in time, which
+/// covers lost packets as well as replies that failed their CRC check. NACKs are replies, so
+/// they don't count as failed attempts.
+///
+/// Round trips are timed from sending the attempt that got a reply to the time that the reply
+/// arrived, as returned by [`Connection::receive_packet_timed`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ConnectionStats {
+    /// Handshakes that got a reply, whether on the first attempt or after retrying.
+    pub handshakes: u64,
+    /// Attempts that failed, including every attempt of a handshake that gave up.
+    pub failed_attempts: u64,
+    /// Handshakes that gave up after every attempt failed.
+    pub failed_handshakes: u64,
+    /// Handshakes whose round trip was timed.
+    pub round_trips: u64,
+    /// The sum of every timed round trip.
+    pub round_trip_total: Duration,
+}
+impl ConnectionStats {
+    /// Returns the counts since `earlier` was taken from the same connection.
+    pub fn since(&self, earlier: &ConnectionStats) -> ConnectionStats {
+        ConnectionStats {
+            handshakes: self.handshakes.saturating_sub(earlier.handshakes),
+            failed_attempts: self.failed_attempts.saturating_sub(earlier.failed_attempts),
+            failed_handshakes: self
+                .failed_handshakes
+                .saturating_sub(earlier.failed_handshakes),
+            round_trips: self.round_trips.saturating_sub(earlier.round_trips),
+            round_trip_total: self
+                .round_trip_total
+                .saturating_sub(earlier.round_trip_total),
         }
     }
 
-    pub fn is_obsolete(&self, timeout: Duration) -> bool {
-        self.timestamp.elapsed() > timeout || self.used
+    /// Returns the mean of the timed round trips, or `None` if none were timed.
+    pub fn average_round_trip(&self) -> Option<Duration> {
+        let count = u32::try_from(self.round_trips).ok().filter(|&n| n > 0)?;
+        Some(self.round_trip_total / count)
     }
 
-    pub fn check_header<H: CheckHeader>(&self) -> bool {
-        H::has_valid_header(self.bytes.clone())
+    /// Adds a handshake, for connections implementing [`Connection::record_handshake`].
+    pub fn record_handshake(&mut self, failed_attempts: usize, succeeded: bool) {
+        self.failed_attempts += failed_attempts as u64;
+        if succeeded {
+            self.handshakes += 1;
+        } else {
+            self.failed_handshakes += 1;
+        }
     }
 
-    /// Decodes the packet into the given type.
-    /// If successful, marks the packet as used.
-    /// # Note
-    /// This function will **NOT** fail if the packet has already been used.
-    pub fn decode_and_use<D: Decode>(&mut self) -> Result<D, DecodeError> {
-        let decoded = D::decode(self.bytes.clone())?;
-        self.used = true;
-        Ok(decoded)
+    /// Adds a round trip, for connections implementing [`Connection::record_round_trip`].
+    pub fn record_round_trip(&mut self, round_trip: Duration) {
+        self.round_trips += 1;
+        self.round_trip_total += round_trip;
     }
 }
-/// Removes old and used packets from the incoming packets buffer.
-pub(crate) fn trim_packets(packets: &mut Vec<RawPacket>) {
-    trace!("Trimming packets. Length before: {}", packets.len());
 
-    // Remove packets that are obsolete
-    packets.retain(|packet| !packet.is_obsolete(Duration::from_secs(2)));
+/// A connection whose timeouts are scaled until this is dropped, returned by
+/// [`Connection::scale_timeouts`].
+///
+/// This dereferences to the connection, so commands can be run through it. Dropping it puts
+/// back the scale that the connection had before.
+#[derive(Debug)]
+pub struct TimeoutScale<'a, C: Connection + ?Sized> {
+    connection: &'a mut C,
+    previous: f64,
+}
+impl<C: Connection + ?Sized> Deref for TimeoutScale<'_, C> {
+    type Target = C;
+
+    fn deref(&self) -> &C {
+        self.connection
+    }
+}
+impl<C: Connection + ?Sized> DerefMut for TimeoutScale<'_, C> {
+    fn deref_mut(&mut self) -> &mut C {
+        self.connection
+    }
+}
+impl<C: Connection + ?Sized> Drop for TimeoutScale<'_, C> {
+    fn drop(&mut self) {
+        self.connection.set_timeout_scale(self.previous);
+    }
+}
 
-    trace!("Trimmed packets. Length after: {}", packets.len());
+/// Every attempt of [`Connection::packet_handshake`] failed.
+#[derive(Error, Debug, Clone, Eq, PartialEq)]
+pub struct HandshakeError {
+    /// The type name of the reply that was expected.
+    pub reply: &'static str,
+    /// The failures in the order the attempts were made.
+    pub failures: Vec<HandshakeFailure>,
+}
+impl fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Handshake for {} failed after {} attempts",
+            self.reply,
+            self.failures.len()
+        )?;
+        for failure in &self.failures {
+            write!(
+                f,
+                "; attempt {} {}: {}",
+                failure.attempt + 1,
+                failure.stage,
+                failure.message
+            )?;
+        }
+        Ok(())
+    }
 }
 
 /// Represents an open connection to a V5 peripheral.
 #[allow(async_fn_in_trait)]
 pub trait Connection {
-    type Error: std::error::Error + From<EncodeError> + From<DecodeError> + From<Cdc2Ack>;
+    type Error: std::error::Error
+        + Send
+        + Sync
+        + 'static
+        + From<EncodeError>
+        + From<DecodeError>
+        + From<Cdc2Ack>
+        + From<CommandError>
+        + From<HandshakeError>;
 
     fn connection_type(&self) -> ConnectionType;
 
@@ -77,16 +234,164 @@ pub trait Connection {
     fn send_packet(&mut self, packet: impl Encode)
         -> impl Future<Output = Result<(), Self::Error>>;
 
+    /// Sends several packets, in order.
+    ///
+    /// Connections that can send the packets in a single write override this, so a sequence
+    /// of small packets doesn't wait for a USB frame per packet. The default implementation
+    /// sends each packet with [`Connection::send_packet`].
+    ///
+    /// Packets of different types can be sent together by encoding them first, since encoded
+    /// packets are sent as they are.
+    async fn send_batch<P: Encode>(&mut self, packets: &[P]) -> Result<(), Self::Error> {
+        for packet in packets {
+            self.send_packet(packet.encode()?).await?;
+        }
+        Ok(())
+    }
+
     /// Receives a packet.
     fn receive_packet<P: Decode + CheckHeader>(
         &mut self,
         timeout: Duration,
     ) -> impl Future<Output = Result<P, Self::Error>>;
 
+    /// Receives a packet along with the time that it arrived.
+    ///
+    /// The default implementation can only timestamp the packet after it has been received
+    /// and decoded. Connections that buffer incoming packets should override this to return
+    /// the time that the packet's bytes were actually read.
+    async fn receive_packet_timed<P: Decode + CheckHeader>(
+        &mut self,
+        timeout: Duration,
+    ) -> Result<(P, Instant), Self::Error> {
+        let packet = self.receive_packet::<P>(timeout).await?;
+        Ok((packet, clock::now()))
+    }
+
+    /// Sets the minimum time between sent packets on wireless links, or `None` to send
+    /// packets as fast as possible.
+    ///
+    /// Sending packets back to back over a controller's radio link starves the joystick data
+    /// sharing it, making the controller lag. Connections that don't relay packets over a
+    /// radio ignore this.
+    fn set_send_pacing(&mut self, _pacing: Option<Duration>) {}
+
+    /// Returns the minimum time between sent packets, if pacing is enabled.
+    fn send_pacing(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Waits for `duration` to pass.
+    ///
+    /// Commands call this between polls of the brain, such as while waiting for a background
+    /// erase to finish. Connections that can't wait return straight away, which makes those
+    /// commands give up after the same number of polls but much sooner.
+    async fn sleep(&mut self, _duration: Duration) {}
+
+    /// Records the file transfer that has been opened, or `None` once it has finished.
+    ///
+    /// Connections that don't track transfers ignore this, and never report one as active.
+    fn set_active_transfer(&mut self, _transfer: Option<TransferState>) {}
+
+    /// Returns the file transfer that's currently open, if any.
+    fn active_transfer(&self) -> Option<&TransferState> {
+        None
+    }
+
+    /// Records the chunk size that file reads settled on after the brain didn't report one.
+    ///
+    /// Connections that don't remember it ignore this, so each read negotiates it again.
+    fn set_read_chunk_size(&mut self, _size: u16) {}
+
+    /// Returns the chunk size recorded with [`Connection::set_read_chunk_size`], if any.
+    fn read_chunk_size(&self) -> Option<u16> {
+        None
+    }
+
+    /// Sets the factor that receive timeouts are multiplied by, which must be positive.
+    ///
+    /// This is for links that are slow for a while, such as a brain that has just rebooted.
+    /// [`Connection::with_timeout_scale`] and [`Connection::scale_timeouts`] set it for a
+    /// block of commands and put back the previous factor afterwards. Connections that don't
+    /// support scaling ignore this.
+    fn set_timeout_scale(&mut self, _scale: f64) {}
+
+    /// Returns the factor set with [`Connection::set_timeout_scale`], which defaults to 1.
+    fn timeout_scale(&self) -> f64 {
+        1.0
+    }
+
+    /// Scales receive timeouts by `scale` until the returned guard is dropped.
+    ///
+    /// The scale replaces the current one rather than multiplying it, and the current one is
+    /// put back when the guard is dropped, so scales can be nested.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `scale` isn't positive and finite.
+    fn scale_timeouts(&mut self, scale: f64) -> TimeoutScale<'_, Self> {
+        assert!(
+            scale.is_finite() && scale > 0.0,
+            "Timeout scale must be positive and finite, got {scale}"
+        );
+        let previous = self.timeout_scale();
+        self.set_timeout_scale(scale);
+        TimeoutScale {
+            connection: self,
+            previous,
+        }
+    }
+
+    /// Runs `f` with receive timeouts scaled by `scale`, as described on
+    /// [`Connection::scale_timeouts`].
+    ///
+    /// The previous scale is put back however `f` finishes, including when it returns an error
+    /// or the returned future is dropped before finishing.
+    async fn with_timeout_scale<T>(
+        &mut self,
+        scale: f64,
+        f: impl AsyncFnOnce(&mut Self) -> T,
+    ) -> T {
+        let mut scaled = self.scale_timeouts(scale);
+        f(&mut scaled).await
+    }
+
+    /// Records how a [`Connection::packet_handshake`] went, after `failed_attempts` attempts
+    /// failed.
+    ///
+    /// Connections that don't keep statistics ignore this, and report zeroes from
+    /// [`Connection::stats`].
+    fn record_handshake(&mut self, _failed_attempts: usize, _succeeded: bool) {}
+
+    /// Records the round trip time of a [`Connection::packet_handshake`] that got a reply.
+    ///
+    /// Connections that don't keep statistics ignore this.
+    fn record_round_trip(&mut self, _round_trip: Duration) {}
+
+    /// Returns the statistics recorded with [`Connection::record_handshake`] and
+    /// [`Connection::record_round_trip`].
+    fn stats(&self) -> ConnectionStats {
+        ConnectionStats::default()
+    }
+
+    /// Discards any packets that have been received but not yet used.
+    ///
+    /// This should be called before starting a sequence of packets that can't tolerate a
+    /// stale reply from an earlier, timed out request being matched in place of a new one.
+    ///
+    /// The default implementation does nothing, which suits connections that don't buffer
+    /// incoming packets.
+    async fn flush_incoming(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
     /// Read user program output.
     fn read_user(&mut self, buf: &mut [u8]) -> impl Future<Output = Result<usize, Self::Error>>;
 
     /// Write to user program stdio.
+    ///
+    /// Like [`std::io::Write::write`], this may write only part of `buf` and returns the
+    /// number of bytes that were written.
     fn write_user(&mut self, buf: &[u8]) -> impl Future<Output = Result<usize, Self::Error>>;
 
     /// Executes a [`Command`].
@@ -96,8 +401,14 @@ pub trait Connection {
 
     /// Sends a packet and waits for a response.
     ///
-    /// This function will retry the handshake `retries` times
-    /// before giving up and erroring with the error thrown on the last retry.
+    /// The packet is sent up to `retries + 1` times, so a `retries` of zero makes exactly
+    /// one attempt. Failing to send the packet and failing to receive a reply both use up
+    /// an attempt. If every attempt fails, the returned [`HandshakeError`] lists what went
+    /// wrong in each of them.
+    ///
+    /// After [`HANDSHAKE_FLUSH_THRESHOLD`] failed attempts, incoming packets are flushed
+    /// before each retry so that late replies to earlier attempts can't be mistaken for
+    /// the current one.
     ///
     /// # Note
     ///
@@ -108,27 +419,28 @@ pub trait Connection {
         retries: usize,
         packet: impl Encode + Clone,
     ) -> Result<D, Self::Error> {
-        let mut last_error = None;
-
-        for _ in 0..retries {
-            self.send_packet(packet.clone()).await?;
-            match self.receive_packet::<D>(timeout).await {
-                Ok(decoded) => return Ok(decoded),
-                Err(e) => {
-                    warn!(
-                        "Handshake failed while waiting for {}: {:?}. Retrying...",
-                        std::any::type_name::<D>(),
-                        e
-                    );
-                    last_error = Some(e);
-                }
-            }
-        }
-        error!(
-            "Handshake failed after {} retries with error: {:?}",
-            retries, last_error
-        );
-        Err(last_error.unwrap())
+        let encoded = packet.encode()?;
+        handshake::handshake(
+            self,
+            retries,
+            &encoded,
+            std::any::type_name::<D>(),
+            async |connection| connection.receive_packet_timed::<D>(timeout).await,
+        )
+        .await
+    }
+
+    /// Sends a packet and waits for its [`CdcCommand::Reply`].
+    ///
+    /// This behaves exactly like [`Connection::packet_handshake`], except that the
+    /// reply type is inferred from the packet being sent.
+    fn request<P: CdcCommand + Encode + Clone>(
+        &mut self,
+        timeout: Duration,
+        retries: usize,
+        packet: P,
+    ) -> impl Future<Output = Result<P::Reply, Self::Error>> {
+        self.packet_handshake::<P::Reply>(timeout, retries, packet)
     }
 }
 
@@ -149,3 +461,205 @@ impl ConnectionType {
         matches!(self, ConnectionType::Bluetooth)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{
+        mock::{block_on, init_transfer_reply, MockConnection, MockError},
+        CheckHeader, Connection, ConnectionType, HandshakeError, HandshakeFailure, HandshakeStage,
+    };
+    use crate::{decode::Decode, encode::Encode, packets::file::InitFileTransferReplyPacket};
+
+    fn handshake_window_size(connection: &mut MockConnection) -> u16 {
+        block_on(connection.packet_handshake::<InitFileTransferReplyPacket>(
+            Duration::from_millis(500),
+            0,
+            (),
+        ))
+        .unwrap()
+        .try_into_inner()
+        .unwrap()
+        .window_size
+    }
+
+    #[test]
+    fn flush_discards_stale_reply() {
+        let mut connection = MockConnection {
+            incoming: vec![init_transfer_reply(64, 0x300000)],
+            replies: [
+                init_transfer_reply(4096, 0x300000),
+                init_transfer_reply(4096, 0x300000),
+            ]
+            .into(),
+            ..Default::default()
+        };
+        assert_eq!(handshake_window_size(&mut connection), 64);
+
+        connection.incoming.push(init_transfer_reply(64, 0x300000));
+        block_on(connection.flush_incoming()).unwrap();
+        assert_eq!(handshake_window_size(&mut connection), 4096);
+    }
+
+    #[test]
+    fn round_trips_are_timed() {
+        let mut connection = MockConnection {
+            replies: [
+                init_transfer_reply(4096, 0x300000),
+                init_transfer_reply(4096, 0x300000),
+            ]
+            .into(),
+            ..Default::default()
+        };
+        handshake_window_size(&mut connection);
+        handshake_window_size(&mut connection);
+
+        let stats = connection.stats();
+        assert_eq!(stats.handshakes, 2);
+        assert_eq!(stats.round_trips, 2);
+        assert!(stats.average_round_trip().unwrap() <= stats.round_trip_total);
+        assert_eq!(stats.since(&stats).average_round_trip(), None);
+    }
+
+    fn handshake_failures(
+        connection: &mut impl Connection<Error = MockError>,
+        retries: usize,
+    ) -> Vec<(usize, HandshakeStage)> {
+        let result = block_on(connection.packet_handshake::<InitFileTransferReplyPacket>(
+            Duration::from_millis(500),
+            retries,
+            (),
+        ));
+        let Err(MockError::Handshake(HandshakeError { failures, .. })) = result else {
+            panic!("Expected a handshake error, got {result:?}");
+        };
+        failures
+            .into_iter()
+            .map(|HandshakeFailure { attempt, stage, .. }| (attempt, stage))
+            .collect()
+    }
+
+    #[test]
+    fn handshake_without_retries() {
+        let mut connection = MockConnection::default();
+        assert_eq!(
+            handshake_failures(&mut connection, 0),
+            [(0, HandshakeStage::Receive)]
+        );
+        assert_eq!(connection.sent.len(), 1);
+    }
+
+    #[test]
+    fn handshake_retries_send_failures() {
+        let mut connection = MockConnection {
+            replies: [init_transfer_reply(4096, 0x300000)].into(),
+            send_failures: 2,
+            ..Default::default()
+        };
+        block_on(connection.packet_handshake::<InitFileTransferReplyPacket>(
+            Duration::from_millis(500),
+            2,
+            (),
+        ))
+        .unwrap();
+        assert_eq!(connection.sent.len(), 1);
+
+        connection.send_failures = 1;
+        assert_eq!(
+            handshake_failures(&mut connection, 2),
+            [
+                (0, HandshakeStage::Send),
+                (1, HandshakeStage::Receive),
+                (2, HandshakeStage::Receive),
+            ]
+        );
+        assert_eq!(connection.sent.len(), 3);
+    }
+
+    #[test]
+    fn scaled_timeouts_restored() {
+        let mut connection = MockConnection::default();
+        let result = block_on(connection.with_timeout_scale(3.0, async |connection| {
+            let inner = connection
+                .with_timeout_scale(2.0, async |connection| handshake_failures(connection, 0))
+                .await;
+            assert_eq!(inner.len(), 1);
+            assert_eq!(connection.timeout_scale(), 3.0);
+            connection
+                .packet_handshake::<InitFileTransferReplyPacket>(Duration::from_millis(500), 0, ())
+                .await
+        }));
+        assert!(result.is_err());
+        assert_eq!(connection.timeout_scale(), 1.0);
+        assert_eq!(
+            connection.timeouts,
+            [Duration::from_millis(1000), Duration::from_millis(1500)]
+        );
+
+        // The guard puts the scale back when it's dropped
+        let mut scaled = connection.scale_timeouts(0.5);
+        handshake_failures(&mut *scaled, 0);
+        drop(scaled);
+        assert_eq!(connection.timeout_scale(), 1.0);
+        assert_eq!(connection.timeouts[2], Duration::from_millis(250));
+    }
+
+    /// A connection that only implements the required methods, so it uses the default
+    /// [`Connection::packet_handshake`].
+    struct Unmatched(MockConnection);
+    impl Connection for Unmatched {
+        type Error = MockError;
+
+        fn connection_type(&self) -> ConnectionType {
+            self.0.connection_type()
+        }
+
+        async fn send_packet(&mut self, packet: impl Encode) -> Result<(), MockError> {
+            self.0.send_packet(packet).await
+        }
+
+        async fn receive_packet<P: Decode + CheckHeader>(
+            &mut self,
+            timeout: Duration,
+        ) -> Result<P, MockError> {
+            self.0.receive_packet(timeout).await
+        }
+
+        async fn flush_incoming(&mut self) -> Result<(), MockError> {
+            self.0.flush_incoming().await
+        }
+
+        async fn read_user(&mut self, buf: &mut [u8]) -> Result<usize, MockError> {
+            self.0.read_user(buf).await
+        }
+
+        async fn write_user(&mut self, buf: &[u8]) -> Result<usize, MockError> {
+            self.0.write_user(buf).await
+        }
+    }
+
+    #[test]
+    fn default_handshake() {
+        let script = || MockConnection {
+            replies: [Vec::new(), Vec::new(), init_transfer_reply(4096, 0x300000)].into(),
+            send_failures: 1,
+            ..Default::default()
+        };
+        let mut matched = script();
+        let mut unmatched = Unmatched(script());
+        assert_eq!(
+            handshake_failures(&mut unmatched, 2),
+            handshake_failures(&mut matched, 2)
+        );
+        assert_eq!(unmatched.0.sent, matched.sent);
+
+        unmatched.0.replies = [init_transfer_reply(4096, 0x300000)].into();
+        block_on(unmatched.packet_handshake::<InitFileTransferReplyPacket>(
+            Duration::from_millis(500),
+            0,
+            (),
+        ))
+        .unwrap();
+    }
+}
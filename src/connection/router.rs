@@ -0,0 +1,173 @@
+//! Buffering and routing of received packets to the receives waiting on them.
+
+use std::{collections::VecDeque, time::Duration};
+
+use log::{error, trace};
+
+use super::{
+    clock::{self, Instant},
+    Matcher,
+};
+use crate::decode::DecodeError;
+
+/// How long an unclaimed packet is kept before it is dropped.
+const PACKET_LIFETIME: Duration = Duration::from_secs(2);
+
+/// A received packet that hasn't been claimed yet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct RawPacket {
+    bytes: Vec<u8>,
+    sequence: u64,
+    timestamp: Instant,
+}
+impl RawPacket {
+    fn is_obsolete(&self) -> bool {
+        clock::since(self.timestamp) > PACKET_LIFETIME
+    }
+}
+
+/// Holds received packets until a receive of the matching type claims them.
+///
+/// Packets are numbered in the order they arrive and always claimed oldest first, so replies of
+/// the same type are handed out in the order the device sent them. Claiming a packet removes it,
+/// so it can never be returned to a second receive.
+#[derive(Debug, Default)]
+pub(crate) struct PacketRouter {
+    packets: VecDeque<RawPacket>,
+    next_sequence: u64,
+}
+impl PacketRouter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a received packet to the back of the queue, dropping any obsolete ones.
+    pub fn push(&mut self, bytes: Vec<u8>) {
+        self.trim();
+        self.packets.push_back(RawPacket {
+            bytes,
+            sequence: self.next_sequence,
+            timestamp: clock::now(),
+        });
+        self.next_sequence += 1;
+    }
+
+    /// Claims the oldest packet that `matcher` accepts, returning the time it was received.
+    ///
+    /// Returns `None` if no such packet has been received. A packet that fails to decode is
+    /// still removed from the queue.
+    pub fn claim_matching(
+        &mut self,
+        matcher: &mut Matcher<'_>,
+    ) -> Option<Result<Instant, DecodeError>> {
+        let (index, outcome) = self
+            .packets
+            .iter()
+            .enumerate()
+            .find_map(|(index, packet)| Some((index, matcher(&packet.bytes)?)))?;
+        let packet = self.packets.remove(index)?;
+
+        trace!("Packet #{} claimed", packet.sequence);
+        Some(match outcome {
+            Ok(()) => Ok(packet.timestamp),
+            Err(e) => {
+                error!("Failed to decode packet with valid header: {}", e);
+                Err(e)
+            }
+        })
+    }
+
+    /// Claims the oldest packet with a header matching `P`, along with the time it was received.
+    #[cfg(test)]
+    pub fn claim<P: crate::decode::Decode + super::CheckHeader>(
+        &mut self,
+    ) -> Option<Result<(P, Instant), DecodeError>> {
+        let mut packet = None;
+        let claimed = self.claim_matching(&mut super::matching::decode_into(&mut packet))?;
+        Some(claimed.map(|timestamp| (packet.unwrap(), timestamp)))
+    }
+
+    /// Removes packets that have gone unclaimed for too long.
+    pub fn trim(&mut self) {
+        trace!("Trimming packets. Length before: {}", self.packets.len());
+        self.packets.retain(|packet| !packet.is_obsolete());
+        trace!("Trimmed packets. Length after: {}", self.packets.len());
+    }
+
+    /// Drops every unclaimed packet.
+    pub fn clear(&mut self) {
+        self.packets.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.packets.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PacketRouter;
+    use crate::{
+        connection::CheckHeader,
+        decode::{self, Decode, DecodeError},
+    };
+
+    /// A test packet made of a one byte type tag followed by a one byte value.
+    #[derive(Debug, PartialEq)]
+    struct Tagged<const TAG: u8>(u8);
+    impl<const TAG: u8> Decode for Tagged<TAG> {
+        fn decode(data: impl IntoIterator<Item = u8>) -> Result<Self, DecodeError> {
+            let [_, value] = decode::bytes(data)?;
+            Ok(Self(value))
+        }
+    }
+    impl<const TAG: u8> CheckHeader for Tagged<TAG> {
+        fn has_valid_header(data: impl IntoIterator<Item = u8>) -> bool {
+            data.into_iter().next() == Some(TAG)
+        }
+    }
+
+    fn claim<const TAG: u8>(router: &mut PacketRouter) -> Option<u8> {
+        router
+            .claim::<Tagged<TAG>>()
+            .map(|claimed| claimed.unwrap().0 .0)
+    }
+
+    #[test]
+    fn interleaved_waiters() {
+        let mut router = PacketRouter::new();
+        router.push(vec![1, 10]);
+        router.push(vec![2, 20]);
+        router.push(vec![1, 11]);
+        router.push(vec![2, 21]);
+
+        assert_eq!(claim::<2>(&mut router), Some(20));
+        assert_eq!(claim::<1>(&mut router), Some(10));
+        assert_eq!(claim::<1>(&mut router), Some(11));
+        assert_eq!(claim::<1>(&mut router), None);
+        assert_eq!(claim::<2>(&mut router), Some(21));
+        assert_eq!(router.len(), 0);
+    }
+
+    #[test]
+    fn claimed_packets_are_removed() {
+        let mut router = PacketRouter::new();
+        router.push(vec![1, 10]);
+
+        assert_eq!(claim::<1>(&mut router), Some(10));
+        assert_eq!(claim::<1>(&mut router), None);
+    }
+
+    #[test]
+    fn undecodable_packet_is_dropped() {
+        let mut router = PacketRouter::new();
+        router.push(vec![1]);
+        router.push(vec![1, 10]);
+
+        assert_eq!(
+            router.claim::<Tagged<1>>().unwrap(),
+            Err(DecodeError::PacketTooShort)
+        );
+        assert_eq!(claim::<1>(&mut router), Some(10));
+    }
+}
@@ -0,0 +1,275 @@
+//! The small set of async runtime primitives needed by the serial backend.
+//!
+//! With the `serial` feature these are provided by tokio. With only the `smol-serial` feature,
+//! timers come from `async-io`, so the backend can run on smol, async-std, or any other executor.
+//! On unix the port is put in non-blocking mode and registered with the `async-io` reactor. On
+//! windows, where that isn't possible, separate read and write handles are each driven by
+//! [`blocking::Unblock`] so that a pending read never holds up a write.
+
+use std::{future::Future, time::Duration};
+
+#[cfg(feature = "serial")]
+mod imp {
+    use std::{future::Future, time::Duration};
+
+    pub use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader};
+
+    /// An async serial port stream.
+    pub type SerialStream = tokio_serial::SerialStream;
+
+    pub fn open(builder: serialport::SerialPortBuilder) -> Result<SerialStream, serialport::Error> {
+        SerialStream::open(&builder)
+    }
+
+    pub async fn timeout<F: Future>(duration: Duration, future: F) -> Option<F::Output> {
+        tokio::time::timeout(duration, future).await.ok()
+    }
+
+    pub async fn sleep(duration: Duration) {
+        tokio::time::sleep(duration).await;
+    }
+}
+
+#[cfg(not(feature = "serial"))]
+mod imp {
+    use std::{future::Future, time::Duration};
+
+    pub use futures_lite::io::{AsyncReadExt, AsyncWriteExt, BufReader};
+
+    #[cfg(unix)]
+    pub use unix::{from_native, SerialStream};
+    #[cfg(windows)]
+    pub use windows::{from_native, SerialStream};
+
+    pub fn open(builder: serialport::SerialPortBuilder) -> Result<SerialStream, serialport::Error> {
+        Ok(from_native(builder.open_native()?)?)
+    }
+
+    #[cfg(unix)]
+    mod unix {
+        use std::{
+            io::{self, Read, Write},
+            os::fd::{AsFd, AsRawFd, BorrowedFd},
+            time::Duration,
+        };
+
+        use serialport::{SerialPort, TTYPort};
+
+        /// A serial port in non-blocking mode.
+        ///
+        /// Reads and writes that would have to wait report [`io::ErrorKind::WouldBlock`] rather
+        /// than timing out, so [`async_io::Async`] waits on the reactor instead.
+        #[derive(Debug)]
+        pub struct NonBlockingPort(TTYPort);
+
+        impl AsFd for NonBlockingPort {
+            fn as_fd(&self) -> BorrowedFd<'_> {
+                // SAFETY: The descriptor is owned by the `TTYPort`, which outlives the borrow.
+                unsafe { BorrowedFd::borrow_raw(self.0.as_raw_fd()) }
+            }
+        }
+
+        // SAFETY: `TTYPort`'s `Read` and `Write` implementations never close or replace its
+        // descriptor.
+        unsafe impl async_io::IoSafe for NonBlockingPort {}
+
+        fn would_block(error: io::Error) -> io::Error {
+            if error.kind() == io::ErrorKind::TimedOut {
+                io::ErrorKind::WouldBlock.into()
+            } else {
+                error
+            }
+        }
+
+        impl Read for NonBlockingPort {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                self.0.read(buf).map_err(would_block)
+            }
+        }
+
+        impl Write for NonBlockingPort {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.write(buf).map_err(would_block)
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                self.0.flush().map_err(would_block)
+            }
+        }
+
+        /// An async serial port stream.
+        pub type SerialStream = async_io::Async<NonBlockingPort>;
+
+        pub fn from_native(mut port: TTYPort) -> io::Result<SerialStream> {
+            // Readiness comes from the reactor, so the port itself should never wait.
+            port.set_timeout(Duration::ZERO)?;
+            async_io::Async::new(NonBlockingPort(port))
+        }
+    }
+
+    #[cfg(windows)]
+    mod windows {
+        use std::{
+            io::{self, Read},
+            pin::Pin,
+            sync::{
+                atomic::{AtomicBool, Ordering},
+                Arc,
+            },
+            task::{Context, Poll},
+        };
+
+        use blocking::Unblock;
+        use futures_lite::io::{AsyncRead, AsyncWrite};
+        use serialport::COMPort;
+
+        /// The read half of a port, which keeps waiting for data through the port's timeout
+        /// until the stream it belongs to is dropped.
+        #[derive(Debug)]
+        struct Reader {
+            port: COMPort,
+            closed: Arc<AtomicBool>,
+        }
+
+        impl Read for Reader {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                loop {
+                    match self.port.read(buf) {
+                        Err(error) if error.kind() == io::ErrorKind::TimedOut => {
+                            if self.closed.load(Ordering::Relaxed) {
+                                return Ok(0);
+                            }
+                        }
+                        result => return result,
+                    }
+                }
+            }
+        }
+
+        /// An async serial port stream.
+        #[derive(Debug)]
+        pub struct SerialStream {
+            reader: Unblock<Reader>,
+            writer: Unblock<COMPort>,
+            closed: Arc<AtomicBool>,
+        }
+
+        pub fn from_native(port: COMPort) -> io::Result<SerialStream> {
+            let closed = Arc::new(AtomicBool::new(false));
+            Ok(SerialStream {
+                reader: Unblock::new(Reader {
+                    port: port.try_clone_native()?,
+                    closed: closed.clone(),
+                }),
+                writer: Unblock::new(port),
+                closed,
+            })
+        }
+
+        impl Drop for SerialStream {
+            fn drop(&mut self) {
+                self.closed.store(true, Ordering::Relaxed);
+            }
+        }
+
+        impl AsyncRead for SerialStream {
+            fn poll_read(
+                mut self: Pin<&mut Self>,
+                cx: &mut Context<'_>,
+                buf: &mut [u8],
+            ) -> Poll<io::Result<usize>> {
+                Pin::new(&mut self.reader).poll_read(cx, buf)
+            }
+        }
+
+        impl AsyncWrite for SerialStream {
+            fn poll_write(
+                mut self: Pin<&mut Self>,
+                cx: &mut Context<'_>,
+                buf: &[u8],
+            ) -> Poll<io::Result<usize>> {
+                Pin::new(&mut self.writer).poll_write(cx, buf)
+            }
+
+            fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+                Pin::new(&mut self.writer).poll_flush(cx)
+            }
+
+            fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+                Pin::new(&mut self.writer).poll_close(cx)
+            }
+        }
+    }
+
+    pub async fn timeout<F: Future>(duration: Duration, future: F) -> Option<F::Output> {
+        futures_lite::future::or(async { Some(future.await) }, async {
+            async_io::Timer::after(duration).await;
+            None
+        })
+        .await
+    }
+
+    pub async fn sleep(duration: Duration) {
+        async_io::Timer::after(duration).await;
+    }
+}
+
+pub(crate) use imp::{open, AsyncReadExt, AsyncWriteExt, BufReader, SerialStream};
+
+/// Runs a future to completion, returning `None` if it takes longer than `duration`.
+pub(crate) async fn timeout<F: Future>(duration: Duration, future: F) -> Option<F::Output> {
+    imp::timeout(duration, future).await
+}
+
+/// Waits for `duration` to pass.
+pub(crate) async fn sleep(duration: Duration) {
+    imp::sleep(duration).await
+}
+
+/// Reads a single byte from a stream.
+pub(crate) async fn read_u8<R: AsyncReadExt + Unpin>(reader: &mut R) -> std::io::Result<u8> {
+    let mut byte = [0];
+    reader.read_exact(&mut byte).await?;
+    Ok(byte[0])
+}
+
+#[cfg(all(test, unix, not(feature = "serial")))]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        time::Duration,
+    };
+
+    use super::*;
+
+    #[test]
+    fn send_after_receive() {
+        let (mut brain, port) = serialport::TTYPort::pair().unwrap();
+        let mut stream = imp::from_native(port).unwrap();
+
+        futures_lite::future::block_on(async {
+            brain.write_all(&[0xAA]).unwrap();
+            assert_eq!(read_u8(&mut stream).await.unwrap(), 0xAA);
+
+            // The connection gives up on reads like this between replies.
+            assert!(timeout(Duration::from_millis(50), read_u8(&mut stream))
+                .await
+                .is_none());
+
+            timeout(Duration::from_secs(1), async {
+                stream.write_all(&[1, 2, 3]).await?;
+                stream.flush().await
+            })
+            .await
+            .expect("write waited on the abandoned read")
+            .unwrap();
+
+            let mut sent = [0; 3];
+            brain.read_exact(&mut sent).unwrap();
+            assert_eq!(sent, [1, 2, 3]);
+
+            brain.write_all(&[0xBB]).unwrap();
+            assert_eq!(read_u8(&mut stream).await.unwrap(), 0xBB);
+        });
+    }
+}
@@ -1,30 +1,53 @@
-//! Implements discovering, opening, and interacting with vex devices connected over USB. This module does not have async support.
+//! Implements discovering, opening, and interacting with vex devices connected over USB.
+//!
+//! This module runs on tokio with the `serial` feature, or on any async-io based runtime
+//! (such as smol) with only the `smol-serial` feature.
 
-use log::{debug, error, trace, warn};
+use log::{debug, trace, warn};
 use serialport::{SerialPortInfo, SerialPortType};
-use std::time::Duration;
+use std::{collections::VecDeque, future::Future, time::Duration};
 use thiserror::Error;
-use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt, BufReader},
-    select,
-    time::sleep,
-};
-use tokio_serial::SerialStream;
 
-use super::{CheckHeader, Connection, ConnectionType};
+use super::{
+    clock::{self, Instant},
+    handshake_matched, receive_matched,
+    runtime::{self, AsyncReadExt, AsyncWriteExt, BufReader, SerialStream},
+    CheckHeader, Connection, ConnectionStats, ConnectionType, HandshakeError, MatchReceive,
+    Matcher, TransferState,
+};
 use crate::{
-    connection::{trim_packets, RawPacket},
+    commands::{
+        controller::{UserFifoSettings, WriteUserFifo},
+        CommandError,
+    },
+    connection::PacketRouter,
     decode::{Decode, DecodeError},
     encode::{Encode, EncodeError},
+    hex::HexPreview,
     packets::{
         cdc2::Cdc2Ack,
-        controller::{UserFifoPacket, UserFifoPayload, UserFifoReplyPacket},
+        controller::{UserFifoPacket, UserFifoPayload},
+        system::{GetSystemStatusPacket, GetSystemVersionPacket, ProductType},
         HOST_BOUND_HEADER,
     },
-    string::FixedString,
     varint::VarU16,
+    version::Version,
 };
 
+/// How long the port must be quiet before [`SerialConnection::flush_incoming`] stops draining it.
+const FLUSH_TIMEOUT: Duration = Duration::from_millis(20);
+
+/// How long [`probe`] waits for each reply.
+const PROBE_TIMEOUT: Duration = Duration::from_millis(250);
+
+/// How long to wait for the rest of a packet once its header and size have been read.
+const FRAME_READ_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The default value of [`SerialConnection::max_payload_size`].
+///
+/// This leaves room for a full 4 KiB file transfer chunk along with the rest of its reply.
+pub const DEFAULT_MAX_PAYLOAD_SIZE: usize = 8192;
+
 /// The USB venddor ID for VEX devices
 pub const VEX_USB_VID: u16 = 0x2888;
 
@@ -42,7 +65,7 @@ pub const V5_SERIAL_BAUDRATE: u32 = 115200;
 /// The information of a generic vex serial port
 #[derive(Clone, Debug)]
 pub struct VexSerialPort {
-    pub port_info: tokio_serial::SerialPortInfo,
+    pub port_info: SerialPortInfo,
     pub port_type: VexSerialPortType,
 }
 
@@ -115,11 +138,9 @@ fn types_by_location(ports: &[SerialPortInfo]) -> Option<Vec<VexSerialPort>> {
 }
 
 /// Assign port types based on the last character of the port name.
-/// This is the fallback option for macOS.
+/// This is the fallback option for macOS, and is only called there.
 /// This is a band-aid solution and will become obsolete once serialport correctly gets the interface number.
 fn types_by_name_darwin(ports: &[SerialPortInfo]) -> Option<Vec<VexSerialPort>> {
-    assert!(cfg!(target_os = "macos"));
-
     debug!("Attempting to infer serial port types by name. (Darwin fallback)");
     let mut vex_ports = Vec::new();
 
@@ -206,7 +227,7 @@ fn types_by_name_order(ports: &[SerialPortInfo]) -> Option<Vec<VexSerialPort>> {
 /// Finds all available VEX serial ports that can be connected to.
 fn find_ports() -> Result<Vec<VexSerialPort>, SerialError> {
     // Get all available serial ports
-    let ports = tokio_serial::available_ports()?;
+    let ports = serialport::available_ports()?;
 
     // Create a vector that will contain all vex ports
     let mut filtered_ports = Vec::new();
@@ -345,14 +366,184 @@ impl SerialDevice {
     }
 }
 
-/// Decodes a [`HostBoundPacket`]'s header sequence.
-fn decode_header(data: impl IntoIterator<Item = u8>) -> Result<[u8; 2], DecodeError> {
-    let mut data = data.into_iter();
-    let header = Decode::decode(&mut data)?;
-    if header != HOST_BOUND_HEADER {
-        return Err(DecodeError::InvalidHeader);
+/// Opens a device's system port.
+fn open_system_port(device: &SerialDevice, timeout: Duration) -> Result<SerialStream, SerialError> {
+    runtime::open(
+        serialport::new(device.system_port(), V5_SERIAL_BAUDRATE)
+            .parity(serialport::Parity::None)
+            .timeout(timeout)
+            .stop_bits(serialport::StopBits::One),
+    )
+    .map_err(SerialError::SerialportError)
+}
+
+/// Basic information about a device, read by [`probe`].
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct ProbeInfo {
+    pub product_type: ProductType,
+    /// The VEXos version the device is running.
+    pub version: Version,
+    /// The brain's serial number, from [`SystemDetails::unique_id`].
+    ///
+    /// This is `None` for controllers, and for brains that don't report their details.
+    ///
+    /// [`SystemDetails::unique_id`]: crate::packets::system::SystemDetails::unique_id
+    pub ssn: Option<u32>,
+}
+
+/// Reads a device's product, VEXos version, and serial number without connecting to it, so
+/// that a user can pick between several devices.
+///
+/// Only the system port is opened, with short timeouts, and it's closed again before this
+/// returns. Anything the device is still sending is drained first, so that a late reply can't
+/// be mistaken for the reply to a packet sent by the next connection.
+pub async fn probe(device: &SerialDevice) -> Result<ProbeInfo, SerialError> {
+    let system_port = open_system_port(device, PROBE_TIMEOUT)?;
+    let mut connection = SerialConnection::from_ports(system_port, None);
+
+    let info = probe_connection(&mut connection).await;
+    connection.flush_incoming().await?;
+    info
+}
+
+async fn probe_connection<C: Connection + ?Sized>(
+    connection: &mut C,
+) -> Result<ProbeInfo, C::Error> {
+    let version = connection
+        .request(PROBE_TIMEOUT, 1, GetSystemVersionPacket::new(()))
+        .await?
+        .payload;
+
+    // A controller would answer with its brain's status, if it's linked to one at all
+    let ssn = match version.product_type {
+        ProductType::Controller | ProductType::ExpController => None,
+        _ => connection
+            .request(PROBE_TIMEOUT, 1, GetSystemStatusPacket::new(()))
+            .await?
+            .try_into_inner()?
+            .details
+            .map(|details| details.unique_id),
+    };
+
+    Ok(ProbeInfo {
+        product_type: version.product_type,
+        version: version.version,
+        ssn,
+    })
+}
+
+/// Reads a byte from `unread`, or from `reader` once `unread` is empty.
+async fn next_byte<R: AsyncReadExt + Unpin>(
+    reader: &mut R,
+    unread: &mut VecDeque<u8>,
+) -> std::io::Result<u8> {
+    match unread.pop_front() {
+        Some(byte) => Ok(byte),
+        None => runtime::read_u8(reader).await,
+    }
+}
+
+/// Reads until a [`HOST_BOUND_HEADER`] has been read, returning the number of bytes skipped
+/// before it.
+async fn skip_to_header<R: AsyncReadExt + Unpin>(
+    reader: &mut R,
+    unread: &mut VecDeque<u8>,
+) -> std::io::Result<usize> {
+    let mut read = 0;
+    let mut previous = None;
+    loop {
+        let byte = next_byte(reader, unread).await?;
+        read += 1;
+        if previous == Some(HOST_BOUND_HEADER[0]) && byte == HOST_BOUND_HEADER[1] {
+            return Ok(read - HOST_BOUND_HEADER.len());
+        }
+        previous = Some(byte);
+    }
+}
+
+/// Reads a single host-bound packet, starting with any bytes left in `unread` by an earlier
+/// call.
+///
+/// Returns `None` if the packet had to be dropped, because it declared a payload larger than
+/// `max_payload_size` or the rest of it didn't arrive in time. A corrupted size would otherwise
+/// leave the connection waiting for bytes that never come. An oversized packet is dropped
+/// after its header and size, and a timed out one after the part of its payload that did
+/// arrive. That part may hold the start of the next packet, so everything from the first
+/// header in it is put back in `unread` for the next read to resynchronize on.
+async fn read_frame<R: AsyncReadExt + Unpin>(
+    reader: &mut R,
+    unread: &mut VecDeque<u8>,
+    max_payload_size: usize,
+) -> Result<Option<Vec<u8>>, SerialError> {
+    let skipped = skip_to_header(reader, unread).await?;
+    if skipped > 0 {
+        warn!("Skipped {} bytes before the next packet header", skipped);
+    }
+    let mut packet = HOST_BOUND_HEADER.to_vec();
+
+    // Push the command's ID
+    packet.push(next_byte(reader, unread).await?);
+
+    // Get the size of the packet
+    // We do some extra logic to make sure we only read the necessary amount of bytes
+    let first_size_byte = next_byte(reader, unread).await?;
+    packet.push(first_size_byte);
+    if VarU16::check_wide(first_size_byte) {
+        packet.push(next_byte(reader, unread).await?);
+    }
+    let size = VarU16::decode(packet[3..].iter().copied())?.into_inner() as usize;
+
+    if size > max_payload_size {
+        warn!(
+            "Skipping packet {:x?} with a {} byte payload, over the {} byte limit",
+            HexPreview(&packet),
+            size,
+            max_payload_size
+        );
+        return Ok(None);
+    }
+
+    // Read the rest of the packet
+    let mut payload = vec![0; size];
+    let mut filled = unread.len().min(size);
+    for (byte, unread) in payload.iter_mut().zip(unread.drain(..filled)) {
+        *byte = unread;
+    }
+    let read = runtime::timeout(FRAME_READ_TIMEOUT, async {
+        while filled < size {
+            match reader.read(&mut payload[filled..]).await? {
+                0 => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+                read => filled += read,
+            }
+        }
+        Ok::<_, std::io::Error>(())
+    })
+    .await;
+    match read {
+        Some(result) => {
+            result?;
+        }
+        None => {
+            warn!(
+                "Timed out reading the {} byte payload of packet {:x?}, {} bytes arrived",
+                size,
+                HexPreview(&packet),
+                filled
+            );
+            // Keep everything from the first header, including half of one at the very end
+            let start = (0..filled).find(|&i| {
+                let end = (i + HOST_BOUND_HEADER.len()).min(filled);
+                HOST_BOUND_HEADER.starts_with(&payload[i..end])
+            });
+            if let Some(start) = start {
+                unread.extend(&payload[start..filled]);
+            }
+            return Ok(None);
+        }
     }
-    Ok(header)
+    packet.extend(payload);
+
+    Ok(Some(packet))
 }
 
 /// An open serial connection to a V5 device.
@@ -360,30 +551,31 @@ fn decode_header(data: impl IntoIterator<Item = u8>) -> Result<[u8; 2], DecodeEr
 pub struct SerialConnection {
     system_port: SerialStream,
     user_port: Option<BufReader<SerialStream>>,
-    incoming_packets: Vec<RawPacket>,
+    incoming_packets: PacketRouter,
+    /// Bytes read from the system port that belong to packets not yet received.
+    unread: VecDeque<u8>,
+    send_pacing: Option<Duration>,
+    last_send: Option<Instant>,
+    max_payload_size: usize,
+    user_fifo: UserFifoSettings,
+    active_transfer: Option<TransferState>,
+    read_chunk_size: Option<u16>,
+    timeout_scale: f64,
+    stats: ConnectionStats,
 }
 
 impl SerialConnection {
     /// Opens a new serial connection to a V5 Brain.
     pub fn open(device: SerialDevice, timeout: Duration) -> Result<Self, SerialError> {
-        // Open the system port
-        let system_port = match tokio_serial::SerialStream::open(
-            &tokio_serial::new(device.system_port(), 115200)
-                .parity(tokio_serial::Parity::None)
-                .timeout(timeout)
-                .stop_bits(tokio_serial::StopBits::One),
-        ) {
-            Ok(v) => Ok(v),
-            Err(e) => Err(SerialError::SerialportError(e)),
-        }?;
+        let system_port = open_system_port(&device, timeout)?;
 
         // Open the user port (if it exists)
         let user_port = if let Some(port) = &device.user_port() {
-            Some(match tokio_serial::SerialStream::open(
-                &tokio_serial::new(port, V5_SERIAL_BAUDRATE)
-                    .parity(tokio_serial::Parity::None)
+            Some(match runtime::open(
+                serialport::new(port, V5_SERIAL_BAUDRATE)
+                    .parity(serialport::Parity::None)
                     .timeout(timeout)
-                    .stop_bits(tokio_serial::StopBits::One),
+                    .stop_bits(serialport::StopBits::One),
             ) {
                 Ok(v) => Ok(BufReader::new(v)),
                 Err(e) => Err(SerialError::SerialportError(e)),
@@ -392,67 +584,120 @@ impl SerialConnection {
             None
         };
 
-        Ok(Self {
-            system_port,
-            user_port,
-            incoming_packets: Default::default(),
-        })
+        Ok(Self::from_ports(system_port, user_port))
     }
 
-    /// Receives a single packet from the serial port and adds it to the queue of incoming packets.
-    async fn receive_one_packet(&mut self) -> Result<(), SerialError> {
-        // Read the header into an array
-        let mut header = [0u8; 2];
-        self.system_port.read_exact(&mut header).await?;
-
-        // Verify that the header is valid
-        if let Err(e) = decode_header(header) {
-            warn!(
-                "Skipping packet with invalid header: {:x?}. Error: {}",
-                header, e
-            );
-            return Ok(());
+    fn from_ports(system_port: SerialStream, user_port: Option<BufReader<SerialStream>>) -> Self {
+        Self {
+            system_port,
+            user_port,
+            incoming_packets: PacketRouter::new(),
+            unread: VecDeque::new(),
+            send_pacing: None,
+            last_send: None,
+            max_payload_size: DEFAULT_MAX_PAYLOAD_SIZE,
+            user_fifo: UserFifoSettings::default(),
+            active_transfer: None,
+            read_chunk_size: None,
+            timeout_scale: 1.0,
+            stats: ConnectionStats::default(),
         }
+    }
 
-        // Create a buffer to store the entire packet
-        let mut packet = Vec::from(header);
+    /// Sets the largest payload size that a received packet may declare.
+    ///
+    /// Packets declaring larger payloads are treated as corrupted and dropped. Defaults to
+    /// [`DEFAULT_MAX_PAYLOAD_SIZE`].
+    pub fn set_max_payload_size(&mut self, max_payload_size: usize) {
+        self.max_payload_size = max_payload_size;
+    }
 
-        // Push the command's ID
-        packet.push(self.system_port.read_u8().await?);
+    /// Returns the largest payload size that a received packet may declare.
+    pub fn max_payload_size(&self) -> usize {
+        self.max_payload_size
+    }
 
-        // Get the size of the packet
-        // We do some extra logic to make sure we only read the necessary amount of bytes
-        let first_size_byte = self.system_port.read_u8().await?;
-        let size = if VarU16::check_wide(first_size_byte) {
-            let second_size_byte = self.system_port.read_u8().await?;
-            packet.extend([first_size_byte, second_size_byte]);
+    /// Sets how [`Connection::write_user`] writes to the user FIFO when connected through a
+    /// controller.
+    ///
+    /// Use [`UserFifoSettings::for_channel`] with [`RadioChannel::Download`] to write faster
+    /// while the radio is on the download channel.
+    ///
+    /// [`RadioChannel::Download`]: crate::packets::radio::RadioChannel::Download
+    pub fn set_user_fifo_settings(&mut self, settings: UserFifoSettings) {
+        self.user_fifo = settings;
+    }
 
-            // Decode the size of the packet
-            VarU16::decode(vec![first_size_byte, second_size_byte])?
-        } else {
-            packet.push(first_size_byte);
+    /// Returns how [`Connection::write_user`] writes to the user FIFO.
+    pub fn user_fifo_settings(&self) -> UserFifoSettings {
+        self.user_fifo
+    }
 
-            // Decode the size of the packet
-            VarU16::decode(vec![first_size_byte])?
+    /// Receives a single packet from the serial port and adds it to the queue of incoming packets.
+    async fn receive_one_packet(&mut self) -> Result<(), SerialError> {
+        if let Some(packet) = read_frame(
+            &mut self.system_port,
+            &mut self.unread,
+            self.max_payload_size,
+        )
+        .await?
+        {
+            debug!("received packet: {:x?}", HexPreview(&packet));
+
+            // Push the packet to the incoming packets buffer
+            self.incoming_packets.push(packet);
         }
-        .into_inner() as usize;
 
-        // Read the rest of the packet
-        let mut payload = vec![0; size];
-        self.system_port.read_exact(&mut payload).await?;
+        Ok(())
+    }
 
-        // Completely fill the packet
-        packet.extend(payload);
+    /// Writes encoded packets to the system port, waiting for the send pacing first.
+    async fn write_system(&mut self, encoded: &[u8]) -> Result<(), SerialError> {
+        // Give the controller's radio link time to carry joystick data between packets
+        if let (Some(pacing), Some(last_send)) = (self.send_pacing, self.last_send) {
+            if self.connection_type().is_controller() {
+                if let Some(remaining) = pacing.checked_sub(clock::since(last_send)) {
+                    runtime::sleep(remaining).await;
+                }
+            }
+        }
 
-        debug!("received packet: {:x?}", packet);
+        // Write the packet to the serial port
+        match self.system_port.write_all(encoded).await {
+            Ok(_) => (),
+            Err(e) => return Err(SerialError::IoError(e)),
+        };
 
-        // Push the packet to the incoming packets buffer
-        self.incoming_packets.push(RawPacket::new(packet));
+        match self.system_port.flush().await {
+            Ok(_) => (),
+            Err(e) => return Err(SerialError::IoError(e)),
+        };
+        self.last_send = Some(clock::now());
 
         Ok(())
     }
 }
 
+impl MatchReceive for SerialConnection {
+    async fn receive_matching(
+        &mut self,
+        timeout: Duration,
+        matcher: &mut Matcher<'_>,
+    ) -> Result<Instant, SerialError> {
+        // Return an error if the right packet is not received within the timeout
+        runtime::timeout(timeout, async {
+            loop {
+                if let Some(claimed) = self.incoming_packets.claim_matching(matcher) {
+                    return claimed.map_err(SerialError::DecodeError);
+                }
+                self.receive_one_packet().await?;
+            }
+        })
+        .await
+        .unwrap_or(Err(SerialError::Timeout))
+    }
+}
+
 impl Connection for SerialConnection {
     type Error = SerialError;
 
@@ -468,48 +713,113 @@ impl Connection for SerialConnection {
         // Encode the packet
         let encoded = packet.encode()?;
 
-        trace!("Sending packet: {:x?}", encoded);
+        trace!("Sending packet: {:x?}", HexPreview(&encoded));
 
-        // Write the packet to the serial port
-        match self.system_port.write_all(&encoded).await {
-            Ok(_) => (),
-            Err(e) => return Err(SerialError::IoError(e)),
-        };
+        self.write_system(&encoded).await
+    }
 
-        match self.system_port.flush().await {
-            Ok(_) => (),
-            Err(e) => return Err(SerialError::IoError(e)),
-        };
+    async fn send_batch<P: Encode>(&mut self, packets: &[P]) -> Result<(), SerialError> {
+        // Paced packets have to be sent one at a time
+        if self.send_pacing.is_some() && self.connection_type().is_controller() {
+            for packet in packets {
+                self.send_packet(packet.encode()?).await?;
+            }
+            return Ok(());
+        }
 
-        Ok(())
+        let mut encoded = Vec::new();
+        for packet in packets {
+            encoded.extend(packet.encode()?);
+        }
+
+        trace!(
+            "Sending {} packets in one write: {:x?}",
+            packets.len(),
+            HexPreview(&encoded)
+        );
+
+        self.write_system(&encoded).await
+    }
+
+    fn set_send_pacing(&mut self, pacing: Option<Duration>) {
+        self.send_pacing = pacing;
+    }
+
+    fn send_pacing(&self) -> Option<Duration> {
+        self.send_pacing
+    }
+
+    async fn sleep(&mut self, duration: Duration) {
+        runtime::sleep(duration).await;
+    }
+
+    fn set_active_transfer(&mut self, transfer: Option<TransferState>) {
+        self.active_transfer = transfer;
+    }
+
+    fn active_transfer(&self) -> Option<&TransferState> {
+        self.active_transfer.as_ref()
+    }
+
+    fn set_read_chunk_size(&mut self, size: u16) {
+        self.read_chunk_size = Some(size);
+    }
+
+    fn read_chunk_size(&self) -> Option<u16> {
+        self.read_chunk_size
+    }
+
+    fn set_timeout_scale(&mut self, scale: f64) {
+        self.timeout_scale = scale;
+    }
+
+    fn timeout_scale(&self) -> f64 {
+        self.timeout_scale
+    }
+
+    fn record_handshake(&mut self, failed_attempts: usize, succeeded: bool) {
+        self.stats.record_handshake(failed_attempts, succeeded);
+    }
+
+    fn record_round_trip(&mut self, round_trip: Duration) {
+        self.stats.record_round_trip(round_trip);
+    }
+
+    fn stats(&self) -> ConnectionStats {
+        self.stats
     }
 
     async fn receive_packet<P: Decode + CheckHeader>(&mut self, timeout: Duration) -> Result<P, SerialError> {
-        // Return an error if the right packet is not received within the timeout
-        select! {
-            result = async {
-                loop {
-                    for packet in self.incoming_packets.iter_mut() {
-                        if packet.check_header::<P>() {
-                            match packet.decode_and_use::<P>() {
-                                Ok(decoded) => {
-                                    trim_packets(&mut self.incoming_packets);
-                                    return Ok(decoded);
-                                }
-                                Err(e) => {
-                                    error!("Failed to decode packet with valid header: {}", e);
-                                    packet.used = true;
-                                    return Err(SerialError::DecodeError(e));
-                                }
-                            }
-                        }
-                    }
-                    trim_packets(&mut self.incoming_packets);
-                    self.receive_one_packet().await?;
-                }
-            } => result,
-            _ = sleep(timeout) => Err(SerialError::Timeout)
+        receive_matched(self, timeout)
+            .await
+            .map(|(packet, _)| packet)
+    }
+
+    fn receive_packet_timed<P: Decode + CheckHeader>(
+        &mut self,
+        timeout: Duration,
+    ) -> impl Future<Output = Result<(P, Instant), SerialError>> {
+        receive_matched(self, timeout)
+    }
+
+    fn packet_handshake<D: Decode + CheckHeader>(
+        &mut self,
+        timeout: Duration,
+        retries: usize,
+        packet: impl Encode + Clone,
+    ) -> impl Future<Output = Result<D, SerialError>> {
+        handshake_matched(self, timeout, retries, packet)
+    }
+
+    async fn flush_incoming(&mut self) -> Result<(), SerialError> {
+        // Drain whatever is still arriving on the wire until the port goes quiet
+        while let Some(result) = runtime::timeout(FLUSH_TIMEOUT, self.receive_one_packet()).await {
+            result?;
         }
+
+        debug!("Flushing {} incoming packets", self.incoming_packets.len());
+        self.incoming_packets.clear();
+        Ok(())
     }
 
     async fn read_user(&mut self, buf: &mut [u8]) -> Result<usize, SerialError> {
@@ -519,7 +829,7 @@ impl Connection for SerialConnection {
             let mut data = Vec::new();
             loop {
                 let fifo = self
-                    .packet_handshake::<UserFifoReplyPacket>(
+                    .request(
                         Duration::from_millis(100),
                         1,
                         UserFifoPacket::new(UserFifoPayload {
@@ -542,31 +852,16 @@ impl Connection for SerialConnection {
         }
     }
 
-    async fn write_user(&mut self, mut buf: &[u8]) -> Result<usize, SerialError> {
+    async fn write_user(&mut self, buf: &[u8]) -> Result<usize, SerialError> {
         if let Some(user_port) = &mut self.user_port {
             Ok(user_port.write(buf).await?)
         } else {
-            let buf_len = buf.len();
-            while !buf.is_empty() {
-                let (chunk, rest) = buf.split_at(std::cmp::min(224, buf.len()));
-                _ = self
-                    .packet_handshake::<UserFifoReplyPacket>(
-                        Duration::from_millis(100),
-                        1,
-                        UserFifoPacket::new(UserFifoPayload {
-                            channel: 2, // stdio channel
-                            write: Some(
-                                FixedString::new(String::from_utf8(chunk.to_vec()).unwrap())
-                                    .unwrap(),
-                            ),
-                        }),
-                    )
-                    .await?
-                    .try_into_inner()?;
-                buf = rest;
-            }
-
-            Ok(buf_len)
+            let settings = self.user_fifo;
+            self.execute_command(WriteUserFifo {
+                data: buf,
+                settings,
+            })
+            .await
         }
     }
 }
@@ -583,8 +878,126 @@ pub enum SerialError {
     Timeout,
     #[error("NACK received: {0:?}")]
     Nack(#[from] Cdc2Ack),
+    #[error("Command error: {0}")]
+    CommandError(#[from] CommandError),
+    #[error("{0}")]
+    HandshakeError(#[from] HandshakeError),
     #[error("Serialport Error")]
-    SerialportError(#[from] tokio_serial::Error),
+    SerialportError(#[from] serialport::Error),
     #[error("Could not infer serial port types")]
     CouldntInferTypes,
 }
+
+#[cfg(all(test, feature = "serial"))]
+mod tests {
+    use std::collections::VecDeque;
+
+    use super::{probe_connection, read_frame};
+    use crate::{
+        connection::mock::{block_on, cdc2_reply, version_reply, MockConnection},
+        packets::system::ProductType,
+    };
+
+    #[test]
+    fn oversized_frame_resync() {
+        let mut stream: Vec<u8> = vec![0x12];
+        // A corrupted frame claiming a 0x7FFF byte payload
+        stream.extend([0xAA, 0x55, 0x56, 0xFF, 0xFF, 0x01, 0x02]);
+        let valid = [0xAA, 0x55, 0xA4, 0x02, 0x07, 0x08];
+        stream.extend(valid);
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let mut reader = stream.as_slice();
+            let mut unread = VecDeque::new();
+            assert_eq!(
+                read_frame(&mut reader, &mut unread, 64).await.unwrap(),
+                None
+            );
+            assert_eq!(
+                read_frame(&mut reader, &mut unread, 64).await.unwrap(),
+                Some(valid.to_vec())
+            );
+            assert!(reader.is_empty());
+        });
+    }
+
+    #[test]
+    fn truncated_frame_times_out() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            // The writer is kept open, so the reader waits for the missing payload byte
+            let (mut reader, mut writer) = tokio::io::duplex(64);
+            tokio::io::AsyncWriteExt::write_all(&mut writer, &[0xAA, 0x55, 0xA4, 0x02, 0x07])
+                .await
+                .unwrap();
+            assert_eq!(
+                read_frame(&mut reader, &mut VecDeque::new(), 64)
+                    .await
+                    .unwrap(),
+                None
+            );
+        });
+    }
+
+    #[test]
+    fn truncated_frame_resync() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            // A frame cut short after one byte of its 32 byte payload, then a complete frame
+            // that is read as the rest of that payload
+            let mut stream = vec![0xAA, 0x55, 0xA4, 0x20, 0x01];
+            let valid = [0xAA, 0x55, 0xA4, 0x02, 0x07, 0x08];
+            stream.extend(valid);
+            let (mut reader, mut writer) = tokio::io::duplex(64);
+            tokio::io::AsyncWriteExt::write_all(&mut writer, &stream)
+                .await
+                .unwrap();
+
+            let mut unread = VecDeque::new();
+            assert_eq!(
+                read_frame(&mut reader, &mut unread, 64).await.unwrap(),
+                None
+            );
+            assert_eq!(
+                read_frame(&mut reader, &mut unread, 64).await.unwrap(),
+                Some(valid.to_vec())
+            );
+            assert!(unread.is_empty());
+        });
+    }
+
+    #[test]
+    fn probe_brain_and_controller() {
+        let mut status = vec![0; 17];
+        status.extend(0x0123_4567u32.to_le_bytes());
+        status.extend([0; 12]);
+        let mut brain = MockConnection {
+            replies: [version_reply(0x10), cdc2_reply(0x22, &status)].into(),
+            ..Default::default()
+        };
+        let info = block_on(probe_connection(&mut brain)).unwrap();
+        assert_eq!(info.product_type, ProductType::Brain);
+        assert_eq!(info.version.build, 4);
+        assert_eq!(info.ssn, Some(0x0123_4567));
+
+        // Controllers aren't asked for a status
+        let mut controller = MockConnection {
+            replies: [version_reply(0x11)].into(),
+            ..Default::default()
+        };
+        let info = block_on(probe_connection(&mut controller)).unwrap();
+        assert_eq!(info.product_type, ProductType::Controller);
+        assert_eq!(info.ssn, None);
+        assert_eq!(controller.sent.len(), 1);
+    }
+}
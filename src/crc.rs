@@ -1,12 +1,19 @@
-use crc::Crc;
+use crc::{Algorithm, Crc};
+use thiserror::Error;
 
-/// Vex uses CRC16/XMODEM as the CRC16.
-pub const VEX_CRC16: crc::Crc<u16> = Crc::<u16>::new(&crc::CRC_16_XMODEM);
+use crate::{
+    decode::Decode,
+    packets::{DEVICE_BOUND_HEADER, HOST_BOUND_HEADER},
+    varint::VarU16,
+};
 
-/// Vex uses a CRC32 that I found on page 6 of this document:
-/// <https://www.matec-conferences.org/articles/matecconf/pdf/2016/11/matecconf_tomsk2016_04001.pdf>
-/// I literally just discovered it by guessing and checking against the PROS implementation.
-pub const VEX_CRC32: crc::Crc<u32> = Crc::<u32>::new(&crc::Algorithm {
+/// The parameters of [`VEX_CRC16`], which is CRC16/XMODEM.
+pub const VEX_CRC16_ALGORITHM: Algorithm<u16> = crc::CRC_16_XMODEM;
+
+/// The parameters of [`VEX_CRC32`].
+///
+/// This is CRC-32/CKSUM without the final XOR: no reflection, zero init, and zero xorout.
+pub const VEX_CRC32_ALGORITHM: Algorithm<u32> = Algorithm {
     poly: 0x04C11DB7,
     init: 0x00000000,
     refin: false,
@@ -15,4 +22,111 @@ pub const VEX_CRC32: crc::Crc<u32> = Crc::<u32>::new(&crc::Algorithm {
     check: 0x89A1897F,
     residue: 0x00000000,
     width: 32,
-});
+};
+
+/// Vex uses CRC16/XMODEM as the CRC16.
+pub const VEX_CRC16: crc::Crc<u16> = Crc::<u16>::new(&VEX_CRC16_ALGORITHM);
+
+/// Vex uses a CRC32 that I found on page 6 of this document:
+/// <https://www.matec-conferences.org/articles/matecconf/pdf/2016/11/matecconf_tomsk2016_04001.pdf>
+/// I literally just discovered it by guessing and checking against the PROS implementation.
+pub const VEX_CRC32: crc::Crc<u32> = Crc::<u32>::new(&VEX_CRC32_ALGORITHM);
+
+/// An error found while verifying a frame's CRC16.
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChecksumError {
+    #[error("Frame does not start with a CDC header")]
+    InvalidHeader,
+    #[error("Frame is too short to hold a CRC16")]
+    TooShort,
+    #[error("Frame is {actual} bytes long, but its header describes {expected} bytes")]
+    LengthMismatch { expected: usize, actual: usize },
+    #[error("CRC16 mismatch: frame has {expected:#06x}, but its contents have {actual:#06x}")]
+    Mismatch { expected: u16, actual: u16 },
+}
+
+/// Checks the trailing big-endian CRC16 of a complete CDC2 command or reply frame.
+///
+/// The frame's length must match the payload size in its header, so trailing bytes from the
+/// next frame are reported as [`ChecksumError::LengthMismatch`].
+pub fn verify_cdc2_frame(frame: &[u8]) -> Result<(), ChecksumError> {
+    // Command frames have the extended command ID before the size, replies have it after
+    let (size_start, crc_in_size) = if frame.starts_with(&DEVICE_BOUND_HEADER) {
+        (DEVICE_BOUND_HEADER.len() + 2, false)
+    } else if frame.starts_with(&HOST_BOUND_HEADER) {
+        (HOST_BOUND_HEADER.len() + 1, true)
+    } else {
+        return Err(ChecksumError::InvalidHeader);
+    };
+
+    let first = *frame.get(size_start).ok_or(ChecksumError::TooShort)?;
+    let size_len = if VarU16::check_wide(first) { 2 } else { 1 };
+    let size = VarU16::decode(frame[size_start..].iter().copied())
+        .map_err(|_| ChecksumError::TooShort)?
+        .into_inner() as usize;
+
+    let body_start = size_start + size_len;
+    let expected_len = body_start + size + if crc_in_size { 0 } else { 2 };
+    if expected_len < body_start + 2 {
+        return Err(ChecksumError::TooShort);
+    }
+    if frame.len() != expected_len {
+        return Err(ChecksumError::LengthMismatch {
+            expected: expected_len,
+            actual: frame.len(),
+        });
+    }
+
+    let (contents, crc) = frame.split_at(frame.len() - 2);
+    let expected = u16::from_be_bytes([crc[0], crc[1]]);
+    let actual = VEX_CRC16.checksum(contents);
+    if expected != actual {
+        return Err(ChecksumError::Mismatch { expected, actual });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{verify_cdc2_frame, ChecksumError, VEX_CRC16, VEX_CRC32};
+    use crate::{encode::Encode, packets::device::GetDeviceStatusPacket};
+
+    #[test]
+    fn check_strings() {
+        assert_eq!(VEX_CRC16.checksum(b"123456789"), 0x31C3);
+        // CRC-32/CKSUM's check value of 0x765E7680 without the final XOR
+        assert_eq!(VEX_CRC32.checksum(b"123456789"), 0x89A1897F);
+        assert_eq!(VEX_CRC32.checksum(b"123456789"), !0x765E7680);
+    }
+
+    #[test]
+    fn verify_frames() {
+        let mut frame = GetDeviceStatusPacket::new(()).encode().unwrap();
+        assert_eq!(verify_cdc2_frame(&frame), Ok(()));
+
+        frame.push(0xAA);
+        assert_eq!(
+            verify_cdc2_frame(&frame),
+            Err(ChecksumError::LengthMismatch {
+                expected: frame.len() - 1,
+                actual: frame.len(),
+            })
+        );
+        frame.pop();
+
+        *frame.last_mut().unwrap() ^= 0x01;
+        assert!(matches!(
+            verify_cdc2_frame(&frame),
+            Err(ChecksumError::Mismatch { .. })
+        ));
+
+        assert_eq!(
+            verify_cdc2_frame(&[0xAA, 0x55, 0x56, 0x01, 0x00]),
+            Err(ChecksumError::TooShort)
+        );
+        assert_eq!(
+            verify_cdc2_frame(b"hello"),
+            Err(ChecksumError::InvalidHeader)
+        );
+    }
+}
@@ -1,3 +1,12 @@
+//! Decoding packets from bytes.
+//!
+//! Decoders pull bytes from an iterator, so running out of input is a
+//! [`DecodeError::PacketTooShort`] rather than an out of bounds index. Bytes come straight from
+//! the device and may be corrupted, so decoding must never panic: indexing and slicing are
+//! denied here and in [`packets`](crate::packets) to keep it that way.
+
+#![deny(clippy::indexing_slicing)]
+
 use std::str::Utf8Error;
 use thiserror::Error;
 
@@ -5,8 +14,16 @@ use thiserror::Error;
 pub enum DecodeError {
     #[error("Packet too short")]
     PacketTooShort,
+    /// The reply was acknowledged but carried no payload, where one was expected.
+    ///
+    /// Unlike [`DecodeError::PacketTooShort`], this means the reply arrived intact. Devices
+    /// send these when they have nothing to report.
+    #[error("Reply was acknowledged but its payload was empty")]
+    EmptyPayload,
     #[error("Invalid response header")]
     InvalidHeader,
+    #[error("Packet checksum did not match")]
+    Checksum,
     #[error("String ran past expected nul terminator")]
     UnterminatedString,
     #[error("String contained invalid UTF-8: {0}")]
@@ -18,6 +35,11 @@ pub enum DecodeError {
         left: Box<DecodeError>,
         right: Box<DecodeError>,
     },
+    #[error("Could not decode array element {index}: {error}")]
+    ArrayElement {
+        index: usize,
+        error: Box<DecodeError>,
+    },
 }
 
 pub trait Decode {
@@ -62,52 +84,112 @@ impl Decode for i8 {
 }
 impl Decode for u16 {
     fn decode(data: impl IntoIterator<Item = u8>) -> Result<Self, DecodeError> {
-        let mut data = data.into_iter();
-        Ok(u16::from_le_bytes(Decode::decode(&mut data)?))
+        Ok(u16::from_le_bytes(bytes(data)?))
     }
 }
 impl Decode for i16 {
     fn decode(data: impl IntoIterator<Item = u8>) -> Result<Self, DecodeError> {
-        let mut data = data.into_iter();
-        Ok(i16::from_le_bytes(Decode::decode(&mut data)?))
+        Ok(i16::from_le_bytes(bytes(data)?))
     }
 }
 impl Decode for u32 {
     fn decode(data: impl IntoIterator<Item = u8>) -> Result<Self, DecodeError> {
-        let mut data = data.into_iter();
-        Ok(u32::from_le_bytes(Decode::decode(&mut data)?))
+        Ok(u32::from_le_bytes(bytes(data)?))
     }
 }
 impl Decode for i32 {
     fn decode(data: impl IntoIterator<Item = u8>) -> Result<Self, DecodeError> {
-        let mut data = data.into_iter();
-        Ok(i32::from_le_bytes(Decode::decode(&mut data)?))
+        Ok(i32::from_le_bytes(bytes(data)?))
     }
 }
+/// Decodes `None` if there are no bytes left, such as when a reply from older firmware ends
+/// before a field that was added later.
 impl<D: Decode> Decode for Option<D> {
     fn decode(data: impl IntoIterator<Item = u8>) -> Result<Self, DecodeError> {
-        D::decode(data).map(|decoded| Some(decoded))
+        let mut data = data.into_iter().peekable();
+        if data.peek().is_none() {
+            return Ok(None);
+        }
+        D::decode(data).map(Some)
     }
 }
-impl<D: Decode + Default, const N: usize> Decode for [D; N] {
+/// Decodes `N` elements in order.
+///
+/// If an element fails to decode, decoding stops there and the error is wrapped in
+/// [`DecodeError::ArrayElement`] with the element's index. The bytes read up to that point
+/// can't be given back to an arbitrary iterator, so use [`rewind_on_error`] to leave the input
+/// at the start of the array on failure.
+impl<D: Decode, const N: usize> Decode for [D; N] {
     fn decode(data: impl IntoIterator<Item = u8>) -> Result<Self, DecodeError> {
         let mut data = data.into_iter();
-        let results: [_; N] = std::array::from_fn(move |_| D::decode(&mut data));
-        let mut decoded = Vec::new();
-        for result in results.into_iter() {
-            match result {
-                Ok(d) => decoded.push(d),
-                Err(e) => return Err(e),
+        let mut decoded = Vec::with_capacity(N);
+        for index in 0..N {
+            let element = D::decode(&mut data).map_err(|error| DecodeError::ArrayElement {
+                index,
+                error: Box::new(error),
+            })?;
+            decoded.push(element);
+        }
+
+        let Ok(array) = decoded.try_into() else {
+            unreachable!("exactly N elements were decoded");
+        };
+        Ok(array)
+    }
+}
+
+macro_rules! impl_tuple_decode {
+    ($($ty:ident),+) => {
+        impl<$($ty: Decode),+> Decode for ($($ty,)+) {
+            fn decode(data: impl IntoIterator<Item = u8>) -> Result<Self, DecodeError> {
+                let mut data = data.into_iter();
+                Ok(($(<$ty>::decode(&mut data)?,)+))
             }
         }
-        let mut decoded_array = std::array::from_fn(|_| D::default());
-        decoded_array
-            .iter_mut()
-            .zip(decoded)
-            .for_each(|(a, b)| *a = b);
+    };
+}
+impl_tuple_decode!(A);
+impl_tuple_decode!(A, B);
+impl_tuple_decode!(A, B, C);
+impl_tuple_decode!(A, B, C, D);
+impl_tuple_decode!(A, B, C, D, E);
+impl_tuple_decode!(A, B, C, D, E, F);
+impl_tuple_decode!(A, B, C, D, E, F, G);
+impl_tuple_decode!(A, B, C, D, E, F, G, H);
+
+/// Reads `N` raw bytes.
+///
+/// Unlike decoding a `[u8; N]`, running out of bytes is reported as a plain
+/// [`DecodeError::PacketTooShort`] rather than the index of the missing byte.
+pub fn bytes<const N: usize>(data: impl IntoIterator<Item = u8>) -> Result<[u8; N], DecodeError> {
+    let mut data = data.into_iter();
+    let mut bytes = [0; N];
+    for byte in &mut bytes {
+        *byte = data.next().ok_or(DecodeError::PacketTooShort)?;
+    }
+    Ok(bytes)
+}
+
+/// Decodes a `T`, leaving `data` where it started if decoding fails.
+///
+/// On success, `data` is advanced past the decoded value as usual.
+pub fn rewind_on_error<T: Decode, I: Iterator<Item = u8> + Clone>(
+    data: &mut I,
+) -> Result<T, DecodeError> {
+    let mut attempt = data.clone();
+    let decoded = T::decode(&mut attempt)?;
+    *data = attempt;
+    Ok(decoded)
+}
 
-        Ok(decoded_array)
+/// Skips `N` bytes of padding or unknown data.
+///
+/// Fails with [`DecodeError::PacketTooShort`] if fewer than `N` bytes are left.
+pub fn pad<const N: usize>(data: &mut impl Iterator<Item = u8>) -> Result<(), DecodeError> {
+    for _ in 0..N {
+        data.next().ok_or(DecodeError::PacketTooShort)?;
     }
+    Ok(())
 }
 
 impl<T: Decode> SizedDecode for Vec<T> {
@@ -123,3 +205,70 @@ impl<T: Decode> SizedDecode for Vec<T> {
         Ok(vec)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{bytes, pad, rewind_on_error, Decode, DecodeError};
+    use crate::encode::Encode;
+
+    #[test]
+    fn tuple() {
+        assert_eq!(
+            <(u8, u16, [u8; 2])>::decode([1, 0x34, 0x12, 5, 6]),
+            Ok((1, 0x1234, [5, 6]))
+        );
+        assert_eq!(
+            <(u8, u16)>::decode([1, 0x34]),
+            Err(DecodeError::PacketTooShort)
+        );
+    }
+
+    #[test]
+    fn option() {
+        assert_eq!(Option::<u16>::decode([]), Ok(None));
+        assert_eq!(Option::<u16>::decode([0x34, 0x12]), Ok(Some(0x1234)));
+        assert_eq!(
+            Option::<u16>::decode([0x34]),
+            Err(DecodeError::PacketTooShort)
+        );
+    }
+
+    #[test]
+    fn array() {
+        let encoded = [[1u8, 2], [3, 4]].encode().unwrap();
+        assert_eq!(encoded, [1, 2, 3, 4]);
+        assert_eq!(<[[u8; 2]; 2]>::decode(encoded), Ok([[1, 2], [3, 4]]));
+        assert_eq!(<[u16; 2]>::decode([1, 0, 2, 0]), Ok([1, 2]));
+        assert_eq!(<[u16; 0]>::decode([]), Ok([]));
+        assert_eq!(
+            <[u16; 3]>::decode([1, 0, 2, 0, 3]),
+            Err(DecodeError::ArrayElement {
+                index: 2,
+                error: Box::new(DecodeError::PacketTooShort),
+            })
+        );
+        assert_eq!(bytes::<3>([1, 2]), Err(DecodeError::PacketTooShort));
+    }
+
+    #[test]
+    fn array_rewind() {
+        let data = [1, 0, 2, 0, 3];
+        let mut cursor = data.into_iter();
+        assert!(matches!(
+            rewind_on_error::<[u16; 3], _>(&mut cursor),
+            Err(DecodeError::ArrayElement { index: 2, .. })
+        ));
+        assert_eq!(cursor.as_slice(), data);
+
+        assert_eq!(rewind_on_error::<[u16; 2], _>(&mut cursor), Ok([1, 2]));
+        assert_eq!(cursor.as_slice(), [3]);
+    }
+
+    #[test]
+    fn padding() {
+        let mut data = [1, 2, 3].into_iter();
+        assert_eq!(pad::<2>(&mut data), Ok(()));
+        assert_eq!(u8::decode(&mut data), Ok(3));
+        assert_eq!(pad::<1>(&mut data), Err(DecodeError::PacketTooShort));
+    }
+}
@@ -6,6 +6,8 @@ pub enum EncodeError {
     StringTooLong,
     #[error("Value too large for variable length u16")]
     VarShortTooLarge,
+    #[error("Too many items to fit in the length prefix")]
+    CountTooLarge,
 }
 
 /// A trait that allows for encoding a structure into a byte sequence.
@@ -24,6 +26,41 @@ impl Encode for () {
         Ok(Vec::new())
     }
 }
+impl Encode for u8 {
+    fn encode(&self) -> Result<Vec<u8>, EncodeError> {
+        Ok(vec![*self])
+    }
+}
+macro_rules! impl_tuple_encode {
+    ($($ty:ident $index:tt),+) => {
+        impl<$($ty: Encode),+> Encode for ($($ty,)+) {
+            fn encode(&self) -> Result<Vec<u8>, EncodeError> {
+                let mut encoded = Vec::new();
+                $(encoded.extend(self.$index.encode()?);)+
+                Ok(encoded)
+            }
+        }
+    };
+}
+impl_tuple_encode!(A 0);
+impl_tuple_encode!(A 0, B 1);
+impl_tuple_encode!(A 0, B 1, C 2);
+impl_tuple_encode!(A 0, B 1, C 2, D 3);
+impl_tuple_encode!(A 0, B 1, C 2, D 3, E 4);
+impl_tuple_encode!(A 0, B 1, C 2, D 3, E 4, F 5);
+impl_tuple_encode!(A 0, B 1, C 2, D 3, E 4, F 5, G 6);
+impl_tuple_encode!(A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7);
+
+impl<T: Encode, const N: usize> Encode for [T; N] {
+    fn encode(&self) -> Result<Vec<u8>, EncodeError> {
+        let mut encoded = Vec::new();
+        for item in self {
+            encoded.extend(item.encode()?);
+        }
+        Ok(encoded)
+    }
+}
+
 impl Encode for Vec<u8> {
     fn encode(&self) -> Result<Vec<u8>, EncodeError> {
         Ok(self.clone())
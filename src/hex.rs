@@ -0,0 +1,94 @@
+//! Compact hex formatting of packet bytes for logging.
+//!
+//! File transfers send kilobytes of data per packet, so logging every byte makes logs huge and
+//! unreadable. [`HexPreview`] only prints the start and end of long buffers. Full dumps can be
+//! turned back on with [`set_full_dump`] or by setting the `VEX_V5_SERIAL_FULL_DUMP` environment
+//! variable to `1`.
+
+use std::{
+    fmt,
+    sync::atomic::{AtomicU8, Ordering},
+};
+
+/// Environment variable that enables full dumps when set to `1` or `true`.
+pub const FULL_DUMP_ENV: &str = "VEX_V5_SERIAL_FULL_DUMP";
+
+/// Number of bytes shown at the start of a truncated buffer.
+const PREVIEW_HEAD: usize = 16;
+/// Number of bytes shown at the end of a truncated buffer.
+const PREVIEW_TAIL: usize = 8;
+
+const UNSET: u8 = 0;
+const TRUNCATED: u8 = 1;
+const FULL: u8 = 2;
+
+static FULL_DUMP: AtomicU8 = AtomicU8::new(UNSET);
+
+/// Sets whether [`HexPreview`] prints every byte instead of a truncated preview.
+///
+/// This overrides the `VEX_V5_SERIAL_FULL_DUMP` environment variable.
+pub fn set_full_dump(enabled: bool) {
+    FULL_DUMP.store(if enabled { FULL } else { TRUNCATED }, Ordering::Relaxed);
+}
+
+/// Returns whether [`HexPreview`] prints every byte instead of a truncated preview.
+pub fn full_dump() -> bool {
+    match FULL_DUMP.load(Ordering::Relaxed) {
+        UNSET => {
+            let enabled = std::env::var(FULL_DUMP_ENV)
+                .is_ok_and(|value| value == "1" || value.eq_ignore_ascii_case("true"));
+            set_full_dump(enabled);
+            enabled
+        }
+        state => state == FULL,
+    }
+}
+
+/// Formats bytes as hex, truncating long buffers to their first and last few bytes.
+#[derive(Clone, Copy)]
+pub struct HexPreview<'a>(pub &'a [u8]);
+
+impl fmt::Debug for HexPreview<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let bytes = self.0;
+        if bytes.len() <= PREVIEW_HEAD + PREVIEW_TAIL || full_dump() {
+            return write!(f, "{:x?}", bytes);
+        }
+
+        write!(
+            f,
+            "{:x?} .. {:x?} ({} bytes)",
+            &bytes[..PREVIEW_HEAD],
+            &bytes[bytes.len() - PREVIEW_TAIL..],
+            bytes.len()
+        )
+    }
+}
+
+impl fmt::Display for HexPreview<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{set_full_dump, HexPreview};
+
+    #[test]
+    fn preview() {
+        assert_eq!(format!("{:?}", HexPreview(&[0xAA, 0x55])), "[aa, 55]");
+
+        let bytes: Vec<u8> = (0..=0xFF).collect();
+        set_full_dump(false);
+        assert_eq!(
+            format!("{:?}", HexPreview(&bytes)),
+            "[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, a, b, c, d, e, f] .. \
+             [f8, f9, fa, fb, fc, fd, fe, ff] (256 bytes)"
+        );
+
+        set_full_dump(true);
+        assert_eq!(format!("{:?}", HexPreview(&bytes)), format!("{:x?}", bytes));
+        set_full_dump(false);
+    }
+}
@@ -6,19 +6,27 @@
 //!
 //! Because manually sending and receiving packets is a chore, this library also provides high level [`Command`](commands::Command)s.
 //! These commands provide easier ways to perform complicated tasks, such as uploading a program.
+//! The [`prelude`] re-exports them along with everything else needed to use them.
+//!
+//! With `default-features = false`, only the packet codec and command types are built.
+//! Transports are enabled with the `serial` and `bluetooth` features, while the `compression`
+//! and `ini` features enable the gzip compression and program config used by program uploads.
 
 mod choice;
 
+pub mod array;
+pub mod commands;
+pub mod connection;
 pub mod crc;
 pub mod decode;
 pub mod encode;
+pub mod hex;
 pub mod packets;
+pub mod prelude;
+#[cfg(feature = "serial")]
+pub mod simple;
 pub mod string;
 pub mod timestamp;
+pub mod user_channel;
 pub mod varint;
 pub mod version;
-
-#[cfg(feature = "connection")]
-pub mod commands;
-#[cfg(feature = "connection")]
-pub mod connection;
@@ -1,4 +1,8 @@
-use super::cdc2::{Cdc2CommandPacket, Cdc2ReplyPacket};
+use super::{
+    cdc2::{Cdc2CommandPacket, Cdc2ReplyPacket},
+    cdc_command,
+};
 
 pub type ScreenCapturePacket = Cdc2CommandPacket<86, 40, ()>;
 pub type ScreenCaptureReplyPacket = Cdc2ReplyPacket<86, 40, ()>;
+cdc_command!(ScreenCapturePacket => ScreenCaptureReplyPacket);
@@ -1,7 +1,7 @@
 use std::fmt::Debug;
 
 use crate::{
-    connection, decode::{Decode, DecodeError}, encode::{Encode, EncodeError}, varint::VarU16
+    connection, crc::VEX_CRC16, decode::{self, Decode, DecodeError}, encode::{Encode, EncodeError}, varint::VarU16
 };
 
 use super::{DEVICE_BOUND_HEADER, HOST_BOUND_HEADER};
@@ -36,7 +36,7 @@ impl<const ID: u8, P: Encode> Encode for CdcCommandPacket<ID, P> {
 
         // We only encode the payload size if there is a payload
         if !payload_bytes.is_empty() {
-            let size = VarU16::new(payload_bytes.len() as _);
+            let size = VarU16::try_from_len(payload_bytes.len())?;
             encoded.extend(size.encode()?);
             encoded.extend(payload_bytes);
         }
@@ -57,7 +57,11 @@ impl<const ID: u8, P: Encode + Clone> Clone for CdcCommandPacket<ID, P> {
 /// CDC (Simple) Command Reply Packet
 ///
 /// Encodes a reply payload to a [`CdcCommandPacket`] for a given ID.
-pub struct CdcReplyPacket<const ID: u8, P: Decode> {
+///
+/// Most simple replies carry no checksum. Replies that end with a big-endian CRC16
+/// of the whole frame (included in the payload size) should set `CRC16` to `true`,
+/// which makes decoding fail with [`DecodeError::Checksum`] if the frame is corrupted.
+pub struct CdcReplyPacket<const ID: u8, P: Decode, const CRC16: bool = false> {
     /// Host-bound Packet Header
     ///
     /// This must be `Self::HEADER` or `[0xAA, 0x55]`.
@@ -72,10 +76,11 @@ pub struct CdcReplyPacket<const ID: u8, P: Decode> {
     pub payload: P,
 }
 
-impl<const ID: u8, P: Decode> Decode for CdcReplyPacket<ID, P> {
+impl<const ID: u8, P: Decode, const CRC16: bool> Decode for CdcReplyPacket<ID, P, CRC16> {
     fn decode(data: impl IntoIterator<Item = u8>) -> Result<Self, DecodeError> {
-        let mut data = data.into_iter();
-        let header = Decode::decode(&mut data)?;
+        let frame = data.into_iter().collect::<Vec<_>>();
+        let mut data = frame.iter().copied();
+        let header = decode::bytes(&mut data)?;
         if header != HOST_BOUND_HEADER {
             return Err(DecodeError::InvalidHeader);
         }
@@ -84,7 +89,23 @@ impl<const ID: u8, P: Decode> Decode for CdcReplyPacket<ID, P> {
             return Err(DecodeError::InvalidHeader);
         }
         let payload_size = VarU16::decode(&mut data)?.into_inner();
-        let payload = P::decode(data.take(payload_size as usize))?;
+
+        if CRC16 {
+            // The checksum covers everything up to and including itself, so a valid
+            // frame always has a remainder of zero.
+            let frame_len = frame.len() - data.len() + payload_size as usize;
+            let frame = frame.get(..frame_len).ok_or(DecodeError::PacketTooShort)?;
+            if VEX_CRC16.checksum(frame) != 0 {
+                return Err(DecodeError::Checksum);
+            }
+        }
+
+        let payload = match P::decode(data.take(payload_size as usize)) {
+            Err(DecodeError::PacketTooShort) if payload_size == 0 => {
+                return Err(DecodeError::EmptyPayload);
+            }
+            payload => payload?,
+        };
 
         Ok(Self {
             header,
@@ -94,7 +115,9 @@ impl<const ID: u8, P: Decode> Decode for CdcReplyPacket<ID, P> {
     }
 }
 
-impl<const ID: u8, P: Decode> connection::CheckHeader for CdcReplyPacket<ID, P> {
+impl<const ID: u8, P: Decode, const CRC16: bool> connection::CheckHeader
+    for CdcReplyPacket<ID, P, CRC16>
+{
     fn has_valid_header(data: impl IntoIterator<Item = u8>) -> bool {
         let mut data = data.into_iter();
         if <[u8; 2] as Decode>::decode(&mut data).map(|header| header != HOST_BOUND_HEADER).unwrap_or(true) {
@@ -109,7 +132,7 @@ impl<const ID: u8, P: Decode> connection::CheckHeader for CdcReplyPacket<ID, P>
     }
 }
 
-impl<const ID: u8, P: Decode + Debug> Debug for CdcReplyPacket<ID, P> {
+impl<const ID: u8, P: Decode + Debug, const CRC16: bool> Debug for CdcReplyPacket<ID, P, CRC16> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("HostBoundPacket")
             .field("header", &self.header)
@@ -119,7 +142,7 @@ impl<const ID: u8, P: Decode + Debug> Debug for CdcReplyPacket<ID, P> {
     }
 }
 
-impl<const ID: u8, P: Decode + Clone> Clone for CdcReplyPacket<ID, P> {
+impl<const ID: u8, P: Decode + Clone, const CRC16: bool> Clone for CdcReplyPacket<ID, P, CRC16> {
     fn clone(&self) -> Self {
         Self {
             header: self.header,
@@ -131,12 +154,71 @@ impl<const ID: u8, P: Decode + Clone> Clone for CdcReplyPacket<ID, P> {
 
 #[cfg(test)]
 mod tests {
+    use crate::packets::cdc2::{Cdc2Ack, Cdc2ReplyMeta};
     use crate::packets::file::ReadFileReplyPacket;
+    use crate::packets::system::GetSystemVersionReplyPayload;
     use crate::connection::CheckHeader;
+    use crate::crc::VEX_CRC16;
+    use crate::decode::{Decode, DecodeError};
+
+    use super::CdcReplyPacket;
 
     #[test]
     fn has_valid_header_success() {
         let data: &[u8] = &[0xaa, 0x55, 0x56, 0x7, 0x14, 0xd4, 0xff, 0xff, 0xff, 0xca, 0x3d];
         assert!(ReadFileReplyPacket::has_valid_header(data.iter().cloned()));
     }
+
+    #[test]
+    fn checked_reply_success() {
+        let data: &[u8] = &[0xaa, 0x55, 0x56, 0x7, 0x14, 0xd4, 0xff, 0xff, 0xff, 0xca, 0x3d];
+        assert!(ReadFileReplyPacket::decode(data.iter().cloned()).is_ok());
+    }
+
+    #[test]
+    fn read_reply_meta() {
+        let mut data = vec![0xaa, 0x55, 0x56, 0x0b, 0x14, 0x00, 0x00, 0x80, 0x03, 1, 2, 3, 4];
+        let crc = VEX_CRC16.checksum(&data);
+        data.extend(crc.to_be_bytes());
+
+        let reply = ReadFileReplyPacket::decode(data).unwrap();
+        assert_eq!(reply.ack(), Cdc2Ack::Ack);
+        assert_eq!(reply.declared_size(), 11);
+        assert_eq!(reply.crc(), crc);
+    }
+
+    #[test]
+    fn checked_reply_corrupted() {
+        let data: &[u8] = &[0xaa, 0x55, 0x56, 0x7, 0x14, 0xd5, 0xff, 0xff, 0xff, 0xca, 0x3d];
+        assert_eq!(
+            ReadFileReplyPacket::decode(data.iter().cloned()).unwrap_err(),
+            DecodeError::Checksum
+        );
+    }
+
+    #[test]
+    fn empty_system_version() {
+        let data: &[u8] = &[0xaa, 0x55, 0xa4, 0x00];
+        assert_eq!(
+            CdcReplyPacket::<164, GetSystemVersionReplyPayload>::decode(data.iter().cloned())
+                .err(),
+            Some(DecodeError::EmptyPayload)
+        );
+    }
+
+    #[test]
+    fn checked_system_version_corrupted() {
+        type CheckedVersionReplyPacket = CdcReplyPacket<164, GetSystemVersionReplyPayload, true>;
+
+        let mut data = vec![0xaa, 0x55, 0xa4, 0x09, 0x01, 0x01, 0x04, 0x00, 0x00, 0x10, 0x00];
+        data.extend(VEX_CRC16.checksum(&data).to_be_bytes());
+        assert!(CheckedVersionReplyPacket::decode(data.clone()).is_ok());
+
+        // Flip a bit in the version's build number.
+        data[6] ^= 0x01;
+        assert_eq!(
+            CheckedVersionReplyPacket::decode(data).unwrap_err(),
+            DecodeError::Checksum
+        );
+    }
 }
@@ -11,115 +11,147 @@ use crate::{
 };
 
 use super::{DEVICE_BOUND_HEADER, HOST_BOUND_HEADER};
-use crate::decode::{Decode, DecodeError};
+use crate::decode::{self, Decode, DecodeError};
 
 /// CDC2 Packet Acknowledgement Codes
-#[repr(u8)]
+///
+/// Codes that this crate doesn't know about decode to [`Cdc2Ack::Unknown`] rather than failing,
+/// so that a NACK added by newer firmware is reported as a failed command instead of a
+/// malformed reply.
+#[non_exhaustive]
 #[derive(Debug, Clone, Copy, Eq, PartialEq, Error)]
 pub enum Cdc2Ack {
     /// Acknowledges that a packet has been received successfully.
     #[error("Packet was recieved successfully. Wait, how'd this happen??")]
-    Ack = 0x76,
+    Ack,
 
     /// A general negative-acknowledgement (NACK) that is sometimes received.
     #[error("V5 device sent back a general negative-acknowledgement.")]
-    Nack = 0xFF,
+    Nack,
 
     /// Returned by the brain when a CDC2 packet's CRC Checksum does not validate.
     #[error("Packet CRC checksum did not validate. (NACK 0xCE)")]
-    NackPacketCrc = 0xCE,
+    NackPacketCrc,
 
     /// Returned by the brain when a packet's payload is of unexpected length (too short or too long).
     #[error("Packet payload length was either too short or too long. (NACK 0xD0)")]
-    NackPacketLength = 0xD0,
+    NackPacketLength,
 
     /// Returned by the brain when we attempt to transfer too much data.
     #[error("Attempted to transfer too much data. (NACK 0xD1)")]
-    NackTransferSize = 0xD1,
+    NackTransferSize,
 
     /// Returned by the brain when a program's CRC checksum fails.
     #[error("Program CRC checksum did not validate. (NACK 0xD2)")]
-    NackProgramCrc = 0xD2,
+    NackProgramCrc,
 
     /// Returned by the brain when there is an error with the program file.
     #[error("Invalid program file. (NACK 0xD3)")]
-    NackProgramFile = 0xD3,
+    NackProgramFile,
 
     /// Returned by the brain when we fail to initialize a file transfer before beginning file operations.
     #[error(
         "Attempted to perform a file transfer operation before one was initialized. (NACK 0xD4)"
     )]
-    NackUninitializedTransfer = 0xD4,
+    NackUninitializedTransfer,
 
     /// Returned by the brain when we initialize a file transfer incorrectly.
     #[error("File transfer was initialized incorrectly. (NACK 0xD5)")]
-    NackInvalidInitialization = 0xD5,
+    NackInvalidInitialization,
 
     /// Returned by the brain when we fail to pad a transfer to a four byte boundary.
     #[error("File transfer was initialized incorrectly. (NACK 0xD6)")]
-    NackAlignment = 0xD6,
+    NackAlignment,
 
     /// Returned by the brain when the addr on a file transfer does not match
     #[error("File transfer address did not match. (NACK 0xD7)")]
-    NackAddress = 0xD7,
+    NackAddress,
 
     /// Returned by the brain when the download length on a file transfer does not match
     #[error("File transfer download length did not match. (NACK 0xD8)")]
-    NackIncomplete = 0xD8,
+    NackIncomplete,
 
     /// Returned by the brain when a file transfer attempts to access a directory that does not exist
     #[error("Attempted to transfer file to a directory that does not exist. (NACK 0xD9)")]
-    NackNoDirectory = 0xD9,
+    NackNoDirectory,
 
     /// Returned when the limit for user files has been reached
     #[error("Limit for user files has been reached. (NACK 0xDA)")]
-    NackMaxUserFiles = 0xDA,
+    NackMaxUserFiles,
 
     /// Returned when a file already exists and we did not specify overwrite when initializing the transfer
     #[error("File already exists. (NACK 0xDB)")]
-    NackFileAlreadyExists = 0xDB,
+    NackFileAlreadyExists,
 
     /// Returned when the filesystem is full.
     #[error("Filesystem storage is full. (NACK 0xDC)")]
-    NackFileStorageFull = 0xDC,
+    NackFileStorageFull,
 
     /// Packet timed out.
     #[error("Packet timed out.")]
-    Timeout = 0x00,
+    Timeout,
 
     /// Internal Write Error.
     #[error("Internal write error occurred.")]
-    WriteError = 0x01,
+    WriteError,
+
+    /// A code that this crate doesn't know about.
+    #[error("V5 device sent back an unknown negative-acknowledgement. (NACK 0x{0:02X})")]
+    Unknown(u8),
+}
+impl Cdc2Ack {
+    /// Returns the raw byte used to identify this code on the wire.
+    pub fn value(&self) -> u8 {
+        match self {
+            Self::Ack => 0x76,
+            Self::Nack => 0xFF,
+            Self::NackPacketCrc => 0xCE,
+            Self::NackPacketLength => 0xD0,
+            Self::NackTransferSize => 0xD1,
+            Self::NackProgramCrc => 0xD2,
+            Self::NackProgramFile => 0xD3,
+            Self::NackUninitializedTransfer => 0xD4,
+            Self::NackInvalidInitialization => 0xD5,
+            Self::NackAlignment => 0xD6,
+            Self::NackAddress => 0xD7,
+            Self::NackIncomplete => 0xD8,
+            Self::NackNoDirectory => 0xD9,
+            Self::NackMaxUserFiles => 0xDA,
+            Self::NackFileAlreadyExists => 0xDB,
+            Self::NackFileStorageFull => 0xDC,
+            Self::Timeout => 0x00,
+            Self::WriteError => 0x01,
+            Self::Unknown(value) => *value,
+        }
+    }
 }
 impl Decode for Cdc2Ack {
     fn decode(data: impl IntoIterator<Item = u8>) -> Result<Self, DecodeError> {
-        let this = u8::decode(data)?;
-        match this {
-            0x76 => Ok(Self::Ack),
-            0xFF => Ok(Self::Nack),
-            0xCE => Ok(Self::NackPacketCrc),
-            0xD0 => Ok(Self::NackPacketLength),
-            0xD1 => Ok(Self::NackTransferSize),
-            0xD2 => Ok(Self::NackProgramCrc),
-            0xD3 => Ok(Self::NackProgramFile),
-            0xD4 => Ok(Self::NackUninitializedTransfer),
-            0xD5 => Ok(Self::NackInvalidInitialization),
-            0xD6 => Ok(Self::NackAlignment),
-            0xD7 => Ok(Self::NackAddress),
-            0xD8 => Ok(Self::NackIncomplete),
-            0xD9 => Ok(Self::NackNoDirectory),
-            0xDA => Ok(Self::NackMaxUserFiles),
-            0xDB => Ok(Self::NackFileAlreadyExists),
-            0xDC => Ok(Self::NackFileStorageFull),
-            0x00 => Ok(Self::Timeout),
-            0x01 => Ok(Self::WriteError),
-            v => Err(DecodeError::UnexpectedValue {
-                value: v,
-                expected: &[
-                    0x76, 0xFF, 0xCE, 0xD0, 0xD1, 0xD2, 0xD3, 0xD4, 0xD5, 0xD6, 0xD7, 0xD8, 0xD9,
-                    0xDA, 0xDB, 0xDC, 0x00, 0x01,
-                ],
-            }),
+        Ok(Self::from(u8::decode(data)?))
+    }
+}
+impl From<u8> for Cdc2Ack {
+    fn from(value: u8) -> Self {
+        match value {
+            0x76 => Self::Ack,
+            0xFF => Self::Nack,
+            0xCE => Self::NackPacketCrc,
+            0xD0 => Self::NackPacketLength,
+            0xD1 => Self::NackTransferSize,
+            0xD2 => Self::NackProgramCrc,
+            0xD3 => Self::NackProgramFile,
+            0xD4 => Self::NackUninitializedTransfer,
+            0xD5 => Self::NackInvalidInitialization,
+            0xD6 => Self::NackAlignment,
+            0xD7 => Self::NackAddress,
+            0xD8 => Self::NackIncomplete,
+            0xD9 => Self::NackNoDirectory,
+            0xDA => Self::NackMaxUserFiles,
+            0xDB => Self::NackFileAlreadyExists,
+            0xDC => Self::NackFileStorageFull,
+            0x00 => Self::Timeout,
+            0x01 => Self::WriteError,
+            v => Self::Unknown(v),
         }
     }
 }
@@ -154,7 +186,7 @@ impl<const ID: u8, const EXT_ID: u8, P: Encode> Encode for Cdc2CommandPacket<ID,
 
         // Push the payload size and encoded bytes
         let payload_bytes = self.payload.encode()?;
-        let payload_size = VarU16::new(payload_bytes.len() as u16);
+        let payload_size = VarU16::try_from_len(payload_bytes.len())?;
         encoded.extend(payload_size.encode()?);
         encoded.extend(payload_bytes);
 
@@ -179,6 +211,20 @@ impl<P: Encode + Debug, const ID: u8, const EXTENDED_ID: u8> Debug
     }
 }
 
+/// The acknowledgement, size, and checksum of a CDC2 reply, whichever form it takes.
+///
+/// Most replies are [`Cdc2ReplyPacket`]s, but some (like
+/// [`ReadFileReplyPacket`](super::file::ReadFileReplyPacket)) carry their acknowledgement and
+/// CRC16 inside their payload. This lets logging and validation code treat them the same way.
+pub trait Cdc2ReplyMeta {
+    /// The acknowledgement code sent by the device.
+    fn ack(&self) -> Cdc2Ack;
+    /// The payload size from the reply's header.
+    fn declared_size(&self) -> u16;
+    /// The CRC16 at the end of the reply, read as big-endian like it was computed.
+    fn crc(&self) -> u16;
+}
+
 pub struct Cdc2ReplyPacket<const ID: u8, const EXT_ID: u8, P: SizedDecode> {
     pub header: [u8; 2],
     pub ack: Cdc2Ack,
@@ -197,10 +243,27 @@ impl<const ID: u8, const EXT_ID: u8, P: SizedDecode> Cdc2ReplyPacket<ID, EXT_ID,
     }
 }
 
+impl<const ID: u8, const EXT_ID: u8, P: SizedDecode> Cdc2ReplyMeta
+    for Cdc2ReplyPacket<ID, EXT_ID, P>
+{
+    fn ack(&self) -> Cdc2Ack {
+        self.ack
+    }
+
+    fn declared_size(&self) -> u16 {
+        self.payload_size
+    }
+
+    fn crc(&self) -> u16 {
+        // The `crc` field is decoded as little-endian
+        self.crc.swap_bytes()
+    }
+}
+
 impl<const ID: u8, const EXT_ID: u8, P: SizedDecode> Decode for Cdc2ReplyPacket<ID, EXT_ID, P> {
     fn decode(data: impl IntoIterator<Item = u8>) -> Result<Self, DecodeError> {
         let mut data = data.into_iter();
-        let header = Decode::decode(&mut data)?;
+        let header = decode::bytes(&mut data)?;
         if header != HOST_BOUND_HEADER {
             return Err(DecodeError::InvalidHeader);
         }
@@ -219,7 +282,17 @@ impl<const ID: u8, const EXT_ID: u8, P: SizedDecode> Decode for Cdc2ReplyPacket<
 
         let ack = Cdc2Ack::decode(&mut data)?;
 
-        let payload = P::sized_decode(&mut data, payload_size)?;
+        // The payload size also counts the extended command ID, ack, and CRC
+        let payload_len = payload_size.saturating_sub(4);
+        let mut payload_data = (&mut data).take(payload_len as usize);
+        let payload = match P::sized_decode(&mut payload_data, payload_size) {
+            Err(DecodeError::PacketTooShort) if payload_len == 0 && ack == Cdc2Ack::Ack => {
+                return Err(DecodeError::EmptyPayload);
+            }
+            payload => payload?,
+        };
+        // Skip any fields added by newer firmware that the payload doesn't know about
+        payload_data.for_each(drop);
         let crc = u16::decode(&mut data)?;
 
         Ok(Self {
@@ -288,10 +361,227 @@ impl<const ID: u8, const EXT_ID: u8, P: Decode + Debug> Debug for Cdc2ReplyPacke
     }
 }
 
+/// A CDC2 command whose IDs are only known at runtime.
+///
+/// This is for tools that pass along or inspect packets without a type for each one, such as
+/// loggers and proxies. Decoding fails with [`DecodeError::Checksum`] if the frame's CRC16
+/// doesn't match.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RawCdc2Command {
+    pub cmd: u8,
+    pub ecmd: u8,
+    pub payload: Vec<u8>,
+}
+impl RawCdc2Command {
+    /// Encodes the payload of a typed packet.
+    pub fn from_packet<const ID: u8, const EXT_ID: u8, P: Encode>(
+        packet: &Cdc2CommandPacket<ID, EXT_ID, P>,
+    ) -> Result<Self, EncodeError> {
+        Ok(Self {
+            cmd: ID,
+            ecmd: EXT_ID,
+            payload: packet.payload.encode()?,
+        })
+    }
+
+    /// Decodes the payload as a typed packet.
+    ///
+    /// Fails with [`DecodeError::InvalidHeader`] if the IDs don't match the packet's.
+    pub fn to_packet<const ID: u8, const EXT_ID: u8, P: Encode + Decode>(
+        &self,
+    ) -> Result<Cdc2CommandPacket<ID, EXT_ID, P>, DecodeError> {
+        if (self.cmd, self.ecmd) != (ID, EXT_ID) {
+            return Err(DecodeError::InvalidHeader);
+        }
+        let payload = P::decode(self.payload.iter().copied())?;
+        Ok(Cdc2CommandPacket::new(payload))
+    }
+}
+impl Encode for RawCdc2Command {
+    fn encode(&self) -> Result<Vec<u8>, EncodeError> {
+        let mut encoded = DEVICE_BOUND_HEADER.to_vec();
+        encoded.extend([self.cmd, self.ecmd]);
+        encoded.extend(VarU16::try_from_len(self.payload.len())?.encode()?);
+        encoded.extend(&self.payload);
+        encoded.extend(VEX_CRC16.checksum(&encoded).to_be_bytes());
+        Ok(encoded)
+    }
+}
+impl Decode for RawCdc2Command {
+    fn decode(data: impl IntoIterator<Item = u8>) -> Result<Self, DecodeError> {
+        let frame = data.into_iter().collect::<Vec<_>>();
+        let mut data = frame.iter().copied();
+        let header: [u8; 4] = decode::bytes(&mut data)?;
+        if header != DEVICE_BOUND_HEADER {
+            return Err(DecodeError::InvalidHeader);
+        }
+        let cmd = u8::decode(&mut data)?;
+        let ecmd = u8::decode(&mut data)?;
+        let payload_size = VarU16::decode(&mut data)?.into_inner() as usize;
+
+        // The CRC16 follows the payload
+        let frame_len = frame.len() - data.len() + payload_size + 2;
+        let frame = frame.get(..frame_len).ok_or(DecodeError::PacketTooShort)?;
+        if VEX_CRC16.checksum(frame) != 0 {
+            return Err(DecodeError::Checksum);
+        }
+
+        Ok(Self {
+            cmd,
+            ecmd,
+            payload: data.take(payload_size).collect(),
+        })
+    }
+}
+
+/// A CDC2 reply whose IDs are only known at runtime, like [`RawCdc2Command`].
+///
+/// Unlike commands, replies with a bad CRC16 still decode, with `crc_ok` set to `false`, so
+/// that tools can show what was received. Encoding always writes the correct CRC16.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RawCdc2Reply {
+    pub cmd: u8,
+    pub ecmd: u8,
+    pub ack: Cdc2Ack,
+    pub payload: Vec<u8>,
+    /// Whether the frame's CRC16 matched when it was decoded.
+    pub crc_ok: bool,
+}
+impl RawCdc2Reply {
+    /// Encodes the payload of a typed packet.
+    pub fn from_packet<const ID: u8, const EXT_ID: u8, P: SizedDecode + Encode>(
+        packet: &Cdc2ReplyPacket<ID, EXT_ID, P>,
+    ) -> Result<Self, EncodeError> {
+        Ok(Self {
+            cmd: ID,
+            ecmd: EXT_ID,
+            ack: packet.ack,
+            payload: packet.payload.encode()?,
+            crc_ok: true,
+        })
+    }
+
+    /// Decodes the reply as a typed packet, whose CRC16 is the correct one even if `crc_ok`
+    /// is `false`.
+    ///
+    /// Fails with [`DecodeError::InvalidHeader`] if the IDs don't match the packet's.
+    pub fn to_packet<const ID: u8, const EXT_ID: u8, P: SizedDecode>(
+        &self,
+    ) -> Result<Cdc2ReplyPacket<ID, EXT_ID, P>, DecodeError> {
+        let frame = self.encode().map_err(|_| DecodeError::PacketTooShort)?;
+        Cdc2ReplyPacket::decode(frame)
+    }
+}
+impl Encode for RawCdc2Reply {
+    fn encode(&self) -> Result<Vec<u8>, EncodeError> {
+        let mut encoded = HOST_BOUND_HEADER.to_vec();
+        encoded.push(self.cmd);
+        // The payload size also counts the extended command ID, ack, and CRC
+        encoded.extend(VarU16::try_from_len(self.payload.len() + 4)?.encode()?);
+        encoded.extend([self.ecmd, self.ack.value()]);
+        encoded.extend(&self.payload);
+        encoded.extend(VEX_CRC16.checksum(&encoded).to_be_bytes());
+        Ok(encoded)
+    }
+}
+impl Decode for RawCdc2Reply {
+    fn decode(data: impl IntoIterator<Item = u8>) -> Result<Self, DecodeError> {
+        let frame = data.into_iter().collect::<Vec<_>>();
+        let mut data = frame.iter().copied();
+        let header: [u8; 2] = decode::bytes(&mut data)?;
+        if header != HOST_BOUND_HEADER {
+            return Err(DecodeError::InvalidHeader);
+        }
+        let cmd = u8::decode(&mut data)?;
+        let payload_size = VarU16::decode(&mut data)?.into_inner() as usize;
+        let frame_len = frame.len() - data.len() + payload_size;
+        let frame = frame.get(..frame_len).ok_or(DecodeError::PacketTooShort)?;
+
+        let ecmd = u8::decode(&mut data)?;
+        let ack = Cdc2Ack::decode(&mut data)?;
+        let payload = data.take(payload_size.saturating_sub(4)).collect();
+        Ok(Self {
+            cmd,
+            ecmd,
+            ack,
+            payload,
+            crc_ok: VEX_CRC16.checksum(frame) == 0,
+        })
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use super::{Cdc2Ack, Cdc2ReplyMeta, Cdc2ReplyPacket, RawCdc2Command, RawCdc2Reply};
     use crate::connection::CheckHeader;
-    use crate::packets::device::GetDeviceStatusReplyPacket;
+    use crate::decode::{Decode, DecodeError};
+    use crate::encode::Encode;
+    use crate::packets::device::{
+        GetDeviceStatusPacket, GetDeviceStatusReplyPacket, GetDeviceStatusReplyPayload,
+    };
+    use crate::packets::kv::ReadKeyValuePacket;
+    use crate::string::FixedString;
+
+    #[test]
+    fn trailing_payload_bytes() {
+        // A u16 payload followed by two bytes that a newer firmware might have added
+        let reply = Cdc2ReplyPacket::<0x56, 0x21, u16>::decode([
+            0xaa, 0x55, 0x56, 0x08, 0x21, 0x76, 0x34, 0x12, 0xff, 0xff, 0xab, 0xcd,
+        ])
+        .unwrap();
+        assert_eq!(reply.payload, 0x1234);
+        assert_eq!(reply.ack(), Cdc2Ack::Ack);
+        assert_eq!(reply.declared_size(), 8);
+        assert_eq!(reply.crc, 0xcdab);
+        assert_eq!(reply.crc(), 0xabcd);
+
+        // The payload can't read past its declared size into the CRC
+        assert_eq!(
+            Cdc2ReplyPacket::<0x56, 0x21, u16>::decode([
+                0xaa, 0x55, 0x56, 0x05, 0x21, 0x76, 0x34, 0xab, 0xcd,
+            ])
+            .err(),
+            Some(DecodeError::PacketTooShort)
+        );
+    }
+
+    #[test]
+    fn empty_payload() {
+        // An acknowledged reply with nothing after the ack
+        let empty = [0xaa, 0x55, 0x56, 0x04, 0x21, 0x76, 0xab, 0xcd];
+        assert_eq!(
+            Cdc2ReplyPacket::<0x56, 0x21, u16>::decode(empty).err(),
+            Some(DecodeError::EmptyPayload)
+        );
+        let reply = Cdc2ReplyPacket::<0x56, 0x21, Option<u16>>::decode(empty).unwrap();
+        assert_eq!(reply.payload, None);
+
+        // A payload cut short partway is still too short
+        assert_eq!(
+            Cdc2ReplyPacket::<0x56, 0x21, (u16, u16)>::decode([
+                0xaa, 0x55, 0x56, 0x06, 0x21, 0x76, 0x34, 0x12, 0xab, 0xcd,
+            ])
+            .err(),
+            Some(DecodeError::PacketTooShort)
+        );
+    }
+
+    #[test]
+    fn unknown_ack() {
+        for value in [0x76, 0xD4, 0x01] {
+            assert_eq!(Cdc2Ack::from(value).value(), value);
+        }
+        assert_eq!(Cdc2Ack::decode([0xDE]), Ok(Cdc2Ack::Unknown(0xDE)));
+        assert_eq!(Cdc2Ack::Unknown(0xDE).value(), 0xDE);
+        assert!(Cdc2Ack::Unknown(0xDE).to_string().contains("0xDE"));
+
+        // The reply still decodes, and reports the code as a failure
+        let reply = Cdc2ReplyPacket::<0x56, 0x21, ()>::decode([
+            0xaa, 0x55, 0x56, 0x04, 0x21, 0xde, 0xab, 0xcd,
+        ])
+        .unwrap();
+        assert_eq!(reply.try_into_inner(), Err(Cdc2Ack::Unknown(0xDE)));
+    }
 
     #[test]
     fn has_valid_header_success() {
@@ -303,4 +593,77 @@ mod tests {
             data.iter().cloned()
         ));
     }
+
+    #[test]
+    fn raw_command_round_trip() {
+        let packet = ReadKeyValuePacket::new(FixedString::new("teamnumber".to_string()).unwrap());
+        let encoded = packet.encode().unwrap();
+
+        let raw = RawCdc2Command::decode(encoded.clone()).unwrap();
+        assert_eq!((raw.cmd, raw.ecmd), (0x56, 0x2E));
+        assert_eq!(raw, RawCdc2Command::from_packet(&packet).unwrap());
+        assert_eq!(raw.encode().unwrap(), encoded);
+
+        let typed: ReadKeyValuePacket = raw.to_packet().unwrap();
+        assert_eq!(typed.encode().unwrap(), encoded);
+        assert_eq!(
+            raw.to_packet::<0x56, 0x21, ()>().err(),
+            Some(DecodeError::InvalidHeader)
+        );
+
+        let mut corrupted = encoded;
+        *corrupted.last_mut().unwrap() ^= 1;
+        assert_eq!(
+            RawCdc2Command::decode(corrupted).err(),
+            Some(DecodeError::Checksum)
+        );
+        let empty = GetDeviceStatusPacket::new(()).encode().unwrap();
+        assert_eq!(
+            RawCdc2Command::decode(empty.clone())
+                .unwrap()
+                .encode()
+                .unwrap(),
+            empty
+        );
+    }
+
+    #[test]
+    fn raw_reply_round_trip() {
+        let frame = vec![
+            0xaa, 0x55, 0x56, 0x15, 0x21, 0x76, 0x2, 0x16, 0xc, 0, 0xb, 0, 0x40, 0x1, 0x40, 0x17,
+            0xe, 0, 0x19, 0x1, 0x40, 0x6, 0x40, 0x23, 0x87,
+        ];
+        let raw = RawCdc2Reply::decode(frame.clone()).unwrap();
+        assert_eq!((raw.cmd, raw.ecmd, raw.ack), (0x56, 0x21, Cdc2Ack::Ack));
+        assert_eq!(raw.payload.len(), 17);
+        assert!(raw.crc_ok);
+        assert_eq!(raw.encode().unwrap(), frame);
+
+        let typed: GetDeviceStatusReplyPacket = raw.to_packet().unwrap();
+        let payload: GetDeviceStatusReplyPayload = typed.try_into_inner().unwrap();
+        assert_eq!(payload.devices.len(), 2);
+
+        // A corrupted reply still decodes, and is re-encoded with a correct CRC
+        let mut corrupted = frame.clone();
+        *corrupted.last_mut().unwrap() ^= 1;
+        let raw = RawCdc2Reply::decode(corrupted).unwrap();
+        assert!(!raw.crc_ok);
+        assert_eq!(raw.encode().unwrap(), frame);
+
+        // Typed replies with an encodable payload convert back to raw
+        let reply = Cdc2ReplyPacket::<0x56, 0x2F, u8>::decode(
+            RawCdc2Reply {
+                cmd: 0x56,
+                ecmd: 0x2F,
+                ack: Cdc2Ack::NackProgramCrc,
+                payload: vec![7],
+                crc_ok: true,
+            }
+            .encode()
+            .unwrap(),
+        )
+        .unwrap();
+        let raw = RawCdc2Reply::from_packet(&reply).unwrap();
+        assert_eq!((raw.ack, raw.payload), (Cdc2Ack::NackProgramCrc, vec![7]));
+    }
 }
@@ -1,29 +1,44 @@
-use super::cdc2::{Cdc2CommandPacket, Cdc2ReplyPacket};
+use super::{
+    cdc2::{Cdc2CommandPacket, Cdc2ReplyPacket},
+    cdc_command,
+    system::ProductType,
+};
 use crate::{
     decode::{Decode, DecodeError, SizedDecode},
     encode::{Encode, EncodeError},
-    string::FixedString,
+    version::Version,
 };
 
 pub type UserFifoPacket = Cdc2CommandPacket<86, 39, UserFifoPayload>;
 pub type UserFifoReplyPacket = Cdc2ReplyPacket<86, 39, UserFifoReplyPayload>;
+cdc_command!(UserFifoPacket => UserFifoReplyPacket);
+
+/// The number of bytes a [`UserFifoPacket`] adds around the bytes it writes.
+///
+/// This covers the packet header, command IDs, the two byte payload size used by full
+/// writes, the channel and write length, and the CRC.
+pub const USER_FIFO_WRITE_OVERHEAD: usize = 12;
+
+/// The largest number of bytes a single [`UserFifoPayload`] can write, limited by its one
+/// byte write length.
+pub const USER_FIFO_MAX_WRITE: usize = u8::MAX as usize;
 
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct UserFifoPayload {
     /// stdio channel is 1, other channels unknown.
     pub channel: u8,
 
-    /// Write (stdin) bytes.
-    pub write: Option<FixedString<224>>,
+    /// Write (stdin) bytes, up to [`USER_FIFO_MAX_WRITE`] of them.
+    pub write: Option<Vec<u8>>,
 }
 impl Encode for UserFifoPayload {
     fn encode(&self) -> Result<Vec<u8>, EncodeError> {
         let mut encoded = Vec::new();
         encoded.extend(self.channel.to_le_bytes());
         if let Some(write) = &self.write {
-            let encoded_write = write.encode()?;
-            encoded.extend((encoded_write.len() as u8).to_le_bytes());
-            encoded.extend(encoded_write);
+            let len = u8::try_from(write.len()).map_err(|_| EncodeError::CountTooLarge)?;
+            encoded.push(len);
+            encoded.extend(write);
         } else {
             encoded.extend([0]); // 0 write length
         }
@@ -74,3 +89,72 @@ impl SizedDecode for UserFifoReplyPayload {
         })
     }
 }
+
+/// Forces the controller's radio into a specific mode and channel.
+///
+/// This can recover a controller that is stuck trying to reconnect to a brain without
+/// power cycling it, but sending an invalid mode or channel can temporarily kill the link.
+pub type ForceControllerRadioPacket = Cdc2CommandPacket<88, 63, ForceControllerRadioPayload>;
+pub type ForceControllerRadioReplyPacket = Cdc2ReplyPacket<88, 63, ()>;
+cdc_command!(ForceControllerRadioPacket => ForceControllerRadioReplyPacket);
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct ForceControllerRadioPayload {
+    pub mode: ControllerRadioMode,
+    pub channel: u8,
+}
+impl Encode for ForceControllerRadioPayload {
+    fn encode(&self) -> Result<Vec<u8>, EncodeError> {
+        Ok(vec![self.mode.0, self.channel])
+    }
+}
+
+/// Switches the controller's radio into a different mode.
+///
+/// The controller's screen changes to show the new mode, and modes other than the one used to
+/// drive a robot drop the controller's VEXnet link to the brain until it's switched back.
+pub type ControllerRadioModePacket = Cdc2CommandPacket<88, 65, ControllerRadioMode>;
+pub type ControllerRadioModeReplyPacket = Cdc2ReplyPacket<88, 65, ()>;
+cdc_command!(ControllerRadioModePacket => ControllerRadioModeReplyPacket);
+
+/// A controller radio mode, such as VEXnet, Bluetooth pairing, or a download-oriented mode.
+///
+/// (RESEARCH NEEDED) The values of the individual modes haven't been captured yet, so this
+/// holds the raw mode byte sent to the controller.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub struct ControllerRadioMode(pub u8);
+impl Encode for ControllerRadioMode {
+    fn encode(&self) -> Result<Vec<u8>, EncodeError> {
+        Ok(vec![self.0])
+    }
+}
+
+/// Tells the controller which firmware version the host expects it to be running.
+///
+/// This is sent during the official controller update flow. Without it, the controller
+/// prompts the user on-screen while it is being updated.
+pub type ControllerVersionExpectPacket =
+    Cdc2CommandPacket<88, 73, ControllerVersionExpectPayload>;
+pub type ControllerVersionExpectReplyPacket = Cdc2ReplyPacket<88, 73, ()>;
+cdc_command!(ControllerVersionExpectPacket => ControllerVersionExpectReplyPacket);
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct ControllerVersionExpectPayload {
+    pub version: Version,
+    pub product: ProductType,
+}
+impl Encode for ControllerVersionExpectPayload {
+    fn encode(&self) -> Result<Vec<u8>, EncodeError> {
+        let mut encoded = self.version.encode()?;
+        encoded.push(self.product.value());
+        Ok(encoded)
+    }
+}
+impl Decode for ControllerVersionExpectPayload {
+    fn decode(data: impl IntoIterator<Item = u8>) -> Result<Self, DecodeError> {
+        let mut data = data.into_iter();
+        let version = Version::decode(&mut data)?;
+        let product = ProductType::from(u8::decode(&mut data)?);
+        Ok(Self { version, product })
+    }
+}
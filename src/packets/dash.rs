@@ -1,6 +1,20 @@
-use super::cdc2::{Cdc2CommandPacket, Cdc2ReplyPacket};
-use crate::encode::{Encode, EncodeError};
+use super::{
+    cdc2::{Cdc2CommandPacket, Cdc2ReplyPacket},
+    cdc_command,
+};
+use crate::{
+    decode::{Decode, DecodeError},
+    encode::{Encode, EncodeError},
+};
 
+/// A screen of the brain's dashboard.
+///
+/// Each screen's value is both what [`SelectDashPacket`] opens and the page index that the
+/// brain reports in [`SystemFlags`](super::system::SystemFlags) while the screen is shown.
+///
+/// The two don't line up completely. Page indices 2, 7, 9, 11, 12, 23, 35 to 39, and 44 fall
+/// between the known select values and have no variant, so a page reported by the brain
+/// might not decode. Which screens those indices are is unknown.
 #[repr(u8)]
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub enum DashScreen {
@@ -41,9 +55,79 @@ pub enum DashScreen {
     SignatureId = 46,
     LogData = 47,
 }
+impl DashScreen {
+    /// Every screen, in order of value.
+    const ALL: [DashScreen; 36] = [
+        Self::Home,
+        Self::Battery,
+        Self::Led,
+        Self::MatchConfig,
+        Self::MatchConfigMore,
+        Self::Wiring,
+        Self::Radio,
+        Self::Brain,
+        Self::RunProgram,
+        Self::DriveProgramControlLeftMapping,
+        Self::DriveProgramMenu,
+        Self::Devices,
+        Self::UserProgramFolder,
+        Self::VexProgramFolder,
+        Self::Settings,
+        Self::ScaryConfiguration,
+        Self::Language,
+        Self::DriveMotorConfig,
+        Self::ProgramMenu,
+        Self::Shutdown,
+        Self::Controller2Mapping,
+        Self::ScaryConfigurationMore,
+        Self::ConfirmXX,
+        Self::Controller1Mapping,
+        Self::DriveProgramControlDualMapping,
+        Self::DriveProgramControlSplitMapping,
+        Self::DriveProgramControlRightMapping,
+        Self::Match24Players,
+        Self::EventLog,
+        Self::UserProgramWiring,
+        Self::ClawbotProgramMenu,
+        Self::About,
+        Self::LanguageMore,
+        Self::ObjectColor,
+        Self::SignatureId,
+        Self::LogData,
+    ];
+
+    /// The value of each screen in [`DashScreen::ALL`].
+    // Evaluated at compile time, so an out of bounds index fails the build instead of panicking
+    #[allow(clippy::indexing_slicing)]
+    const VALUES: [u8; 36] = {
+        let mut values = [0; 36];
+        let mut i = 0;
+        while i < values.len() {
+            values[i] = Self::ALL[i] as u8;
+            i += 1;
+        }
+        values
+    };
+
+    /// Returns the screen shown at a page index reported by the brain, or `None` if the page
+    /// has no [`DashScreen`] variant.
+    pub fn from_page_index(index: u8) -> Option<DashScreen> {
+        Self::ALL.into_iter().find(|screen| *screen as u8 == index)
+    }
+}
+impl Decode for DashScreen {
+    fn decode(data: impl IntoIterator<Item = u8>) -> Result<Self, DecodeError> {
+        let value = u8::decode(data)?;
+        Self::from_page_index(value).ok_or(DecodeError::UnexpectedValue {
+            value,
+            expected: &Self::VALUES,
+        })
+    }
+}
 
 pub type SendDashTouchPacket = Cdc2CommandPacket<86, 42, SendDashTouchPayload>;
 pub type SendDashTouchReplyPacket = Cdc2ReplyPacket<86, 42, ()>;
+cdc_command!(SendDashTouchPacket => SendDashTouchReplyPacket);
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub struct SendDashTouchPayload {
@@ -64,6 +148,7 @@ impl Encode for SendDashTouchPayload {
 
 pub type SelectDashPacket = Cdc2CommandPacket<86, 43, SelectDashPayload>;
 pub type SelectDashReplyPacket = Cdc2ReplyPacket<86, 43, ()>;
+cdc_command!(SelectDashPacket => SelectDashReplyPacket);
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub struct SelectDashPayload {
@@ -76,3 +161,22 @@ impl Encode for SelectDashPayload {
         Ok(vec![self.screen as u8, self.port])
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::DashScreen;
+    use crate::decode::{Decode, DecodeError};
+
+    #[test]
+    fn page_index_round_trip() {
+        for screen in DashScreen::ALL {
+            assert_eq!(DashScreen::from_page_index(screen as u8), Some(screen));
+            assert_eq!(DashScreen::decode([screen as u8]), Ok(screen));
+        }
+        assert_eq!(DashScreen::from_page_index(2), None);
+        assert!(matches!(
+            DashScreen::decode([44]),
+            Err(DecodeError::UnexpectedValue { value: 44, .. })
+        ));
+    }
+}
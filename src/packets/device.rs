@@ -1,5 +1,12 @@
-use super::cdc2::{Cdc2CommandPacket, Cdc2ReplyPacket};
-use crate::decode::{Decode, DecodeError, SizedDecode};
+use super::{
+    cdc2::{Cdc2CommandPacket, Cdc2ReplyPacket},
+    cdc_command,
+};
+use crate::{
+    array::CountedVec,
+    decode::{Decode, DecodeError},
+    encode::{Encode, EncodeError},
+};
 
 // This is copied from vex-sdk
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
@@ -35,49 +42,120 @@ pub enum DeviceType {
     GenericSerial = 129,
     UndefinedSensor = 255,
 }
+impl DeviceType {
+    /// Returns a human-readable name for the device type, such as `"Inertial Sensor"`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::NoSensor => "No Device",
+            Self::Motor => "Motor",
+            Self::Led => "LED",
+            Self::AbsEncoder => "Rotation Sensor",
+            Self::CrMotor => "CR Motor",
+            Self::Imu => "Inertial Sensor",
+            Self::DistanceSensor => "Distance Sensor",
+            Self::Radio => "Radio",
+            Self::TetheredController => "Tethered Controller",
+            Self::Brain => "Brain",
+            Self::VisionSensor => "Vision Sensor",
+            Self::AdiExpander => "ADI Expander",
+            Self::Res1Sensor => "Reserved",
+            Self::Battery => "Battery",
+            Self::Res3Sensor => "Reserved",
+            Self::OpticalSensor => "Optical Sensor",
+            Self::Magnet => "Electromagnet",
+            Self::GpsSensor => "GPS Sensor",
+            Self::AicameraSensor => "AI Camera",
+            Self::LightTower => "Light Tower",
+            Self::ArmDevice => "Arm",
+            Self::AiVisionSensor => "AI Vision Sensor",
+            Self::Pneumatic => "Pneumatics",
+            Self::BumperSensor => "Bumper Switch",
+            Self::GyroSensor => "Gyro",
+            Self::SonarSensor => "Sonar",
+            Self::GenericSensor => "Generic Sensor",
+            Self::GenericSerial => "Generic Serial",
+            Self::UndefinedSensor => "Unknown Device",
+        }
+    }
+
+    /// Returns whether this is a device that's plugged into a smart port.
+    ///
+    /// The brain, its battery, and a tethered controller are reported alongside smart port
+    /// devices, but aren't plugged into one.
+    pub fn is_smart_port_device(&self) -> bool {
+        matches!(
+            self,
+            Self::Motor
+                | Self::Led
+                | Self::AbsEncoder
+                | Self::CrMotor
+                | Self::Imu
+                | Self::DistanceSensor
+                | Self::Radio
+                | Self::VisionSensor
+                | Self::AdiExpander
+                | Self::OpticalSensor
+                | Self::Magnet
+                | Self::GpsSensor
+                | Self::AicameraSensor
+                | Self::LightTower
+                | Self::ArmDevice
+                | Self::AiVisionSensor
+                | Self::Pneumatic
+                | Self::GenericSerial
+        )
+    }
+
+    /// Returns whether this is a legacy device that's plugged into an ADI port.
+    pub fn is_adi_device(&self) -> bool {
+        matches!(
+            self,
+            Self::BumperSensor | Self::GyroSensor | Self::SonarSensor
+        )
+    }
+}
+/// Converts a raw device type, falling back to [`DeviceType::UndefinedSensor`] for values that
+/// this crate doesn't know about.
+impl From<u8> for DeviceType {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => Self::NoSensor,
+            2 => Self::Motor,
+            3 => Self::Led,
+            4 => Self::AbsEncoder,
+            5 => Self::CrMotor,
+            6 => Self::Imu,
+            7 => Self::DistanceSensor,
+            8 => Self::Radio,
+            9 => Self::TetheredController,
+            10 => Self::Brain,
+            11 => Self::VisionSensor,
+            12 => Self::AdiExpander,
+            13 => Self::Res1Sensor,
+            14 => Self::Battery,
+            15 => Self::Res3Sensor,
+            16 => Self::OpticalSensor,
+            17 => Self::Magnet,
+            20 => Self::GpsSensor,
+            26 => Self::AicameraSensor,
+            27 => Self::LightTower,
+            28 => Self::ArmDevice,
+            29 => Self::AiVisionSensor,
+            30 => Self::Pneumatic,
+            0x40 => Self::BumperSensor,
+            0x46 => Self::GyroSensor,
+            0x47 => Self::SonarSensor,
+            128 => Self::GenericSensor,
+            129 => Self::GenericSerial,
+            _ => Self::UndefinedSensor,
+        }
+    }
+}
+/// Decoding never fails on an unknown device type, so that a new kind of device plugged into
+/// the brain doesn't break the whole device status reply.
 impl Decode for DeviceType {
     fn decode(data: impl IntoIterator<Item = u8>) -> Result<Self, DecodeError> {
-        let value = u8::decode(data)?;
-        Ok(match value {
-            0 => DeviceType::NoSensor,
-            2 => DeviceType::Motor,
-            3 => DeviceType::Led,
-            4 => DeviceType::AbsEncoder,
-            5 => DeviceType::CrMotor,
-            6 => DeviceType::Imu,
-            7 => DeviceType::DistanceSensor,
-            8 => DeviceType::Radio,
-            9 => DeviceType::TetheredController,
-            10 => DeviceType::Brain,
-            11 => DeviceType::VisionSensor,
-            12 => DeviceType::AdiExpander,
-            13 => DeviceType::Res1Sensor,
-            14 => DeviceType::Battery,
-            15 => DeviceType::Res3Sensor,
-            16 => DeviceType::OpticalSensor,
-            17 => DeviceType::Magnet,
-            20 => DeviceType::GpsSensor,
-            26 => DeviceType::AicameraSensor,
-            27 => DeviceType::LightTower,
-            28 => DeviceType::ArmDevice,
-            29 => DeviceType::AiVisionSensor,
-            30 => DeviceType::Pneumatic,
-            0x40 => DeviceType::BumperSensor,
-            0x46 => DeviceType::GyroSensor,
-            0x47 => DeviceType::SonarSensor,
-            128 => DeviceType::GenericSensor,
-            129 => DeviceType::GenericSerial,
-            255 => DeviceType::UndefinedSensor,
-            _ => {
-                return Err(DecodeError::UnexpectedValue {
-                    value,
-                    expected: &[
-                        0, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 20, 26, 27, 28,
-                        29, 30, 0x40, 0x46, 0x47, 128, 129, 255,
-                    ],
-                })
-            }
-        })
+        Ok(Self::from(u8::decode(data)?))
     }
 }
 
@@ -115,20 +193,93 @@ impl Decode for DeviceStatus {
     }
 }
 
+/// A payload addressed to the device on a specific smart port.
+///
+/// The port is sent as a single 1-indexed byte directly before the inner payload, using the
+/// same numbering as [`DeviceStatus::port`]. Device-specific packets should wrap their payload
+/// in this rather than handling the port byte themselves.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct DevicePacket<P> {
+    /// 1-indexed smart port number of the addressed device.
+    pub port: u8,
+    pub payload: P,
+}
+impl<P> DevicePacket<P> {
+    pub fn new(port: u8, payload: P) -> Self {
+        Self { port, payload }
+    }
+}
+impl<P: Encode> Encode for DevicePacket<P> {
+    fn encode(&self) -> Result<Vec<u8>, EncodeError> {
+        let mut encoded = vec![self.port];
+        encoded.extend(self.payload.encode()?);
+        Ok(encoded)
+    }
+}
+impl<P: Decode> Decode for DevicePacket<P> {
+    fn decode(data: impl IntoIterator<Item = u8>) -> Result<Self, DecodeError> {
+        let mut data = data.into_iter();
+        let port = u8::decode(&mut data)?;
+        let payload = P::decode(&mut data)?;
+        Ok(Self { port, payload })
+    }
+}
+
+/// Over a controller, this is relayed to the brain it's linked to.
 pub type GetDeviceStatusPacket = Cdc2CommandPacket<86, 33, ()>;
 pub type GetDeviceStatusReplyPacket = Cdc2ReplyPacket<86, 33, GetDeviceStatusReplyPayload>;
+cdc_command!(GetDeviceStatusPacket => GetDeviceStatusReplyPacket);
 
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct GetDeviceStatusReplyPayload {
-    /// Number of elements in the following array.
-    pub count: u8,
-    pub devices: Vec<DeviceStatus>,
+    pub devices: CountedVec<u8, DeviceStatus>,
 }
 impl Decode for GetDeviceStatusReplyPayload {
     fn decode(data: impl IntoIterator<Item = u8>) -> Result<Self, DecodeError> {
-        let mut data = data.into_iter();
-        let count = u8::decode(&mut data)?;
-        let devices = Vec::sized_decode(&mut data, count as _)?;
-        Ok(Self { count, devices })
+        Ok(Self {
+            devices: CountedVec::decode(data)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DevicePacket, DeviceType};
+    use crate::{
+        decode::{Decode, DecodeError},
+        encode::Encode,
+    };
+
+    #[test]
+    fn device_packet_round_trip() {
+        let packet = DevicePacket::new(5, 0x7Fu8);
+        let encoded = packet.encode().unwrap();
+        assert_eq!(encoded, [5, 0x7F]);
+        assert_eq!(DevicePacket::<u8>::decode(encoded).unwrap(), packet);
+    }
+
+    #[test]
+    fn device_packet_missing_payload() {
+        assert_eq!(
+            DevicePacket::<u8>::decode([5]),
+            Err(DecodeError::PacketTooShort)
+        );
+    }
+
+    #[test]
+    fn device_types() {
+        for value in 0..=u8::MAX {
+            let device_type = DeviceType::from(value);
+            if device_type != DeviceType::UndefinedSensor {
+                assert_eq!(device_type as u8, value);
+            }
+        }
+        assert_eq!(DeviceType::decode([0x23]), Ok(DeviceType::UndefinedSensor));
+
+        assert!(DeviceType::Motor.is_smart_port_device());
+        assert!(!DeviceType::Battery.is_smart_port_device());
+        assert!(DeviceType::SonarSensor.is_adi_device());
+        assert!(!DeviceType::AdiExpander.is_adi_device());
+        assert_eq!(DeviceType::Imu.name(), "Inertial Sensor");
     }
 }
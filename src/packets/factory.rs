@@ -1,24 +1,26 @@
 //! Factory Control
 
-use super::cdc2::{Cdc2CommandPacket, Cdc2ReplyPacket};
+use super::{
+    cdc2::{Cdc2CommandPacket, Cdc2ReplyPacket},
+    cdc_command,
+    device::DeviceType,
+};
 use crate::{
-    decode::{Decode, DecodeError, SizedDecode},
+    array::CountedVec,
+    decode::{Decode, DecodeError},
     encode::{Encode, EncodeError},
 };
+#[cfg(feature = "factory")]
+use crate::decode::SizedDecode;
 
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct FdtStatus {
-    pub count: u8,
-    pub files: Vec<Fdt>,
+    pub files: CountedVec<u8, Fdt>,
 }
 impl Decode for FdtStatus {
     fn decode(data: impl IntoIterator<Item = u8>) -> Result<Self, DecodeError> {
-        let mut data = data.into_iter();
-        let count = u8::decode(&mut data)?;
-        let entries = Vec::sized_decode(&mut data, count as _)?;
         Ok(Self {
-            count,
-            files: entries,
+            files: CountedVec::decode(data)?,
         })
     }
 }
@@ -26,12 +28,19 @@ impl Decode for FdtStatus {
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub struct Fdt {
     pub index: u8,
+    /// The raw type of the device, which can be converted with [`Fdt::device_type`].
     pub fdt_type: u8,
     pub status: u8,
     pub beta_version: u8,
     pub version: u16,
     pub boot_version: u16,
 }
+impl Fdt {
+    /// Returns the type of the device, using the same numbering as [`DeviceStatus`](super::device::DeviceStatus).
+    pub fn device_type(&self) -> DeviceType {
+        DeviceType::from(self.fdt_type)
+    }
+}
 impl Decode for Fdt {
     fn decode(data: impl IntoIterator<Item = u8>) -> Result<Self, DecodeError> {
         let mut data = data.into_iter();
@@ -69,12 +78,15 @@ impl Decode for FactoryStatus {
 
 pub type GetFdtStatusPacket = Cdc2CommandPacket<86, 35, ()>;
 pub type GetFdtStatusReplyPacket = Cdc2ReplyPacket<86, 35, FdtStatus>;
+cdc_command!(GetFdtStatusPacket => GetFdtStatusReplyPacket);
 
 pub type GetFactoryStatusPacket = Cdc2CommandPacket<86, 241, ()>;
 pub type GetFactoryStatusReplyPacket = Cdc2ReplyPacket<86, 241, FactoryStatus>;
+cdc_command!(GetFactoryStatusPacket => GetFactoryStatusReplyPacket);
 
 pub type FactoryEnablePacket = Cdc2CommandPacket<86, 255, FactoryEnablePayload>;
 pub type FactoryEnableReplyPacket = Cdc2ReplyPacket<86, 255, ()>;
+cdc_command!(FactoryEnablePacket => FactoryEnableReplyPacket);
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub struct FactoryEnablePayload(pub [u8; 4]);
@@ -97,3 +109,72 @@ impl FactoryEnablePayload {
         Self(Self::FACTORY_ENABLE_BYTES)
     }
 }
+
+/// Opaque payload used by the factory challenge/response exchange.
+///
+/// The contents of these payloads are not understood. They are framed and
+/// parsed so that the exchange can be captured and replayed for research.
+#[cfg(feature = "factory")]
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct FactoryPayload(pub Vec<u8>);
+#[cfg(feature = "factory")]
+impl Encode for FactoryPayload {
+    fn encode(&self) -> Result<Vec<u8>, EncodeError> {
+        Ok(self.0.clone())
+    }
+}
+#[cfg(feature = "factory")]
+impl SizedDecode for FactoryPayload {
+    fn sized_decode(
+        data: impl IntoIterator<Item = u8>,
+        payload_size: u16,
+    ) -> Result<Self, DecodeError> {
+        // The payload size includes the extended ID, ack, and CRC bytes.
+        let len = payload_size.saturating_sub(4);
+        Ok(Self(Vec::sized_decode(data, len)?))
+    }
+}
+
+/// Requests a factory challenge from the brain.
+///
+/// # Warning
+///
+/// This packet is unstable and dangerous. It is part of an undocumented factory
+/// process and may put the brain into an unexpected state.
+#[cfg(feature = "factory")]
+pub type FactoryChallengePacket = Cdc2CommandPacket<86, 252, FactoryPayload>;
+#[cfg(feature = "factory")]
+pub type FactoryChallengeReplyPacket = Cdc2ReplyPacket<86, 252, FactoryPayload>;
+#[cfg(feature = "factory")]
+cdc_command!(FactoryChallengePacket => FactoryChallengeReplyPacket);
+
+/// Sends a response to a factory challenge.
+///
+/// # Warning
+///
+/// This packet is unstable and dangerous. It is part of an undocumented factory
+/// process and may put the brain into an unexpected state.
+#[cfg(feature = "factory")]
+pub type FactoryResponsePacket = Cdc2CommandPacket<86, 253, FactoryPayload>;
+#[cfg(feature = "factory")]
+pub type FactoryResponseReplyPacket = Cdc2ReplyPacket<86, 253, FactoryPayload>;
+#[cfg(feature = "factory")]
+cdc_command!(FactoryResponsePacket => FactoryResponseReplyPacket);
+
+#[cfg(all(test, feature = "factory"))]
+mod tests {
+    use super::{FactoryChallengeReplyPacket, FactoryPayload};
+    use crate::{crc::VEX_CRC16, decode::Decode};
+
+    #[test]
+    fn challenge_reply_payload_length() {
+        let mut data = vec![0xaa, 0x55, 0x56, 0x8, 0xfc, 0x76, 0xde, 0xad, 0xbe, 0xef];
+        data.extend(VEX_CRC16.checksum(&data).to_be_bytes());
+
+        let reply = FactoryChallengeReplyPacket::decode(data).unwrap();
+        assert_eq!(
+            reply.try_into_inner().unwrap(),
+            FactoryPayload(vec![0xde, 0xad, 0xbe, 0xef])
+        );
+    }
+}
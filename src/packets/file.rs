@@ -1,15 +1,17 @@
 //! Filesystem Access
 
-use std::{vec, str};
+use std::{fmt::Debug, str, vec};
 
 use super::{
     cdc::CdcReplyPacket,
-    cdc2::{Cdc2Ack, Cdc2CommandPacket, Cdc2ReplyPacket},
+    cdc2::{Cdc2Ack, Cdc2CommandPacket, Cdc2ReplyMeta, Cdc2ReplyPacket},
+    cdc_command,
 };
 use crate::{
     choice::{Choice, PrefferedChoice},
-    decode::{Decode, DecodeError, SizedDecode},
+    decode::{self, pad, Decode, DecodeError, SizedDecode},
     encode::{Encode, EncodeError},
+    hex::HexPreview,
     string::FixedString,
     version::Version,
 };
@@ -44,6 +46,12 @@ pub enum FileTransferTarget {
     B2 = 15,
 }
 
+/// The directory on the brain that a file is stored in.
+///
+/// Programs and their `.ini` files go in [`FileVendor::User`], which every brain has. The other
+/// directories may not exist, and writing to one that doesn't fails with
+/// [`Cdc2Ack::NackNoDirectory`](super::cdc2::Cdc2Ack::NackNoDirectory) when the transfer is
+/// opened.
 #[repr(u8)]
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub enum FileVendor {
@@ -134,9 +142,9 @@ pub struct FileMetadata {
 
 impl Encode for FileMetadata {
     fn encode(&self) -> Result<Vec<u8>, EncodeError> {
-        let mut data = vec![0; 3];
         // extension is not null terminated and is fixed length
-        data[..self.extension.as_ref().len()].copy_from_slice(self.extension.as_ref().as_bytes());
+        let mut data = self.extension.as_ref().as_bytes().to_vec();
+        data.resize(3, 0);
         data.push(self.extension_type as _);
         data.extend(self.timestamp.to_le_bytes());
         data.extend(self.version.encode()?);
@@ -156,10 +164,10 @@ impl Decode for FileMetadata {
             // SAFETY: length is guaranteed to be less than 4.
             extension: unsafe {
                 FixedString::new_unchecked(
-                    str::from_utf8(&<[u8; 3]>::decode(&mut data)?)?.to_string(),
+                    str::from_utf8(&decode::bytes::<3>(&mut data)?)?.to_string(),
                 )
             },
-            extension_type: Decode::decode(&mut data).unwrap(),
+            extension_type: Decode::decode(&mut data)?,
             timestamp: i32::decode(&mut data)?,
             version: Version::decode(&mut data)?,
         })
@@ -169,6 +177,7 @@ impl Decode for FileMetadata {
 /// Start uploading or downloading file from the device
 pub type InitFileTransferPacket = Cdc2CommandPacket<86, 17, InitFileTransferPayload>;
 pub type InitFileTransferReplyPacket = Cdc2ReplyPacket<86, 17, InitFileTransferReplyPayload>;
+cdc_command!(InitFileTransferPacket => InitFileTransferReplyPacket);
 
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct InitFileTransferPayload {
@@ -235,14 +244,25 @@ impl Decode for InitFileTransferReplyPayload {
 /// Finish uploading or downloading file from the device
 pub type ExitFileTransferPacket = Cdc2CommandPacket<86, 18, FileExitAction>;
 pub type ExitFileTransferReplyPacket = Cdc2ReplyPacket<86, 18, ()>;
+cdc_command!(ExitFileTransferPacket => ExitFileTransferReplyPacket);
 
 /// The action to run when a file transfer is completed.
 #[repr(u8)]
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub enum FileExitAction {
     DoNothing = 0,
+    /// Runs the uploaded program.
+    ///
+    /// After a wireless upload, the brain has been reported to sometimes start the slot's
+    /// previous binary, presumably because the exit races the file being committed to flash.
+    /// This hasn't been reproduced with a capture. See
+    /// [`UploadFile::confirm_run`](crate::commands::file::UploadFile::confirm_run).
     RunProgram = 1,
     Halt = 2,
+    /// Shows the uploaded program's run screen.
+    ///
+    /// The brain has been reported to sometimes ignore this after an upload through a
+    /// controller. This hasn't been reproduced with a capture either.
     ShowRunScreen = 3,
 }
 impl Encode for FileExitAction {
@@ -253,8 +273,9 @@ impl Encode for FileExitAction {
 /// Write to the brain
 pub type WriteFilePacket = Cdc2CommandPacket<86, 19, WriteFilePayload>;
 pub type WriteFileReplyPacket = Cdc2ReplyPacket<86, 19, ()>;
+cdc_command!(WriteFilePacket => WriteFileReplyPacket);
 
-#[derive(Debug, Clone, Eq, PartialEq)]
+#[derive(Clone, Eq, PartialEq)]
 pub struct WriteFilePayload {
     /// Memory address to write to.
     pub address: i32,
@@ -262,8 +283,42 @@ pub struct WriteFilePayload {
     /// A sequence of bytes to write. Must be 4-byte aligned.
     pub chunk_data: Vec<u8>,
 }
+impl Debug for WriteFilePayload {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WriteFilePayload")
+            .field("address", &self.address)
+            .field("chunk_data", &HexPreview(&self.chunk_data))
+            .finish()
+    }
+}
+impl WriteFilePayload {
+    /// Creates a payload that writes `chunk_data` exactly as given.
+    ///
+    /// The brain will reply with [`Cdc2Ack::NackAlignment`] if the data isn't 4-byte aligned.
+    pub fn new(address: i32, chunk_data: Vec<u8>) -> Self {
+        Self {
+            address,
+            chunk_data,
+        }
+    }
+
+    /// Creates a payload that writes `chunk_data`, padded with zeros to a multiple of 4 bytes.
+    pub fn new_aligned(address: i32, mut chunk_data: Vec<u8>) -> Self {
+        chunk_data.resize(chunk_data.len().next_multiple_of(4), 0);
+        Self {
+            address,
+            chunk_data,
+        }
+    }
+}
 impl Encode for WriteFilePayload {
     fn encode(&self) -> Result<Vec<u8>, EncodeError> {
+        debug_assert!(
+            self.chunk_data.len().is_multiple_of(4),
+            "File chunk of {} bytes is not 4-byte aligned",
+            self.chunk_data.len()
+        );
+
         let mut encoded = Vec::new();
 
         encoded.extend(self.address.to_le_bytes());
@@ -276,7 +331,29 @@ impl Encode for WriteFilePayload {
 /// Read from the brain
 pub type ReadFilePacket = Cdc2CommandPacket<86, 20, ReadFilePayload>;
 /// Returns the file content. This packet doesn't have an ack if the data is available.
-pub type ReadFileReplyPacket = CdcReplyPacket<86, ReadFileReplyPayload>;
+///
+/// Unlike other simple replies, this one ends with a CRC16 of the whole frame, which is verified when decoding.
+pub type ReadFileReplyPacket = CdcReplyPacket<86, ReadFileReplyPayload, true>;
+cdc_command!(ReadFilePacket => ReadFileReplyPacket);
+impl Cdc2ReplyMeta for ReadFileReplyPacket {
+    fn ack(&self) -> Cdc2Ack {
+        match self.payload.contents {
+            ReadFileReplyContents::Success { .. } => Cdc2Ack::Ack,
+            ReadFileReplyContents::Failure { nack, .. } => nack,
+        }
+    }
+
+    fn declared_size(&self) -> u16 {
+        self.payload_size
+    }
+
+    fn crc(&self) -> u16 {
+        match self.payload.contents {
+            ReadFileReplyContents::Success { crc, .. }
+            | ReadFileReplyContents::Failure { crc, .. } => crc,
+        }
+    }
+}
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub struct ReadFilePayload {
@@ -295,7 +372,7 @@ impl Encode for ReadFilePayload {
     }
 }
 
-#[derive(Debug, Clone, Eq, PartialEq)]
+#[derive(Clone, Eq, PartialEq)]
 pub enum ReadFileReplyContents {
     Failure {
         nack: Cdc2Ack,
@@ -308,6 +385,23 @@ pub enum ReadFileReplyContents {
         crc: u16,
     },
 }
+impl Debug for ReadFileReplyContents {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Failure { nack, crc } => f
+                .debug_struct("Failure")
+                .field("nack", nack)
+                .field("crc", crc)
+                .finish(),
+            Self::Success { address, data, crc } => f
+                .debug_struct("Success")
+                .field("address", address)
+                .field("data", &HexPreview(data))
+                .field("crc", crc)
+                .finish(),
+        }
+    }
+}
 impl Decode for ReadFileReplyContents {
     fn decode(data: impl IntoIterator<Item = u8>) -> Result<Self, DecodeError> {
         struct Success {
@@ -322,7 +416,11 @@ impl Decode for ReadFileReplyContents {
 
                 // This is a cursed way to get the number of bytes in chunk_data.
                 let data_vec = data.collect::<Vec<_>>();
-                // The last two bytes are the CRC checksum.
+                // The last two bytes are the CRC checksum. Reads always return some data, and
+                // without any this is the same length as a NACK reply.
+                if data_vec.len() <= 2 {
+                    return Err(DecodeError::PacketTooShort);
+                }
                 let num_bytes = data_vec.len() - 2;
                 let mut data = data_vec.into_iter();
 
@@ -402,6 +500,7 @@ impl ReadFileReplyPayload {
 /// This is used in PROS for the hot/cold linking.
 pub type LinkFilePacket = Cdc2CommandPacket<86, 21, LinkFilePayload>;
 pub type LinkFileReplyPacket = Cdc2ReplyPacket<86, 21, ()>;
+cdc_command!(LinkFilePacket => LinkFileReplyPacket);
 
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct LinkFilePayload {
@@ -422,6 +521,7 @@ impl Encode for LinkFilePayload {
 
 pub type GetDirectoryFileCountPacket = Cdc2CommandPacket<86, 22, GetDirectoryFileCountPayload>;
 pub type GetDirectoryFileCountReplyPacket = Cdc2ReplyPacket<86, 22, u16>;
+cdc_command!(GetDirectoryFileCountPacket => GetDirectoryFileCountReplyPacket);
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub struct GetDirectoryFileCountPayload {
@@ -438,6 +538,7 @@ impl Encode for GetDirectoryFileCountPayload {
 pub type GetDirectoryEntryPacket = Cdc2CommandPacket<86, 23, GetDirectoryEntryPayload>;
 pub type GetDirectoryEntryReplyPacket =
     Cdc2ReplyPacket<86, 23, Option<GetDirectoryEntryReplyPayload>>;
+cdc_command!(GetDirectoryEntryPacket => GetDirectoryEntryReplyPacket);
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub struct GetDirectoryEntryPayload {
@@ -476,7 +577,7 @@ impl Decode for GetDirectoryEntryReplyPayload {
         let mut data = data.peekable();
 
         let metadata = if data.peek() == Some(&255) {
-            let _ = <[u8; 12]>::decode(&mut data);
+            pad::<12>(&mut data)?;
             None
         } else {
             Some(FileMetadata::decode(&mut data)?)
@@ -498,6 +599,7 @@ impl Decode for GetDirectoryEntryReplyPayload {
 /// Run a binrary file on the brain or stop the program running on the brain.
 pub type LoadFileActionPacket = Cdc2CommandPacket<86, 24, LoadFileActionPayload>;
 pub type LoadFileActionReplyPacket = Cdc2ReplyPacket<86, 24, ()>;
+cdc_command!(LoadFileActionPacket => LoadFileActionReplyPacket);
 
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct LoadFileActionPayload {
@@ -516,6 +618,7 @@ impl Encode for LoadFileActionPayload {
 }
 pub type GetFileMetadataPacket = Cdc2CommandPacket<86, 25, GetFileMetadataPayload>;
 pub type GetFileMetadataReplyPacket = Cdc2ReplyPacket<86, 25, Option<GetFileMetadataReplyPayload>>;
+cdc_command!(GetFileMetadataPacket => GetFileMetadataReplyPacket);
 
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct GetFileMetadataPayload {
@@ -547,7 +650,7 @@ pub struct GetFileMetadataReplyPayload {
 impl Decode for Option<GetFileMetadataReplyPayload> {
     fn decode(data: impl IntoIterator<Item = u8>) -> Result<Self, DecodeError> {
         let mut data = data.into_iter();
-        let maybe_vid = u8::decode(&mut data).unwrap();
+        let maybe_vid = u8::decode(&mut data)?;
 
         let linked_vendor = match maybe_vid {
             // 0 is returned if there is no linked file.
@@ -585,6 +688,7 @@ impl Decode for Option<GetFileMetadataReplyPayload> {
 
 pub type SetFileMetadataPacket = Cdc2CommandPacket<86, 26, SetFileMetadataPayload>;
 pub type SetFileMetadataReplyPacket = Cdc2ReplyPacket<86, 26, ()>;
+cdc_command!(SetFileMetadataPacket => SetFileMetadataReplyPacket);
 
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct SetFileMetadataPayload {
@@ -608,17 +712,36 @@ impl Encode for SetFileMetadataPayload {
 
 pub type EraseFilePacket = Cdc2CommandPacket<86, 27, EraseFilePayload>;
 pub type EraseFileReplyPacket = Cdc2ReplyPacket<86, 27, ()>;
+cdc_command!(EraseFilePacket => EraseFileReplyPacket);
+
+/// Controls how the brain performs a file erase.
+///
+/// Captured VEXcode traffic shows two values for this byte. A foreground erase blocks until the
+/// file has been removed from flash before replying, which for large files can take longer than
+/// a typical handshake timeout. A background erase replies immediately and removes the file
+/// afterwards, so callers should poll [`GetFileMetadataPacket`] until the file is gone.
+///
+/// Which VEXos versions honor [`FileEraseOption::Background`] hasn't been recorded, nor how
+/// long a background erase takes. A brain that erased in the foreground instead would reply
+/// once the file is gone, so polling for the file works either way.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum FileEraseOption {
+    /// Erase the file before replying.
+    Normal = 0,
+    /// Reply immediately and erase the file in the background.
+    Background = 128,
+}
 
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct EraseFilePayload {
     pub vendor: FileVendor,
-    /// 128 = default. (RESEARCH NEEDED)
-    pub option: u8,
+    pub option: FileEraseOption,
     pub file_name: FixedString<23>,
 }
 impl Encode for EraseFilePayload {
     fn encode(&self) -> Result<Vec<u8>, EncodeError> {
-        let mut encoded = vec![self.vendor as _, self.option];
+        let mut encoded = vec![self.vendor as _, self.option as _];
         encoded.extend(self.file_name.encode()?);
 
         Ok(encoded)
@@ -690,3 +813,94 @@ impl Encode for FileFormatConfirmation {
         Ok(self.confirmation_code.to_vec())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        ExtensionType, FileMetadata, GetDirectoryEntryReplyPayload, GetFileMetadataReplyPacket,
+        ReadFileReplyPacket, WriteFilePayload,
+    };
+    use crate::{
+        connection::mock::cdc2_reply, crc::VEX_CRC16, decode::Decode, encode::Encode,
+        string::FixedString, version::Version,
+    };
+
+    fn metadata() -> FileMetadata {
+        FileMetadata {
+            extension: FixedString::new("bin".to_string()).unwrap(),
+            extension_type: ExtensionType::Binary,
+            timestamp: 0x1234,
+            version: Version {
+                major: 1,
+                minor: 0,
+                build: 0,
+                beta: 0,
+            },
+        }
+    }
+
+    #[test]
+    fn metadata_round_trip() {
+        let encoded = metadata().encode().unwrap();
+        assert_eq!(encoded.len(), 12);
+        assert_eq!(FileMetadata::decode(encoded).unwrap(), metadata());
+    }
+
+    #[test]
+    fn directory_entry_file_name() {
+        let mut payload = vec![3];
+        payload.extend(8u32.to_le_bytes());
+        payload.extend(0x0380_0000u32.to_le_bytes());
+        payload.extend(0x1234_5678u32.to_le_bytes());
+        payload.extend(metadata().encode().unwrap());
+        payload.extend(
+            FixedString::<23>::new("slot_1.bin".to_string())
+                .unwrap()
+                .encode()
+                .unwrap(),
+        );
+        // The reply's CRC follows the payload
+        payload.extend([0xAB, 0xCD]);
+
+        let mut data = payload.into_iter();
+        let entry = GetDirectoryEntryReplyPayload::decode(&mut data).unwrap();
+        assert_eq!(entry.metadata, Some(metadata()));
+        assert_eq!(entry.file_name, "slot_1.bin");
+        assert_eq!(data.collect::<Vec<_>>(), [0xAB, 0xCD]);
+    }
+
+    #[test]
+    fn write_payload_alignment() {
+        for len in 1..=8 {
+            let payload = WriteFilePayload::new_aligned(0, vec![0xFF; len]);
+            let padded_len = len.div_ceil(4) * 4;
+
+            assert_eq!(payload.chunk_data.len(), padded_len);
+            assert!(payload.chunk_data[..len].iter().all(|&b| b == 0xFF));
+            assert!(payload.chunk_data[len..].iter().all(|&b| b == 0));
+            assert_eq!(payload.encode().unwrap().len(), 4 + padded_len);
+        }
+    }
+
+    /// Frames found by the `decode_reply` fuzz target that used to panic instead of failing.
+    /// The same inputs are kept in `fuzz/corpus/decode_reply`.
+    #[test]
+    fn malformed_replies() {
+        let metadata_reply = |payload: &[u8]| cdc2_reply(0x19, payload);
+
+        // No vendor byte
+        assert!(GetFileMetadataReplyPacket::decode(metadata_reply(&[])).is_err());
+        // Cut off before the extension type
+        let mut payload = vec![0];
+        payload.extend([0; 12]);
+        payload.extend(b"bin");
+        assert!(GetFileMetadataReplyPacket::decode(metadata_reply(&payload)).is_err());
+
+        // Too short to be a successful read once the CRC is taken off
+        let mut frame = vec![0xAA, 0x55, 0x56, 0x05, 0x14, 0x00, 0x00];
+        frame.extend(VEX_CRC16.checksum(&frame).to_be_bytes());
+        if let Ok(reply) = ReadFileReplyPacket::decode(frame) {
+            assert!(reply.payload.unwrap().is_err());
+        }
+    }
+}
@@ -1,16 +1,151 @@
 //! Global key-value store.
+//!
+//! The brain stores a few settings, such as its team number, as string values under string
+//! keys. The keys this crate knows about are listed in [`keys`], but any key can be read or
+//! written with a [`KvKey`].
 
-use super::cdc2::{Cdc2CommandPacket, Cdc2ReplyPacket};
+use super::{
+    cdc2::{Cdc2CommandPacket, Cdc2ReplyPacket},
+    cdc_command,
+};
 use crate::{
+    decode::{DecodeError, SizedDecode},
     encode::{Encode, EncodeError},
     string::FixedString,
 };
 
+/// The longest key the brain accepts, in bytes.
+pub const MAX_KEY_LEN: usize = 31;
+
+/// The longest value that can be written, in bytes.
+pub const MAX_VALUE_LEN: usize = 255;
+
+/// A key in the key-value store, checked to be at most [`MAX_KEY_LEN`] bytes long.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct KvKey(FixedString<MAX_KEY_LEN>);
+impl KvKey {
+    pub fn new(key: &str) -> Result<Self, EncodeError> {
+        Ok(Self(FixedString::new(key.to_string())?))
+    }
+
+    pub fn as_str(&self) -> &str {
+        self.0.as_ref()
+    }
+
+    pub fn into_inner(self) -> FixedString<MAX_KEY_LEN> {
+        self.0
+    }
+}
+impl TryFrom<&str> for KvKey {
+    type Error = EncodeError;
+
+    fn try_from(key: &str) -> Result<Self, EncodeError> {
+        Self::new(key)
+    }
+}
+impl From<keys::KnownKey> for KvKey {
+    fn from(key: keys::KnownKey) -> Self {
+        // Known keys are all well under the limit
+        Self::new(key.name).unwrap()
+    }
+}
+impl std::fmt::Display for KvKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Keys with a known meaning.
+pub mod keys {
+    /// A key with a known meaning and value length limit.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct KnownKey {
+        pub name: &'static str,
+        /// The longest value that should be written to this key, in bytes.
+        pub max_len: usize,
+    }
+
+    /// The longest team number, such as `1234A` or `BLRS2`.
+    pub const MAX_TEAM_NUMBER_LEN: usize = 6;
+
+    /// The team number shown on the brain's screen and used by field control.
+    pub const TEAM_NUMBER: KnownKey = KnownKey {
+        name: "teamnumber",
+        max_len: MAX_TEAM_NUMBER_LEN,
+    };
+
+    /// The robot name shown on the brain's screen.
+    pub const ROBOT_NAME: KnownKey = KnownKey {
+        name: "robotname",
+        max_len: super::MAX_VALUE_LEN,
+    };
+
+    /// Every known key.
+    pub const ALL: &[KnownKey] = &[TEAM_NUMBER, ROBOT_NAME];
+
+    /// Looks up a known key by name.
+    pub fn find(name: &str) -> Option<KnownKey> {
+        ALL.iter().copied().find(|key| key.name == name)
+    }
+
+    /// Returns whether `team_number` looks like a VEX team number.
+    ///
+    /// Team numbers are one to [`MAX_TEAM_NUMBER_LEN`] ASCII letters and digits, starting
+    /// with a digit followed by an optional letter (`1234A`), or with letters followed by an
+    /// optional digit (`BLRS2`).
+    pub fn is_valid_team_number(team_number: &str) -> bool {
+        let bytes = team_number.as_bytes();
+        let Some(first) = bytes.first() else {
+            return false;
+        };
+        if bytes.len() > MAX_TEAM_NUMBER_LEN {
+            return false;
+        }
+
+        let digits_first = first.is_ascii_digit();
+        let is_leading = |byte: &u8| {
+            if digits_first {
+                byte.is_ascii_digit()
+            } else {
+                byte.is_ascii_alphabetic()
+            }
+        };
+        let split = bytes.iter().position(|byte| !is_leading(byte));
+        match split.and_then(|split| bytes.get(split..)) {
+            None => true,
+            Some([last]) if digits_first => last.is_ascii_alphabetic(),
+            Some([last]) => last.is_ascii_digit(),
+            Some(_) => false,
+        }
+    }
+}
+
 pub type ReadKeyValuePacket = Cdc2CommandPacket<86, 46, FixedString<31>>;
-pub type ReadKeyValueReplyPacket = Cdc2ReplyPacket<86, 46, FixedString<255>>;
+pub type ReadKeyValueReplyPacket = Cdc2ReplyPacket<86, 46, ReadKeyValueReplyPayload>;
+cdc_command!(ReadKeyValuePacket => ReadKeyValueReplyPacket);
+
+/// A value read from the key-value store.
+///
+/// The brain sends the value as a nul-terminated string without padding it to its
+/// capacity, so it isn't decoded as a [`FixedString`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReadKeyValueReplyPayload {
+    pub value: String,
+}
+impl SizedDecode for ReadKeyValueReplyPayload {
+    fn sized_decode(
+        data: impl IntoIterator<Item = u8>,
+        payload_size: u16,
+    ) -> Result<Self, DecodeError> {
+        // The payload size includes the extended ID, ack, and CRC bytes.
+        let value = String::sized_decode(data, payload_size.saturating_sub(4))?;
+        Ok(Self { value })
+    }
+}
 
 pub type WriteKeyValuePacket = Cdc2CommandPacket<86, 47, WriteKeyValuePayload>;
 pub type WriteKeyValueReplyPacket = Cdc2ReplyPacket<86, 47, ()>;
+cdc_command!(WriteKeyValuePacket => WriteKeyValueReplyPacket);
 
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct WriteKeyValuePayload {
@@ -27,3 +162,28 @@ impl Encode for WriteKeyValuePayload {
         Ok(encoded)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{keys, KvKey, MAX_KEY_LEN};
+
+    #[test]
+    fn key_validation() {
+        assert_eq!(KvKey::new("teamnumber").unwrap().as_str(), "teamnumber");
+        assert!(KvKey::new(&"k".repeat(MAX_KEY_LEN)).is_ok());
+        assert!(KvKey::new(&"k".repeat(MAX_KEY_LEN + 1)).is_err());
+        assert_eq!(KvKey::from(keys::ROBOT_NAME).to_string(), "robotname");
+        assert_eq!(keys::find("teamnumber"), Some(keys::TEAM_NUMBER));
+        assert_eq!(keys::find("team_number"), None);
+    }
+
+    #[test]
+    fn team_numbers() {
+        for valid in ["1", "1234A", "99999Z", "BLRS", "BLRS2", "a"] {
+            assert!(keys::is_valid_team_number(valid), "{valid}");
+        }
+        for invalid in ["", "1234AB", "12A4", "BLRS22", "1234567", "12 34", "1234-A"] {
+            assert!(!keys::is_valid_team_number(invalid), "{invalid}");
+        }
+    }
+}
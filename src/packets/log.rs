@@ -1,6 +1,10 @@
-use super::cdc2::{Cdc2CommandPacket, Cdc2ReplyPacket};
+use super::{
+    cdc2::{Cdc2CommandPacket, Cdc2ReplyPacket},
+    cdc_command,
+};
 use crate::{
-    decode::{Decode, DecodeError, SizedDecode},
+    array::CountedVec,
+    decode::{Decode, DecodeError},
     encode::{Encode, EncodeError},
 };
 
@@ -41,6 +45,7 @@ impl Decode for Log {
 
 pub type GetLogCountPacket = Cdc2CommandPacket<86, 36, ()>;
 pub type GetLogCountReplyPacket = Cdc2ReplyPacket<86, 36, GetLogCountReplyPayload>;
+cdc_command!(GetLogCountPacket => GetLogCountReplyPacket);
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub struct GetLogCountReplyPayload {
@@ -57,8 +62,12 @@ impl Decode for GetLogCountReplyPayload {
 }
 
 /// For example: If the brain has 26 logs, from A to Z. With offset 5 and count 5, it returns [V, W, X, Y, Z]. With offset 10 and count 5, it returns [Q, R, S, T, U].
+///
+/// Offsets shift as entries are logged, so use [`ReadLog`](crate::commands::log::ReadLog) to
+/// page through the log rather than working them out by hand.
 pub type ReadLogPagePacket = Cdc2CommandPacket<86, 37, ReadLogPagePayload>;
 pub type ReadLogPageReplyPacket = Cdc2ReplyPacket<86, 37, ReadLogPageReplyPayload>;
+cdc_command!(ReadLogPagePacket => ReadLogPageReplyPacket);
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub struct ReadLogPagePayload {
@@ -80,9 +89,7 @@ pub struct ReadLogPageReplyPayload {
     pub log_size: u8,
     /// The offset number used in this packet.
     pub offset: u32,
-    /// Number of elements in the following array.
-    pub count: u16,
-    pub entries: Vec<Log>,
+    pub entries: CountedVec<u16, Log>,
 }
 impl Decode for ReadLogPageReplyPayload {
     fn decode(data: impl IntoIterator<Item = u8>) -> Result<Self, DecodeError>
@@ -93,12 +100,10 @@ impl Decode for ReadLogPageReplyPayload {
 
         let log_size = u8::decode(&mut data)?;
         let offset = u32::decode(&mut data)?;
-        let count = u16::decode(&mut data)?;
-        let entries = Vec::sized_decode(&mut data, count)?;
+        let entries = CountedVec::decode(&mut data)?;
         Ok(Self {
             log_size,
             offset,
-            count,
             entries,
         })
     }
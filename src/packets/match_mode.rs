@@ -1,6 +1,9 @@
 use crate::encode::Encode;
 
-use super::cdc2::{Cdc2CommandPacket, Cdc2ReplyPacket};
+use super::{
+    cdc2::{Cdc2CommandPacket, Cdc2ReplyPacket},
+    cdc_command,
+};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum MatchMode {
@@ -25,3 +28,4 @@ impl Encode for SetMatchModePayload {
 
 pub type SetMatchModePacket = Cdc2CommandPacket<88, 193, SetMatchModePayload>;
 pub type SetMatchModeReplyPacket = Cdc2ReplyPacket<88, 193, ()>;
+cdc_command!(SetMatchModePacket => SetMatchModeReplyPacket);
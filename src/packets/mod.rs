@@ -1,4 +1,55 @@
-use crate::decode::{Decode, DecodeError};
+//! Packets sent to and received from V5 devices.
+//!
+//! # Reply payloads
+//!
+//! Newer VEXos versions sometimes append fields to existing replies, so payload decoders must
+//! not fail just because a payload is longer than the fields they know about:
+//!
+//! - [`Cdc2ReplyPacket`](cdc2::Cdc2ReplyPacket) and [`CdcReplyPacket`](cdc::CdcReplyPacket)
+//!   only give the payload decoder the bytes covered by the frame's payload size, and skip any
+//!   bytes it leaves unread. Payload decoders should read the fields they know and stop.
+//! - Fields that older firmware leaves off the end of a payload should be decoded as an
+//!   [`Option`], which decodes to `None` when no bytes are left.
+//! - Payloads whose length varies should implement [`SizedDecode`](crate::decode::SizedDecode)
+//!   and work out their length from the payload size rather than reading until the end of the
+//!   input.
+//! - Devices can acknowledge a request with an empty payload when they have nothing to report.
+//!   Payloads that may legitimately be empty should be decoded as an [`Option`], like
+//!   [`GetDirectoryEntryReplyPacket`](file::GetDirectoryEntryReplyPacket)'s, so that an empty
+//!   payload decodes to `None` rather than made-up values. Any other payload that's
+//!   acknowledged empty fails with [`DecodeError::EmptyPayload`], which tells callers that the
+//!   reply arrived intact but had nothing in it.
+//!
+//! Decoders must never panic, whatever bytes they're given, so indexing and slicing are denied
+//! in this module. Use [`Iterator::next`] and [`slice::get`] instead.
+//!
+//! # Talking through a controller
+//!
+//! Over a controller's system port, some packets are answered by the controller and others are
+//! relayed over its radio to the brain that it's linked to:
+//!
+//! - CDC2 packets with command ID 86 (`USER_CDC`), such as
+//!   [`GetSystemStatusPacket`](system::GetSystemStatusPacket) and
+//!   [`GetDeviceStatusPacket`](device::GetDeviceStatusPacket), are relayed to the brain. Some
+//!   replies come back cut short; see [`SystemStatus`](system::SystemStatus).
+//! - CDC2 packets with command ID 88 (`CON_CDC`) are answered by the controller itself. These
+//!   are [`ForceControllerRadioPacket`](controller::ForceControllerRadioPacket) (63),
+//!   [`ControllerRadioModePacket`](controller::ControllerRadioModePacket) (65),
+//!   [`ControllerVersionExpectPacket`](controller::ControllerVersionExpectPacket) (73) and
+//!   [`SetMatchModePacket`](match_mode::SetMatchModePacket) (193).
+//! - Simple CDC packets are answered by the controller, so
+//!   [`GetSystemVersionPacket`](system::GetSystemVersionPacket) reports the controller's
+//!   version and product type rather than the brain's.
+//!
+//! This crate doesn't know of a way to ask a controller for the brain's version, or for the
+//! controller's own system or device status.
+
+#![deny(clippy::indexing_slicing)]
+
+use crate::{
+    connection::CheckHeader,
+    decode::{Decode, DecodeError},
+};
 
 pub mod capture;
 pub mod cdc;
@@ -20,3 +71,22 @@ pub const DEVICE_BOUND_HEADER: [u8; 4] = [0xC9, 0x36, 0xB8, 0x47];
 
 /// Header byte sequence used for all host-bound packets.
 pub const HOST_BOUND_HEADER: [u8; 2] = [0xAA, 0x55];
+
+/// A device-bound packet with a known reply packet.
+///
+/// This lets [`Connection::request`](crate::connection::Connection::request) infer
+/// which packet to wait for, so that a command can't be paired with the wrong reply.
+pub trait CdcCommand {
+    /// The packet sent back by the device in response to this one.
+    type Reply: Decode + CheckHeader;
+}
+
+/// Implements [`CdcCommand`] for a command packet and its reply.
+macro_rules! cdc_command {
+    ($packet:ty => $reply:ty) => {
+        impl $crate::packets::CdcCommand for $packet {
+            type Reply = $reply;
+        }
+    };
+}
+pub(crate) use cdc_command;
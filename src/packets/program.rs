@@ -19,7 +19,8 @@ impl Decode for Slot {
         let mut data = data.into_iter();
         let icon_number = u16::decode(&mut data)?;
         let name_length = u8::decode(&mut data)?;
-        let name = String::sized_decode(&mut data, (name_length - 1) as _)?;
+        // The name's length includes its nul terminator
+        let name = String::sized_decode(&mut data, name_length as _)?;
 
         Ok(Self {
             icon_number,
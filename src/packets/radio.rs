@@ -2,7 +2,7 @@ use crate::encode::{Encode, EncodeError};
 
 use super::{
     cdc2::{Cdc2CommandPacket, Cdc2ReplyPacket},
-    Decode,
+    cdc_command, Decode,
 };
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
@@ -37,6 +37,7 @@ impl Decode for RadioStatus {
 
 pub type GetRadioStatusPacket = Cdc2CommandPacket<86, 38, ()>;
 pub type GetRadioStatusReplyPacket = Cdc2ReplyPacket<86, 38, RadioStatus>;
+cdc_command!(GetRadioStatusPacket => GetRadioStatusReplyPacket);
 
 #[repr(u8)]
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
@@ -57,6 +58,7 @@ impl Encode for RadioChannel {
 }
 pub type SelectRadioChannelPacket = Cdc2CommandPacket<86, 16, SelectRadioChannelPayload>;
 pub type SelectRadioChannelReplyPacket = Cdc2ReplyPacket<86, 16, ()>;
+cdc_command!(SelectRadioChannelPacket => SelectRadioChannelReplyPacket);
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub struct SelectRadioChannelPayload {
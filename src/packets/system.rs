@@ -1,36 +1,74 @@
 use super::{
     cdc::{CdcCommandPacket, CdcReplyPacket},
     cdc2::{Cdc2CommandPacket, Cdc2ReplyPacket},
+    cdc_command,
+    dash::DashScreen,
 };
 use crate::{
-    decode::{Decode, DecodeError},
+    decode::{pad, Decode, DecodeError},
     version::Version,
 };
 use bitflags::bitflags;
 
-#[repr(u16)]
+/// The kind of VEX product on the other end of a connection.
+#[non_exhaustive]
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub enum ProductType {
-    Brain = 0x10,
-    Controller = 0x11,
+    /// V5 Brain
+    Brain,
+    /// V5 Controller
+    Controller,
+    /// EXP Brain
+    ///
+    /// Decoded from 0x60, which hasn't been confirmed from a capture.
+    ExpBrain,
+    /// EXP Controller
+    ///
+    /// Decoded from 0x61, which hasn't been confirmed from a capture.
+    ExpController,
+    /// A product that this crate doesn't know about yet.
+    ///
+    /// AIM and AIR products currently decode to this variant until their
+    /// discriminants have been confirmed from captures. Like them, the EXP
+    /// values are unverified, and an EXP product may turn out to decode here.
+    Unknown(u8),
+}
+impl ProductType {
+    /// Returns the raw byte used to identify this product on the wire.
+    pub fn value(&self) -> u8 {
+        match self {
+            Self::Brain => 0x10,
+            Self::Controller => 0x11,
+            Self::ExpBrain => 0x60,
+            Self::ExpController => 0x61,
+            Self::Unknown(value) => *value,
+        }
+    }
 }
 impl Decode for ProductType {
     fn decode(data: impl IntoIterator<Item = u8>) -> Result<Self, DecodeError> {
         let mut data = data.into_iter();
-        let _unknown = u8::decode(&mut data)?;
-        let val = u8::decode(data)?;
-        match val {
-            0x10 => Ok(Self::Brain),
-            0x11 => Ok(Self::Controller),
-            v => Err(DecodeError::UnexpectedValue {
-                value: v,
-                expected: &[0x10, 0x11],
-            }),
+        pad::<1>(&mut data)?;
+        Ok(Self::from(u8::decode(data)?))
+    }
+}
+impl From<u8> for ProductType {
+    fn from(value: u8) -> Self {
+        match value {
+            0x10 => Self::Brain,
+            0x11 => Self::Controller,
+            0x60 => Self::ExpBrain,
+            0x61 => Self::ExpController,
+            v => Self::Unknown(v),
         }
     }
 }
 
 bitflags! {
+    /// Flags reported alongside the [`ProductType`].
+    ///
+    /// These bits have only been observed on V5 controllers. Other products
+    /// may set different bits, which are discarded when decoding.
     #[derive(Debug, Clone, Copy, Eq, PartialEq)]
     pub struct ProductFlags: u8 {
         /// Bit 1 is set when the controller is connected over a cable to the V5 Brain
@@ -72,13 +110,32 @@ pub struct SystemFlags {
     /// 145 = Driver program
     pub current_program: u8,
 }
+impl SystemFlags {
+    /// Returns the index of the dashboard page shown on the brain, from the top 8 bits of
+    /// `flags`.
+    pub fn page_index(&self) -> u8 {
+        (self.flags >> 24) as u8
+    }
+
+    /// Returns the dashboard screen shown on the brain, or `None` if its page index doesn't
+    /// match a [`DashScreen`].
+    pub fn dash_screen(&self) -> Option<DashScreen> {
+        DashScreen::from_page_index(self.page_index())
+    }
+
+    /// Returns the brain's battery percentage, in steps of 8%.
+    pub fn battery_percent(&self) -> u8 {
+        ((self.byte_1 >> 4) * 8).min(100)
+    }
+
+    /// Returns the battery percentage of the controller, in steps of 8%.
+    pub fn controller_battery_percent(&self) -> u8 {
+        ((self.byte_1 & 0x0F) * 8).min(100)
+    }
+}
 impl Decode for SystemFlags {
     fn decode(data: impl IntoIterator<Item = u8>) -> Result<Self, DecodeError> {
-        let mut data = data.into_iter();
-        let flags = u32::decode(&mut data)?;
-        let byte_1 = u8::decode(&mut data)?;
-        let byte_2 = u8::decode(&mut data)?;
-        let current_program = u8::decode(&mut data)?;
+        let (flags, byte_1, byte_2, current_program) = Decode::decode(data)?;
 
         Ok(Self {
             flags,
@@ -89,36 +146,63 @@ impl Decode for SystemFlags {
     }
 }
 
+/// The version that devices report in place of a version they don't know.
+const UNREPORTED: Version = Version {
+    major: 0,
+    minor: 0,
+    build: 0,
+    beta: 0,
+};
+
+/// Returns `None` for [`UNREPORTED`] versions.
+fn reported(version: Version) -> Option<Version> {
+    (version != UNREPORTED).then_some(version)
+}
+
+/// Decodes a trailing optional field, treating a field cut off partway as missing.
+fn truncated<D: Decode>(data: impl IntoIterator<Item = u8>) -> Result<Option<D>, DecodeError> {
+    match Option::<D>::decode(data) {
+        Err(DecodeError::PacketTooShort) => Ok(None),
+        result => result,
+    }
+}
+
+/// The brain's firmware versions and system details.
+///
+/// Versions reported as all zeroes decode as `None`. Over a wired connection to a brain, every
+/// field is reported. Over a controller's radio link, `system_version` is zeroed,
+/// `cpu1_version` and `touch_version` are sometimes zeroed, and `details` may be cut short,
+/// in which case it decodes as `None`. `cpu0_version` is reported over both.
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub struct SystemStatus {
     pub unknown: u8,
-    pub system_version: Version,
-    pub cpu0_version: Version,
-    pub cpu1_version: Version,
+    pub system_version: Option<Version>,
+    pub cpu0_version: Option<Version>,
+    pub cpu1_version: Option<Version>,
     /// NOTE: Encoded as little endian
-    pub touch_version: Version,
+    pub touch_version: Option<Version>,
     pub details: Option<SystemDetails>,
 }
 impl Decode for SystemStatus {
     fn decode(data: impl IntoIterator<Item = u8>) -> Result<Self, DecodeError> {
         let mut data = data.into_iter();
         let unknown = u8::decode(&mut data)?;
-        let system_version = Version::decode(&mut data)?;
-        let cpu0_version = Version::decode(&mut data)?;
-        let cpu1_version = Version::decode(&mut data)?;
+        let system_version = reported(Version::decode(&mut data)?);
+        let cpu0_version = reported(Version::decode(&mut data)?);
+        let cpu1_version = reported(Version::decode(&mut data)?);
 
         // This version is little endian for some reason
         let touch_beta = u8::decode(&mut data)?;
         let touch_build = u8::decode(&mut data)?;
         let touch_minor = u8::decode(&mut data)?;
         let touch_major = u8::decode(&mut data)?;
-        let touch_version = Version {
+        let touch_version = reported(Version {
             major: touch_major,
             minor: touch_minor,
             build: touch_build,
             beta: touch_beta,
-        };
-        let details = Option::<SystemDetails>::decode(&mut data)?;
+        });
+        let details = truncated::<SystemDetails>(&mut data)?;
 
         Ok(Self {
             unknown,
@@ -131,6 +215,157 @@ impl Decode for SystemStatus {
     }
 }
 
+impl SystemStatus {
+    /// Compares the brain's firmware versions against the versions that a host expects.
+    ///
+    /// Only `major`, `minor`, and `build` are compared. It isn't known whether a beta build
+    /// comes before or after the release with the same version, so any `beta` of the expected
+    /// version counts as up to date.
+    pub fn firmware_status(&self, expected: &ExpectedFirmware) -> FirmwareStatus {
+        let release = |version: Version| (version.major, version.minor, version.build);
+        let mut status = FirmwareStatus::default();
+        let mut check = |component, actual: Option<Version>, expected: Option<Version>| {
+            match (actual, expected) {
+                (_, None) => {}
+                (None, Some(_)) => status.unknown |= component,
+                (Some(actual), Some(expected)) => {
+                    if release(actual) < release(expected) {
+                        status.outdated |= component;
+                    }
+                }
+            }
+        };
+
+        check(FirmwareComponents::CPU0, self.cpu0_version, Some(expected.cpu0));
+        check(FirmwareComponents::CPU1, self.cpu1_version, Some(expected.cpu1));
+        check(
+            FirmwareComponents::GOLDEN,
+            self.details.and_then(|details| details.golden_version),
+            expected.golden,
+        );
+        check(
+            FirmwareComponents::NXP,
+            self.details.and_then(|details| details.nxp_version),
+            expected.nxp,
+        );
+
+        status
+    }
+
+    /// Returns `system_version`, or an all-zero version if it wasn't reported.
+    #[deprecated(note = "`system_version` is now an `Option`; use it directly")]
+    pub fn system_version_or_zero(&self) -> Version {
+        self.system_version.unwrap_or(UNREPORTED)
+    }
+
+    /// Returns `cpu0_version`, or an all-zero version if it wasn't reported.
+    #[deprecated(note = "`cpu0_version` is now an `Option`; use it directly")]
+    pub fn cpu0_version_or_zero(&self) -> Version {
+        self.cpu0_version.unwrap_or(UNREPORTED)
+    }
+
+    /// Returns `cpu1_version`, or an all-zero version if it wasn't reported.
+    #[deprecated(note = "`cpu1_version` is now an `Option`; use it directly")]
+    pub fn cpu1_version_or_zero(&self) -> Version {
+        self.cpu1_version.unwrap_or(UNREPORTED)
+    }
+
+    /// Returns `touch_version`, or an all-zero version if it wasn't reported.
+    #[deprecated(note = "`touch_version` is now an `Option`; use it directly")]
+    pub fn touch_version_or_zero(&self) -> Version {
+        self.touch_version.unwrap_or(UNREPORTED)
+    }
+}
+
+/// The firmware versions that a host expects a brain to be running, usually taken
+/// from the VEXos release being installed.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct ExpectedFirmware {
+    pub cpu0: Version,
+    pub cpu1: Version,
+    /// The expected golden (recovery) image version, if it should be checked.
+    pub golden: Option<Version>,
+    /// The expected NXP (radio/IO processor) version, if it should be checked.
+    pub nxp: Option<Version>,
+}
+
+bitflags! {
+    /// Firmware components of a brain.
+    #[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+    pub struct FirmwareComponents: u8 {
+        const CPU0 = 1 << 0;
+        const CPU1 = 1 << 1;
+        const GOLDEN = 1 << 2;
+        const NXP = 1 << 3;
+    }
+}
+
+/// The result of [`SystemStatus::firmware_status`].
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct FirmwareStatus {
+    /// Components running an older version than expected.
+    pub outdated: FirmwareComponents,
+    /// Components whose version the brain didn't report, so they couldn't be compared.
+    pub unknown: FirmwareComponents,
+}
+impl FirmwareStatus {
+    /// Returns `true` if no component is known to be out of date.
+    pub fn is_up_to_date(&self) -> bool {
+        self.outdated.is_empty()
+    }
+}
+
+bitflags! {
+    /// Known bits of [`SystemDetails::flags_2`].
+    ///
+    /// Each bit is named after the bit list on [`SystemDetails::flags_2`], which numbers bits
+    /// from the most significant, so "no.1 bit" is `1 << 15`. That list is marked as needing
+    /// research, and none of these bits has been checked against a capture. Unknown bits are
+    /// preserved.
+    #[derive(Debug, Clone, Copy, Eq, PartialEq)]
+    pub struct StatusFlags: u16 {
+        /// The master controller is charging (no.1 bit).
+        const CONTROLLER_CHARGING = 1 << 15;
+
+        /// The brain is in autonomous mode (no.2 bit).
+        const AUTONOMOUS = 1 << 14;
+
+        /// The brain is disabled (no.3 bit).
+        const DISABLED = 1 << 13;
+
+        /// A field controller is connected (no.4 bit).
+        const FIELD_CONTROLLER_CONNECTED = 1 << 12;
+    }
+}
+
+bitflags! {
+    /// Known bits of [`SystemDetails::flags_3`].
+    ///
+    /// Each bit is named after the bit list on [`SystemDetails::flags_3`], like
+    /// [`StatusFlags`], and none has been checked against a capture. The top four bits are the
+    /// language index rather than flags, so they're read with [`SystemDetails::language_index`].
+    ///
+    /// No bits are known for booting from an SD card, the golden image being active, or a
+    /// failed RAM test, so those states can't be read from here yet. Unknown bits are preserved.
+    #[derive(Debug, Clone, Copy, Eq, PartialEq)]
+    pub struct BootFlags: u16 {
+        /// The brain is using the white theme (no.6 bit).
+        const WHITE_THEME = 1 << 10;
+
+        /// The screen rotation is normal (no.8 bit).
+        const ROTATION_NORMAL = 1 << 8;
+
+        /// The RAM boot loader is active (no.14 bit).
+        const RAM_BOOT_LOADER = 1 << 2;
+
+        /// The ROM boot loader is active (no.15 bit).
+        const ROM_BOOT_LOADER = 1 << 1;
+
+        /// This is an event brain, or field control is being signaled over serial (no.16 bit).
+        const EVENT_BRAIN = 1 << 0;
+    }
+}
+
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub struct SystemDetails {
     pub unique_id: u32,
@@ -158,20 +393,18 @@ pub struct SystemDetails {
     /// (RESEARCH NEEDED)
     pub flags_3: u16,
     pub unknown: u16,
-    pub golden_version: Version,
+    /// `None` if it was reported as all zeroes.
+    pub golden_version: Option<Version>,
+    /// `None` if it was reported as all zeroes, or left out entirely.
     pub nxp_version: Option<Version>,
 }
 impl Decode for SystemDetails {
     fn decode(data: impl IntoIterator<Item = u8>) -> Result<Self, DecodeError> {
         let mut data = data.into_iter();
-
-        let unique_id = u32::decode(&mut data)?;
-        let flags_1 = u16::decode(&mut data)?;
-        let flags_2 = u16::decode(&mut data)?;
-        let flags_3 = u16::decode(&mut data)?;
-        let unknown = u16::decode(&mut data)?;
-        let golden_version = Version::decode(&mut data)?;
-        let nxp_version = Option::<Version>::decode(&mut data)?;
+        let (unique_id, flags_1, flags_2, flags_3, unknown, golden_version) =
+            <(u32, u16, u16, u16, u16, Version)>::decode(&mut data)?;
+        let golden_version = reported(golden_version);
+        let nxp_version = truncated::<Version>(&mut data)?.and_then(reported);
 
         Ok(Self {
             unique_id,
@@ -184,39 +417,68 @@ impl Decode for SystemDetails {
         })
     }
 }
+impl SystemDetails {
+    /// Returns the known bits of `flags_2`.
+    pub fn status_flags(&self) -> StatusFlags {
+        StatusFlags::from_bits_retain(self.flags_2)
+    }
+
+    /// Returns the known bits of `flags_3`. The language index bits are kept as unknown bits.
+    pub fn boot_flags(&self) -> BootFlags {
+        BootFlags::from_bits_retain(self.flags_3)
+    }
+
+    /// Returns the language index shown on the brain's language settings page, from the top
+    /// four bits of `flags_3`.
+    pub fn language_index(&self) -> u8 {
+        (self.flags_3 >> 12) as u8
+    }
+}
 
 pub type GetSystemFlagsPacket = Cdc2CommandPacket<86, 32, ()>;
 pub type GetSystemFlagsReplyPacket = Cdc2ReplyPacket<86, 32, SystemFlags>;
+cdc_command!(GetSystemFlagsPacket => GetSystemFlagsReplyPacket);
 
+/// Over a controller, this is relayed to the brain it's linked to.
 pub type GetSystemStatusPacket = Cdc2CommandPacket<86, 34, ()>;
 pub type GetSystemStatusReplyPacket = Cdc2ReplyPacket<86, 34, SystemStatus>;
+cdc_command!(GetSystemStatusPacket => GetSystemStatusReplyPacket);
 
+/// Over a controller, this is answered by the controller rather than its brain.
 pub type GetSystemVersionPacket = CdcCommandPacket<164, ()>;
 pub type GetSystemVersionReplyPacket = CdcReplyPacket<164, GetSystemVersionReplyPayload>;
+cdc_command!(GetSystemVersionPacket => GetSystemVersionReplyPacket);
 
-#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq)]
 pub struct GetSystemVersionReplyPayload {
     pub version: Version,
     pub product_type: ProductType,
     pub flags: ProductFlags,
+    /// Bytes after the known fields, or empty if there aren't any.
+    ///
+    /// Newer VEXos versions append bytes to this reply. What they contain hasn't been
+    /// identified yet, so they're kept as they are for comparing across firmware versions,
+    /// until they can be decoded as typed fields.
+    pub extra: Vec<u8>,
 }
 impl Decode for GetSystemVersionReplyPayload {
     fn decode(data: impl IntoIterator<Item = u8>) -> Result<Self, DecodeError> {
         let mut data = data.into_iter();
-        let version = Version::decode(&mut data)?;
-        let product_type = ProductType::decode(&mut data)?;
-        let flags = ProductFlags::from_bits_truncate(u8::decode(&mut data)?);
+        let (version, product_type, flags) = <(Version, ProductType, u8)>::decode(&mut data)?;
+        let flags = ProductFlags::from_bits_truncate(flags);
 
         Ok(Self {
             version,
             product_type,
             flags,
+            extra: data.collect(),
         })
     }
 }
 
 pub type Query1Packet = CdcCommandPacket<33, ()>;
 pub type Query1ReplyPacket = CdcReplyPacket<33, Query1ReplyPayload>;
+cdc_command!(Query1Packet => Query1ReplyPacket);
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub struct Query1ReplyPayload {
@@ -234,16 +496,16 @@ pub struct Query1ReplyPayload {
 
 impl Decode for Query1ReplyPayload {
     fn decode(data: impl IntoIterator<Item = u8>) -> Result<Self, DecodeError> {
-        let mut data = data.into_iter();
-
-        let unknown_1 = <[u8; 4]>::decode(&mut data)?;
-        let joystick_flag_1 = u8::decode(&mut data)?;
-        let joystick_flag_2 = u8::decode(&mut data)?;
-        let brain_flag_1 = u8::decode(&mut data)?;
-        let brain_flag_2 = u8::decode(&mut data)?;
-        let unknown_2 = <[u8; 2]>::decode(&mut data)?;
-        let bootload_flag_1 = u8::decode(&mut data)?;
-        let bootload_flag_2 = u8::decode(&mut data)?;
+        let (
+            unknown_1,
+            joystick_flag_1,
+            joystick_flag_2,
+            brain_flag_1,
+            brain_flag_2,
+            unknown_2,
+            bootload_flag_1,
+            bootload_flag_2,
+        ) = Decode::decode(data)?;
 
         Ok(Self {
             unknown_1,
@@ -256,4 +518,122 @@ impl Decode for Query1ReplyPayload {
             bootload_flag_2,
         })
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::{
+        BootFlags, ExpectedFirmware, FirmwareComponents, GetSystemVersionReplyPacket, ProductType,
+        SystemDetails, SystemStatus,
+    };
+    use crate::{decode::Decode, version::Version};
+
+    fn version(major: u8, minor: u8, build: u8, beta: u8) -> Version {
+        Version {
+            major,
+            minor,
+            build,
+            beta,
+        }
+    }
+
+    #[test]
+    fn decode_exp_brain_version() {
+        // Hand-written with the unconfirmed EXP brain value, not captured from one
+        let data: &[u8] = &[0xaa, 0x55, 0xa4, 0x7, 0x1, 0x1, 0x4, 0x0, 0x0, 0x60, 0x0];
+        let reply = GetSystemVersionReplyPacket::decode(data.iter().cloned()).unwrap();
+        assert_eq!(reply.payload.product_type, ProductType::ExpBrain);
+    }
+
+    #[test]
+    fn decode_unknown_product() {
+        let data: &[u8] = &[0xaa, 0x55, 0xa4, 0x7, 0x1, 0x1, 0x4, 0x0, 0x0, 0x70, 0x0];
+        let reply = GetSystemVersionReplyPacket::decode(data.iter().cloned()).unwrap();
+        assert_eq!(reply.payload.product_type, ProductType::Unknown(0x70));
+    }
+
+    #[test]
+    fn language_index() {
+        let details = SystemDetails {
+            unique_id: 0,
+            flags_1: 0,
+            flags_2: 0,
+            flags_3: 0x3401,
+            unknown: 0,
+            golden_version: None,
+            nxp_version: None,
+        };
+
+        assert_eq!(details.language_index(), 3);
+        let flags = details.boot_flags();
+        assert!(flags.contains(BootFlags::WHITE_THEME | BootFlags::EVENT_BRAIN));
+        assert!(!flags.contains(BootFlags::ROTATION_NORMAL));
+    }
+
+    #[test]
+    fn firmware_status() {
+        let mut status = SystemStatus {
+            unknown: 0,
+            system_version: Some(version(1, 1, 4, 0)),
+            cpu0_version: Some(version(1, 1, 4, 0)),
+            cpu1_version: Some(version(1, 1, 3, 9)),
+            touch_version: Some(version(1, 0, 0, 0)),
+            details: None,
+        };
+        let expected = ExpectedFirmware {
+            cpu0: version(1, 1, 4, 0),
+            cpu1: version(1, 1, 4, 0),
+            golden: Some(version(1, 1, 0, 0)),
+            nxp: None,
+        };
+
+        let firmware = status.firmware_status(&expected);
+        assert_eq!(firmware.outdated, FirmwareComponents::CPU1);
+        assert_eq!(firmware.unknown, FirmwareComponents::GOLDEN);
+        assert!(!firmware.is_up_to_date());
+
+        status.cpu1_version = Some(version(1, 1, 4, 0));
+        status.details = Some(SystemDetails {
+            unique_id: 0,
+            flags_1: 0,
+            flags_2: 0,
+            flags_3: 0,
+            unknown: 0,
+            golden_version: Some(version(1, 1, 0, 0)),
+            nxp_version: None,
+        });
+        let firmware = status.firmware_status(&expected);
+        assert!(firmware.is_up_to_date());
+        assert!(firmware.unknown.is_empty());
+    }
+
+    #[test]
+    fn firmware_status_beta() {
+        let mut status = SystemStatus {
+            unknown: 0,
+            system_version: Some(version(1, 1, 4, 0)),
+            cpu0_version: Some(version(1, 1, 4, 0)),
+            cpu1_version: Some(version(1, 1, 4, 0)),
+            touch_version: None,
+            details: None,
+        };
+        let mut expected = ExpectedFirmware {
+            cpu0: version(1, 1, 4, 0),
+            cpu1: version(1, 1, 4, 3),
+            golden: None,
+            nxp: None,
+        };
+
+        // A release and a beta of the same version satisfy each other
+        assert!(status.firmware_status(&expected).is_up_to_date());
+        status.cpu0_version = Some(version(1, 1, 4, 3));
+        expected.cpu1 = version(1, 1, 4, 0);
+        assert!(status.firmware_status(&expected).is_up_to_date());
+
+        // but not an earlier build, whatever its beta
+        status.cpu1_version = Some(version(1, 1, 3, 9));
+        assert_eq!(
+            status.firmware_status(&expected).outdated,
+            FirmwareComponents::CPU1
+        );
+    }
+}
@@ -0,0 +1,59 @@
+//! Re-exports of the types most programs need, meant to be glob imported.
+//!
+//! ```
+//! use vex_v5_serial::prelude::*;
+//! ```
+//!
+//! This brings in the connection traits, each enabled transport, the [`Command`]s, and the types
+//! used to fill them in. Transports are re-exported as modules so that their discovery functions
+//! don't clash, e.g. `serial::find_devices()`.
+//!
+//! Packet types are deliberately left out, since most tasks are better served by a command.
+//! They can still be imported from [`packets`](crate::packets) when a command doesn't exist.
+
+#[cfg(feature = "ini")]
+pub use crate::commands::file::{LongTextPolicy, UploadProgram};
+#[cfg(feature = "screen-command")]
+pub use crate::commands::screen::{
+    GetCurrentDashScreen, MockTap, MockTouch, OpenDashScreen, ScreenCapture,
+};
+#[cfg(feature = "bluetooth")]
+pub use crate::connection::bluetooth::{
+    self, BluetoothConnection, BluetoothDevice, BluetoothError,
+};
+#[cfg(all(
+    any(feature = "serial", feature = "smol-serial"),
+    feature = "bluetooth"
+))]
+pub use crate::connection::generic::{self, GenericConnection, GenericDevice, GenericError};
+#[cfg(any(feature = "serial", feature = "smol-serial"))]
+pub use crate::connection::serial::{self, ProbeInfo, SerialConnection, SerialDevice, SerialError};
+pub use crate::{
+    commands::{
+        controller::{
+            DownloadChannelGuard, ForceRadio, SetControllerRadioMode, UserFifoSettings,
+            WriteUserFifo,
+        },
+        file::{
+            BrainFileReader, ColdLibrary, DownloadFile, DownloadedFile, EraseFile, EraseProgram,
+            FileUploadOutcome, GetSlotDigest, GetStorageUsage, HotColdUpload, LinkedFile,
+            LowBatteryPolicy, ProgramData, ReadMemory, SlotsChanged, TransferSummary, UploadFile,
+            UploadReport, DEFAULT_MIN_BATTERY_PERCENT, DEFAULT_WIRELESS_PACING,
+        },
+        fs::{BrainFs, WriteOptions},
+        kv::{ReadKv, WriteKv},
+        lock::{AcquireLock, BrainLock, LockPolicy},
+        log::{LogCursor, LogPage, ReadLog},
+        progress::{ProgressStream, TransferProgress},
+        Command, CommandError,
+    },
+    connection::{Connection, ConnectionType, HandshakeError, TransferState},
+    packets::{
+        controller::ControllerRadioMode,
+        dash::DashScreen,
+        file::{ExtensionType, FileExitAction, FileMetadata, FileTransferTarget, FileVendor},
+        kv::KvKey,
+    },
+    string::FixedString,
+    version::Version,
+};
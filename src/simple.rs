@@ -0,0 +1,249 @@
+//! A blocking, all-in-one program upload for build scripts and CI.
+//!
+//! [`upload_program`] finds a device, connects to it, and uploads a program with sensible
+//! defaults, running its own async runtime so that callers don't need one:
+//!
+//! ```no_run
+//! use vex_v5_serial::simple::{upload_program, UploadOptions};
+//!
+//! let report = upload_program("target/program.bin", 1, UploadOptions::default())?;
+//! eprintln!("Uploaded {} files", report.files.len());
+//! # Ok::<(), vex_v5_serial::simple::UploadError>(())
+//! ```
+//!
+//! Requires the `serial` feature.
+
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use thiserror::Error;
+
+use crate::{
+    commands::file::{
+        LongTextPolicy, LowBatteryPolicy, ProgramData, UploadProgram, UploadReport,
+        DEFAULT_MIN_BATTERY_PERCENT,
+    },
+    connection::{
+        serial::{self, SerialDevice, SerialError},
+        Connection,
+    },
+    packets::file::FileExitAction,
+};
+
+/// The program to upload, either as a file to read or as its bytes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProgramSource {
+    Path(PathBuf),
+    Bytes(Vec<u8>),
+}
+impl From<&Path> for ProgramSource {
+    fn from(path: &Path) -> Self {
+        Self::Path(path.to_path_buf())
+    }
+}
+impl From<PathBuf> for ProgramSource {
+    fn from(path: PathBuf) -> Self {
+        Self::Path(path)
+    }
+}
+impl From<&str> for ProgramSource {
+    fn from(path: &str) -> Self {
+        Self::Path(path.into())
+    }
+}
+impl From<Vec<u8>> for ProgramSource {
+    fn from(bytes: Vec<u8>) -> Self {
+        Self::Bytes(bytes)
+    }
+}
+
+/// Settings for [`upload_program`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UploadOptions {
+    pub name: String,
+    pub description: String,
+    pub icon: String,
+    /// The IDE or toolchain reported in the program's ini file.
+    pub program_type: String,
+    pub after_upload: FileExitAction,
+    pub compress: bool,
+    /// Whether to print upload progress to stderr.
+    pub progress: bool,
+    /// How long to wait for the device's serial ports to open.
+    pub connect_timeout: Duration,
+}
+impl Default for UploadOptions {
+    fn default() -> Self {
+        Self {
+            name: "program".to_string(),
+            description: String::new(),
+            icon: "USER029x.bmp".to_string(),
+            program_type: "vex-v5-serial".to_string(),
+            after_upload: FileExitAction::ShowRunScreen,
+            compress: true,
+            progress: true,
+            connect_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+/// An error from [`upload_program`].
+#[derive(Error, Debug)]
+pub enum UploadError {
+    #[error("Program slot {0} is out of range. Slots are numbered 1 to 8")]
+    InvalidSlot(u8),
+    #[error("Couldn't read the program file {}: {source}", .path.display())]
+    ReadProgram {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("Couldn't start the async runtime: {0}")]
+    Runtime(std::io::Error),
+    #[error("No V5 brain or controller is plugged in")]
+    NoDevice,
+    #[error(transparent)]
+    Serial(#[from] SerialError),
+}
+
+/// Uploads a program to a slot on the first brain plugged in over USB.
+///
+/// If no brain is plugged in, the program is uploaded through the first controller instead,
+/// switching its radio to the download channel for the upload. Long names and descriptions
+/// are truncated rather than rejected.
+///
+/// `slot` is 1-indexed, like the slots shown on the brain.
+pub fn upload_program(
+    program: impl Into<ProgramSource>,
+    slot: u8,
+    options: UploadOptions,
+) -> Result<UploadReport, UploadError> {
+    if !(1..=8).contains(&slot) {
+        return Err(UploadError::InvalidSlot(slot));
+    }
+
+    let data = match program.into() {
+        ProgramSource::Bytes(bytes) => bytes,
+        ProgramSource::Path(path) => match std::fs::read(&path) {
+            Ok(bytes) => bytes,
+            Err(source) => return Err(UploadError::ReadProgram { path, source }),
+        },
+    };
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .map_err(UploadError::Runtime)?;
+
+    runtime.block_on(async {
+        let device = choose_device(serial::find_devices()?).ok_or(UploadError::NoDevice)?;
+        let mut connection = device.connect(options.connect_timeout)?;
+        Ok(connection
+            .execute_command(upload_command(data, slot, options))
+            .await?)
+    })
+}
+
+/// Picks the first brain, or the first controller if there are no brains.
+fn choose_device(devices: Vec<SerialDevice>) -> Option<SerialDevice> {
+    let controller = devices
+        .iter()
+        .find(|device| matches!(device, SerialDevice::Controller { .. }))
+        .cloned();
+    devices
+        .into_iter()
+        .find(|device| matches!(device, SerialDevice::Brain { .. }))
+        .or(controller)
+}
+
+fn upload_command(data: Vec<u8>, slot: u8, options: UploadOptions) -> UploadProgram<'static> {
+    let progress = |step: &'static str| -> Option<Box<dyn FnMut(f32) + Send>> {
+        if !options.progress {
+            return None;
+        }
+        Some(Box::new(move |percent| {
+            eprint!("\r{step}: {percent:5.1}%");
+            if percent >= 100.0 {
+                eprintln!();
+            }
+            _ = std::io::stderr().flush();
+        }))
+    };
+
+    UploadProgram {
+        name: options.name,
+        description: options.description,
+        icon: options.icon,
+        program_type: options.program_type,
+        slot,
+        compress_program: options.compress,
+        data: ProgramData::Monolith(data),
+        after_upload: options.after_upload,
+        storage_capacity: None,
+        wireless_pacing: None,
+        download_channel: true,
+        long_text: LongTextPolicy::Truncate(Box::new(|warning| eprintln!("warning: {warning}"))),
+        low_battery: LowBatteryPolicy::Warn {
+            threshold: DEFAULT_MIN_BATTERY_PERCENT,
+            callback: Box::new(|percent| {
+                eprintln!("warning: the brain's battery is at {percent}%, the upload may fail")
+            }),
+        },
+        ini_callback: progress("ini"),
+        bin_callback: progress("bin"),
+        lib_callback: progress("lib"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{choose_device, upload_command, upload_program, UploadError, UploadOptions};
+    use crate::{commands::file::ProgramData, connection::serial::SerialDevice};
+
+    #[test]
+    fn invalid_input() {
+        assert!(matches!(
+            upload_program(vec![0], 9, UploadOptions::default()),
+            Err(UploadError::InvalidSlot(9))
+        ));
+        assert!(matches!(
+            upload_program("does/not/exist.bin", 1, UploadOptions::default()),
+            Err(UploadError::ReadProgram { .. })
+        ));
+    }
+
+    #[test]
+    fn prefers_brain() {
+        let controller = SerialDevice::Controller {
+            system_port: "controller".to_string(),
+        };
+        let brain = SerialDevice::Brain {
+            user_port: "user".to_string(),
+            system_port: "system".to_string(),
+        };
+        let port = |devices| choose_device(devices).map(|device| device.system_port());
+        assert_eq!(
+            port(vec![controller.clone(), brain]).as_deref(),
+            Some("system")
+        );
+        assert_eq!(port(vec![controller]).as_deref(), Some("controller"));
+        assert_eq!(port(Vec::new()), None);
+    }
+
+    #[test]
+    fn quiet_command() {
+        let command = upload_command(
+            vec![1, 2, 3],
+            2,
+            UploadOptions {
+                progress: false,
+                ..Default::default()
+            },
+        );
+        assert_eq!(command.slot, 2);
+        assert!(matches!(command.data, ProgramData::Monolith(ref data) if data == &[1, 2, 3]));
+        assert!(command.bin_callback.is_none());
+    }
+}
@@ -1,4 +1,4 @@
-use std::{ffi::CStr, fmt::Display, str::FromStr};
+use std::{fmt::Display, str::FromStr};
 
 use crate::{
     decode::{Decode, DecodeError, SizedDecode},
@@ -6,12 +6,19 @@ use crate::{
 };
 
 /// A string with a maximum capacity of `len <= N`.
-#[derive(Debug, PartialEq, PartialOrd, Eq, Ord, Clone, Hash)]
+///
+/// # Wire format
+///
+/// A `FixedString<N>` is always a field of exactly `N + 1` bytes: the string, padded out with
+/// nul bytes so that the last byte is always a nul terminator. [`Encode`] and [`Decode`] both
+/// use the full field, so the fields after a `FixedString` stay aligned. Packets where the
+/// brain sends a string without padding use a [`String`] instead.
+#[derive(Debug, Default, PartialEq, PartialOrd, Eq, Ord, Clone, Hash)]
 pub struct FixedString<const N: usize>(String);
 
 impl<const N: usize> FixedString<N> {
     pub fn new(string: String) -> Result<Self, EncodeError> {
-        if string.as_bytes().len() > N {
+        if string.len() > N {
             return Err(EncodeError::StringTooLong);
         }
 
@@ -79,7 +86,11 @@ impl<const N: usize> Decode for FixedString<N> {
     where
         Self: Sized,
     {
-        Ok(Self(String::sized_decode(data, N as u16)?))
+        let string = String::sized_decode(data, N as u16 + 1)?;
+        if string.len() > N {
+            return Err(DecodeError::UnterminatedString);
+        }
+        Ok(Self(string))
     }
 }
 
@@ -92,33 +103,85 @@ impl Encode for String {
 }
 
 impl SizedDecode for String {
+    /// Decodes a nul-terminated string from a field of exactly `size` bytes.
+    ///
+    /// The string ends at the first nul, or fills the whole field if there isn't one. The rest
+    /// of the field is skipped, so exactly `size` bytes are always consumed.
     fn sized_decode(data: impl IntoIterator<Item = u8>, size: u16) -> Result<Self, DecodeError>
     where
         Self: Sized,
     {
-        let max_size = size as _;
         let mut data = data.into_iter();
-
-        let mut utf8 = vec![0u8; max_size];
-        for (i, string_byte) in utf8.iter_mut().enumerate() {
+        let mut bytes = Vec::new();
+        let mut terminated = false;
+        for _ in 0..size {
             let byte = u8::decode(&mut data)?;
-
-            if i == max_size {
-                if byte != 0 {
-                    return Err(DecodeError::UnterminatedString);
-                }
-                break;
-            }
             if byte == 0 {
-                break;
+                terminated = true;
+            } else if !terminated {
+                bytes.push(byte);
             }
-
-            *string_byte = byte;
         }
 
-        let cstr =
-            CStr::from_bytes_until_nul(&utf8).map_err(|_| DecodeError::UnterminatedString)?;
+        Ok(String::from_utf8(bytes).map_err(|e| e.utf8_error())?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FixedString;
+    use crate::{
+        decode::{Decode, DecodeError, SizedDecode},
+        encode::Encode,
+    };
+
+    #[test]
+    fn sized_string() {
+        let mut data = b"abc\0\0\0xyz".iter().copied();
+        assert_eq!(String::sized_decode(&mut data, 6).unwrap(), "abc");
+        // The padding after the terminator is consumed too
+        assert_eq!(data.collect::<Vec<_>>(), b"xyz");
+
+        let mut data = b"abcdefxyz".iter().copied();
+        assert_eq!(String::sized_decode(&mut data, 6).unwrap(), "abcdef");
+        assert_eq!(data.collect::<Vec<_>>(), b"xyz");
+
+        assert_eq!(
+            String::sized_decode(b"abc".iter().copied(), 6),
+            Err(DecodeError::PacketTooShort)
+        );
+    }
 
-        Ok(cstr.to_str()?.to_owned())
+    #[test]
+    fn fixed_string() {
+        let mut data = b"abc\0\0xyz".iter().copied();
+        assert_eq!(FixedString::<4>::decode(&mut data).unwrap().as_ref(), "abc");
+        assert_eq!(data.collect::<Vec<_>>(), b"xyz");
+
+        let mut data = b"abcd\0xyz".iter().copied();
+        assert_eq!(
+            FixedString::<4>::decode(&mut data).unwrap().as_ref(),
+            "abcd"
+        );
+        assert_eq!(data.collect::<Vec<_>>(), b"xyz");
+
+        assert_eq!(
+            FixedString::<4>::decode(b"abcde".iter().copied()),
+            Err(DecodeError::UnterminatedString)
+        );
+        assert_eq!(
+            FixedString::<4>::decode(b"abc\0".iter().copied()),
+            Err(DecodeError::PacketTooShort)
+        );
+    }
+
+    #[test]
+    fn fixed_string_round_trip() {
+        for string in ["", "slot_1.bin", "a_file_name_of_23_bytes"] {
+            let value = (FixedString::<23>::new(string.to_string()).unwrap(), 0x42u8);
+            let encoded = value.encode().unwrap();
+            assert_eq!(encoded.len(), 24 + 1);
+            assert_eq!(<(FixedString<23>, u8)>::decode(encoded).unwrap(), value);
+        }
     }
 }
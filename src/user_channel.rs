@@ -0,0 +1,342 @@
+//! Separating CDC frames from plain output on the user port.
+//!
+//! User programs can write CDC frames to the user port between their regular output, for
+//! example to send panic reports or telemetry in a structured form. [`UserChannelDemuxer`]
+//! splits the bytes read with [`Connection::read_user`](crate::connection::Connection::read_user)
+//! (or from any other byte stream) back into text and frames:
+//!
+//! ```
+//! use vex_v5_serial::user_channel::{UserChannelDemuxer, UserChannelEvent};
+//!
+//! let mut demuxer = UserChannelDemuxer::new();
+//! demuxer.push(b"hello\n\xAA\x55\x21\x01\x07world\n");
+//!
+//! while let Some(event) = demuxer.next_event() {
+//!     match event {
+//!         UserChannelEvent::Output(text) => print!("{}", String::from_utf8_lossy(&text)),
+//!         UserChannelEvent::Frame(frame) => println!("<frame {:02x?}>", frame.id()),
+//!     }
+//! }
+//! ```
+//!
+//! Host-bound frames use the same layout as replies from the brain, so they can be decoded
+//! with the reply packet types in [`packets`](crate::packets) using [`UserFrame::decode`].
+//! Host-bound CDC2 frames must end with a valid CRC16. Simple host-bound frames have no CRC, so
+//! their payload is limited to [`MAX_UNCHECKED_PAYLOAD`] bytes to keep a stray header in the
+//! program's output from holding back the output that follows it.
+//! Device-bound frames must use the CDC2 layout and end with a valid CRC16, since a simple
+//! CDC command without a payload has no length to find the end of the frame with.
+
+use crate::{
+    crc::VEX_CRC16,
+    decode::{Decode, DecodeError},
+    hex::HexPreview,
+    packets::{DEVICE_BOUND_HEADER, HOST_BOUND_HEADER},
+    varint::VarU16,
+};
+
+/// The largest payload of a host-bound frame that doesn't end with a CRC16.
+pub const MAX_UNCHECKED_PAYLOAD: usize = 0x7F;
+
+/// The command IDs of CDC2 frames, which end with a CRC16.
+const CDC2_IDS: [u8; 2] = [0x56, 0x58];
+
+/// The direction a frame's header marks it as being sent in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameDirection {
+    /// The frame starts with [`DEVICE_BOUND_HEADER`].
+    DeviceBound,
+    /// The frame starts with [`HOST_BOUND_HEADER`].
+    HostBound,
+}
+impl FrameDirection {
+    fn header(self) -> &'static [u8] {
+        match self {
+            Self::DeviceBound => &DEVICE_BOUND_HEADER,
+            Self::HostBound => &HOST_BOUND_HEADER,
+        }
+    }
+}
+
+/// A CDC frame found in the user port's output.
+#[derive(Clone, PartialEq, Eq)]
+pub struct UserFrame {
+    pub direction: FrameDirection,
+    /// The whole frame, including its header.
+    pub bytes: Vec<u8>,
+}
+impl UserFrame {
+    /// Returns the frame's command ID, or `None` if `bytes` ends after the header.
+    pub fn id(&self) -> Option<u8> {
+        self.bytes.get(self.direction.header().len()).copied()
+    }
+
+    /// Decodes the frame as a packet, such as a [`CdcReplyPacket`](crate::packets::cdc::CdcReplyPacket).
+    pub fn decode<P: Decode>(&self) -> Result<P, DecodeError> {
+        P::decode(self.bytes.iter().copied())
+    }
+}
+impl std::fmt::Debug for UserFrame {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("UserFrame")
+            .field("direction", &self.direction)
+            .field("bytes", &HexPreview(&self.bytes))
+            .finish()
+    }
+}
+
+/// A piece of the user port's output.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UserChannelEvent {
+    /// Bytes printed by the program. These are usually, but not always, UTF-8.
+    Output(Vec<u8>),
+    Frame(UserFrame),
+}
+
+/// The result of trying to read a frame from the start of the buffer.
+enum Parse {
+    /// The buffer holds the start of a frame, but not all of it.
+    Incomplete,
+    /// The buffer doesn't start with a frame.
+    Invalid,
+    /// The buffer starts with a frame of this many bytes.
+    Frame(FrameDirection, usize),
+}
+
+/// Splits a user port byte stream into program output and CDC frames.
+///
+/// Output is returned as soon as it can't be the start of a frame. Bytes that might start a
+/// frame are held back until enough of the frame has been pushed to tell.
+#[derive(Debug, Default)]
+pub struct UserChannelDemuxer {
+    buffer: Vec<u8>,
+}
+impl UserChannelDemuxer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds bytes read from the user port.
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    /// Returns the next piece of output or frame, or `None` if more bytes are needed.
+    pub fn next_event(&mut self) -> Option<UserChannelEvent> {
+        let output_len = match find_header(&self.buffer, 0)? {
+            0 => match parse_frame(&self.buffer) {
+                Parse::Incomplete => return None,
+                Parse::Frame(direction, len) => {
+                    let bytes = self.buffer.drain(..len).collect();
+                    return Some(UserChannelEvent::Frame(UserFrame { direction, bytes }));
+                }
+                // Only the first byte is known not to be part of a frame
+                Parse::Invalid => find_header(&self.buffer, 1).unwrap_or(self.buffer.len()),
+            },
+            start => start,
+        };
+
+        Some(UserChannelEvent::Output(
+            self.buffer.drain(..output_len).collect(),
+        ))
+    }
+
+    /// Returns everything still buffered as output, such as an incomplete frame at the end of
+    /// the stream.
+    pub fn flush(&mut self) -> Option<UserChannelEvent> {
+        if self.buffer.is_empty() {
+            return None;
+        }
+        Some(UserChannelEvent::Output(std::mem::take(&mut self.buffer)))
+    }
+}
+
+/// Returns the index of the first byte at or after `from` that could start a frame, or the end
+/// of the buffer if it's past `from`.
+///
+/// Returns `None` if the buffer is empty from `from` on.
+fn find_header(buffer: &[u8], from: usize) -> Option<usize> {
+    if from >= buffer.len() {
+        return None;
+    }
+
+    Some(
+        (from..buffer.len())
+            .find(|&start| {
+                let rest = &buffer[start..];
+                [DEVICE_BOUND_HEADER.as_slice(), HOST_BOUND_HEADER.as_slice()]
+                    .iter()
+                    .any(|header| {
+                        let len = rest.len().min(header.len());
+                        rest[..len] == header[..len]
+                    })
+            })
+            .unwrap_or(buffer.len()),
+    )
+}
+
+/// Tries to read a frame from the start of `buffer`.
+fn parse_frame(buffer: &[u8]) -> Parse {
+    let direction = if buffer.starts_with(&HOST_BOUND_HEADER) {
+        FrameDirection::HostBound
+    } else if buffer.starts_with(&DEVICE_BOUND_HEADER) {
+        FrameDirection::DeviceBound
+    } else if DEVICE_BOUND_HEADER.starts_with(buffer) || HOST_BOUND_HEADER.starts_with(buffer) {
+        return Parse::Incomplete;
+    } else {
+        return Parse::Invalid;
+    };
+
+    // Device-bound frames have an extended command ID before the size
+    let (size_start, trailer) = match direction {
+        FrameDirection::HostBound => (HOST_BOUND_HEADER.len() + 1, 0),
+        FrameDirection::DeviceBound => (DEVICE_BOUND_HEADER.len() + 2, 2),
+    };
+    let Some(&first) = buffer.get(size_start) else {
+        return Parse::Incomplete;
+    };
+    let size_len = if VarU16::check_wide(first) { 2 } else { 1 };
+    let Some(size) = buffer.get(size_start..size_start + size_len) else {
+        return Parse::Incomplete;
+    };
+    let Ok(size) = VarU16::decode(size.iter().copied()) else {
+        return Parse::Invalid;
+    };
+
+    let size = size.into_inner() as usize;
+    let has_crc = match direction {
+        FrameDirection::DeviceBound => true,
+        FrameDirection::HostBound => CDC2_IDS.contains(&buffer[HOST_BOUND_HEADER.len()]),
+    };
+    if !has_crc && size > MAX_UNCHECKED_PAYLOAD {
+        return Parse::Invalid;
+    }
+
+    let len = size_start + size_len + size + trailer;
+    let Some(frame) = buffer.get(..len) else {
+        return Parse::Incomplete;
+    };
+    if has_crc && VEX_CRC16.checksum(frame) != 0 {
+        return Parse::Invalid;
+    }
+
+    Parse::Frame(direction, len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FrameDirection, UserChannelDemuxer, UserChannelEvent, UserFrame};
+    use crate::{
+        crc::VEX_CRC16,
+        encode::Encode,
+        packets::{cdc::CdcReplyPacket, cdc2::Cdc2CommandPacket},
+    };
+
+    fn events(demuxer: &mut UserChannelDemuxer) -> Vec<UserChannelEvent> {
+        std::iter::from_fn(|| demuxer.next_event()).collect()
+    }
+
+    fn output(bytes: &[u8]) -> UserChannelEvent {
+        UserChannelEvent::Output(bytes.to_vec())
+    }
+
+    #[test]
+    fn text_only() {
+        let mut demuxer = UserChannelDemuxer::new();
+        demuxer.push(b"hello\n");
+        assert_eq!(events(&mut demuxer), [output(b"hello\n")]);
+        assert_eq!(demuxer.flush(), None);
+    }
+
+    #[test]
+    fn host_bound_frame() {
+        let mut demuxer = UserChannelDemuxer::new();
+        demuxer.push(b"before\xAA\x55\x21\x02\x07\x08after");
+
+        let events = events(&mut demuxer);
+        assert_eq!(events.len(), 3);
+        assert_eq!(events[0], output(b"before"));
+        assert_eq!(events[2], output(b"after"));
+
+        let UserChannelEvent::Frame(frame) = &events[1] else {
+            panic!("Expected a frame");
+        };
+        assert_eq!(frame.direction, FrameDirection::HostBound);
+        assert_eq!(frame.id(), Some(0x21));
+        let packet = frame.decode::<CdcReplyPacket<0x21, [u8; 2]>>().unwrap();
+        assert_eq!(packet.payload, [7, 8]);
+    }
+
+    #[test]
+    fn device_bound_frame() {
+        let bytes = Cdc2CommandPacket::<0x56, 0x10, (u8, u8, u8)>::new((1, 2, 3))
+            .encode()
+            .unwrap();
+        let mut demuxer = UserChannelDemuxer::new();
+        demuxer.push(&bytes);
+        assert_eq!(
+            events(&mut demuxer),
+            [UserChannelEvent::Frame(UserFrame {
+                direction: FrameDirection::DeviceBound,
+                bytes,
+            })]
+        );
+    }
+
+    #[test]
+    fn corrupted_device_bound_frame_is_output() {
+        let mut bytes = Cdc2CommandPacket::<0x56, 0x10, (u8, u8, u8)>::new((1, 2, 3))
+            .encode()
+            .unwrap();
+        *bytes.last_mut().unwrap() ^= 0xFF;
+        let mut demuxer = UserChannelDemuxer::new();
+        demuxer.push(&bytes);
+        assert_eq!(events(&mut demuxer), [output(&bytes)]);
+    }
+
+    #[test]
+    fn host_bound_cdc2_frame() {
+        let mut bytes = vec![0xAA, 0x55, 0x56, 0x05, 0x21, 0x76, 0x01];
+        bytes.extend(VEX_CRC16.checksum(&bytes).to_be_bytes());
+        let mut demuxer = UserChannelDemuxer::new();
+        demuxer.push(&bytes);
+        assert!(matches!(
+            events(&mut demuxer)[..],
+            [UserChannelEvent::Frame(_)]
+        ));
+
+        *bytes.last_mut().unwrap() ^= 0xFF;
+        demuxer.push(&bytes);
+        assert_eq!(events(&mut demuxer), [output(&bytes)]);
+    }
+
+    #[test]
+    fn oversized_host_bound_frame_is_output() {
+        // Output that happens to look like the start of a frame with a 32 KiB payload
+        let mut demuxer = UserChannelDemuxer::new();
+        demuxer.push(b"\xAA\x55\x21\xFF\xFFtext");
+        assert_eq!(events(&mut demuxer), [output(b"\xAA\x55\x21\xFF\xFFtext")]);
+    }
+
+    #[test]
+    fn frame_split_across_pushes() {
+        let mut demuxer = UserChannelDemuxer::new();
+        demuxer.push(b"ab\xAA");
+        assert_eq!(events(&mut demuxer), [output(b"ab")]);
+        demuxer.push(b"\x55\x21\x02\x07");
+        assert_eq!(events(&mut demuxer), []);
+        demuxer.push(b"\x08");
+        assert!(matches!(
+            events(&mut demuxer)[..],
+            [UserChannelEvent::Frame(_)]
+        ));
+    }
+
+    #[test]
+    fn flush_incomplete_frame() {
+        let mut demuxer = UserChannelDemuxer::new();
+        demuxer.push(b"\xAA\x55\x21\x05\x01");
+        assert_eq!(events(&mut demuxer), []);
+        assert_eq!(demuxer.flush(), Some(output(b"\xAA\x55\x21\x05\x01")));
+    }
+}
@@ -8,6 +8,9 @@ use crate::encode::{Encode, EncodeError};
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct VarU16(u16);
 impl VarU16 {
+    /// The largest value that can be represented by a variable length u16.
+    pub const MAX: u16 = u16::MAX >> 1;
+
     /// Creates a new variable length u16.
     ///
     /// # Panics
@@ -19,13 +22,23 @@ impl VarU16 {
 
     /// Creates a new variable length u16.
     pub const fn try_new(value: u16) -> Result<Self, VarU16SizeError> {
-        if value > (u16::MAX >> 1) {
+        if value > Self::MAX {
             Err(VarU16SizeError(value))
         } else {
             Ok(Self(value))
         }
     }
 
+    /// Creates a new variable length u16 from a payload length.
+    ///
+    /// Fails if the length is larger than [`VarU16::MAX`].
+    pub fn try_from_len(len: usize) -> Result<Self, EncodeError> {
+        u16::try_from(len)
+            .ok()
+            .and_then(|len| Self::try_new(len).ok())
+            .ok_or(EncodeError::VarShortTooLarge)
+    }
+
     pub fn into_inner(self) -> u16 {
         self.0
     }
@@ -37,7 +50,7 @@ impl VarU16 {
 }
 impl Encode for VarU16 {
     fn encode(&self) -> Result<Vec<u8>, EncodeError> {
-        if self.0 > (u16::MAX >> 1) {
+        if self.0 > Self::MAX {
             return Err(EncodeError::VarShortTooLarge);
         }
 
@@ -107,4 +120,28 @@ mod tests {
         assert_eq!(ENCODED.to_vec(), var.encode().unwrap());
         assert_eq!(VAL, VarU16::decode(ENCODED).unwrap().into_inner())
     }
+
+    #[test]
+    fn boundaries() {
+        for (val, encoded) in [
+            (0x7F, vec![0x7F]),
+            (0x80, vec![0x80, 0x80]),
+            (0x7FFF, vec![0xFF, 0xFF]),
+        ] {
+            let var = VarU16::try_new(val).unwrap();
+            assert_eq!(encoded, var.encode().unwrap());
+            assert_eq!(val, VarU16::decode(encoded).unwrap().into_inner());
+        }
+
+        assert!(VarU16::try_new(0x8000).is_err());
+        assert!(VarU16::try_from_len(0x7FFF).is_ok());
+        assert_eq!(
+            VarU16::try_from_len(0x8000),
+            Err(crate::encode::EncodeError::VarShortTooLarge)
+        );
+        assert_eq!(
+            VarU16::try_from_len(0x1_0000),
+            Err(crate::encode::EncodeError::VarShortTooLarge)
+        );
+    }
 }
@@ -1,7 +1,10 @@
 use crate::decode::{Decode, DecodeError};
 use crate::encode::{Encode, EncodeError};
 
-#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+/// A firmware version.
+///
+/// Versions are ordered by `major`, then `minor`, `build`, and `beta`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub struct Version {
     pub major: u8,
     pub minor: u8,
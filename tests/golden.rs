@@ -0,0 +1,368 @@
+//! Byte-level tests comparing packets against annotated hex fixtures.
+//!
+//! Fixtures in `tests/golden/` are frames captured from real hardware. None have been checked in
+//! yet, so the directory doesn't exist. Fixtures in `tests/roundtrip/` were written by hand from
+//! the documented packet layouts or copied from this crate's unit tests, so they only catch
+//! changes to how this crate encodes and decodes a packet, not disagreements with VEXos. Replace
+//! a round-trip fixture with a capture by moving it into `tests/golden/` once one is available.
+//!
+//! Fixtures are plain text files containing whitespace-separated hex bytes. Anything after a
+//! `#` on a line is a comment. To add a fixture, drop a `.hex` file into one of those directories
+//! and add a line to either the `golden_encode!` or `golden_decode!` table below.
+
+use std::{fs, path::Path, str::FromStr};
+
+use vex_v5_serial::{
+    crc::{verify_cdc2_frame, ChecksumError, VEX_CRC16},
+    decode::Decode,
+    encode::Encode,
+    packets::{
+        capture::{ScreenCapturePacket, ScreenCaptureReplyPacket},
+        cdc2::{Cdc2Ack, Cdc2ReplyMeta},
+        controller::{
+            ControllerVersionExpectPacket, ControllerVersionExpectPayload,
+            ControllerVersionExpectReplyPacket,
+        },
+        device::{GetDeviceStatusPacket, GetDeviceStatusReplyPacket},
+        file::{
+            ExitFileTransferPacket, ExitFileTransferReplyPacket, ExtensionType, FileExitAction,
+            FileInitAction, FileInitOption, FileMetadata, FileTransferTarget, FileVendor,
+            InitFileTransferPacket, InitFileTransferPayload, InitFileTransferReplyPacket,
+            ReadFilePacket, ReadFilePayload, ReadFileReplyPacket, WriteFilePacket,
+            WriteFilePayload, WriteFileReplyPacket,
+        },
+        system::{
+            GetSystemStatusPacket, GetSystemStatusReplyPacket, GetSystemVersionPacket,
+            GetSystemVersionReplyPacket, GetSystemVersionReplyPayload, ProductType,
+        },
+    },
+    string::FixedString,
+    varint::VarU16,
+    version::Version,
+};
+
+/// Directories under `tests/` that hold fixtures.
+const FIXTURE_DIRS: [&str; 2] = ["golden", "roundtrip"];
+
+/// Reads a fixture from `tests/`, ignoring comments and whitespace.
+fn fixture(name: &str) -> Vec<u8> {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join(name);
+    let contents = fs::read_to_string(&path)
+        .unwrap_or_else(|e| panic!("Failed to read fixture {}: {e}", path.display()));
+
+    contents
+        .lines()
+        .map(|line| line.split('#').next().unwrap())
+        .flat_map(str::split_whitespace)
+        .map(|byte| {
+            u8::from_str_radix(byte, 16)
+                .unwrap_or_else(|e| panic!("Invalid byte {byte:?} in fixture {name}: {e}"))
+        })
+        .collect()
+}
+
+/// Formats a byte diff between a fixture and encoded output, 16 bytes per line.
+fn diff(expected: &[u8], actual: &[u8]) -> String {
+    let mut out = String::new();
+    let lines = expected.len().max(actual.len()).div_ceil(16);
+
+    for line in 0..lines {
+        let range = line * 16..(line + 1) * 16;
+        let format = |bytes: &[u8]| {
+            range
+                .clone()
+                .map(|i| bytes.get(i).map_or("  ".to_string(), |b| format!("{b:02x}")))
+                .collect::<Vec<_>>()
+                .join(" ")
+        };
+        let (expected_line, actual_line) = (format(expected), format(actual));
+        let marker = if expected_line == actual_line { ' ' } else { '!' };
+
+        out.push_str(&format!(
+            "{marker} {:04x}: {expected_line} | {actual_line}\n",
+            range.start
+        ));
+    }
+
+    out
+}
+
+/// Asserts that a packet encodes to the exact bytes of a fixture.
+fn check_encode(name: &str, packet: impl Encode) {
+    let expected = fixture(name);
+    let actual = packet.encode().expect("Failed to encode packet");
+
+    assert!(
+        expected == actual,
+        "Encoded packet does not match fixture {name} (expected | actual):\n{}",
+        diff(&expected, &actual)
+    );
+}
+
+/// Asserts that a fixture decodes as the given reply type.
+fn check_decode<D: Decode>(name: &str) -> D {
+    D::decode(fixture(name)).unwrap_or_else(|e| {
+        panic!(
+            "Failed to decode fixture {name} as {}: {e}",
+            std::any::type_name::<D>()
+        )
+    })
+}
+
+/// Appends `extra` to the end of a reply's payload, as if newer firmware had added a field.
+///
+/// The payload size is updated to match, as is the CRC16 of frames that end with one.
+fn extend_payload(frame: &[u8], extra: &[u8]) -> Vec<u8> {
+    let size_len = if VarU16::check_wide(frame[3]) { 2 } else { 1 };
+    let size = VarU16::decode(frame[3..].iter().copied())
+        .unwrap()
+        .into_inner();
+    let body = &frame[3 + size_len..];
+    // CDC2 frames count their CRC in the payload size
+    let is_cdc2 = [0x56, 0x58].contains(&frame[2]);
+    let crc_len = if is_cdc2 { 2 } else { 0 };
+    let (payload, _) = body.split_at(body.len() - crc_len);
+
+    let mut extended = frame[..3].to_vec();
+    let size = VarU16::try_from_len(size as usize + extra.len()).unwrap();
+    extended.extend(size.encode().unwrap());
+    extended.extend(payload);
+    extended.extend(extra);
+    if crc_len != 0 {
+        extended.extend(VEX_CRC16.checksum(&extended).to_be_bytes());
+    }
+    extended
+}
+
+macro_rules! golden_encode {
+    ($($test:ident: $fixture:literal => $packet:expr,)*) => {
+        $(
+            #[test]
+            fn $test() {
+                check_encode($fixture, $packet);
+            }
+        )*
+    };
+}
+
+macro_rules! golden_decode {
+    ($($test:ident: $fixture:literal => $packet:ty,)*) => {
+        $(
+            #[test]
+            fn $test() {
+                let decoded = check_decode::<$packet>($fixture);
+
+                // Replies must still decode the same way if newer firmware appends fields
+                let extended = extend_payload(&fixture($fixture), &[0xEE; 6]);
+                let mut data = extended.into_iter();
+                let extended = <$packet>::decode(&mut data).unwrap_or_else(|e| {
+                    panic!("Failed to decode fixture {} with extra payload bytes: {e}", $fixture)
+                });
+                assert_eq!(
+                    data.len(),
+                    0,
+                    "Decoding fixture {} with extra payload bytes stopped before the end of the frame",
+                    $fixture
+                );
+                assert_eq!(
+                    format!("{:?}", extended.payload),
+                    format!("{:?}", decoded.payload),
+                    "Extra payload bytes changed how fixture {} decodes",
+                    $fixture
+                );
+            }
+        )*
+    };
+}
+
+fn init_write_payload() -> InitFileTransferPayload {
+    InitFileTransferPayload {
+        operation: FileInitAction::Write,
+        target: FileTransferTarget::Qspi,
+        vendor: FileVendor::User,
+        options: FileInitOption::Overwrite,
+        file_size: 8,
+        load_address: 0x03800000,
+        write_file_crc: 0x12345678,
+        metadata: FileMetadata {
+            extension: FixedString::from_str("bin").unwrap(),
+            extension_type: ExtensionType::Binary,
+            timestamp: 0,
+            version: Version {
+                major: 1,
+                minor: 0,
+                build: 0,
+                beta: 0,
+            },
+        },
+        file_name: FixedString::from_str("slot_1.bin").unwrap(),
+    }
+}
+
+golden_encode! {
+    system_version: "roundtrip/system/version.hex" => GetSystemVersionPacket::new(()),
+    system_status: "roundtrip/system/status.hex" => GetSystemStatusPacket::new(()),
+    device_status: "roundtrip/device/status.hex" => GetDeviceStatusPacket::new(()),
+    file_init_write: "roundtrip/file/init_write.hex" => InitFileTransferPacket::new(init_write_payload()),
+    file_write: "roundtrip/file/write.hex" => WriteFilePacket::new(WriteFilePayload {
+        address: 0x03800000,
+        chunk_data: vec![0xde, 0xad, 0xbe, 0xef, 0x01, 0x02, 0x03, 0x04],
+    }),
+    file_exit: "roundtrip/file/exit.hex" => ExitFileTransferPacket::new(FileExitAction::DoNothing),
+    file_read: "roundtrip/file/read.hex" => ReadFilePacket::new(ReadFilePayload {
+        address: 0x03800000,
+        size: 4096,
+    }),
+    screen_capture: "roundtrip/capture/screen.hex" => ScreenCapturePacket::new(()),
+    controller_version_expect: "roundtrip/controller/version_expect.hex" => ControllerVersionExpectPacket::new(
+        ControllerVersionExpectPayload {
+            version: Version {
+                major: 1,
+                minor: 3,
+                build: 0,
+                beta: 0,
+            },
+            product: ProductType::Controller,
+        },
+    ),
+}
+
+golden_decode! {
+    system_status_reply: "roundtrip/system/status_reply.hex" => GetSystemStatusReplyPacket,
+    device_status_reply: "roundtrip/device/status_reply.hex" => GetDeviceStatusReplyPacket,
+    file_init_reply: "roundtrip/file/init_reply.hex" => InitFileTransferReplyPacket,
+    file_write_reply: "roundtrip/file/write_reply.hex" => WriteFileReplyPacket,
+    file_exit_reply: "roundtrip/file/exit_reply.hex" => ExitFileTransferReplyPacket,
+    screen_capture_reply: "roundtrip/capture/screen_reply.hex" => ScreenCaptureReplyPacket,
+    controller_version_expect_reply: "roundtrip/controller/version_expect_reply.hex" => ControllerVersionExpectReplyPacket,
+}
+
+/// Read replies are sized by the amount of data read, so they aren't checked with extra bytes.
+#[test]
+fn file_read_reply_nack() {
+    let reply = check_decode::<ReadFileReplyPacket>("roundtrip/file/read_reply_nack.hex");
+    assert_eq!(reply.ack(), Cdc2Ack::NackUninitializedTransfer);
+}
+
+/// Version replies keep the bytes that newer firmware appends, so they can't be checked by
+/// `golden_decode!`.
+#[test]
+fn system_version_reply() {
+    let decoded =
+        check_decode::<GetSystemVersionReplyPacket>("roundtrip/system/version_reply.hex").payload;
+    assert_eq!(decoded.product_type, ProductType::Brain);
+    assert!(decoded.extra.is_empty());
+
+    let extended = extend_payload(&fixture("roundtrip/system/version_reply.hex"), &[0xEE; 6]);
+    let extended = GetSystemVersionReplyPacket::decode(extended)
+        .unwrap()
+        .payload;
+    assert_eq!(extended.extra, [0xEE; 6]);
+    assert_eq!(
+        GetSystemVersionReplyPayload {
+            extra: Vec::new(),
+            ..extended
+        },
+        decoded
+    );
+}
+
+#[test]
+fn system_status_reply_versions() {
+    let version = Some(Version {
+        major: 1,
+        minor: 1,
+        build: 4,
+        beta: 0,
+    });
+
+    let wired =
+        check_decode::<GetSystemStatusReplyPacket>("roundtrip/system/status_reply.hex").payload;
+    assert_eq!(wired.system_version, version);
+    assert_eq!(wired.cpu0_version, version);
+    assert_eq!(wired.cpu1_version, version);
+    assert_eq!(wired.touch_version, version);
+    let details = wired.details.expect("Wired replies should include details");
+    assert_eq!(details.unique_id, 0x12345678);
+    assert!(details.golden_version.is_some());
+    assert!(details.nxp_version.is_some());
+}
+
+/// Controllers zero out some versions and cut the details short, so the extra payload bytes
+/// checked by `golden_decode!` would be read as details.
+#[test]
+fn system_status_reply_from_controller() {
+    let relayed =
+        check_decode::<GetSystemStatusReplyPacket>("roundtrip/system/status_reply_controller.hex")
+            .payload;
+    assert_eq!(relayed.system_version, None);
+    assert_eq!(
+        relayed.cpu0_version,
+        Some(Version {
+            major: 1,
+            minor: 1,
+            build: 4,
+            beta: 0,
+        })
+    );
+    assert_eq!(relayed.cpu1_version, None);
+    assert_eq!(relayed.touch_version, None);
+    assert_eq!(relayed.details, None);
+
+    #[allow(deprecated)]
+    let cpu1_version = relayed.cpu1_version_or_zero();
+    assert_eq!(
+        cpu1_version,
+        Version {
+            major: 0,
+            minor: 0,
+            build: 0,
+            beta: 0,
+        }
+    );
+}
+
+/// Every CDC2 fixture is a complete frame, so each one's CRC16 should check out.
+#[test]
+fn fixture_checksums() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests");
+    for dir in FIXTURE_DIRS {
+        // A directory without fixtures doesn't exist in a checkout
+        let Ok(groups) = fs::read_dir(root.join(dir)) else {
+            continue;
+        };
+        for group in groups {
+            for file in fs::read_dir(group.unwrap().path()).unwrap() {
+                let path = file.unwrap().path();
+                let name = path.strip_prefix(&root).unwrap().to_str().unwrap();
+                let frame = fixture(name);
+
+                // Simple CDC frames don't have a CRC
+                let id_index = if frame.starts_with(&[0xAA, 0x55]) {
+                    2
+                } else {
+                    4
+                };
+                if ![0x56, 0x58].contains(&frame[id_index]) {
+                    continue;
+                }
+
+                if let Err(e) = verify_cdc2_frame(&frame) {
+                    panic!("Fixture {name} failed CRC verification: {e}");
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn corrupted_frame() {
+    let mut frame = fixture("roundtrip/device/status_reply.hex");
+    frame[8] ^= 0x01;
+    assert!(matches!(
+        verify_cdc2_frame(&frame),
+        Err(ChecksumError::Mismatch { .. })
+    ));
+}